@@ -0,0 +1,72 @@
+use sgx_types::*;
+use std::{mem, ptr, slice};
+
+/*
+    Attestation
+        enclaveのreportをquoteにして，clientがquoteを検証する
+        IAS/DCAPはQuoteProvider/QuoteVerifierを実装すれば差し替えられる
+        SGX simulation modeではMockQuoteProvider/MockQuoteVerifierを使う
+*/
+pub struct Quote {
+    pub bytes: Vec<u8>,
+}
+
+pub trait QuoteProvider {
+    // target info of the quoting enclave, the enclave creates its report for it
+    fn target_info(&self) -> Result<sgx_target_info_t, String>;
+    fn get_quote(&self, report: &sgx_report_t) -> Result<Quote, String>;
+}
+
+pub trait QuoteVerifier {
+    // returns the attested report body when the quote is genuine
+    fn verify(&self, quote: &Quote) -> Result<sgx_report_body_t, String>;
+}
+
+const MOCK_QUOTE_MAGIC: &[u8; 8] = b"PCTMOCKQ";
+
+// mock quote = magic(8byte) + report body, nothing is signed
+pub struct MockQuoteProvider;
+
+impl QuoteProvider for MockQuoteProvider {
+    fn target_info(&self) -> Result<sgx_target_info_t, String> {
+        Ok(sgx_target_info_t::default())
+    }
+
+    fn get_quote(&self, report: &sgx_report_t) -> Result<Quote, String> {
+        let body_bytes = unsafe {
+            slice::from_raw_parts(&report.body as *const sgx_report_body_t as *const u8, mem::size_of::<sgx_report_body_t>())
+        };
+        let mut bytes = MOCK_QUOTE_MAGIC.to_vec();
+        bytes.extend_from_slice(body_bytes);
+        Ok(Quote { bytes })
+    }
+}
+
+// accepts any mock quote, optionally pinned to an expected MRENCLAVE
+pub struct MockQuoteVerifier {
+    pub expected_mr_enclave: Option<sgx_measurement_t>,
+}
+
+impl MockQuoteVerifier {
+    pub fn new() -> Self {
+        MockQuoteVerifier { expected_mr_enclave: None }
+    }
+}
+
+impl QuoteVerifier for MockQuoteVerifier {
+    fn verify(&self, quote: &Quote) -> Result<sgx_report_body_t, String> {
+        let magic_size = MOCK_QUOTE_MAGIC.len();
+        if quote.bytes.len() != magic_size + mem::size_of::<sgx_report_body_t>() || &quote.bytes[..magic_size] != MOCK_QUOTE_MAGIC {
+            return Err("malformed mock quote".to_string());
+        }
+        let body: sgx_report_body_t = unsafe {
+            ptr::read_unaligned(quote.bytes[magic_size..].as_ptr() as *const sgx_report_body_t)
+        };
+        if let Some(expected) = self.expected_mr_enclave {
+            if body.mr_enclave.m != expected.m {
+                return Err("unexpected MRENCLAVE".to_string());
+            }
+        }
+        Ok(body)
+    }
+}
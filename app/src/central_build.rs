@@ -0,0 +1,88 @@
+use sgx_types::*;
+
+use crate::central_data::CentralChunks;
+use crate::ecalls::{check_ecall, begin_central_build, append_central_records, build_central_chunks, take_central_chunk};
+use crate::enc_util::encrypt_central_records;
+use crate::secure_channel::SecureChannel;
+
+// records of the data owner in one append_central_records ECALL
+pub const CENTRAL_RECORDS_PER_BATCH: usize = 100000;
+
+/*
+    Central build
+        the data owner encrypts its raw records (keys, visits or hashes with raw points) under the
+        central session key, and the enclave sorts, chunks and builds the tries or hash sets.
+        the host only relays ciphertext: the record batches in, the encrypted chunks out.
+        the enclave declares the manifest itself, so nothing is uploaded by upload_central_manifest.
+*/
+
+// data owner side, records: the lines of the central data file
+pub fn upload_central_records(
+    eid: sgx_enclave_id_t,
+    records: &[Vec<u8>],
+    encoded_value_size: usize,
+    chunk_format: u64,
+    central_channel: &SecureChannel,
+) -> Result<(), String> {
+    let batches: Vec<Vec<u8>> = encrypt_central_records(records, CENTRAL_RECORDS_PER_BATCH, central_channel);
+
+    /* host side, only the encrypted batches from here */
+    let mut retval = sgx_status_t::SGX_SUCCESS;
+    let mut error: u32 = 0;
+    let result = unsafe { begin_central_build(eid, &mut retval, encoded_value_size, chunk_format, &mut error) };
+    check_ecall("begin_central_build", result, retval, error)?;
+    for (batch_index, batch) in batches.iter().enumerate() {
+        let result = unsafe {
+            append_central_records(eid, &mut retval, batch.as_ptr() as * const u8, batch.len(), batch_index, &mut error)
+        };
+        check_ecall("append_central_records", result, retval, error)?;
+    }
+    Ok(())
+}
+
+// chunks built in the enclave, encrypted under the central session key as the data owner's chunks
+#[derive(Clone, Default, Debug)]
+pub struct EnclaveBuiltCentral {
+    data: Vec<Vec<u8>>,
+}
+
+impl EnclaveBuiltCentral {
+    // the records stay in the enclave, a smaller threashould rebuilds the chunks without the data owner
+    // size_class > 0 pads the chunks as the data owner's chunks (central_data::pad_chunk)
+    pub fn build(eid: sgx_enclave_id_t, threashould: usize, size_class: usize) -> Result<Self, String> {
+        let mut chunk_count: usize = 0;
+        let mut max_chunk_size: usize = 0;
+        let mut retval = sgx_status_t::SGX_SUCCESS;
+        let mut error: u32 = 0;
+        let result = unsafe { build_central_chunks(eid, &mut retval, threashould, size_class, &mut chunk_count, &mut max_chunk_size, &mut error) };
+        check_ecall("build_central_chunks", result, retval, error)?;
+
+        let mut this = EnclaveBuiltCentral { data: Vec::with_capacity(chunk_count) };
+        for chunk_index in 0..chunk_count {
+            let mut chunk: Vec<u8> = vec![0; max_chunk_size];
+            let mut chunk_size: usize = 0;
+            let result = unsafe {
+                take_central_chunk(eid, &mut retval, chunk_index, chunk.as_mut_ptr(), max_chunk_size, &mut chunk_size, &mut error)
+            };
+            check_ecall("take_central_chunk", result, retval, error)?;
+            chunk.truncate(chunk_size);
+            println!("[EnclaveBuilt] r_i encrypted size = {} bytes", chunk.len());
+            this.data.push(chunk);
+        }
+        Ok(this)
+    }
+}
+
+impl CentralChunks for EnclaveBuiltCentral {
+    fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    fn prepare_sgx_data(&self, index: usize) -> &Vec<u8> {
+        &self.data[index]
+    }
+
+    fn manifest(&self, _central_channel: &SecureChannel) -> Option<Vec<u8>> {
+        None
+    }
+}
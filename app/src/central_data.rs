@@ -1,112 +1,416 @@
 use std::vec::Vec;
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 use succinct_trie::trie::Trie;
 use bincode;
 use std::mem;
 
-use crate::enc_util::encrypt_central_data;
+use crate::enc_util::{encrypt_central_data, encrypt_central_manifest};
+use crate::secure_channel::{SecureChannel, sha256};
 
 
 /* rie
     チャンク化しない
 */
 pub type EncodedValue = Vec<u8>;
-const CENTRAL_KEY: u64 = 777;
+
+// chunk format: trie or hashtable (build feature)
+pub const CHUNK_FORMAT_DICTIONARY: u64 = 0;
+// chunk format: sorted keys for the oblivious join
+pub const CHUNK_FORMAT_SORTED_KEYS: u64 = 1;
+// chunk format: visit times of each location for the interval mode
+pub const CHUNK_FORMAT_VISITS: u64 = 2;
+
+// chunk format: trie or hashtable followed by the raw points for exact verification
+pub const CHUNK_FORMAT_VERIFIED_DICTIONARY: u64 = 3;
+
+// interval mode: every record is the location followed by the unixepoch (8 bytes, big endian)
+pub const UNIXEPOCH_BE_SIZE_U8: usize = 8;
+// exact verification: every record is the hash followed by latitude, longitude (f64) and unixepoch (u64), big endian
+pub const RAW_POINT_SIZE_U8: usize = 8 + 8 + UNIXEPOCH_BE_SIZE_U8;
+
+// chunk header: encoded value size (8byte) + chunk format (8byte), validated in the enclave
+pub fn chunk_header(encoded_value_size: usize, chunk_format: u64) -> Vec<u8> {
+    let mut header = (encoded_value_size as u64).to_be_bytes().to_vec();
+    header.extend_from_slice(&chunk_format.to_be_bytes());
+    header
+}
+
+// padded chunk: chunk byte length (8byte) + chunk + zeros, the real length is inside the ciphertext
+pub const CHUNK_LENGTH_SIZE_U8: usize = 8;
+
+// size_class > 0 pads every chunk to exactly size_class bytes and fails when a chunk does not fit,
+// same as chunk_padding::pad in the enclave
+pub fn pad_chunk(bytes: &[u8], size_class: usize) -> Result<Vec<u8>, String> {
+    let framed_size = CHUNK_LENGTH_SIZE_U8 + bytes.len();
+    if size_class > 0 && framed_size > size_class {
+        return Err(format!("a chunk of {} bytes does not fit in the chunk size class of {} bytes, use a larger class or a smaller chunk size", framed_size, size_class));
+    }
+    let padded_size = if size_class == 0 { framed_size } else { size_class };
+    let mut padded: Vec<u8> = Vec::with_capacity(padded_size);
+    padded.extend_from_slice(&(bytes.len() as u64).to_be_bytes());
+    padded.extend_from_slice(bytes);
+    padded.resize(padded_size, 0);
+    Ok(padded)
+}
+
+// key padding: 最後のchunkに最初のchunkの先頭を足して，ほかのchunkと同じthreashould個にする (enclaveのcentral_builderと同じ)
+// 足したrecordもcentral dataなので，hitしても本当のcontact (chunkの結果はORする)
+// weight: 1つのitemが数えるrecordの数 (Visitsはlocationごとの訪問の数)
+fn _pad_last_chunk<T: Copy>(chunks: &mut Vec<Vec<T>>, threashould: usize, weight: impl Fn(T) -> usize) {
+    if chunks.len() < 2 {
+        return;
+    }
+    let last = chunks.pop().unwrap();
+    let mut count: usize = last.iter().map(|item| weight(*item)).sum();
+    let mut borrowed = 0;
+    while count < threashould && borrowed < chunks[0].len() {
+        count += weight(chunks[0][borrowed]);
+        borrowed += 1;
+    }
+    // 最初のchunkのrecordはどれも最後のchunkより小さいので，前に足せばソート済みのまま
+    let mut padded: Vec<T> = chunks[0][..borrowed].to_vec();
+    padded.extend(last);
+    chunks.push(padded);
+}
+
+// Visitsのchunkはlocationの途中で閉じないので訪問の数が違う (enclaveのcentral_builderと同じ)
+// 一番多いchunkにそろえるまで，各chunkの最後のlocationの訪問を別のentryとしてくり返して足す
+// enclaveは続く同じlocationの時刻を重複なしでmergeするので，periodは変わらない
+fn _pad_visit_chunks(chunks: &mut Vec<Vec<(&Vec<u8>, &[u64])>>) {
+    let target = chunks.iter().map(|locations| locations.iter().map(|(_, unixepochs)| unixepochs.len()).sum::<usize>()).max().unwrap_or(0);
+    for locations in chunks.iter_mut() {
+        let mut count: usize = locations.iter().map(|(_, unixepochs)| unixepochs.len()).sum();
+        let (location, unixepochs) = match locations.last() {
+            Some(last) => *last,
+            None => continue,
+        };
+        while count < target {
+            let take = (target - count).min(unixepochs.len());
+            locations.push((location, &unixepochs[..take]));
+            count += take;
+        }
+    }
+}
+
+// sorted records split into chunks of threashould records
+fn _key_chunks(records: &[Vec<u8>], threashould: usize, size_class: usize) -> Vec<Vec<&Vec<u8>>> {
+    let mut chunks: Vec<Vec<&Vec<u8>>> = records.chunks(threashould.max(1)).map(|records| records.iter().collect()).collect();
+    if size_class > 0 {
+        _pad_last_chunk(&mut chunks, threashould, |_| 1);
+    }
+    chunks
+}
+
+// verified dictionary chunk: header + dictionary size (8byte) + dictionary + (hash + raw point) in ascending hash order
+fn _verified_chunk(encoded_value_size: usize, dictionary: Vec<u8>, records: &[&Vec<u8>]) -> Vec<u8> {
+    let mut bytes = chunk_header(encoded_value_size, CHUNK_FORMAT_VERIFIED_DICTIONARY);
+    bytes.extend_from_slice(&(dictionary.len() as u64).to_be_bytes());
+    bytes.extend(dictionary);
+    for record in records.iter() {
+        bytes.extend_from_slice(record);
+    }
+    bytes
+}
+
+// 暗号化済みのchunk列，sgxには1つずつ渡す
+// shared by the worker threads of the contact trace
+pub trait CentralChunks: Send + Sync {
+    fn len(&self) -> usize;
+    fn prepare_sgx_data(&self, index: usize) -> &Vec<u8>;
+    // None: the enclave built the chunks and declared the manifest itself
+    fn manifest(&self, central_channel: &SecureChannel) -> Option<Vec<u8>>;
+}
 
 // vector of binary central data 
 #[derive(Clone, Default, Debug)]
 pub struct CentralTrie {
     data: Vec<Vec<u8>>,
+    digests: Vec<[u8; 32]>,
 }
 
 impl CentralTrie {
     pub fn new() -> Self {
         CentralTrie {
             data: Vec::with_capacity(100),
+            digests: Vec::with_capacity(100),
         }
     }
 
-    pub fn len(&self) -> usize {
-        self.data.len()
-    }
 
-    pub fn prepare_sgx_data(&self, index: usize) -> &Vec<u8> {
-        &self.data[index]
+    // digest of the plaintext chunk is declared in the manifest, the padded chunk is encrypted
+    // returns the padded size, the only chunk size the host sees
+    fn push_chunk(&mut self, bytes: Vec<u8>, size_class: usize, central_channel: &SecureChannel) -> Result<usize, String> {
+        let padded = pad_chunk(&bytes, size_class)?;
+        self.digests.push(sha256(&bytes).unwrap());
+        self.data.push(encrypt_central_data(&padded, self.data.len(), central_channel));
+        Ok(padded.len())
     }
 
-    pub fn from_encoded_data(mut encoded_data: Vec<Vec<u8>>, threashould: usize) -> Self {
+    // size_class > 0: the last chunk is padded with keys of the first chunk, every chunk with zeros (pad_chunk)
+    pub fn from_encoded_data(mut encoded_data: Vec<Vec<u8>>, threashould: usize, encoded_value_size: usize, size_class: usize, central_channel: &SecureChannel) -> Result<Self, String> {
         encoded_data.sort();
 
-        let mut ordered_vec: Vec<EncodedValue> = vec![];
         let mut this = CentralTrie::new();
-        let mut count = 1;
-        for (i, value) in encoded_data.iter().enumerate() {
-            ordered_vec.push(value.clone());
-            if (i+1) % threashould == 0 {
-                println!("{}th trie creation", count);
-                let trie: Trie = Trie::new(&ordered_vec);
-                println!(" r_i (server side chunk data) size = {} bytes", trie.byte_size());
-                let bytes = trie.serialize();
-                this.data.push(encrypt_central_data(&bytes, CENTRAL_KEY));
-                ordered_vec = vec![];
-                count += 1;
-            }
-        }
-        if ordered_vec.len() > 0 {
-            println!("Last trie creation");
+        for (count, keys) in _key_chunks(&encoded_data, threashould, size_class).iter().enumerate() {
+            println!("{}th trie creation", count + 1);
+            let ordered_vec: Vec<EncodedValue> = keys.iter().map(|key| key.to_vec()).collect();
             let trie: Trie = Trie::new(&ordered_vec);
-            println!(" r_i (server side chunk data) size = {} bytes", trie.byte_size());
-            let bytes = trie.serialize();
-            println!("Trie byte len = {}", bytes.len());
-            this.data.push(encrypt_central_data(&bytes, CENTRAL_KEY));
+            let mut bytes = chunk_header(encoded_value_size, CHUNK_FORMAT_DICTIONARY);
+            bytes.extend(trie.serialize());
+            let padded_size = this.push_chunk(bytes, size_class, central_channel)?;
+            println!(" r_i (server side chunk data) padded size = {} bytes", padded_size);
         }
-        this
+        Ok(this)
+    }
+}
+
+impl CentralTrie {
+    // raw_data: hash + raw point, the raw points of a chunk follow its trie
+    pub fn from_raw_data(mut raw_data: Vec<Vec<u8>>, threashould: usize, encoded_value_size: usize, size_class: usize, central_channel: &SecureChannel) -> Result<Self, String> {
+        raw_data.sort();
+
+        let mut this = CentralTrie::new();
+        for records in _key_chunks(&raw_data, threashould, size_class).iter() {
+            let mut keys: Vec<EncodedValue> = records.iter().map(|record| record[..encoded_value_size].to_vec()).collect();
+            keys.dedup();
+            let trie: Trie = Trie::new(&keys);
+            let bytes = _verified_chunk(encoded_value_size, trie.serialize(), records);
+            let padded_size = this.push_chunk(bytes, size_class, central_channel)?;
+            println!("[VerifiedTrie] r_i padded size = {} bytes", padded_size);
+        }
+        Ok(this)
+    }
+}
+
+impl CentralChunks for CentralTrie {
+    fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    fn prepare_sgx_data(&self, index: usize) -> &Vec<u8> {
+        &self.data[index]
+    }
+
+    fn manifest(&self, central_channel: &SecureChannel) -> Option<Vec<u8>> {
+        Some(encrypt_central_manifest(&self.digests, central_channel))
     }
 }
 
 #[derive(Clone, Default, Debug)]
 pub struct CentralHashSet {
     data: Vec<Vec<u8>>,
+    digests: Vec<[u8; 32]>,
 }
 
 impl CentralHashSet {
     pub fn new() -> Self {
         CentralHashSet {
             data: Vec::with_capacity(100),
+            digests: Vec::with_capacity(100),
+        }
+    }
+
+
+    // digest of the plaintext chunk is declared in the manifest, the padded chunk is encrypted
+    // returns the padded size, the only chunk size the host sees
+    fn push_chunk(&mut self, bytes: Vec<u8>, size_class: usize, central_channel: &SecureChannel) -> Result<usize, String> {
+        let padded = pad_chunk(&bytes, size_class)?;
+        self.digests.push(sha256(&bytes).unwrap());
+        self.data.push(encrypt_central_data(&padded, self.data.len(), central_channel));
+        Ok(padded.len())
+    }
+
+    // size_class > 0: the last chunk is padded with keys of the first chunk, every chunk with zeros (pad_chunk)
+    pub fn from_encoded_data(mut encoded_data: Vec<Vec<u8>>, threashould: usize, encoded_value_size: usize, size_class: usize, central_channel: &SecureChannel) -> Result<Self, String> {
+        encoded_data.sort();
+
+        let mut this = CentralHashSet::new();
+        for keys in _key_chunks(&encoded_data, threashould, size_class).iter() {
+            let hashset: HashSet<EncodedValue> = keys.iter().map(|key| key.to_vec()).collect();
+            let mut bytes: Vec<u8> = chunk_header(encoded_value_size, CHUNK_FORMAT_DICTIONARY);
+            bytes.extend(bincode::serialize(&hashset).unwrap());
+            let padded_size = this.push_chunk(bytes, size_class, central_channel)?;
+            println!("[HashSet] r_i padded size = {} bytes", padded_size);
+        }
+        Ok(this)
+    }
+}
+
+impl CentralHashSet {
+    // raw_data: hash + raw point, the raw points of a chunk follow its hash set
+    pub fn from_raw_data(mut raw_data: Vec<Vec<u8>>, threashould: usize, encoded_value_size: usize, size_class: usize, central_channel: &SecureChannel) -> Result<Self, String> {
+        raw_data.sort();
+
+        let mut this = CentralHashSet::new();
+        for records in _key_chunks(&raw_data, threashould, size_class).iter() {
+            let hashset: HashSet<EncodedValue> = records.iter().map(|record| record[..encoded_value_size].to_vec()).collect();
+            let bytes = _verified_chunk(encoded_value_size, bincode::serialize(&hashset).unwrap(), records);
+            let padded_size = this.push_chunk(bytes, size_class, central_channel)?;
+            println!("[VerifiedHashSet] r_i padded size = {} bytes", padded_size);
         }
+        Ok(this)
     }
+}
 
-    pub fn len(&self) -> usize {
+impl CentralChunks for CentralHashSet {
+    fn len(&self) -> usize {
         self.data.len()
     }
 
-    pub fn prepare_sgx_data(&self, index: usize) -> &Vec<u8> {
+    fn prepare_sgx_data(&self, index: usize) -> &Vec<u8> {
         &self.data[index]
     }
 
-    pub fn from_encoded_data(mut encoded_data: Vec<Vec<u8>>, threashould: usize) -> Self {
+    fn manifest(&self, central_channel: &SecureChannel) -> Option<Vec<u8>> {
+        Some(encrypt_central_manifest(&self.digests, central_channel))
+    }
+}
+
+/* 
+    oblivious join用，ソート済みのkeyをそのまま並べる
+    format: header + key_1 + key_2 + ... (encoded value sizeごと)
+*/
+#[derive(Clone, Default, Debug)]
+pub struct CentralSortedKeys {
+    data: Vec<Vec<u8>>,
+    digests: Vec<[u8; 32]>,
+}
+
+impl CentralSortedKeys {
+    pub fn new() -> Self {
+        CentralSortedKeys {
+            data: Vec::with_capacity(100),
+            digests: Vec::with_capacity(100),
+        }
+    }
+
+    pub fn from_encoded_data(mut encoded_data: Vec<Vec<u8>>, threashould: usize, encoded_value_size: usize, size_class: usize, central_channel: &SecureChannel) -> Result<Self, String> {
         encoded_data.sort();
+        encoded_data.dedup();
 
-        let mut hashset: HashSet<[u8; 8]> = HashSet::with_capacity(0);
-        
-        let mut this = CentralHashSet::new();
-        for (i, value) in encoded_data.iter().enumerate() {
-            let mut arr = [0u8; 8];
-            arr.copy_from_slice(value);
-            hashset.insert(arr);
-            if (i+1) % threashould == 0 {
-                let bytes: Vec<u8> = bincode::serialize(&hashset).unwrap();
-                println!("[HashSet] r_i size = {} bytes", bytes.len());
-                this.data.push(encrypt_central_data(&bytes, CENTRAL_KEY));
-                hashset = HashSet::with_capacity(threashould);
+        let mut this = CentralSortedKeys::new();
+        for keys in _key_chunks(&encoded_data, threashould, size_class).iter() {
+            let mut bytes: Vec<u8> = chunk_header(encoded_value_size, CHUNK_FORMAT_SORTED_KEYS);
+            for key in keys.iter() {
+                bytes.extend_from_slice(key);
             }
+            let padded_size = this.push_chunk(bytes, size_class, central_channel)?;
+            println!("[SortedKeys] r_i padded size = {} bytes", padded_size);
         }
-        if hashset.len() > 0 {
-            let bytes: Vec<u8> = bincode::serialize(&hashset).unwrap();
-            println!("[HashSet] r_i size = {} bytes", bytes.len());
-            this.data.push(encrypt_central_data(&bytes, CENTRAL_KEY));
+        Ok(this)
+    }
+
+    // digest of the plaintext chunk is declared in the manifest, the padded chunk is encrypted
+    // returns the padded size, the only chunk size the host sees
+    fn push_chunk(&mut self, bytes: Vec<u8>, size_class: usize, central_channel: &SecureChannel) -> Result<usize, String> {
+        let padded = pad_chunk(&bytes, size_class)?;
+        self.digests.push(sha256(&bytes).unwrap());
+        self.data.push(encrypt_central_data(&padded, self.data.len(), central_channel));
+        Ok(padded.len())
+    }
+}
+
+impl CentralChunks for CentralSortedKeys {
+    fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    fn prepare_sgx_data(&self, index: usize) -> &Vec<u8> {
+        &self.data[index]
+    }
+
+    fn manifest(&self, central_channel: &SecureChannel) -> Option<Vec<u8>> {
+        Some(encrypt_central_manifest(&self.digests, central_channel))
+    }
+}
+
+/* 
+    interval mode用，locationごとに訪問時刻を昇順に並べる
+    format: header + (location + 時刻の数(8byte) + 時刻(8byteずつ)) の繰り返し，locationの昇順
+    periodにまとめるのはenclave側，1つのlocationは1つのchunkに収める
+*/
+#[derive(Clone, Default, Debug)]
+pub struct CentralVisits {
+    data: Vec<Vec<u8>>,
+    digests: Vec<[u8; 32]>,
+}
+
+impl CentralVisits {
+    pub fn new() -> Self {
+        CentralVisits {
+            data: Vec::with_capacity(100),
+            digests: Vec::with_capacity(100),
         }
-        this
+    }
+
+    // threashould: visit times per chunk, a chunk is closed after the location that reaches it
+    // size_class > 0: the last chunk is padded with whole locations of the first chunk,
+    // then every chunk with visits of its last location up to the largest visit count (_pad_visit_chunks)
+    pub fn from_visit_data(visit_data: Vec<Vec<u8>>, threashould: usize, encoded_value_size: usize, size_class: usize, central_channel: &SecureChannel) -> Result<Self, String> {
+        let mut visits: BTreeMap<Vec<u8>, Vec<u64>> = BTreeMap::new();
+        for record in visit_data.iter() {
+            let mut unixepoch_bytes = [0_u8; UNIXEPOCH_BE_SIZE_U8];
+            unixepoch_bytes.copy_from_slice(&record[encoded_value_size..]);
+            _sorted_push(visits.entry(record[..encoded_value_size].to_vec()).or_insert_with(Vec::new), u64::from_be_bytes(unixepoch_bytes));
+        }
+
+        let mut chunks: Vec<Vec<(&Vec<u8>, &[u64])>> = vec![];
+        let mut locations: Vec<(&Vec<u8>, &[u64])> = vec![];
+        let mut visit_count = 0;
+        for (location, unixepochs) in visits.iter() {
+            locations.push((location, &unixepochs[..]));
+            visit_count += unixepochs.len();
+            if visit_count >= threashould {
+                chunks.push(locations);
+                locations = vec![];
+                visit_count = 0;
+            }
+        }
+        if visit_count > 0 {
+            chunks.push(locations);
+        }
+        if size_class > 0 {
+            _pad_last_chunk(&mut chunks, threashould, |(_, unixepochs)| unixepochs.len());
+            _pad_visit_chunks(&mut chunks);
+        }
+
+        let mut this = CentralVisits::new();
+        for locations in chunks.iter() {
+            let mut bytes: Vec<u8> = chunk_header(encoded_value_size, CHUNK_FORMAT_VISITS);
+            for (location, unixepochs) in locations.iter() {
+                bytes.extend_from_slice(location);
+                bytes.extend_from_slice(&(unixepochs.len() as u64).to_be_bytes());
+                for unixepoch in unixepochs.iter() {
+                    bytes.extend_from_slice(&unixepoch.to_be_bytes());
+                }
+            }
+            let padded_size = this.push_chunk(bytes, size_class, central_channel)?;
+            println!("[Visits] r_i padded size = {} bytes", padded_size);
+        }
+        Ok(this)
+    }
+
+    // digest of the plaintext chunk is declared in the manifest, the padded chunk is encrypted
+    // returns the padded size, the only chunk size the host sees
+    fn push_chunk(&mut self, bytes: Vec<u8>, size_class: usize, central_channel: &SecureChannel) -> Result<usize, String> {
+        let padded = pad_chunk(&bytes, size_class)?;
+        self.digests.push(sha256(&bytes).unwrap());
+        self.data.push(encrypt_central_data(&padded, self.data.len(), central_channel));
+        Ok(padded.len())
+    }
+}
+
+impl CentralChunks for CentralVisits {
+    fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    fn prepare_sgx_data(&self, index: usize) -> &Vec<u8> {
+        &self.data[index]
+    }
+
+    fn manifest(&self, central_channel: &SecureChannel) -> Option<Vec<u8>> {
+        Some(encrypt_central_manifest(&self.digests, central_channel))
     }
 }
 
@@ -0,0 +1,76 @@
+use sgx_types::*;
+
+use crate::ecalls::{check_ecall, set_contact_policies};
+
+/*
+    Contact policies
+        rules evaluated by the enclave in one pass over the chunks, bit i of the result byte is policy i
+        same layout as contact_policy_t in the EDL and ContactPolicy in the enclave
+*/
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct ContactPolicy {
+    pub kind: u32,
+    pub parameter: u32,
+    // consecutive matched query points needed, 0 and 1 mean any single point
+    pub min_exposure: u32,
+}
+
+pub const CONTACT_POLICY_DIRECT: u32 = 0;
+pub const CONTACT_POLICY_NEIGHBOR: u32 = 1;
+pub const CONTACT_POLICY_INDIRECT: u32 = 2;
+
+pub const MAX_CONTACT_POLICIES: usize = 8;
+
+// (direct | neighbor | indirect:<time steps>)[@<points>], without @ the policy takes min_exposure
+pub fn parse_contact_policy(spec: &str, min_exposure: u32) -> Result<ContactPolicy, String> {
+    let mut exposure_parts = spec.splitn(2, '@');
+    let rule = exposure_parts.next().unwrap_or("");
+    let min_exposure = match exposure_parts.next() {
+        Some(points) => points.parse::<u32>().map_err(|_| format!("invalid exposure in contact policy {}", spec))?,
+        None => min_exposure,
+    };
+    let mut parts = rule.splitn(2, ':');
+    let kind = parts.next().unwrap_or("");
+    let parameter = parts.next();
+    match (kind, parameter) {
+        ("direct", None) => Ok(ContactPolicy { kind: CONTACT_POLICY_DIRECT, parameter: 0, min_exposure }),
+        ("neighbor", None) => Ok(ContactPolicy { kind: CONTACT_POLICY_NEIGHBOR, parameter: 0, min_exposure }),
+        ("indirect", Some(window)) => match window.parse::<u32>() {
+            Ok(window) if window > 0 => Ok(ContactPolicy { kind: CONTACT_POLICY_INDIRECT, parameter: window, min_exposure }),
+            _ => Err(format!("invalid indirect window in contact policy {}", spec)),
+        },
+        _ => Err(format!("unknown contact policy {}, expect direct|neighbor|indirect:<time steps> and optionally @<points>", spec)),
+    }
+}
+
+impl ContactPolicy {
+    pub fn name(&self) -> String {
+        let rule = match self.kind {
+            CONTACT_POLICY_DIRECT => "direct".to_string(),
+            CONTACT_POLICY_NEIGHBOR => "neighbor".to_string(),
+            _ => format!("indirect:{}", self.parameter),
+        };
+        if self.min_exposure > 1 {
+            format!("{}@{}", rule, self.min_exposure)
+        } else {
+            rule
+        }
+    }
+
+    // shifted copies of the queries the enclave makes for this policy, shared ones are counted for each policy
+    pub fn variant_count(&self) -> usize {
+        match self.kind {
+            CONTACT_POLICY_DIRECT => 1,
+            CONTACT_POLICY_NEIGHBOR => 9,
+            _ => self.parameter as usize,
+        }
+    }
+}
+
+pub fn set_session_policies(eid: sgx_enclave_id_t, session_handle: u64, policies: &[ContactPolicy]) -> Result<(), String> {
+    let mut retval = sgx_status_t::SGX_SUCCESS;
+    let mut error: u32 = 0;
+    let result = unsafe { set_contact_policies(eid, &mut retval, session_handle, policies.as_ptr(), policies.len(), &mut error) };
+    check_ecall("set_contact_policies", result, retval, error)
+}
@@ -1,179 +1,348 @@
 #include "openssl/evp.h"
-
-#define SGXSSL_CTR_BITS	128
-#define SHIFT_BYTE	8
+#include "openssl/ec.h"
+#include "openssl/ecdh.h"
+#include "openssl/ecdsa.h"
+#include "openssl/cmac.h"
+#include "openssl/sha.h"
+#include "openssl/bn.h"
+#include "openssl/rand.h"
+#include <string.h>
 
 typedef unsigned char uint8_t;
 typedef unsigned int uint32_t;
-typedef uint8_t sgx_aes_ctr_128bit_key_t[16];
+
+#define ECP256_KEY_SIZE	32
+#define AESGCM_IV_SIZE	12
+#define AESGCM_MAC_SIZE	16
 
 /*
-* code taken from OpenSSL project.
-* increment counter (128-bit int) by 1
+* SGX represents P-256 coordinates and shared secrets in little endian,
+* OpenSSL big endian numbers have to be reversed.
 */
-static void ctr128_inc(unsigned char *counter)
+static void reverse_bytes(uint8_t *dst, const uint8_t *src, int len)
 {
-	unsigned int n = 16, c = 1;
-
-	do {
-		--n;
-		c += counter[n];
-		counter[n] = (unsigned char)c;
-		c >>= SHIFT_BYTE;
-	} while (n);
+	for (int i = 0; i < len; i++) {
+		dst[i] = src[len - 1 - i];
+	}
 }
 
-/* AES-CTR 128-bit
- * Parameters:
- *   Return:
- *     int - 0 or -1
- *   Inputs:
- *     sgx_aes_128bit_key_t *p_key - Pointer to the key used in encryption/decryption operation
- *     uint8_t *p_src - Pointer to the input stream to be encrypted/decrypted
- *     uint32_t src_len - Length of the input stream to be encrypted/decrypted
- *     uint8_t *p_ctr - Pointer to the counter block
- *     uint32_t ctr_inc_bits - Number of bits in counter to be incremented
- *   Output:
- *     uint8_t *p_dst - Pointer to the cipher text. Size of buffer should be >= src_len.
- */
 extern "C" {
 
-	uint32_t sgx_aes_ctr_encrypt(const sgx_aes_ctr_128bit_key_t *p_key, const uint8_t *p_src,
-									const uint32_t src_len, uint8_t *p_ctr, const uint32_t ctr_inc_bits,
-									uint8_t *p_dst)
+	/* random bytes for iv, from OpenSSL CSPRNG
+	 *   Return:
+	 *     int - 0 or -1
+	 */
+	int random_bytes(uint8_t *p_dst, const uint32_t len)
 	{
+		if ((len > INT_MAX) || (p_dst == NULL)) {
+			return -1;
+		}
+		return (1 == RAND_bytes(p_dst, len)) ? 0 : -1;
+	}
 
-		if ((src_len > INT_MAX) || (p_key == NULL) || (p_src == NULL) || (p_ctr == NULL) || (p_dst == NULL))
-		{
-			return -2;
+	/* ECDH P-256 key pair, little endian as sgx_ec256_private_t / sgx_ec256_public_t
+	 *   Return:
+	 *     int - 0 or -1
+	 */
+	int ecdh_p256_create_key_pair(uint8_t *p_private, uint8_t *p_public_gx, uint8_t *p_public_gy)
+	{
+		if ((p_private == NULL) || (p_public_gx == NULL) || (p_public_gy == NULL)) {
+			return -1;
 		}
 
-		/* SGXSSL based crypto implementation */
 		int ret = -1;
-		int len = 0;
-		EVP_CIPHER_CTX* ptr_ctx = NULL;
+		uint8_t buf[ECP256_KEY_SIZE];
+		EC_KEY* ec_key = NULL;
+		BIGNUM* x = NULL;
+		BIGNUM* y = NULL;
+
+		do {
+			if (!(ec_key = EC_KEY_new_by_curve_name(NID_X9_62_prime256v1))) {
+				break;
+			}
+			if (1 != EC_KEY_generate_key(ec_key)) {
+				break;
+			}
+			x = BN_new();
+			y = BN_new();
+			if (x == NULL || y == NULL) {
+				break;
+			}
+			if (1 != EC_POINT_get_affine_coordinates_GFp(EC_KEY_get0_group(ec_key), EC_KEY_get0_public_key(ec_key), x, y, NULL)) {
+				break;
+			}
+			if (BN_bn2binpad(EC_KEY_get0_private_key(ec_key), buf, ECP256_KEY_SIZE) != ECP256_KEY_SIZE) {
+				break;
+			}
+			reverse_bytes(p_private, buf, ECP256_KEY_SIZE);
+			if (BN_bn2binpad(x, buf, ECP256_KEY_SIZE) != ECP256_KEY_SIZE) {
+				break;
+			}
+			reverse_bytes(p_public_gx, buf, ECP256_KEY_SIZE);
+			if (BN_bn2binpad(y, buf, ECP256_KEY_SIZE) != ECP256_KEY_SIZE) {
+				break;
+			}
+			reverse_bytes(p_public_gy, buf, ECP256_KEY_SIZE);
+			ret = 0;
+		} while (0);
 
-		// OpenSSL assumes that the counter is in the x lower bits of the IV(ivec), and that the
-		// application has full control over overflow and the rest of the IV. This
-		// implementation takes NO responsibility for checking that the counter
-		// doesn't overflow into the rest of the IV when incremented.
-		//
-		if (ctr_inc_bits != SGXSSL_CTR_BITS)
-		{
-			return -3;
+		OPENSSL_cleanse(buf, ECP256_KEY_SIZE);
+		BN_free(x);
+		BN_free(y);
+		EC_KEY_free(ec_key);
+		return ret;
+	}
+
+	/* ECDH P-256 shared secret (x coordinate), little endian as sgx_ec256_dh_shared_t
+	 *   Return:
+	 *     int - 0 or -1
+	 */
+	int ecdh_p256_compute_shared_dhkey(const uint8_t *p_private, const uint8_t *p_peer_gx, const uint8_t *p_peer_gy,
+									uint8_t *p_shared)
+	{
+		if ((p_private == NULL) || (p_peer_gx == NULL) || (p_peer_gy == NULL) || (p_shared == NULL)) {
+			return -1;
 		}
 
+		int ret = -1;
+		uint8_t buf[ECP256_KEY_SIZE];
+		EC_KEY* ec_key = NULL;
+		EC_POINT* peer = NULL;
+		BIGNUM* d = NULL;
+		BIGNUM* x = NULL;
+		BIGNUM* y = NULL;
 
 		do {
-			// Create and init ctx
-			//
-			if (!(ptr_ctx = EVP_CIPHER_CTX_new())) {
-				ret = -4;
+			if (!(ec_key = EC_KEY_new_by_curve_name(NID_X9_62_prime256v1))) {
 				break;
 			}
+			const EC_GROUP* group = EC_KEY_get0_group(ec_key);
 
-			// Initialise encrypt, key
-			//
-			if (1 != EVP_EncryptInit_ex(ptr_ctx, EVP_aes_128_ctr(), NULL, (unsigned char*)p_key, p_ctr)) {
+			reverse_bytes(buf, p_private, ECP256_KEY_SIZE);
+			d = BN_bin2bn(buf, ECP256_KEY_SIZE, NULL);
+			reverse_bytes(buf, p_peer_gx, ECP256_KEY_SIZE);
+			x = BN_bin2bn(buf, ECP256_KEY_SIZE, NULL);
+			reverse_bytes(buf, p_peer_gy, ECP256_KEY_SIZE);
+			y = BN_bin2bn(buf, ECP256_KEY_SIZE, NULL);
+			if (d == NULL || x == NULL || y == NULL) {
 				break;
 			}
-
-			// Provide the message to be encrypted, and obtain the encrypted output.
-			//
-			if (1 != EVP_EncryptUpdate(ptr_ctx, p_dst, &len, p_src, src_len)) {
+			if (1 != EC_KEY_set_private_key(ec_key, d)) {
+				break;
+			}
+			if (!(peer = EC_POINT_new(group))) {
+				break;
+			}
+			// rejects points which are not on the curve
+			if (1 != EC_POINT_set_affine_coordinates_GFp(group, peer, x, y, NULL)) {
 				break;
 			}
+			if (ECDH_compute_key(buf, ECP256_KEY_SIZE, peer, ec_key, NULL) != ECP256_KEY_SIZE) {
+				break;
+			}
+			reverse_bytes(p_shared, buf, ECP256_KEY_SIZE);
+			ret = 0;
+		} while (0);
 
-			// Finalise the encryption
-			//
-			if (1 != EVP_EncryptFinal_ex(ptr_ctx, p_dst + len, &len)) {
+		OPENSSL_cleanse(buf, ECP256_KEY_SIZE);
+		BN_clear_free(d);
+		BN_free(x);
+		BN_free(y);
+		EC_POINT_free(peer);
+		EC_KEY_free(ec_key);
+		return ret;
+	}
+
+	/* ECDSA P-256 over SHA-256 of the data, little endian r and s as sgx_ec256_signature_t x and y
+	 *   Return:
+	 *     int - 0 or -1
+	 */
+	int ecdsa_p256_sign(const uint8_t *p_private, const uint8_t *p_data, const uint32_t data_len,
+						uint8_t *p_signature_x, uint8_t *p_signature_y)
+	{
+		if ((p_private == NULL) || (p_data == NULL) || (p_signature_x == NULL) || (p_signature_y == NULL)) {
+			return -1;
+		}
+
+		int ret = -1;
+		uint8_t buf[ECP256_KEY_SIZE];
+		uint8_t digest[SHA256_DIGEST_LENGTH];
+		EC_KEY* ec_key = NULL;
+		BIGNUM* d = NULL;
+		ECDSA_SIG* sig = NULL;
+
+		do {
+			if (!(ec_key = EC_KEY_new_by_curve_name(NID_X9_62_prime256v1))) {
+				break;
+			}
+			reverse_bytes(buf, p_private, ECP256_KEY_SIZE);
+			if (!(d = BN_bin2bn(buf, ECP256_KEY_SIZE, NULL))) {
+				break;
+			}
+			if (1 != EC_KEY_set_private_key(ec_key, d)) {
+				break;
+			}
+			SHA256(p_data, data_len, digest);
+			if (!(sig = ECDSA_do_sign(digest, SHA256_DIGEST_LENGTH, ec_key))) {
+				break;
+			}
+			const BIGNUM* r = NULL;
+			const BIGNUM* s = NULL;
+			ECDSA_SIG_get0(sig, &r, &s);
+			if (BN_bn2binpad(r, buf, ECP256_KEY_SIZE) != ECP256_KEY_SIZE) {
+				break;
+			}
+			reverse_bytes(p_signature_x, buf, ECP256_KEY_SIZE);
+			if (BN_bn2binpad(s, buf, ECP256_KEY_SIZE) != ECP256_KEY_SIZE) {
 				break;
 			}
+			reverse_bytes(p_signature_y, buf, ECP256_KEY_SIZE);
+			ret = 0;
+		} while (0);
+
+		OPENSSL_cleanse(buf, ECP256_KEY_SIZE);
+		BN_clear_free(d);
+		ECDSA_SIG_free(sig);
+		EC_KEY_free(ec_key);
+		return ret;
+	}
 
-			// Encryption success, increment counter
-			//
-			len = src_len;
-			while (len >= 0) {
-				ctr128_inc(p_ctr);
-				len -= 16;
+	/* AES-CMAC 128-bit
+	 *   Return:
+	 *     int - 0 or -1
+	 */
+	int aes_cmac_128(const uint8_t *p_key, const uint8_t *p_src, const uint32_t src_len, uint8_t *p_mac)
+	{
+		if ((src_len > INT_MAX) || (p_key == NULL) || (p_src == NULL) || (p_mac == NULL)) {
+			return -1;
+		}
+
+		int ret = -1;
+		size_t mac_len = 0;
+		CMAC_CTX* ptr_ctx = NULL;
+
+		do {
+			if (!(ptr_ctx = CMAC_CTX_new())) {
+				break;
+			}
+			if (1 != CMAC_Init(ptr_ctx, p_key, 16, EVP_aes_128_cbc(), NULL)) {
+				break;
+			}
+			if (1 != CMAC_Update(ptr_ctx, p_src, src_len)) {
+				break;
+			}
+			if (1 != CMAC_Final(ptr_ctx, p_mac, &mac_len)) {
+				break;
 			}
 			ret = 0;
 		} while (0);
 
-		//clean up ctx and return
-		//
 		if (ptr_ctx) {
-			EVP_CIPHER_CTX_free(ptr_ctx);
+			CMAC_CTX_free(ptr_ctx);
 		}
 		return ret;
 	}
 
-	uint32_t sgx_aes_ctr_decrypt(const sgx_aes_ctr_128bit_key_t *p_key, const uint8_t *p_src,
-                                const uint32_t src_len, uint8_t *p_ctr, const uint32_t ctr_inc_bits,
-									uint8_t *p_dst)
+	/* SHA-256
+	 *   Return:
+	 *     int - 0 or -1
+	 */
+	int sha256_digest(const uint8_t *p_src, const uint32_t src_len, uint8_t *p_hash)
 	{
+		if ((p_src == NULL) || (p_hash == NULL)) {
+			return -1;
+		}
+		SHA256(p_src, src_len, p_hash);
+		return 0;
+	}
 
-		if ((src_len > INT_MAX) || (p_key == NULL) || (p_src == NULL) || (p_ctr == NULL) || (p_dst == NULL)) {
-			return -2;
+	/* AES-GCM 128-bit, 12 byte iv and 16 byte mac
+	 *   Return:
+	 *     int - 0 or -1 (-1 also when the mac does not match)
+	 */
+	int aes_gcm_128_encrypt(const uint8_t *p_key, const uint8_t *p_src, const uint32_t src_len,
+							const uint8_t *p_iv, const uint8_t *p_aad, const uint32_t aad_len,
+							uint8_t *p_dst, uint8_t *p_mac)
+	{
+		if ((src_len > INT_MAX) || (aad_len > INT_MAX) || (p_key == NULL) || (p_iv == NULL) || (p_mac == NULL)
+			|| ((src_len > 0) && ((p_src == NULL) || (p_dst == NULL))) || ((aad_len > 0) && (p_aad == NULL))) {
+			return -1;
 		}
 
-		/* SGXSSL based crypto implementation */
-		uint32_t ret = -1;
+		int ret = -1;
 		int len = 0;
 		EVP_CIPHER_CTX* ptr_ctx = NULL;
 
-		// OpenSSL assumes that the counter is in the x lower bits of the IV(ivec), and that the
-		// application has full control over overflow and the rest of the IV. This
-		// implementation takes NO responsibility for checking that the counter
-		// doesn't overflow into the rest of the IV when incremented.
-		//
-		if (ctr_inc_bits != SGXSSL_CTR_BITS) {
-			return -2;
+		do {
+			if (!(ptr_ctx = EVP_CIPHER_CTX_new())) {
+				break;
+			}
+			if (1 != EVP_EncryptInit_ex(ptr_ctx, EVP_aes_128_gcm(), NULL, p_key, p_iv)) {
+				break;
+			}
+			if ((aad_len > 0) && (1 != EVP_EncryptUpdate(ptr_ctx, NULL, &len, p_aad, aad_len))) {
+				break;
+			}
+			if ((src_len > 0) && (1 != EVP_EncryptUpdate(ptr_ctx, p_dst, &len, p_src, src_len))) {
+				break;
+			}
+			if (1 != EVP_EncryptFinal_ex(ptr_ctx, p_dst + len, &len)) {
+				break;
+			}
+			if (1 != EVP_CIPHER_CTX_ctrl(ptr_ctx, EVP_CTRL_GCM_GET_TAG, AESGCM_MAC_SIZE, p_mac)) {
+				break;
+			}
+			ret = 0;
+		} while (0);
+
+		if (ptr_ctx) {
+			EVP_CIPHER_CTX_free(ptr_ctx);
+		}
+		return ret;
+	}
+
+	int aes_gcm_128_decrypt(const uint8_t *p_key, const uint8_t *p_src, const uint32_t src_len,
+							const uint8_t *p_iv, const uint8_t *p_aad, const uint32_t aad_len,
+							const uint8_t *p_mac, uint8_t *p_dst)
+	{
+		if ((src_len > INT_MAX) || (aad_len > INT_MAX) || (p_key == NULL) || (p_iv == NULL) || (p_mac == NULL)
+			|| ((src_len > 0) && ((p_src == NULL) || (p_dst == NULL))) || ((aad_len > 0) && (p_aad == NULL))) {
+			return -1;
 		}
 
+		int ret = -1;
+		int len = 0;
+		uint8_t mac[AESGCM_MAC_SIZE];
+		EVP_CIPHER_CTX* ptr_ctx = NULL;
+
+		memcpy(mac, p_mac, AESGCM_MAC_SIZE);
 		do {
-			// Create and initialise the context
-			//
 			if (!(ptr_ctx = EVP_CIPHER_CTX_new())) {
-				ret = -3;
 				break;
 			}
-
-			// Initialise decrypt, key and CTR
-			//
-			if (!EVP_DecryptInit_ex(ptr_ctx, EVP_aes_128_ctr(), NULL, (unsigned char*)p_key, p_ctr)) {
+			if (1 != EVP_DecryptInit_ex(ptr_ctx, EVP_aes_128_gcm(), NULL, p_key, p_iv)) {
 				break;
 			}
-
-			// Decrypt message, obtain the plaintext output
-			//
-			if (!EVP_DecryptUpdate(ptr_ctx, p_dst, &len, p_src, src_len)) {
+			if ((aad_len > 0) && (1 != EVP_DecryptUpdate(ptr_ctx, NULL, &len, p_aad, aad_len))) {
 				break;
 			}
-
-			// Finalise the decryption. A positive return value indicates success,
-			// anything else is a failure - the plaintext is not trustworthy.
-			//
-			if (EVP_DecryptFinal_ex(ptr_ctx, p_dst + len, &len) <= 0) { // same notes as above - you can't write beyond src_len
+			if ((src_len > 0) && (1 != EVP_DecryptUpdate(ptr_ctx, p_dst, &len, p_src, src_len))) {
 				break;
 			}
-			// Success
-			// Increment counter
-			//
-			len = src_len;
-			while (len >= 0) {
-				ctr128_inc(p_ctr);
-				len -= 16;
+			if (1 != EVP_CIPHER_CTX_ctrl(ptr_ctx, EVP_CTRL_GCM_SET_TAG, AESGCM_MAC_SIZE, mac)) {
+				break;
+			}
+			// the plaintext must not be used unless the tag matches
+			if (EVP_DecryptFinal_ex(ptr_ctx, p_dst + len, &len) <= 0) {
+				break;
 			}
 			ret = 0;
 		} while (0);
 
-		//cleanup ctx, and return
-		//
 		if (ptr_ctx) {
 			EVP_CIPHER_CTX_free(ptr_ctx);
 		}
 		return ret;
 	}
 
-}
\ No newline at end of file
+}
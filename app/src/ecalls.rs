@@ -1,28 +1,282 @@
 use sgx_types::*;
 use sgx_urts::SgxEnclave;
+use crate::enclave_error::describe_enclave_error;
+use crate::memory_budget::MemoryBudget;
+use crate::contact_policy::ContactPolicy;
 
 static ENCLAVE_FILE: &'static str = "bin/enclave.signed.so";
 
 extern {
+    pub fn set_join_mode(
+        eid: sgx_enclave_id_t,
+        retval: *mut sgx_status_t,
+        join_mode: u8,
+        error: *mut u32,
+    ) -> sgx_status_t;
+
+    pub fn get_memory_budget(
+        eid: sgx_enclave_id_t,
+        retval: *mut sgx_status_t,
+        encoded_value_size: usize,
+        budget: *mut MemoryBudget,
+        error: *mut u32,
+    ) -> sgx_status_t;
+
+    pub fn open_session(
+        eid: sgx_enclave_id_t,
+        retval: *mut sgx_status_t,
+        session_handle: *mut u64,
+        error: *mut u32,
+    ) -> sgx_status_t;
+
+    pub fn close_session(
+        eid: sgx_enclave_id_t,
+        retval: *mut sgx_status_t,
+        session_handle: u64,
+        error: *mut u32,
+    ) -> sgx_status_t;
+
+    pub fn client_key_exchange(
+        eid: sgx_enclave_id_t,
+        retval: *mut sgx_status_t,
+        session_handle: u64,
+        query_id: u64,
+        client_public_key: *const sgx_ec256_public_t,
+        identity_key: *const sgx_ec256_public_t,
+        identity_signature: *const sgx_ec256_signature_t,
+        identity_certificate: *const sgx_ec256_signature_t,
+        target_info: *const sgx_target_info_t,
+        enclave_public_key: *mut sgx_ec256_public_t,
+        report: *mut sgx_report_t,
+        wrapped_key: *mut u8,
+        wrapped_key_size: usize,
+        session_id: *mut u64,
+        error: *mut u32,
+    ) -> sgx_status_t;
+
+    pub fn central_key_exchange(
+        eid: sgx_enclave_id_t,
+        retval: *mut sgx_status_t,
+        client_public_key: *const sgx_ec256_public_t,
+        owner_signature: *const sgx_ec256_signature_t,
+        target_info: *const sgx_target_info_t,
+        enclave_public_key: *mut sgx_ec256_public_t,
+        report: *mut sgx_report_t,
+        wrapped_key: *mut u8,
+        wrapped_key_size: usize,
+        session_id: *mut u64,
+        error: *mut u32,
+    ) -> sgx_status_t;
+
+    pub fn begin_upload(
+        eid: sgx_enclave_id_t,
+        retval: *mut sgx_status_t,
+        session_handle: u64,
+        client_size: usize,
+        encoded_value_size: usize,
+        error: *mut u32,
+    ) -> sgx_status_t;
+
+    pub fn set_exact_thresholds(
+        eid: sgx_enclave_id_t,
+        retval: *mut sgx_status_t,
+        session_handle: u64,
+        max_distance_meters: f64,
+        max_time_difference: u64,
+        error: *mut u32,
+    ) -> sgx_status_t;
+
+    pub fn set_contact_policies(
+        eid: sgx_enclave_id_t,
+        retval: *mut sgx_status_t,
+        session_handle: u64,
+        policies: *const ContactPolicy,
+        policy_count: usize,
+        error: *mut u32,
+    ) -> sgx_status_t;
+
+    pub fn append_queries(
+        eid: sgx_enclave_id_t,
+        retval: *mut sgx_status_t,
+        session_handle: u64,
+        query_data: * const u8,
+        query_data_size: usize,
+        client_size: usize,
+        query_id_list: * const u64,
+        error: *mut u32,
+    ) -> sgx_status_t;
+
+    pub fn finish_upload(
+        eid: sgx_enclave_id_t,
+        retval: *mut sgx_status_t,
+        session_handle: u64,
+        error: *mut u32,
+    ) -> sgx_status_t;
+
+    pub fn restart_chunks(
+        eid: sgx_enclave_id_t,
+        retval: *mut sgx_status_t,
+        session_handle: u64,
+        error: *mut u32,
+    ) -> sgx_status_t;
+
+    pub fn set_resident_cache(
+        eid: sgx_enclave_id_t,
+        retval: *mut sgx_status_t,
+        capacity: usize,
+        error: *mut u32,
+    ) -> sgx_status_t;
+
+    pub fn query_resident(
+        eid: sgx_enclave_id_t,
+        retval: *mut sgx_status_t,
+        session_handle: u64,
+        error: *mut u32,
+    ) -> sgx_status_t;
+
     pub fn upload_encoded_query_data(
         eid: sgx_enclave_id_t, retval: *mut sgx_status_t,
+        session_handle: u64,
         total_query_data: * const u8, total_size: usize,
-        client_size: usize, query_id_list: * const u64
+        client_size: usize, query_id_list: * const u64,
+        encoded_value_size: usize,
+        error: *mut u32,
     ) -> sgx_status_t;
     
     
+    pub fn upload_central_manifest(
+        eid: sgx_enclave_id_t,
+        retval: *mut sgx_status_t,
+        manifest: * const u8,
+        manifest_size: usize,
+        error: *mut u32,
+    ) -> sgx_status_t;
+
     pub fn private_encode_contact_trace(
+        eid: sgx_enclave_id_t,
+        retval: *mut sgx_status_t,
+        session_handle: u64,
+        encoded_value_u8: * const u8,
+        encoded_value_u8_size: usize,
+        chunk_index: usize,
+        error: *mut u32,
+    ) -> sgx_status_t;
+
+    pub fn begin_central_build(
+        eid: sgx_enclave_id_t,
+        retval: *mut sgx_status_t,
+        encoded_value_size: usize,
+        chunk_format: u64,
+        error: *mut u32,
+    ) -> sgx_status_t;
+
+    pub fn append_central_records(
+        eid: sgx_enclave_id_t,
+        retval: *mut sgx_status_t,
+        records: * const u8,
+        records_size: usize,
+        batch_index: usize,
+        error: *mut u32,
+    ) -> sgx_status_t;
+
+    pub fn build_central_chunks(
+        eid: sgx_enclave_id_t,
+        retval: *mut sgx_status_t,
+        threashould: usize,
+        size_class: usize,
+        chunk_count: *mut usize,
+        max_chunk_size: *mut usize,
+        error: *mut u32,
+    ) -> sgx_status_t;
+
+    pub fn take_central_chunk(
+        eid: sgx_enclave_id_t,
+        retval: *mut sgx_status_t,
+        chunk_index: usize,
+        chunk: *mut u8,
+        chunk_buffer_size: usize,
+        chunk_size: *mut usize,
+        error: *mut u32,
+    ) -> sgx_status_t;
+
+    pub fn seal_central_manifest(
+        eid: sgx_enclave_id_t,
+        retval: *mut sgx_status_t,
+        key_policy: u16,
+        sealed: *mut u8,
+        sealed_buffer_size: usize,
+        sealed_size: *mut usize,
+        error: *mut u32,
+    ) -> sgx_status_t;
+
+    pub fn seal_central_chunk(
         eid: sgx_enclave_id_t,
         retval: *mut sgx_status_t,
         encoded_value_u8: * const u8,
         encoded_value_u8_size: usize,
+        chunk_index: usize,
+        key_policy: u16,
+        sealed: *mut u8,
+        sealed_buffer_size: usize,
+        sealed_size: *mut usize,
+        error: *mut u32,
+    ) -> sgx_status_t;
+
+    pub fn seal_rate_limits(
+        eid: sgx_enclave_id_t,
+        retval: *mut sgx_status_t,
+        key_policy: u16,
+        sealed: *mut u8,
+        sealed_buffer_size: usize,
+        sealed_size: *mut usize,
+        error: *mut u32,
+    ) -> sgx_status_t;
+
+    pub fn load_rate_limits(
+        eid: sgx_enclave_id_t,
+        retval: *mut sgx_status_t,
+        sealed: *const u8,
+        sealed_size: usize,
+        error: *mut u32,
+    ) -> sgx_status_t;
+
+    pub fn load_sealed_manifest(
+        eid: sgx_enclave_id_t,
+        retval: *mut sgx_status_t,
+        sealed: * const u8,
+        sealed_size: usize,
+        error: *mut u32,
+    ) -> sgx_status_t;
+
+    pub fn private_sealed_contact_trace(
+        eid: sgx_enclave_id_t,
+        retval: *mut sgx_status_t,
+        session_handle: u64,
+        sealed: * const u8,
+        sealed_size: usize,
+        chunk_index: usize,
+        error: *mut u32,
     ) -> sgx_status_t;
 
     pub fn get_encoded_result(
         eid: sgx_enclave_id_t,
         retval: *mut sgx_status_t,
+        session_handle: u64,
         response: *mut u8,
         response_size: usize,
+        error: *mut u32,
+    ) -> sgx_status_t;
+
+    pub fn get_exposure_statistics(
+        eid: sgx_enclave_id_t,
+        retval: *mut sgx_status_t,
+        session_handle: u64,
+        epsilon: f64,
+        region_bits: u32,
+        period_bits: u32,
+        statistics: *mut u8,
+        statistics_size: usize,
+        error: *mut u32,
     ) -> sgx_status_t;
 }
 
@@ -39,3 +293,12 @@ pub fn init_enclave() -> SgxResult<SgxEnclave> {
                        &mut launch_token_updated,
                        &mut misc_attr)
 }
+
+// result: ECALL itself, retval: status returned by the enclave, error: EnclaveError code set by the enclave
+pub fn check_ecall(name: &str, result: sgx_status_t, retval: sgx_status_t, error: u32) -> Result<(), String> {
+    match (result, retval) {
+        (sgx_status_t::SGX_SUCCESS, sgx_status_t::SGX_SUCCESS) => Ok(()),
+        (sgx_status_t::SGX_SUCCESS, _) => Err(format!("{} rejected by enclave: {} ({})", name, describe_enclave_error(error), retval.as_str())),
+        _ => Err(format!("{} Failed {}!", name, result.as_str())),
+    }
+}
@@ -1,80 +1,76 @@
 use hex;
-use util::*;
+use secure_channel::{SecureChannel, MANIFEST_MESSAGE_ID, CENTRAL_RECORDS_MESSAGE_ID_BASE, message_aad, encrypt_message};
 
 // バファリングするクエリはせいぜい10000なので64bitで余裕
 pub type QueryId = u64;
 
-pub fn encrypt_to_flat_vec_u8(query_data: &Vec<Vec<Vec<u8>>>, query_id_list: &Vec<u64>) -> Vec<u8> {
+pub fn encrypt_to_flat_vec_u8(query_data: &[Vec<Vec<u8>>], query_id_list: &[QueryId], channels: &[SecureChannel]) -> Vec<u8> {
     let mut u8_vec_list: Vec<Vec<u8>> = Vec::with_capacity(query_data.len());
-    query_data.iter().zip(query_id_list).for_each(|(detail, query_id)| {
+    query_data.iter().zip(query_id_list).zip(channels).for_each(|((detail, query_id), channel)| {
         // encrypt by session key as secure channel to enclave.
-        u8_vec_list.push(encypt_as_secure_channel_by_query_id(detail, *query_id));
+        u8_vec_list.push(encypt_as_secure_channel(detail, *query_id, channel));
     });
     let total_u8_vec: Vec<u8> = flatten(u8_vec_list);
     total_u8_vec
 }
 
-pub fn encrypt_central_data(central_byte_data: &Vec<u8>, key: u64) -> Vec<u8> {
-    /* Remote Attestation Mock up */
-    // Remote attestation is done and session (shared) key has been exchanged.
-    // Here, suppose that shared key is simply derived from their query_id.
-
-    let mut shared_key: [u8; 16] = [0; 16];
-    shared_key[..8].copy_from_slice(&key.to_be_bytes());
-    let counter_block: [u8; 16] = COUNTER_BLOCK;
-    let ctr_inc_bits: u32 = SGXSSL_CTR_BITS;
-    let src_len: usize = central_byte_data.len();
-    let mut encrypted_buf: Vec<u8> = vec![0; src_len];
-
-    let ret = unsafe { 
-        sgx_aes_ctr_encrypt(
-            &shared_key,
-            central_byte_data.as_ptr() as * const u8,
-            src_len as u32,
-            &counter_block as * const u8,
-            ctr_inc_bits,
-            encrypted_buf.as_mut_ptr()
-        )
-    };
-
-    if ret < 0 {
-        println!("Error in CTR encryption.");
-        std::process::exit(-1);
+pub fn encrypt_central_data(central_byte_data: &Vec<u8>, chunk_index: usize, channel: &SecureChannel) -> Vec<u8> {
+    // session key has been exchanged by the data owner's key exchange with the enclave.
+    // chunk index is bound as AAD so that chunks cannot be swapped.
+    match encrypt_message(&channel.key, central_byte_data, &message_aad(channel.session_id, chunk_index as u64)) {
+        Ok(encrypted) => encrypted,
+        Err(message) => {
+            println!("{}", message);
+            std::process::exit(-1);
+        }
     }
-    encrypted_buf
 }
 
-fn encypt_as_secure_channel_by_query_id(detail: &Vec<Vec<u8>>, query_id: u64) -> Vec<u8> {
-    /* Remote Attestation Mock up */
-    // Remote attestation is done and session (shared) key has been exchanged.
-    // Here, suppose that shared key is simply derived from their query_id.
-   
-    let mut u8_vec: Vec<u8> = flatten(detail.clone());
-    let mut query_size = detail.len();
+// manifest format: chunk count(8byte) + sha256 digest of each plaintext chunk(32byte)
+pub fn encrypt_central_manifest(digests: &Vec<[u8; 32]>, channel: &SecureChannel) -> Vec<u8> {
+    let mut manifest: Vec<u8> = (digests.len() as u64).to_be_bytes().to_vec();
+    for digest in digests.iter() {
+        manifest.extend_from_slice(digest);
+    }
+    match encrypt_message(&channel.key, &manifest, &message_aad(channel.session_id, MANIFEST_MESSAGE_ID)) {
+        Ok(encrypted) => encrypted,
+        Err(message) => {
+            println!("{}", message);
+            std::process::exit(-1);
+        }
+    }
+}
 
-    let mut shared_key: [u8; 16] = [0; 16];
-    shared_key[..8].copy_from_slice(&query_id.to_be_bytes());
-    let counter_block: [u8; 16] = COUNTER_BLOCK;
-    let ctr_inc_bits: u32 = SGXSSL_CTR_BITS;
-    let src_len: usize = query_size*ENCODEDVALUE_SIZE;
-    let mut encrypted_buf: Vec<u8> = vec![0; src_len];
+// record batch format: batch count(8byte) + records, for the chunks built in the enclave
+// the batch count is encrypted too, so the host cannot drop a batch unnoticed
+pub fn encrypt_central_records(records: &[Vec<u8>], records_per_batch: usize, channel: &SecureChannel) -> Vec<Vec<u8>> {
+    let batches: Vec<&[Vec<u8>]> = records.chunks(records_per_batch.max(1)).collect();
+    batches.iter().enumerate().map(|(batch_index, batch)| {
+        let mut plaintext: Vec<u8> = (batches.len() as u64).to_be_bytes().to_vec();
+        for record in batch.iter() {
+            plaintext.extend_from_slice(record);
+        }
+        match encrypt_message(&channel.key, &plaintext, &message_aad(channel.session_id, CENTRAL_RECORDS_MESSAGE_ID_BASE + batch_index as u64)) {
+            Ok(encrypted) => encrypted,
+            Err(message) => {
+                println!("{}", message);
+                std::process::exit(-1);
+            }
+        }
+    }).collect()
+}
 
-    let ret = unsafe { 
-        sgx_aes_ctr_encrypt(
-            &shared_key,
-            u8_vec.as_ptr() as * const u8,
-            src_len as u32,
-            &counter_block as * const u8,
-            ctr_inc_bits,
-            encrypted_buf.as_mut_ptr()
-        )
-    };
+fn encypt_as_secure_channel(detail: &Vec<Vec<u8>>, query_id: QueryId, channel: &SecureChannel) -> Vec<u8> {
+    // session key has been exchanged by the client's key exchange with the enclave.
+    let u8_vec: Vec<u8> = flatten(detail.clone());
 
-    if ret < 0 {
-        println!("Error in CTR encryption.");
-        std::process::exit(-1);
+    match encrypt_message(&channel.key, &u8_vec, &message_aad(channel.session_id, query_id)) {
+        Ok(encrypted) => encrypted,
+        Err(message) => {
+            println!("{}", message);
+            std::process::exit(-1);
+        }
     }
-    encrypted_buf
 }
 
 fn flatten(nested: Vec<Vec<u8>>) -> Vec<u8> {
@@ -84,4 +80,4 @@ fn flatten(nested: Vec<Vec<u8>>) -> Vec<u8> {
 fn hex_string_to_u8(hex_string: &String) -> Vec<u8> {
     let decoded = hex::decode(hex_string).expect("Decoding failed: Expect hex string!");
     decoded
-}
\ No newline at end of file
+}
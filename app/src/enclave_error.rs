@@ -0,0 +1,55 @@
+pub const ENCLAVE_ERROR_NOT_RESIDENT: u32 = 18;
+pub const ENCLAVE_ERROR_OUT_OF_MEMORY: u32 = 40;
+
+/*
+    Error codes written by the enclave to the [out] error parameter of every ECALL
+    Same values as EnclaveError in enclave/src/enclave_error.rs
+*/
+pub fn describe_enclave_error(error: u32) -> &'static str {
+    match error {
+        0 => "success",
+
+        1 => "invalid buffer length",
+        2 => "invalid encoded value size",
+        3 => "unknown join mode",
+        4 => "unknown seal key policy",
+        5 => "invalid contact policy (unknown kind, bad window, too many, or not usable with the join mode)",
+        6 => "invalid exact distance threshold",
+        7 => "invalid exposure statistics request (epsilon or cell bits)",
+        8 => "the keys are not TrajectoryHash keys of 7 bytes (24 geohash bits, 7 time bits), which this mode needs",
+
+        10 => "unknown session handle",
+        11 => "too many open sessions",
+        12 => "the session is not in the right state for this call",
+        13 => "no key exchange for the query id in this session",
+        14 => "fewer clients were appended than declared in begin_upload",
+        15 => "no key exchange with the data owner",
+        16 => "no chunk manifest has been uploaded or loaded",
+        17 => "not every chunk in the manifest has been processed",
+        18 => "not every chunk in the manifest is resident in the enclave",
+        19 => "the epsilon budget of the session for exposure statistics is used up",
+
+        20 => "decryption failed (wrong key, tampered or wrongly bound message)",
+        21 => "unsealing failed (another enclave, another slot or tampered data)",
+        22 => "the client identity signature or its data owner certificate does not verify",
+        23 => "the query id already has a key exchange in this session",
+        24 => "the data owner signature does not verify with the key built into the enclave",
+        25 => "the central data is already declared, the data owner cannot exchange a new key and no sealed manifest can be loaded",
+        26 => "the rate limits can only be loaded once, before any query is counted",
+
+        30 => "malformed chunk manifest",
+        31 => "malformed central data chunk",
+        32 => "chunk index is not in the manifest",
+        33 => "chunk does not match the digest in the manifest",
+        34 => "chunk has already been processed in this session",
+        35 => "chunk encoded value size does not match the queries",
+        36 => "chunk format does not match the join mode",
+        37 => "the queries were uploaded for another join mode (with or without time)",
+        38 => "a record batch of the central build is missing, repeated or declares another batch count",
+        39 => "a chunk does not fit in the chunk size class, use a larger class or a smaller chunk size",
+
+        40 => "enclave out of memory",
+        41 => "internal enclave error",
+        _ => "unknown enclave error",
+    }
+}
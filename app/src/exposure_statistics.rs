@@ -0,0 +1,67 @@
+use sgx_types::*;
+
+use crate::ecalls::{check_ecall, get_exposure_statistics};
+
+// same value as STATISTICS_VALUE_SIZE_U8 in the enclave
+pub const STATISTICS_VALUE_SIZE_U8: usize = 8;
+
+/*
+    Exposure statistics
+        noisy aggregates released by the enclave instead of the individual results
+        cells are numbered (longitude, latitude, time) from the high bits of the TrajectoryHash
+*/
+pub struct ExposureStatistics {
+    pub exposed_clients: i64,
+    pub region_bits: u32,
+    pub period_bits: u32,
+    pub cell_counts: Vec<i64>,
+}
+
+impl ExposureStatistics {
+    // (longitude, latitude, time) of a cell number
+    pub fn cell(&self, cell: usize) -> (usize, usize, usize) {
+        let period_mask = (1 << self.period_bits) - 1;
+        let region_mask = (1 << self.region_bits) - 1;
+        let period = cell & period_mask;
+        let latitude = (cell >> self.period_bits) & region_mask;
+        let longitude = cell >> (self.period_bits + self.region_bits);
+        (longitude, latitude, period)
+    }
+
+    // the noise also makes empty cells nonzero, only the positive ones are printed
+    pub fn show(&self) {
+        println!("exposed clients (noisy): {}", self.exposed_clients);
+        for (cell, count) in self.cell_counts.iter().enumerate() {
+            if *count > 0 {
+                let (longitude, latitude, period) = self.cell(cell);
+                println!("exposed clients in region ({}, {}) period {} (noisy): {}", longitude, latitude, period, count);
+            }
+        }
+    }
+}
+
+// spends epsilon of the session budget in the enclave
+pub fn request_exposure_statistics(
+    eid: sgx_enclave_id_t,
+    session_handle: u64,
+    epsilon: f64,
+    region_bits: u32,
+    period_bits: u32,
+) -> Result<ExposureStatistics, String> {
+    let cell_count = 1usize.checked_shl(2 * region_bits + period_bits).ok_or("too many statistics cells")?;
+    let statistics_size = (1 + cell_count) * STATISTICS_VALUE_SIZE_U8;
+    let mut statistics: Vec<u8> = vec![0; statistics_size];
+    let mut retval = sgx_status_t::SGX_SUCCESS;
+    let mut error: u32 = 0;
+    let result = unsafe {
+        get_exposure_statistics(eid, &mut retval, session_handle, epsilon, region_bits, period_bits, statistics.as_mut_ptr(), statistics_size, &mut error)
+    };
+    check_ecall("get_exposure_statistics", result, retval, error)?;
+    let mut values = statistics.chunks_exact(STATISTICS_VALUE_SIZE_U8).map(|bytes| {
+        let mut value_bytes = [0_u8; STATISTICS_VALUE_SIZE_U8];
+        value_bytes.copy_from_slice(bytes);
+        i64::from_be_bytes(value_bytes)
+    });
+    let exposed_clients = values.next().unwrap_or(0);
+    Ok(ExposureStatistics { exposed_clients, region_bits, period_bits, cell_counts: values.collect() })
+}
@@ -24,69 +24,555 @@ extern crate bincode;
 extern crate hex;
 extern crate glob;
 extern crate regex;
+extern crate clap;
 
-use std::env;
+use clap::{AppSettings, Clap};
 use std::collections::HashSet;
+use std::fs;
 use std::iter::FromIterator;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::Instant;
 use sgx_types::*;
 mod enc_util;
 // ecallsはnamedで呼び出す
 mod ecalls;
+mod enclave_error;
+use enclave_error::{ENCLAVE_ERROR_OUT_OF_MEMORY, ENCLAVE_ERROR_NOT_RESIDENT};
 use ecalls::{ 
-    upload_encoded_query_data, 
+    begin_upload, append_queries, finish_upload, restart_chunks,
+    set_resident_cache, query_resident,
     init_enclave,
+    upload_central_manifest,
+    private_sealed_contact_trace,
+    set_join_mode, set_exact_thresholds, check_ecall,
+    open_session, close_session,
     private_encode_contact_trace, get_encoded_result
 };
 mod central_data;
 use central_data::*;
+mod central_build;
+use central_build::{EnclaveBuiltCentral, upload_central_records};
+mod attestation;
+use attestation::{MockQuoteProvider, MockQuoteVerifier};
+mod secure_channel;
+use secure_channel::{SecureChannel, IdentityKeyPair, CertifiedIdentity, DataOwnerKey, MESSAGE_OVERHEAD_U8, establish_central_channel, establish_client_channel, message_aad, decrypt_message};
+mod sealed_storage;
+use sealed_storage::{SealedStorage, parse_key_policy};
+mod memory_budget;
+use memory_budget::MemoryBudget;
+mod contact_policy;
+use contact_policy::{ContactPolicy, MAX_CONTACT_POLICIES, parse_contact_policy, set_session_policies};
+mod exposure_statistics;
+use exposure_statistics::request_exposure_statistics;
 mod util;
 use util::*;
 pub const QUERY_ID_SIZE_U8: usize = 8;
+pub const QUERY_STATUS_U8: usize = 1;
 pub const QUERY_RESULT_U8: usize = 1;
-pub const RESPONSE_DATA_SIZE_U8: usize = QUERY_ID_SIZE_U8 + QUERY_RESULT_U8;
-
-/*
-    args[0] = threashold of each chunk block size
-    args[1] = query data file dir (clientfile format => client-(theta_geo)-(theta_time)-(client_id)-(.+).csv
-    args[2] = number of clients
-    args[3] = central data file path"
-*/
-fn _get_options() -> Vec<String> {
-    let args: Vec<String> = env::args().skip(1).collect();
-    if args.len() != 4 {
-        println!(" ERROR bin/app needs 3 arguments!");
-        println!("    args[0] = threashold of each chunk block size");
-        println!("    args[1] = query data file dir (clientfile format => client-(theta_geo)-(theta_time)-(client_id)-(.+).csv");
-        println!("    args[2] = number of clients");
-        println!("    args[3] = central data file path");
-        std::process::exit(-1);
+pub const RESPONSE_DATA_SIZE_U8: usize = QUERY_ID_SIZE_U8 + QUERY_STATUS_U8 + QUERY_RESULT_U8;
+// same values as query_result.rs in the enclave, a rejected query has no result
+pub const QUERY_STATUS_REJECTED: u8 = 1;
+// qeuryId + encrypted status and result (iv + status + result + mac)
+pub const ENCRYPTED_RESPONSE_DATA_SIZE_U8: usize = RESPONSE_DATA_SIZE_U8 + MESSAGE_OVERHEAD_U8;
+
+#[derive(Clap)]
+#[clap(setting = AppSettings::ColoredHelp)]
+struct Opts {
+    /// threashold of each chunk block size
+    threashould: usize,
+
+    /// query data file dir (clientfile format => client-(theta_geo)-(theta_time)-(client_id)-(.+).csv
+    q_dirname: String,
+
+    /// number of clients
+    client_num: u32,
+
+    /// central data file path (with --resume, only used as a label of the result)
+    c_filename: String,
+
+    /// directory to store the central data sealed by the enclave
+    #[clap(long)]
+    sealed_dir: Option<String>,
+
+    /// resume from the sealed central data in --sealed-dir without uploading the central data
+    #[clap(long)]
+    resume: bool,
+
+    /// key policy of the sealed central data mrenclave|mrsigner
+    #[clap(long, default_value = "mrenclave")]
+    seal_policy: String,
+
+    /// file with the data owner's private signing key in hex (little endian), the enclave must be built with its public key;
+    /// it signs the central key exchange and certifies the client identity keys (required)
+    #[clap(long)]
+    data_owner_key: String,
+
+    /// the enclave sorts, chunks and builds the central data from the data owner's encrypted records,
+    /// so the host never handles the plaintext central data
+    #[clap(long)]
+    enclave_build: bool,
+
+    /// pad every encrypted chunk to exactly this many bytes and the last chunk to threashould records,
+    /// a chunk that does not fit fails the distribution; 0 (default) only frames the chunks with their length
+    #[clap(long, default_value = "0")]
+    chunk_size_class: usize,
+
+    /// join mode dictionary|oblivious|adaptive|interval (oblivious hides which query points hit, but is much slower;
+    /// adaptive picks the cheapest of probe-central, query-index and sort-merge for each chunk;
+    /// interval reads "location,unixepoch" lines and matches visit periods within the contact time threshold)
+    #[clap(long, default_value = "dictionary")]
+    join_mode: String,
+
+    /// number of clients sent to the enclave per append_queries ECALL
+    #[clap(long, default_value = "1000")]
+    upload_batch: usize,
+
+    /// number of threads processing chunks in parallel (at most TCSNum in enclave/Enclave.config.xml)
+    #[clap(long, default_value = "1")]
+    threads: usize,
+
+    /// choose the chunk size from the enclave memory budget, threashould becomes the upper bound
+    #[clap(long)]
+    auto_chunk: bool,
+
+    /// bytes of decoded chunks kept in the enclave between batches, 0 (default) disables the resident cache
+    #[clap(long, default_value = "0")]
+    resident_cache: usize,
+
+    /// contact policy evaluated for every query, repeat for up to 8 policies: direct | neighbor | indirect:<time steps>, @<points> sets its minimum exposure
+    #[clap(long, number_of_values = 1)]
+    policy: Vec<String>,
+
+    /// consecutive matched query points (time steps) a policy needs to be positive, 1 (default) is any single point
+    #[clap(long, default_value = "1")]
+    min_exposure: u32,
+
+    /// shorthand for --policy direct --policy indirect:<n>, 0 (default) disables it
+    #[clap(long, default_value = "0")]
+    indirect_window: u32,
+
+    /// verify every dictionary hit against the raw points within this distance in meters;
+    /// central and client files are then read as "hash,unixepoch,latitude,longitude" lines (trajectory_hash --with-raw)
+    #[clap(long)]
+    exact_distance: Option<f64>,
+
+    /// time difference in seconds allowed by --exact-distance
+    #[clap(long, default_value = "600")]
+    exact_time: u64,
+
+    /// also release noisy exposure statistics of each batch with this epsilon (the enclave caps the total per session)
+    #[clap(long)]
+    statistics_epsilon: Option<f64>,
+
+    /// high bits of longitude and latitude kept in a statistics region
+    #[clap(long, default_value = "4")]
+    statistics_region_bits: u32,
+
+    /// high bits of the time step kept in a statistics period
+    #[clap(long, default_value = "2")]
+    statistics_period_bits: u32,
+
+    /// number of times the query batch is run, later batches are answered from the resident chunks
+    #[clap(long, default_value = "1")]
+    repeat: usize,
+}
+
+// enclave側のJoinModeと同じ値
+pub const JOIN_MODE_DICTIONARY: u8 = 0;
+pub const JOIN_MODE_OBLIVIOUS: u8 = 1;
+pub const JOIN_MODE_ADAPTIVE: u8 = 2;
+pub const JOIN_MODE_INTERVAL: u8 = 3;
+
+fn parse_join_mode(join_mode: &str) -> Result<u8, String> {
+    match join_mode {
+        "dictionary" => Ok(JOIN_MODE_DICTIONARY),
+        "oblivious" => Ok(JOIN_MODE_OBLIVIOUS),
+        "adaptive" => Ok(JOIN_MODE_ADAPTIVE),
+        "interval" => Ok(JOIN_MODE_INTERVAL),
+        _ => Err(format!("unknown join mode {}, expect dictionary|oblivious|adaptive|interval", join_mode)),
+    }
+}
+
+#[cfg(feature = "hashtable")]
+type Central = CentralHashSet;
+#[cfg(feature = "fsa")]
+type Central = CentralTrie;
+// the enclave builds the ORAM from the sorted keys
+#[cfg(feature = "oram")]
+type Central = CentralSortedKeys;
+
+// chunk format the enclave builds from the data owner's records, same choice as _distribute_central
+fn _central_chunk_format(join_mode: u8, with_raw_points: bool) -> u64 {
+    if join_mode == JOIN_MODE_INTERVAL {
+        CHUNK_FORMAT_VISITS
+    } else if join_mode != JOIN_MODE_DICTIONARY || cfg!(feature = "oram") {
+        CHUNK_FORMAT_SORTED_KEYS
+    } else if with_raw_points {
+        CHUNK_FORMAT_VERIFIED_DICTIONARY
+    } else {
+        CHUNK_FORMAT_DICTIONARY
+    }
+}
+
+fn _get_options() -> Opts {
+    Opts::parse()
+}
+
+// the data owner chunks, encrypts and declares the central data in a manifest
+// with --enclave-build the enclave chunks the records uploaded by the data owner and declares the manifest
+// with --chunk-size-class both builds pad the chunks, the host sees the same size for every chunk
+// with --sealed-dir the enclave also seals every chunk for restart
+fn _distribute_central(
+    eid: sgx_enclave_id_t,
+    central_data: &Vec<Vec<u8>>,
+    threashould: usize,
+    encoded_value_size: usize,
+    join_mode: u8,
+    with_raw_points: bool,
+    enclave_build: bool,
+    size_class: usize,
+    central_channel: &SecureChannel,
+    sealed_storage: &Option<SealedStorage>,
+    clocker: &mut Clocker,
+) -> Result<Arc<dyn CentralChunks>, String> {
+    /* preprocess central data */
+    clocker.set_and_start("Distribute central data");
+    // the oblivious and adaptive joins need the raw keys instead of the dictionary
+    let central: Arc<dyn CentralChunks> = if enclave_build {
+        Arc::new(EnclaveBuiltCentral::build(eid, threashould, size_class)?)
+    } else if join_mode == JOIN_MODE_INTERVAL {
+        Arc::new(CentralVisits::from_visit_data(central_data.clone(), threashould, encoded_value_size, size_class, central_channel)?)
+    } else if join_mode != JOIN_MODE_DICTIONARY {
+        Arc::new(CentralSortedKeys::from_encoded_data(central_data.clone(), threashould, encoded_value_size, size_class, central_channel)?)
+    } else if with_raw_points {
+        _verified_central(central_data.clone(), threashould, encoded_value_size, size_class, central_channel)?
+    } else {
+        Arc::new(Central::from_encoded_data(central_data.clone(), threashould, encoded_value_size, size_class, central_channel)?)
+    };
+    clocker.stop("Distribute central data");
+
+    /* upload chunk manifest */
+    // the enclave releases results only after every chunk in the manifest is processed exactly once
+    if let Some(manifest) = central.manifest(central_channel) {
+        clocker.set_and_start("ECALL upload_central_manifest");
+        let mut retval = sgx_status_t::SGX_SUCCESS;
+        let mut error: u32 = 0;
+        let result = unsafe {
+            upload_central_manifest(
+                eid,
+                &mut retval,
+                manifest.as_ptr() as * const u8,
+                manifest.len(),
+                &mut error
+            )
+        };
+        check_ecall("upload_central_manifest", result, retval, error)?;
+        clocker.stop("ECALL upload_central_manifest");
+    }
+
+    /* seal central data for restart */
+    if let Some(ref storage) = sealed_storage {
+        clocker.set_and_start("ECALL seal_central_data");
+        storage.seal_manifest(eid, central.len())?;
+        (0..central.len()).try_for_each(|chunk_index| {
+            storage.seal_chunk(eid, chunk_index, central.prepare_sgx_data(chunk_index))
+        })?;
+        clocker.stop("ECALL seal_central_data");
+    }
+    Ok(central)
+}
+
+fn _data_owner_key(path: &str) -> Result<DataOwnerKey, String> {
+    let hex = fs::read_to_string(path).map_err(|e| format!("cannot read the data owner key {}: {}", path, e))?;
+    DataOwnerKey::from_hex(&hex)
+}
+
+#[cfg(not(feature = "oram"))]
+fn _verified_central(raw_data: Vec<Vec<u8>>, threashould: usize, encoded_value_size: usize, size_class: usize, central_channel: &SecureChannel) -> Result<Arc<dyn CentralChunks>, String> {
+    Ok(Arc::new(Central::from_raw_data(raw_data, threashould, encoded_value_size, size_class, central_channel)?))
+}
+
+// the enclave builds the ORAM from the sorted keys only
+#[cfg(feature = "oram")]
+fn _verified_central(_raw_data: Vec<Vec<u8>>, _threashould: usize, _encoded_value_size: usize, _size_class: usize, _central_channel: &SecureChannel) -> Result<Arc<dyn CentralChunks>, String> {
+    Err("exact verification needs the fsa or hashtable build".to_string())
+}
+
+// the enclave decrypts each batch of clients as it arrives, so the whole query data is never copied in at once
+fn _upload_queries(
+    eid: sgx_enclave_id_t,
+    session_handle: u64,
+    query_data: &Vec<Vec<Vec<u8>>>,
+    query_id_list: &Vec<u64>,
+    channels: &Vec<SecureChannel>,
+    encoded_value_size: usize,
+    with_raw_points: bool,
+    upload_batch: usize,
+) -> Result<(), String> {
+    let mut retval = sgx_status_t::SGX_SUCCESS;
+    let mut error: u32 = 0;
+    let result = unsafe { begin_upload(eid, &mut retval, session_handle, query_data.len(), encoded_value_size, &mut error) };
+    check_ecall("begin_upload", result, retval, error)?;
+
+    let batch = upload_batch.max(1);
+    for start in (0..query_data.len()).step_by(batch) {
+        let end = (start + batch).min(query_data.len());
+        // the enclave ignores the hits of the points marked in the dummy mask
+        let batch_data: Vec<Vec<Vec<u8>>> = query_data[start..end].iter().map(|detail| {
+            let mut records = if with_raw_points { util::keys_then_raw_points(detail, encoded_value_size) } else { detail.clone() };
+            records.push(util::dummy_mask(detail, encoded_value_size));
+            records
+        }).collect();
+        let batch_data_vec: Vec<u8> = enc_util::encrypt_to_flat_vec_u8(&batch_data, &query_id_list[start..end], &channels[start..end]);
+        let result = unsafe {
+            append_queries(
+                eid,
+                &mut retval,
+                session_handle,
+                batch_data_vec.as_ptr() as * const u8,
+                batch_data_vec.len(),
+                end - start,
+                query_id_list[start..end].as_ptr() as * const u64,
+                &mut error
+            )
+        };
+        check_ecall("append_queries", result, retval, error)?;
+    }
+
+    let result = unsafe { finish_upload(eid, &mut retval, session_handle, &mut error) };
+    check_ecall("finish_upload", result, retval, error)
+}
+
+// Ok(false): some chunk of the manifest is not resident, the chunks have to be sent
+fn _query_resident(eid: sgx_enclave_id_t, session_handle: u64) -> Result<bool, String> {
+    let mut retval = sgx_status_t::SGX_SUCCESS;
+    let mut error: u32 = 0;
+    let result = unsafe { query_resident(eid, &mut retval, session_handle, &mut error) };
+    if result == sgx_status_t::SGX_SUCCESS && error == ENCLAVE_ERROR_NOT_RESIDENT {
+        return Ok(false);
     }
-    args
+    check_ecall("query_resident", result, retval, error).map(|_| true)
+}
+
+// the enclave could not reserve the heap for a chunk, smaller chunks may fit
+enum ChunkError {
+    OutOfMemory(String),
+    Failed(String),
+}
+
+// chunks are handed out through a shared counter, each worker enters the enclave on its own TCS
+// and the enclave merges the partial result of every chunk into the session
+fn _contact_trace_chunks(
+    eid: sgx_enclave_id_t,
+    session_handle: u64,
+    central: Option<Arc<dyn CentralChunks>>,
+    sealed_storage: Option<SealedStorage>,
+    chunk_count: usize,
+    threads: usize,
+) -> Result<(), ChunkError> {
+    let next_chunk = Arc::new(AtomicUsize::new(0));
+    let failed = Arc::new(AtomicBool::new(false));
+    let workers: Vec<thread::JoinHandle<Result<(), ChunkError>>> = (0..threads.max(1)).map(|_| {
+        let (next_chunk, failed) = (next_chunk.clone(), failed.clone());
+        let (central, sealed_storage) = (central.clone(), sealed_storage.clone());
+        thread::spawn(move || {
+            loop {
+                let chunk_index = next_chunk.fetch_add(1, Ordering::SeqCst);
+                if chunk_index >= chunk_count || failed.load(Ordering::SeqCst) {
+                    return Ok(());
+                }
+                if let Err(chunk_error) = _contact_trace_chunk(eid, session_handle, &central, &sealed_storage, chunk_index) {
+                    failed.store(true, Ordering::SeqCst);
+                    return Err(chunk_error);
+                }
+            }
+        })
+    }).collect();
+
+    let mut ret = Ok(());
+    for worker in workers {
+        let worker_ret = worker.join().unwrap_or_else(|_| Err(ChunkError::Failed("contact trace worker panicked".to_string())));
+        if ret.is_ok() {
+            ret = worker_ret;
+        }
+    }
+    ret
+}
+
+fn _contact_trace_chunk(
+    eid: sgx_enclave_id_t,
+    session_handle: u64,
+    central: &Option<Arc<dyn CentralChunks>>,
+    sealed_storage: &Option<SealedStorage>,
+    chunk_index: usize,
+) -> Result<(), ChunkError> {
+    let mut retval = sgx_status_t::SGX_SUCCESS;
+    let mut error: u32 = 0;
+    let result = match central {
+        Some(central) => {
+            let chunk: &Vec<u8> = central.prepare_sgx_data(chunk_index);
+            unsafe {
+                private_encode_contact_trace(
+                    eid,
+                    &mut retval,
+                    session_handle,
+                    chunk.as_ptr() as * const u8,
+                    chunk.len(),
+                    chunk_index,
+                    &mut error
+                )
+            }
+        },
+        None => {
+            let sealed: Vec<u8> = sealed_storage.as_ref()
+                .ok_or("no sealed storage to resume from".to_string())
+                .and_then(|storage| storage.read_chunk(chunk_index))
+                .map_err(ChunkError::Failed)?;
+            unsafe {
+                private_sealed_contact_trace(
+                    eid,
+                    &mut retval,
+                    session_handle,
+                    sealed.as_ptr() as * const u8,
+                    sealed.len(),
+                    chunk_index,
+                    &mut error
+                )
+            }
+        }
+    };
+    check_ecall("private_contact_trace", result, retval, error).map_err(|message| {
+        let message = format!("chunk {}: {}", chunk_index, message);
+        if error == ENCLAVE_ERROR_OUT_OF_MEMORY {
+            ChunkError::OutOfMemory(message)
+        } else {
+            ChunkError::Failed(message)
+        }
+    })
 }
 
 fn private_set_intersection() {
-    let args = _get_options();
+    let opts = _get_options();
     /* parameters */
-    let threashould: usize = args[0].parse().unwrap();
-    let q_dirname = &args[1];
-    let client_num: u32 = args[2].parse().unwrap();
-    let c_filename = &args[3];
+    let mut threashould: usize = opts.threashould;
+    let q_dirname = &opts.q_dirname;
+    let client_num: u32 = opts.client_num;
+    let c_filename = &opts.c_filename;
+    let sealed_storage: Option<SealedStorage> = match opts.sealed_dir {
+        Some(ref dir) => {
+            match parse_key_policy(&opts.seal_policy).and_then(|key_policy| SealedStorage::new(dir, key_policy)) {
+                Ok(storage) => Some(storage),
+                Err(message) => {
+                    println!("[UNTRUSTED] {}", message);
+                    return;
+                }
+            }
+        },
+        None => None,
+    };
+    if opts.resume && sealed_storage.is_none() {
+        println!("[UNTRUSTED] --resume needs --sealed-dir");
+        return;
+    }
+    let join_mode: u8 = match parse_join_mode(&opts.join_mode) {
+        Ok(join_mode) => join_mode,
+        Err(message) => {
+            println!("[UNTRUSTED] {}", message);
+            return;
+        }
+    };
+
+    // the raw points are only checked after a trie or hashtable hit
+    let with_raw_points = opts.exact_distance.is_some();
+    if with_raw_points && (join_mode != JOIN_MODE_DICTIONARY || cfg!(feature = "oram")) {
+        println!("[UNTRUSTED] --exact-distance needs --join-mode dictionary with the fsa or hashtable build");
+        return;
+    }
+
+    /* contact policies, bit i of each result is policy i */
+    let mut policy_specs: Vec<String> = opts.policy.clone();
+    if policy_specs.is_empty() {
+        policy_specs.push("direct".to_string());
+        if opts.indirect_window > 0 {
+            policy_specs.push(format!("indirect:{}", opts.indirect_window));
+        }
+    }
+    let contact_policies: Vec<ContactPolicy> = match policy_specs.iter().map(|spec| parse_contact_policy(spec, opts.min_exposure)).collect::<Result<Vec<_>, String>>() {
+        Ok(contact_policies) if contact_policies.len() <= MAX_CONTACT_POLICIES => contact_policies,
+        Ok(_) => {
+            println!("[UNTRUSTED] at most {} contact policies", MAX_CONTACT_POLICIES);
+            return;
+        },
+        Err(message) => {
+            println!("[UNTRUSTED] {}", message);
+            return;
+        }
+    };
 
     let mut clocker = Clocker::new();
 
     /* read central data */
-    clocker.set_and_start("Read Central Data");
-    let mut central_data = util::read_trajectory_hash_from_csv(c_filename);
-    clocker.stop("Read Central Data");
+    // resuming from the sealed central data, the data owner uploads nothing
+    let mut central_data: Vec<Vec<u8>> = vec![];
+    if !opts.resume {
+        clocker.set_and_start("Read Central Data");
+        central_data = if join_mode == JOIN_MODE_INTERVAL {
+            util::read_visits_from_csv(c_filename)
+        } else if with_raw_points {
+            util::read_raw_points_from_csv(c_filename)
+        } else {
+            util::read_trajectory_hash_from_csv(c_filename)
+        };
+        clocker.stop("Read Central Data");
+    }
     let central_data_size = central_data.len();
+    // interval mode records carry the unixepoch after the location, exact verification the raw point after the hash
+    let suffix_size = if join_mode == JOIN_MODE_INTERVAL {
+        UNIXEPOCH_BE_SIZE_U8
+    } else if with_raw_points {
+        RAW_POINT_SIZE_U8
+    } else {
+        0
+    };
+    let central_record_size = if opts.resume {
+        None
+    } else {
+        match util::encoded_value_size(&central_data) {
+            Some(size) => Some(size),
+            None => {
+                println!("[UNTRUSTED] central data must have a single encoded value size");
+                return;
+            }
+        }
+    };
 
-    /* preprocess central data */
-    clocker.set_and_start("Distribute central data");
-    #[cfg(feature = "hashtable")]
-    let mut R: CentralHashSet = CentralHashSet::from_encoded_data(central_data, threashould);
-    #[cfg(feature = "fsa")]
-    let mut R: CentralTrie = CentralTrie::from_encoded_data(central_data, threashould);
-    clocker.stop("Distribute central data");
+    /* read query data */
+    clocker.set_and_start("Read Query Data");
+    let query_data = if join_mode == JOIN_MODE_INTERVAL {
+        util::read_visits_from_csv_for_clients(q_dirname, client_num)
+    } else if with_raw_points {
+        util::read_raw_points_from_csv_for_clients(q_dirname, client_num)
+    } else {
+        util::read_trajectory_hash_from_csv_for_clients(q_dirname, client_num)
+    };
+    let client_size = query_data.len();
+    let query_id_list: Vec<u64> = Vec::from_iter((0u64..client_size as u64).into_iter());
+    clocker.stop("Read Query Data");
+    // resuming, the enclave checks the width against the sealed chunks
+    let record_size = match central_record_size.or_else(|| query_data.first().and_then(|detail| util::encoded_value_size(detail))) {
+        Some(size) if size > suffix_size => size,
+        _ => {
+            println!("[UNTRUSTED] query data must have a single encoded value size");
+            return;
+        }
+    };
+    if query_data.iter().any(|detail| util::encoded_value_size(detail) != Some(record_size)) {
+        println!("[UNTRUSTED] query data encoded value size must be {} as central data", record_size - suffix_size);
+        return;
+    }
+    let encoded_value_size = record_size - suffix_size;
 
     /* initialize enclave */
     println!("init_enclave...");
@@ -103,116 +589,310 @@ fn private_set_intersection() {
     };
     clocker.stop("ECALL init_enclave");
 
-    /* read query data */
-    clocker.set_and_start("Read Query Data");
-    let query_data = util::read_trajectory_hash_from_csv_for_clients(q_dirname, client_num);
-    let client_size = query_data.len();
-    let query_id_list: Vec<u64> = Vec::from_iter((0u64..client_size as u64).into_iter());
-    clocker.stop("Read Query Data");
-
-    /* encrypt and upload query data */
-    let total_data_vec: Vec<u8> = enc_util::encrypt_to_flat_vec_u8(&query_data, &query_id_list);
-    clocker.set_and_start("ECALL upload_query_data");
     let mut retval = sgx_status_t::SGX_SUCCESS;
-    let result = unsafe {
-        upload_encoded_query_data(
-            enclave.geteid(),
-            &mut retval,
-            total_data_vec.as_ptr() as * const u8,
-            total_data_vec.len(),
-            client_size,
-            query_id_list.as_ptr() as * const u64
-        )
+    let mut error: u32 = 0;
+    let result = unsafe { set_join_mode(enclave.geteid(), &mut retval, join_mode, &mut error) };
+    if let Err(message) = check_ecall("set_join_mode", result, retval, error) {
+        println!("[UNTRUSTED] {}", message);
+        return;
+    }
+
+    if opts.resident_cache > 0 {
+        let result = unsafe { set_resident_cache(enclave.geteid(), &mut retval, opts.resident_cache, &mut error) };
+        if let Err(message) = check_ecall("set_resident_cache", result, retval, error) {
+            println!("[UNTRUSTED] {}", message);
+            return;
+        }
+    }
+
+    // the enclave keeps counting the queries of each client identity across restarts
+    if let Some(ref storage) = sealed_storage {
+        if let Err(message) = storage.load_rate_limits(enclave.geteid()) {
+            println!("[UNTRUSTED] {}", message);
+            return;
+        }
+    }
+
+    // the data owner signs its ephemeral key and certifies the client identity keys, the enclave rejects any other key
+    let data_owner_key = match _data_owner_key(&opts.data_owner_key) {
+        Ok(key) => key,
+        Err(message) => {
+            println!("[UNTRUSTED] {}", message);
+            return;
+        }
     };
-    match result {
-        sgx_status_t::SGX_SUCCESS => {
-            // println!("[UNTRUSTED] upload_query_data Succes!");
+
+    // each simulated client keeps its identity key over the batches, the data owner issues its certificate once
+    let identities: Vec<CertifiedIdentity> = match (0..client_size).map(|_| IdentityKeyPair::new().and_then(|key| data_owner_key.certify(key))).collect::<Result<Vec<_>, String>>() {
+        Ok(identities) => identities,
+        Err(message) => {
+            println!("[UNTRUSTED] {}", message);
+            return;
+        }
+    };
+
+    // SGX simulation mode has no IAS/DCAP, so the quote is a local mock.
+    let quote_provider = MockQuoteProvider;
+    let quote_verifier = MockQuoteVerifier::new();
+
+    /* chunk size from the enclave memory budget */
+    // the query buffer and one decoded chunk per thread have to fit in the enclave heap
+    if opts.enclave_build && opts.resume {
+        println!("[UNTRUSTED] --enclave-build has no effect with --resume, the sealed chunks are already built");
+    }
+    if opts.auto_chunk && opts.resume {
+        println!("[UNTRUSTED] --auto-chunk has no effect with --resume, the sealed chunks keep their size");
+    } else if opts.auto_chunk {
+        // the enclave adds shifted copies of the queries for the neighbor and indirect policies
+        let variant_count: usize = contact_policies.iter().map(|policy| policy.variant_count()).sum();
+        let query_key_count: usize = query_data.iter().map(|detail| detail.len()).sum::<usize>() * variant_count;
+        let chunk_bytes = MemoryBudget::query(enclave.geteid(), encoded_value_size).and_then(|budget| {
+            budget.show();
+            budget.chunk_bytes(encoded_value_size, query_key_count, opts.threads)
+        });
+        match chunk_bytes {
+            Ok(chunk_bytes) => {
+                // an interval chunk holds at most one location besides each visit time
+                // a verified chunk holds the raw point besides the dictionary key
+                let key_bytes = if join_mode == JOIN_MODE_INTERVAL {
+                    encoded_value_size + 2 * UNIXEPOCH_BE_SIZE_U8
+                } else if with_raw_points {
+                    2 * encoded_value_size + RAW_POINT_SIZE_U8
+                } else {
+                    encoded_value_size
+                };
+                threashould = threashould.min(chunk_bytes / key_bytes);
+                println!("[UNTRUSTED] chunk size = {} bytes ({} keys)", threashould * key_bytes, threashould);
+            },
+            Err(message) => {
+                println!("[UNTRUSTED] {}", message);
+                return;
+            }
+        }
+    }
+
+    // kept to split the chunks when the enclave runs out of memory
+    let mut central_channel: Option<SecureChannel> = None;
+    let mut R: Option<Arc<dyn CentralChunks>> = match sealed_storage {
+        Some(ref storage) if opts.resume => {
+            /* load sealed manifest, chunks are unsealed in the main loop */
+            clocker.set_and_start("ECALL load_sealed_manifest");
+            if let Err(message) = storage.load_manifest(enclave.geteid()) {
+                println!("[UNTRUSTED] {}", message);
+                return;
+            }
+            clocker.stop("ECALL load_sealed_manifest");
+            None
         },
         _ => {
-            println!("[UNTRUSTED] upload_query_data Failed {}!", result.as_str());
+            /* attestation and key exchange of the data owner */
+            clocker.set_and_start("ECALL central_key_exchange");
+            let channel: SecureChannel = match establish_central_channel(enclave.geteid(), &data_owner_key, &quote_provider, &quote_verifier) {
+                Ok(key) => key,
+                Err(message) => {
+                    println!("[UNTRUSTED] {}", message);
+                    return;
+                }
+            };
+            clocker.stop("ECALL central_key_exchange");
+
+            if opts.enclave_build {
+                /* the data owner uploads its encrypted records, the enclave builds the chunks */
+                clocker.set_and_start("ECALL append_central_records");
+                let chunk_format = _central_chunk_format(join_mode, with_raw_points);
+                if let Err(message) = upload_central_records(enclave.geteid(), &central_data, encoded_value_size, chunk_format, &channel) {
+                    println!("[UNTRUSTED] {}", message);
+                    return;
+                }
+                // the host keeps only ciphertext from here
+                central_data.clear();
+                clocker.stop("ECALL append_central_records");
+            }
+
+            match _distribute_central(enclave.geteid(), &central_data, threashould, encoded_value_size, join_mode, with_raw_points, opts.enclave_build, opts.chunk_size_class, &channel, &sealed_storage, &mut clocker) {
+                Ok(central) => {
+                    central_channel = Some(channel);
+                    Some(central)
+                },
+                Err(message) => {
+                    println!("[UNTRUSTED] {}", message);
+                    return;
+                }
+            }
+        }
+    };
+
+    for batch in 0..opts.repeat.max(1) {
+        /* open a session for this query batch */
+        // client keys, queries and results live in the session until close_session
+        let mut session_handle: u64 = 0;
+        let result = unsafe { open_session(enclave.geteid(), &mut retval, &mut session_handle, &mut error) };
+        if let Err(message) = check_ecall("open_session", result, retval, error) {
+            println!("[UNTRUSTED] {}", message);
             return;
         }
-    }
-    clocker.stop("ECALL upload_query_data");
+        if let Err(message) = set_session_policies(enclave.geteid(), session_handle, &contact_policies) {
+            println!("[UNTRUSTED] {}", message);
+            return;
+        }
+        // the clients send their raw points after the hashes
+        if let Some(exact_distance) = opts.exact_distance {
+            let result = unsafe { set_exact_thresholds(enclave.geteid(), &mut retval, session_handle, exact_distance, opts.exact_time, &mut error) };
+            if let Err(message) = check_ecall("set_exact_thresholds", result, retval, error) {
+                println!("[UNTRUSTED] {}", message);
+                return;
+            }
+        }
 
-    /* main logic contact tracing */
-    let mut chunk_index: usize = 0;
-    let last = R.len() - 1;
-    clocker.set_and_start("ECALL private_contact_trace");
-    while last >= chunk_index {
-        let chunk: &Vec<u8> = R.prepare_sgx_data(chunk_index);
+        /* attestation and key exchange of each client */
+        clocker.set_and_start("ECALL client_key_exchange");
+        let mut channels: Vec<SecureChannel> = Vec::with_capacity(client_size);
+        for (query_id, identity) in query_id_list.iter().zip(identities.iter()) {
+            match establish_client_channel(enclave.geteid(), session_handle, *query_id, identity, &quote_provider, &quote_verifier) {
+                Ok(channel) => channels.push(channel),
+                Err(message) => {
+                    println!("[UNTRUSTED] {}", message);
+                    return;
+                }
+            }
+        }
+        clocker.stop("ECALL client_key_exchange");
+
+        /* encrypt and upload query data */
+        clocker.set_and_start("ECALL upload_query_data");
+        if let Err(message) = _upload_queries(enclave.geteid(), session_handle, &query_data, &query_id_list, &channels, encoded_value_size, with_raw_points, opts.upload_batch) {
+            println!("[UNTRUSTED] {}", message);
+            return;
+        }
+        clocker.stop("ECALL upload_query_data");
+
+        /* main logic contact tracing */
+        clocker.set_and_start("ECALL private_contact_trace");
+        let trace_start = Instant::now();
+        // every chunk of the manifest is already decoded in the enclave, nothing is sent
+        let answered_resident = if opts.resident_cache > 0 {
+            match _query_resident(enclave.geteid(), session_handle) {
+                Ok(answered) => answered,
+                Err(message) => {
+                    println!("[UNTRUSTED] {}", message);
+                    return;
+                }
+            }
+        } else {
+            false
+        };
+        if !answered_resident {
+            loop {
+                let chunk_count = match R {
+                    Some(ref central) => central.len(),
+                    None => sealed_storage.as_ref().unwrap().chunk_count(),
+                };
+                match _contact_trace_chunks(enclave.geteid(), session_handle, R.clone(), sealed_storage.clone(), chunk_count, opts.threads) {
+                    Ok(()) => break,
+                    // the data owner splits the chunks in half and the session starts the chunks over
+                    Err(ChunkError::OutOfMemory(message)) if central_channel.is_some() && threashould > 1 => {
+                        threashould = (threashould + 1) / 2;
+                        println!("[UNTRUSTED] {}, splitting the chunks into {} keys", message, threashould);
+                        match _distribute_central(enclave.geteid(), &central_data, threashould, encoded_value_size, join_mode, with_raw_points, opts.enclave_build, opts.chunk_size_class, central_channel.as_ref().unwrap(), &sealed_storage, &mut clocker) {
+                            Ok(central) => R = Some(central),
+                            Err(message) => {
+                                println!("[UNTRUSTED] {}", message);
+                                return;
+                            }
+                        }
+                        let result = unsafe { restart_chunks(enclave.geteid(), &mut retval, session_handle, &mut error) };
+                        if let Err(message) = check_ecall("restart_chunks", result, retval, error) {
+                            println!("[UNTRUSTED] {}", message);
+                            return;
+                        }
+                    },
+                    Err(ChunkError::OutOfMemory(message)) | Err(ChunkError::Failed(message)) => {
+                        println!("[UNTRUSTED] {}", message);
+                        return;
+                    }
+                }
+            }
+        }
+        println!("[UNTRUSTED] batch {} answered {} in {} ms", batch, if answered_resident { "from the resident chunks" } else { "from the chunks" }, trace_start.elapsed().as_millis());
+        clocker.stop("ECALL private_contact_trace");
+
+        /* response reconstruction */
+        clocker.set_and_start("ECALL get_result");
+        let response_size = client_size * ENCRYPTED_RESPONSE_DATA_SIZE_U8;
+        let mut response: Vec<u8> = vec![0; response_size];
         let result = unsafe {
-            private_encode_contact_trace(
+            get_encoded_result(
                 enclave.geteid(),
                 &mut retval,
-                chunk.as_ptr() as * const u8,
-                chunk.len()
+                session_handle,
+                response.as_mut_ptr(),
+                response_size,
+                &mut error
             )
         };
-        match result {
-            sgx_status_t::SGX_SUCCESS => {
-                // print!("\r[UNTRUSTED] private_contact_trace Succes! {} th iteration", chunk_index);
-            },
-            _ => {
-                println!("[UNTRUSTED] private_contact_trace Failed {}!", result.as_str());
-                return;
+        if let Err(message) = check_ecall("get_result", result, retval, error) {
+            println!("[UNTRUSTED] {}", message);
+            return;
+        }
+        clocker.stop("ECALL get_result");
+
+        // aggregates for the health authority, computed from the results still in the session
+        if let Some(epsilon) = opts.statistics_epsilon {
+            match request_exposure_statistics(enclave.geteid(), session_handle, epsilon, opts.statistics_region_bits, opts.statistics_period_bits) {
+                Ok(statistics) => statistics.show(),
+                Err(message) => {
+                    println!("[UNTRUSTED] {}", message);
+                    return;
+                }
             }
         }
-        chunk_index += 1;
-    }
-    // println!("");
-    
-    clocker.stop("ECALL private_contact_trace");
-
-    /* response reconstruction */
-    clocker.set_and_start("ECALL get_result");
-    let response_size = client_size * RESPONSE_DATA_SIZE_U8;
-    let mut response: Vec<u8> = vec![0; response_size];
-    let result = unsafe {
-        get_encoded_result(
-            enclave.geteid(),
-            &mut retval,
-            response.as_mut_ptr(),
-            response_size
-        )
-    };
-    match result {
-        sgx_status_t::SGX_SUCCESS => {
-            // println!("[UNTRUSTED] get_result Succes!");
-        },
-        _ => {
-            println!("[UNTRUSTED] get_result Failed {}!", result.as_str());
+
+        /* close the session, the enclave wipes the queries, results and client keys */
+        let result = unsafe { close_session(enclave.geteid(), &mut retval, session_handle, &mut error) };
+        if let Err(message) = check_ecall("close_session", result, retval, error) {
+            println!("[UNTRUSTED] {}", message);
             return;
         }
-    }
-    clocker.stop("ECALL get_result");
+        if let Some(ref storage) = sealed_storage {
+            if let Err(message) = storage.seal_rate_limits(enclave.geteid()) {
+                println!("[UNTRUSTED] {}", message);
+                return;
+            }
+        }
     
-    let mut positive_queries = vec![];
-    for i in 0..client_size {
-        /* decryption for each clients using their keys */ 
-        let query_id = query_id_from_u8(&response[i*RESPONSE_DATA_SIZE_U8..i*RESPONSE_DATA_SIZE_U8+QUERY_ID_SIZE_U8]);
-        let mut shared_key: [u8; 16] = [0; 16];
-        shared_key[..8].copy_from_slice(&query_id.to_be_bytes());
-        let counter_block: [u8; 16] = COUNTER_BLOCK;
-        let ctr_inc_bits: u32 = SGXSSL_CTR_BITS;
-        let src_len: usize = QUERY_RESULT_U8;
-        let mut result: Vec<u8> = vec![0; src_len];
-        let ret = unsafe {
-            util::sgx_aes_ctr_decrypt(
-                &shared_key,
-                response[i*RESPONSE_DATA_SIZE_U8+QUERY_ID_SIZE_U8..i*RESPONSE_DATA_SIZE_U8+RESPONSE_DATA_SIZE_U8].as_ptr() as *const u8,
-                src_len as u32,
-                &counter_block as * const u8,
-                ctr_inc_bits,
-                result.as_mut_ptr()
-            )
-        };
-        if ret < 0 { println!("Error in CTR decryption."); std::process::exit(-1); }
-        if result[0] > 0 {
-            positive_queries.push(query_id);
+        let mut positive_queries: Vec<Vec<u64>> = vec![vec![]; contact_policies.len()];
+        let mut rejected_queries: Vec<u64> = vec![];
+        for i in 0..client_size {
+            /* decryption for each clients using their keys */ 
+            let cursor = i*ENCRYPTED_RESPONSE_DATA_SIZE_U8;
+            let query_id = query_id_from_u8(&response[cursor..cursor+QUERY_ID_SIZE_U8]);
+            let channel = &channels[i];
+            let result = match decrypt_message(
+                &channel.key,
+                &response[cursor+QUERY_ID_SIZE_U8..cursor+ENCRYPTED_RESPONSE_DATA_SIZE_U8],
+                &message_aad(channel.session_id, query_id)
+            ) {
+                Ok(result) => result,
+                Err(message) => { println!("{}", message); std::process::exit(-1); }
+            };
+            // result: status + bit i for policy i
+            if result[0] == QUERY_STATUS_REJECTED {
+                rejected_queries.push(query_id);
+                continue;
+            }
+            for (bit, positive) in positive_queries.iter_mut().enumerate() {
+                if result[QUERY_STATUS_U8] & (1 << bit) > 0 {
+                    positive.push(query_id);
+                }
+            }
+        }
+        if !rejected_queries.is_empty() {
+            println!("rejected queryIds: {:?}", rejected_queries);
+        }
+        for (policy, positive) in contact_policies.iter().zip(positive_queries.iter()) {
+            println!("positive result queryIds ({}): {:?}", policy.name(), positive);
         }
     }
-    println!("positive result queryIds: {:?}", positive_queries);
 
     /* finish */
     enclave.destroy();
@@ -221,10 +901,13 @@ fn private_set_intersection() {
     let now: String = get_timestamp();
 
 
+    // the oblivious, adaptive and interval joins do not use the dictionary
     #[cfg(feature = "hashtable")]
-    let data_st = "hashtable";
+    let data_st = match join_mode { JOIN_MODE_OBLIVIOUS => "oblivious", JOIN_MODE_ADAPTIVE => "adaptive", JOIN_MODE_INTERVAL => "interval", _ => "hashtable" };
     #[cfg(feature = "fsa")]
-    let data_st = "fsa";
+    let data_st = match join_mode { JOIN_MODE_OBLIVIOUS => "oblivious", JOIN_MODE_ADAPTIVE => "adaptive", JOIN_MODE_INTERVAL => "interval", _ => "fsa" };
+    #[cfg(feature = "oram")]
+    let data_st = match join_mode { JOIN_MODE_OBLIVIOUS => "oblivious", JOIN_MODE_ADAPTIVE => "adaptive", JOIN_MODE_INTERVAL => "interval", _ => "oram" };
 
     write_to_file(
         format!("result/{}-{}-{}-{}-{}.txt",
@@ -242,12 +925,12 @@ fn private_set_intersection() {
 }
 
 fn non_private_set_intersection() {
-    let args = _get_options();
+    let opts = _get_options();
     /* parameters */
-    let threashould: usize = args[0].parse().unwrap();
-    let q_dirname = &args[1];
-    let client_num: u32 = args[2].parse().unwrap();
-    let c_filename = &args[3];
+    let threashould: usize = opts.threashould;
+    let q_dirname = &opts.q_dirname;
+    let client_num: u32 = opts.client_num;
+    let c_filename = &opts.c_filename;
 
     let mut clocker = Clocker::new();
 
@@ -263,6 +946,8 @@ fn non_private_set_intersection() {
     let mut R: NonPrivateHashSet = NonPrivateHashSet::from_encoded_data(central_data);
     #[cfg(feature = "fsa")]
     let mut R: NonPrivateFST = NonPrivateFST::from_encoded_data(central_data);
+    #[cfg(feature = "oram")]
+    let mut R: NonPrivateHashSet = NonPrivateHashSet::from_encoded_data(central_data);
     clocker.stop("Distribute central data");
 
     R.calc_memory();
@@ -310,6 +995,8 @@ fn non_private_set_intersection() {
     let data_st = "hashtable";
     #[cfg(feature = "fsa")]
     let data_st = "fsa";
+    #[cfg(feature = "oram")]
+    let data_st = "oram";
 
     write_to_file(
         format!("result/{}-{}-{}-{}-{}.txt",
@@ -327,12 +1014,12 @@ fn non_private_set_intersection() {
 }
 
 fn size_compare() {
-    let args = _get_options();
+    let opts = _get_options();
     /* parameters */
-    let threashould: usize = args[0].parse().unwrap();
-    let q_dirname = &args[1];
-    let client_num: u32 = args[2].parse().unwrap();
-    let c_filename = &args[3];
+    let threashould: usize = opts.threashould;
+    let q_dirname = &opts.q_dirname;
+    let client_num: u32 = opts.client_num;
+    let c_filename = &opts.c_filename;
 
     let mut clocker = Clocker::new();
 
@@ -344,8 +1031,11 @@ fn size_compare() {
 
     /* preprocess central data */
     clocker.set_and_start("Distribute central data");
-    // let mut R_hash_set: CentralHashSet = CentralHashSet::from_encoded_data(central_data, threashould);
-    let mut R_trie: CentralTrie = CentralTrie::from_encoded_data(central_data, threashould);
+    let encoded_value_size = util::encoded_value_size(&central_data).expect("central data must have a single encoded value size");
+    // only sizes are compared, so the chunks need no exchanged key
+    let size_only_channel = SecureChannel::default();
+    // let mut R_hash_set: CentralHashSet = CentralHashSet::from_encoded_data(central_data, threashould, encoded_value_size, 0, &size_only_channel);
+    let mut R_trie: CentralTrie = CentralTrie::from_encoded_data(central_data, threashould, encoded_value_size, 0, &size_only_channel).expect("chunks without a size class always fit");
     clocker.stop("Distribute central data");
 }
 
@@ -0,0 +1,54 @@
+use sgx_types::*;
+
+use crate::ecalls::{check_ecall, get_memory_budget};
+
+/*
+    Memory budget
+        enclaveのheap size，使用量，ピークと，chunk 1つの処理に要るkeyあたりのheap
+        get_memory_budgetの[out]，EDLのmemory_budget_tと同じ並び
+*/
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct MemoryBudget {
+    pub heap_size: usize,
+    pub heap_used: usize,
+    pub heap_peak: usize,
+    // the enclave keeps this much free for allocations it cannot count
+    pub heap_margin: usize,
+    // heap per central key of a chunk being processed
+    pub chunk_key_cost: usize,
+    // heap per query key for each chunk being processed
+    pub query_key_cost: usize,
+}
+
+impl MemoryBudget {
+    pub fn query(eid: sgx_enclave_id_t, encoded_value_size: usize) -> Result<Self, String> {
+        let mut budget = MemoryBudget::default();
+        let mut retval = sgx_status_t::SGX_SUCCESS;
+        let mut error: u32 = 0;
+        let result = unsafe { get_memory_budget(eid, &mut retval, encoded_value_size, &mut budget, &mut error) };
+        check_ecall("get_memory_budget", result, retval, error)?;
+        Ok(budget)
+    }
+
+    pub fn show(&self) {
+        println!("[UNTRUSTED] enclave heap size = {} bytes, used = {} bytes, peak = {} bytes", self.heap_size, self.heap_used, self.heap_peak);
+    }
+
+    // chunk size in bytes so that the query buffer and one chunk per thread fit in the free heap
+    // query_key_count: keys of the whole batch, which stay in the enclave until close_session
+    pub fn chunk_bytes(&self, encoded_value_size: usize, query_key_count: usize, threads: usize) -> Result<usize, String> {
+        let query_buffer = query_key_count * encoded_value_size;
+        let free = self.heap_size
+            .checked_sub(self.heap_used + self.heap_margin + query_buffer)
+            .ok_or(format!("the query buffer ({} bytes) does not fit in the enclave heap", query_buffer))?;
+        let per_thread = (free / threads.max(1))
+            .checked_sub(query_key_count * self.query_key_cost)
+            .ok_or("the working set of the queries does not fit in the enclave heap, use fewer threads or clients".to_string())?;
+        let chunk_keys = per_thread / self.chunk_key_cost.max(1);
+        if chunk_keys == 0 {
+            return Err("not even one central key per chunk fits in the enclave heap".to_string());
+        }
+        Ok(chunk_keys * encoded_value_size)
+    }
+}
@@ -0,0 +1,161 @@
+use sgx_types::*;
+use std::fs;
+use std::mem;
+use std::path::PathBuf;
+
+use crate::ecalls::{check_ecall, seal_central_manifest, seal_central_chunk, load_sealed_manifest, seal_rate_limits, load_rate_limits};
+use crate::secure_channel::MESSAGE_OVERHEAD_U8;
+
+// sealed blob additional text: version(4byte) + kind(4byte) + chunk index(8byte)
+pub const SEALED_AAD_SIZE_U8: usize = 16;
+// chunk manifest: chunk count(8byte) + sha256 digest of each chunk(32byte)
+const MANIFEST_HEADER_SIZE_U8: usize = 8;
+const CHUNK_DIGEST_SIZE_U8: usize = 32;
+// rate limits: entry count(8byte) + (identity(32byte) + window start(8byte) + count(4byte)) * MAX_RATE_LIMITED_CLIENTS
+const RATE_LIMITS_MAX_SIZE_U8: usize = 8 + (32 + 8 + 4) * 65536;
+
+/*
+    Sealed storage
+        enclaveがsealしたcentral data chunkとmanifestを置くディレクトリ
+        manifest.sealed, chunk-(index).sealed, rate-limits.sealed (clientごとのquery数)
+        hostは中身を読めないのでファイルの読み書きだけを担当する
+*/
+#[derive(Clone)]
+pub struct SealedStorage {
+    dir: PathBuf,
+    key_policy: u16,
+}
+
+pub fn parse_key_policy(policy: &str) -> Result<u16, String> {
+    match policy {
+        "mrenclave" => Ok(SGX_KEYPOLICY_MRENCLAVE),
+        "mrsigner" => Ok(SGX_KEYPOLICY_MRSIGNER),
+        _ => Err(format!("unknown seal policy {}, expect mrenclave|mrsigner", policy)),
+    }
+}
+
+fn sealed_buffer_size(plaintext_size: usize) -> usize {
+    mem::size_of::<sgx_sealed_data_t>() + SEALED_AAD_SIZE_U8 + plaintext_size
+}
+
+impl SealedStorage {
+    pub fn new(dir: &str, key_policy: u16) -> Result<Self, String> {
+        fs::create_dir_all(dir).map_err(|e| format!("cannot create sealed dir {}: {}", dir, e))?;
+        Ok(SealedStorage { dir: PathBuf::from(dir), key_policy })
+    }
+
+    fn manifest_path(&self) -> PathBuf {
+        self.dir.join("manifest.sealed")
+    }
+
+    fn rate_limits_path(&self) -> PathBuf {
+        self.dir.join("rate-limits.sealed")
+    }
+
+    fn chunk_path(&self, chunk_index: usize) -> PathBuf {
+        self.dir.join(format!("chunk-{}.sealed", chunk_index))
+    }
+
+    fn write(&self, path: PathBuf, sealed: &[u8]) -> Result<(), String> {
+        fs::write(&path, sealed).map_err(|e| format!("cannot write {}: {}", path.display(), e))
+    }
+
+    fn read(&self, path: PathBuf) -> Result<Vec<u8>, String> {
+        fs::read(&path).map_err(|e| format!("cannot read {}: {}", path.display(), e))
+    }
+
+    // chunk-0.sealed から連番で存在するものを数える
+    pub fn chunk_count(&self) -> usize {
+        let mut count = 0;
+        while self.chunk_path(count).exists() {
+            count += 1;
+        }
+        count
+    }
+
+    pub fn read_chunk(&self, chunk_index: usize) -> Result<Vec<u8>, String> {
+        self.read(self.chunk_path(chunk_index))
+    }
+
+    // the manifest uploaded by the data owner is sealed as it is
+    pub fn seal_manifest(&self, eid: sgx_enclave_id_t, chunk_count: usize) -> Result<(), String> {
+        let buffer_size = sealed_buffer_size(MANIFEST_HEADER_SIZE_U8 + chunk_count*CHUNK_DIGEST_SIZE_U8);
+        let mut sealed: Vec<u8> = vec![0; buffer_size];
+        let mut sealed_size: usize = 0;
+        let mut retval = sgx_status_t::SGX_SUCCESS;
+        let mut error: u32 = 0;
+        let result = unsafe {
+            seal_central_manifest(eid, &mut retval, self.key_policy, sealed.as_mut_ptr(), buffer_size, &mut sealed_size, &mut error)
+        };
+        check_ecall("seal_central_manifest", result, retval, error)?;
+        self.write(self.manifest_path(), &sealed[..sealed_size])?;
+        self.remove_chunks_from(chunk_count)
+    }
+
+    // chunks of an older manifest with more chunks, chunk_count() must match the new manifest
+    fn remove_chunks_from(&self, chunk_count: usize) -> Result<(), String> {
+        let mut chunk_index = chunk_count;
+        while self.chunk_path(chunk_index).exists() {
+            let path = self.chunk_path(chunk_index);
+            fs::remove_file(&path).map_err(|e| format!("cannot remove {}: {}", path.display(), e))?;
+            chunk_index += 1;
+        }
+        Ok(())
+    }
+
+    // chunk: the chunk encrypted by the data owner, the enclave seals its plaintext
+    pub fn seal_chunk(&self, eid: sgx_enclave_id_t, chunk_index: usize, chunk: &Vec<u8>) -> Result<(), String> {
+        let buffer_size = sealed_buffer_size(chunk.len() - MESSAGE_OVERHEAD_U8);
+        let mut sealed: Vec<u8> = vec![0; buffer_size];
+        let mut sealed_size: usize = 0;
+        let mut retval = sgx_status_t::SGX_SUCCESS;
+        let mut error: u32 = 0;
+        let result = unsafe {
+            seal_central_chunk(
+                eid, &mut retval,
+                chunk.as_ptr() as * const u8, chunk.len(), chunk_index,
+                self.key_policy, sealed.as_mut_ptr(), buffer_size, &mut sealed_size, &mut error
+            )
+        };
+        check_ecall("seal_central_chunk", result, retval, error)?;
+        self.write(self.chunk_path(chunk_index), &sealed[..sealed_size])
+    }
+
+    pub fn load_manifest(&self, eid: sgx_enclave_id_t) -> Result<(), String> {
+        let sealed = self.read(self.manifest_path())?;
+        let mut retval = sgx_status_t::SGX_SUCCESS;
+        let mut error: u32 = 0;
+        let result = unsafe {
+            load_sealed_manifest(eid, &mut retval, sealed.as_ptr() as * const u8, sealed.len(), &mut error)
+        };
+        check_ecall("load_sealed_manifest", result, retval, error)
+    }
+
+    // the enclave counts the queries of each client identity, sealed after every batch
+    pub fn seal_rate_limits(&self, eid: sgx_enclave_id_t) -> Result<(), String> {
+        let buffer_size = sealed_buffer_size(RATE_LIMITS_MAX_SIZE_U8);
+        let mut sealed: Vec<u8> = vec![0; buffer_size];
+        let mut sealed_size: usize = 0;
+        let mut retval = sgx_status_t::SGX_SUCCESS;
+        let mut error: u32 = 0;
+        let result = unsafe {
+            seal_rate_limits(eid, &mut retval, self.key_policy, sealed.as_mut_ptr(), buffer_size, &mut sealed_size, &mut error)
+        };
+        check_ecall("seal_rate_limits", result, retval, error)?;
+        self.write(self.rate_limits_path(), &sealed[..sealed_size])
+    }
+
+    // the first run has no rate limits yet
+    pub fn load_rate_limits(&self, eid: sgx_enclave_id_t) -> Result<(), String> {
+        if !self.rate_limits_path().exists() {
+            return Ok(());
+        }
+        let sealed = self.read(self.rate_limits_path())?;
+        let mut retval = sgx_status_t::SGX_SUCCESS;
+        let mut error: u32 = 0;
+        let result = unsafe {
+            load_rate_limits(eid, &mut retval, sealed.as_ptr() as * const u8, sealed.len(), &mut error)
+        };
+        check_ecall("load_rate_limits", result, retval, error)
+    }
+}
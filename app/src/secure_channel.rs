@@ -0,0 +1,366 @@
+use sgx_types::*;
+use attestation::{QuoteProvider, QuoteVerifier};
+use ecalls::{check_ecall, client_key_exchange, central_key_exchange};
+use util::*;
+
+/*
+    Client side of the key exchange
+        client (or data owner) sends an ephemeral P-256 public key,
+        a client also sends its identity key, a signature over the ephemeral key
+        and the data owner's certificate of the identity key,
+        enclave returns its public key, a report binding the transcript and
+        the enclave generated session key wrapped under the ECDH derived key.
+*/
+pub type SessionKey = [u8; 16];
+pub type SessionId = u64;
+
+// session key and the enclave generated session id, which is bound to every message as AAD
+#[derive(Clone, Default, Debug)]
+pub struct SecureChannel {
+    pub session_id: SessionId,
+    pub key: SessionKey,
+}
+
+pub const SESSION_KEY_SIZE_U8: usize = 16;
+pub const AESGCM_IV_SIZE_U8: usize = 12;
+pub const AESGCM_MAC_SIZE_U8: usize = 16;
+// iv(12byte) + encrypted session key(16byte) + mac(16byte)
+pub const WRAPPED_KEY_SIZE_U8: usize = AESGCM_IV_SIZE_U8 + SESSION_KEY_SIZE_U8 + AESGCM_MAC_SIZE_U8;
+// AES-GCM message: iv(12byte) + ciphertext + mac(16byte)
+pub const MESSAGE_OVERHEAD_U8: usize = AESGCM_IV_SIZE_U8 + AESGCM_MAC_SIZE_U8;
+// AAD id of the chunk manifest, chunk indices never reach this value
+pub const MANIFEST_MESSAGE_ID: u64 = u64::MAX;
+// AAD id of the data owner's record batches for the enclave build is this base + batch index
+pub const CENTRAL_RECORDS_MESSAGE_ID_BASE: u64 = 1 << 62;
+
+pub struct EphemeralKeyPair {
+    private_key: [u8; SGX_ECP256_KEY_SIZE],
+    pub public_key: sgx_ec256_public_t,
+}
+
+impl EphemeralKeyPair {
+    pub fn new() -> Result<Self, String> {
+        let mut private_key = [0_u8; SGX_ECP256_KEY_SIZE];
+        let mut public_key = sgx_ec256_public_t::default();
+        let ret = unsafe {
+            ecdh_p256_create_key_pair(private_key.as_mut_ptr(), public_key.gx.as_mut_ptr(), public_key.gy.as_mut_ptr())
+        };
+        if ret != 0 { return Err("Error in ECDH key pair creation.".to_string()); }
+        Ok(EphemeralKeyPair { private_key, public_key })
+    }
+}
+
+// the client's long-term key, the enclave rate limits the queries of each identity key
+pub type IdentityKeyPair = EphemeralKeyPair;
+
+// an identity key with the data owner's signature over it, the enclave accepts only certified identity keys
+pub struct CertifiedIdentity {
+    pub key: IdentityKeyPair,
+    pub certificate: sgx_ec256_signature_t,
+}
+
+impl EphemeralKeyPair {
+    pub fn sign(&self, data: &[u8]) -> Result<sgx_ec256_signature_t, String> {
+        _ecdsa_sign(&self.private_key, data)
+    }
+}
+
+// the data owner's signing key, private key in little endian as the SGX SDK
+// the enclave is built with its public key (DATA_OWNER_PUBLIC_KEY, tools/data_owner_key/keygen.py makes the pair)
+pub struct DataOwnerKey {
+    private_key: [u8; SGX_ECP256_KEY_SIZE],
+}
+
+impl DataOwnerKey {
+    // hex of the 32 byte private key
+    pub fn from_hex(hex: &str) -> Result<Self, String> {
+        let hex = hex.trim().to_lowercase();
+        if hex.len() != 2*SGX_ECP256_KEY_SIZE || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err("the data owner key must be 32 bytes in hex".to_string());
+        }
+        let bytes = hex_to_bytes(&hex);
+        let mut private_key = [0_u8; SGX_ECP256_KEY_SIZE];
+        private_key.copy_from_slice(&bytes);
+        Ok(DataOwnerKey { private_key })
+    }
+
+    // signs the data owner's ephemeral key for central_key_exchange
+    pub fn sign_key_exchange(&self, public_key: &sgx_ec256_public_t) -> Result<sgx_ec256_signature_t, String> {
+        _ecdsa_sign(&self.private_key, &_with_context(DATA_OWNER_KEY_EXCHANGE_CONTEXT, &_public_key_bytes(public_key)))
+    }
+
+    // issues a certificate for a client's identity key
+    pub fn certify(&self, key: IdentityKeyPair) -> Result<CertifiedIdentity, String> {
+        let certificate = _ecdsa_sign(&self.private_key, &_with_context(IDENTITY_CERTIFICATE_CONTEXT, &_public_key_bytes(&key.public_key)))?;
+        Ok(CertifiedIdentity { key, certificate })
+    }
+}
+
+// same contexts as enclave/src/secure_channel.rs, so that a certificate is never taken as a key exchange signature
+const IDENTITY_CERTIFICATE_CONTEXT: &[u8] = b"client identity certificate";
+const DATA_OWNER_KEY_EXCHANGE_CONTEXT: &[u8] = b"data owner key exchange";
+
+fn _with_context(context: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut signed: Vec<u8> = Vec::with_capacity(context.len() + message.len());
+    signed.extend_from_slice(context);
+    signed.extend_from_slice(message);
+    signed
+}
+
+impl Drop for DataOwnerKey {
+    fn drop(&mut self) {
+        for byte in self.private_key.iter_mut() {
+            unsafe { std::ptr::write_volatile(byte, 0) };
+        }
+    }
+}
+
+fn _ecdsa_sign(private_key: &[u8; SGX_ECP256_KEY_SIZE], data: &[u8]) -> Result<sgx_ec256_signature_t, String> {
+    let mut signature = sgx_ec256_signature_t::default();
+    let ret = unsafe {
+        ecdsa_p256_sign(
+            private_key.as_ptr(),
+            data.as_ptr(),
+            data.len() as u32,
+            signature.x.as_mut_ptr() as *mut u8,
+            signature.y.as_mut_ptr() as *mut u8
+        )
+    };
+    if ret != 0 { return Err("Error in ECDSA signing.".to_string()); }
+    Ok(signature)
+}
+
+// gx + gy, signed by an identity key or the data owner key
+fn _public_key_bytes(public_key: &sgx_ec256_public_t) -> Vec<u8> {
+    let mut bytes: Vec<u8> = Vec::with_capacity(2*SGX_ECP256_KEY_SIZE);
+    bytes.extend_from_slice(&public_key.gx);
+    bytes.extend_from_slice(&public_key.gy);
+    bytes
+}
+
+impl Drop for EphemeralKeyPair {
+    fn drop(&mut self) {
+        for byte in self.private_key.iter_mut() {
+            unsafe { std::ptr::write_volatile(byte, 0) };
+        }
+    }
+}
+
+pub fn establish_client_channel(
+    eid: sgx_enclave_id_t,
+    session_handle: u64,
+    query_id: u64,
+    identity: &CertifiedIdentity,
+    quote_provider: &dyn QuoteProvider,
+    quote_verifier: &dyn QuoteVerifier,
+) -> Result<SecureChannel, String> {
+    let key_pair = EphemeralKeyPair::new()?;
+    // the identity key signs the ephemeral key (gx + gy)
+    let identity_signature = identity.key.sign(&_public_key_bytes(&key_pair.public_key))?;
+    let target_info = quote_provider.target_info()?;
+    let mut enclave_public_key = sgx_ec256_public_t::default();
+    let mut report = sgx_report_t::default();
+    let mut wrapped_key = [0_u8; WRAPPED_KEY_SIZE_U8];
+    let mut session_id: SessionId = 0;
+    let mut retval = sgx_status_t::SGX_SUCCESS;
+    let mut error: u32 = 0;
+    let result = unsafe {
+        client_key_exchange(
+            eid,
+            &mut retval,
+            session_handle,
+            query_id,
+            &key_pair.public_key,
+            &identity.key.public_key,
+            &identity_signature,
+            &identity.certificate,
+            &target_info,
+            &mut enclave_public_key,
+            &mut report,
+            wrapped_key.as_mut_ptr(),
+            WRAPPED_KEY_SIZE_U8,
+            &mut session_id,
+            &mut error
+        )
+    };
+    check_ecall("client_key_exchange", result, retval, error)?;
+    finish_key_exchange(&key_pair, &enclave_public_key, &report, &wrapped_key, session_id, quote_provider, quote_verifier)
+}
+
+pub fn establish_central_channel(
+    eid: sgx_enclave_id_t,
+    data_owner_key: &DataOwnerKey,
+    quote_provider: &dyn QuoteProvider,
+    quote_verifier: &dyn QuoteVerifier,
+) -> Result<SecureChannel, String> {
+    let key_pair = EphemeralKeyPair::new()?;
+    // the enclave accepts only the data owner key built into it
+    let owner_signature = data_owner_key.sign_key_exchange(&key_pair.public_key)?;
+    let target_info = quote_provider.target_info()?;
+    let mut enclave_public_key = sgx_ec256_public_t::default();
+    let mut report = sgx_report_t::default();
+    let mut wrapped_key = [0_u8; WRAPPED_KEY_SIZE_U8];
+    let mut session_id: SessionId = 0;
+    let mut retval = sgx_status_t::SGX_SUCCESS;
+    let mut error: u32 = 0;
+    let result = unsafe {
+        central_key_exchange(
+            eid,
+            &mut retval,
+            &key_pair.public_key,
+            &owner_signature,
+            &target_info,
+            &mut enclave_public_key,
+            &mut report,
+            wrapped_key.as_mut_ptr(),
+            WRAPPED_KEY_SIZE_U8,
+            &mut session_id,
+            &mut error
+        )
+    };
+    check_ecall("central_key_exchange", result, retval, error)?;
+    finish_key_exchange(&key_pair, &enclave_public_key, &report, &wrapped_key, session_id, quote_provider, quote_verifier)
+}
+
+fn finish_key_exchange(
+    key_pair: &EphemeralKeyPair,
+    enclave_public_key: &sgx_ec256_public_t,
+    report: &sgx_report_t,
+    wrapped_key: &[u8; WRAPPED_KEY_SIZE_U8],
+    session_id: SessionId,
+    quote_provider: &dyn QuoteProvider,
+    quote_verifier: &dyn QuoteVerifier,
+) -> Result<SecureChannel, String> {
+    /* attestation */
+    let quote = quote_provider.get_quote(report)?;
+    let report_body = quote_verifier.verify(&quote)?;
+
+    let transcript = key_exchange_transcript(&key_pair.public_key, enclave_public_key);
+    let mut bound = transcript.clone();
+    bound.extend_from_slice(&session_id.to_be_bytes());
+    bound.extend_from_slice(wrapped_key);
+    let hash = sha256(&bound)?;
+    if report_body.report_data.d[..SGX_SHA256_HASH_SIZE] != hash[..] {
+        return Err("report data does not bind the key exchange".to_string());
+    }
+
+    /* unwrap the session key */
+    let mut shared_key = [0_u8; SGX_ECP256_KEY_SIZE];
+    let ret = unsafe {
+        ecdh_p256_compute_shared_dhkey(
+            key_pair.private_key.as_ptr(),
+            enclave_public_key.gx.as_ptr(),
+            enclave_public_key.gy.as_ptr(),
+            shared_key.as_mut_ptr()
+        )
+    };
+    if ret != 0 { return Err("Error in ECDH shared key computation.".to_string()); }
+    let key_encryption_key = derive_key_encryption_key(&shared_key)?;
+
+    let mut session_key: SessionKey = [0; SESSION_KEY_SIZE_U8];
+    let ret = unsafe {
+        aes_gcm_128_decrypt(
+            key_encryption_key.as_ptr(),
+            wrapped_key[AESGCM_IV_SIZE_U8..AESGCM_IV_SIZE_U8+SESSION_KEY_SIZE_U8].as_ptr(),
+            SESSION_KEY_SIZE_U8 as u32,
+            wrapped_key[..AESGCM_IV_SIZE_U8].as_ptr(),
+            transcript.as_ptr(),
+            transcript.len() as u32,
+            wrapped_key[AESGCM_IV_SIZE_U8+SESSION_KEY_SIZE_U8..].as_ptr(),
+            session_key.as_mut_ptr()
+        )
+    };
+    if ret != 0 { return Err("Error in session key unwrapping.".to_string()); }
+    Ok(SecureChannel { session_id, key: session_key })
+}
+
+/*
+    message format (AES-GCM): iv(12byte) + ciphertext + mac(16byte)
+    iv is random for every message, AAD = session id(8byte) + chunk index or query id(8byte)
+*/
+pub fn message_aad(session_id: SessionId, id: u64) -> [u8; 16] {
+    let mut aad = [0_u8; 16];
+    aad[..8].copy_from_slice(&session_id.to_be_bytes());
+    aad[8..].copy_from_slice(&id.to_be_bytes());
+    aad
+}
+
+pub fn encrypt_message(key: &SessionKey, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>, String> {
+    let mut message: Vec<u8> = vec![0; plaintext.len() + MESSAGE_OVERHEAD_U8];
+    let ciphertext_end = message.len() - AESGCM_MAC_SIZE_U8;
+    let (iv, rest) = message.split_at_mut(AESGCM_IV_SIZE_U8);
+    let (ciphertext, mac) = rest.split_at_mut(ciphertext_end - AESGCM_IV_SIZE_U8);
+    let ret = unsafe { random_bytes(iv.as_mut_ptr(), AESGCM_IV_SIZE_U8 as u32) };
+    if ret != 0 { return Err("Error in iv generation.".to_string()); }
+    let ret = unsafe {
+        aes_gcm_128_encrypt(
+            key.as_ptr(),
+            plaintext.as_ptr(),
+            plaintext.len() as u32,
+            iv.as_ptr(),
+            aad.as_ptr(),
+            aad.len() as u32,
+            ciphertext.as_mut_ptr(),
+            mac.as_mut_ptr()
+        )
+    };
+    if ret != 0 { return Err("Error in GCM encryption.".to_string()); }
+    Ok(message)
+}
+
+pub fn decrypt_message(key: &SessionKey, message: &[u8], aad: &[u8]) -> Result<Vec<u8>, String> {
+    if message.len() < MESSAGE_OVERHEAD_U8 {
+        return Err("message is too short".to_string());
+    }
+    let ciphertext_end = message.len() - AESGCM_MAC_SIZE_U8;
+    let mut plaintext: Vec<u8> = vec![0; message.len() - MESSAGE_OVERHEAD_U8];
+    let ret = unsafe {
+        aes_gcm_128_decrypt(
+            key.as_ptr(),
+            message[AESGCM_IV_SIZE_U8..ciphertext_end].as_ptr(),
+            plaintext.len() as u32,
+            message[..AESGCM_IV_SIZE_U8].as_ptr(),
+            aad.as_ptr(),
+            aad.len() as u32,
+            message[ciphertext_end..].as_ptr(),
+            plaintext.as_mut_ptr()
+        )
+    };
+    if ret != 0 { return Err("Error in GCM decryption, message is tampered.".to_string()); }
+    Ok(plaintext)
+}
+
+// same derivation as the enclave
+// KDK = CMAC(0, shared_key), KEK = CMAC(KDK, 0x01 || "SK" || 0x00 || 0x80 0x00)
+fn derive_key_encryption_key(shared_key: &[u8; SGX_ECP256_KEY_SIZE]) -> Result<[u8; 16], String> {
+    let zero_key = [0_u8; 16];
+    let key_derivation_key = cmac(&zero_key, shared_key)?;
+    let derivation_string: [u8; 6] = [0x01, b'S', b'K', 0x00, 0x80, 0x00];
+    cmac(&key_derivation_key, &derivation_string)
+}
+
+fn key_exchange_transcript(
+    client_public_key: &sgx_ec256_public_t,
+    enclave_public_key: &sgx_ec256_public_t,
+) -> Vec<u8> {
+    let mut transcript: Vec<u8> = Vec::with_capacity(4*SGX_ECP256_KEY_SIZE);
+    transcript.extend_from_slice(&client_public_key.gx);
+    transcript.extend_from_slice(&client_public_key.gy);
+    transcript.extend_from_slice(&enclave_public_key.gx);
+    transcript.extend_from_slice(&enclave_public_key.gy);
+    transcript
+}
+
+fn cmac(key: &[u8; 16], src: &[u8]) -> Result<[u8; 16], String> {
+    let mut mac = [0_u8; 16];
+    let ret = unsafe { aes_cmac_128(key.as_ptr(), src.as_ptr(), src.len() as u32, mac.as_mut_ptr()) };
+    if ret != 0 { return Err("Error in CMAC.".to_string()); }
+    Ok(mac)
+}
+
+pub fn sha256(src: &[u8]) -> Result<[u8; SGX_SHA256_HASH_SIZE], String> {
+    let mut hash = [0_u8; SGX_SHA256_HASH_SIZE];
+    let ret = unsafe { sha256_digest(src.as_ptr(), src.len() as u32, hash.as_mut_ptr()) };
+    if ret != 0 { return Err("Error in SHA256.".to_string()); }
+    Ok(hash)
+}
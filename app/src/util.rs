@@ -10,30 +10,67 @@ use std::{
 };
 use regex::Regex;
 
-include!(concat!(env!("OUT_DIR"), "/init_constants.rs"));
+extern "C" {
+    pub fn random_bytes(
+        p_dst: *mut uint8_t,
+        len: uint32_t,
+    ) -> i32;
 
-pub const SGXSSL_CTR_BITS: u32 = 128;
-pub const COUNTER_BLOCK: [u8; 16] = [0; 16];
+    pub fn ecdh_p256_create_key_pair(
+        p_private: *mut uint8_t,
+        p_public_gx: *mut uint8_t,
+        p_public_gy: *mut uint8_t,
+    ) -> i32;
 
-pub type sgx_aes_ctr_128bit_key_t = [uint8_t; 16];
-extern "C" {
-    pub fn sgx_aes_ctr_decrypt(
-        p_key: *const sgx_aes_ctr_128bit_key_t,
+    pub fn ecdh_p256_compute_shared_dhkey(
+        p_private: *const uint8_t,
+        p_peer_gx: *const uint8_t,
+        p_peer_gy: *const uint8_t,
+        p_shared: *mut uint8_t,
+    ) -> i32;
+
+    pub fn ecdsa_p256_sign(
+        p_private: *const uint8_t,
+        p_data: *const uint8_t,
+        data_len: uint32_t,
+        p_signature_x: *mut uint8_t,
+        p_signature_y: *mut uint8_t,
+    ) -> i32;
+
+    pub fn aes_cmac_128(
+        p_key: *const uint8_t,
+        p_src: *const uint8_t,
+        src_len: uint32_t,
+        p_mac: *mut uint8_t,
+    ) -> i32;
+
+    pub fn sha256_digest(
+        p_src: *const uint8_t,
+        src_len: uint32_t,
+        p_hash: *mut uint8_t,
+    ) -> i32;
+
+    pub fn aes_gcm_128_encrypt(
+        p_key: *const uint8_t,
         p_src: *const uint8_t,
         src_len: uint32_t,
-        p_ctr: *const uint8_t,
-        ctr_inc_bits: uint32_t,
+        p_iv: *const uint8_t,
+        p_aad: *const uint8_t,
+        aad_len: uint32_t,
         p_dst: *mut uint8_t,
-    ) -> u32;
+        p_mac: *mut uint8_t,
+    ) -> i32;
 
-    pub fn sgx_aes_ctr_encrypt(
-        p_key: *const sgx_aes_ctr_128bit_key_t,
+    pub fn aes_gcm_128_decrypt(
+        p_key: *const uint8_t,
         p_src: *const uint8_t,
         src_len: uint32_t,
-        p_ctr: *const uint8_t,
-        ctr_inc_bits: uint32_t,
+        p_iv: *const uint8_t,
+        p_aad: *const uint8_t,
+        aad_len: uint32_t,
+        p_mac: *const uint8_t,
         p_dst: *mut uint8_t,
-    ) -> u32;
+    ) -> i32;
 }
 
 #[derive(Clone, Default, Debug)]
@@ -91,24 +128,111 @@ impl<'a> Clocker<'a> {
     }
 }
 
+pub fn hex_to_bytes(hash: &str) -> Vec<u8> {
+    let chars: Vec<char> = hash.chars().collect();
+    let mut hash_bytes: Vec<u8> = Vec::with_capacity(hash.len() / 2);
+    for i in 0..(hash.len() / 2) {
+        hash_bytes.push(16 * hex_to_num(chars[2 * i]) + hex_to_num(chars[2 * i + 1]));
+    }
+    hash_bytes
+}
+
 pub fn read_trajectory_hash_from_csv(filename: &str) -> Vec<Vec<u8>> {
     let file = File::open(filename).expect("file open error");
     let reader = BufReader::new(file);
     let mut hash_vec = Vec::new();
     for line in reader.lines().into_iter() {
         if let Ok(hash) = line {
-            let chars: Vec<char> = hash.chars().collect();
-            let mut hash_bytes: Vec<u8> = Vec::with_capacity(hash.len() / 2);
-            for i in 0..(hash.len() / 2) {
-                hash_bytes.push(16 * hex_to_num(chars[2 * i]) + hex_to_num(chars[2 * i + 1]));
-            }
-            hash_vec.push(hash_bytes);
+            hash_vec.push(hex_to_bytes(&hash));
         }
     }
     hash_vec
 }
 
+// interval mode: "location hex,unixepoch" per line
+// each record is the location bytes followed by the unixepoch (8 bytes, big endian)
+pub fn read_visits_from_csv(filename: &str) -> Vec<Vec<u8>> {
+    let file = File::open(filename).expect("file open error");
+    let reader = BufReader::new(file);
+    let mut visit_vec = Vec::new();
+    for line in reader.lines().into_iter() {
+        if let Ok(visit) = line {
+            let mut columns = visit.split(',');
+            let (location, unixepoch) = match (columns.next(), columns.next()) {
+                (Some(location), Some(unixepoch)) => (location.trim(), unixepoch.trim()),
+                _ => panic!("invalid visit line, expect location,unixepoch"),
+            };
+            let unixepoch: u64 = unixepoch.parse().expect("invalid unixepoch");
+            let mut record = hex_to_bytes(location);
+            record.extend_from_slice(&unixepoch.to_be_bytes());
+            visit_vec.push(record);
+        }
+    }
+    visit_vec
+}
+
+// exact verification: "hash hex,unixepoch,latitude,longitude" per line (trajectory_hash --with-raw)
+// each record is the hash bytes followed by latitude, longitude (f64) and unixepoch (u64), all big endian
+pub fn read_raw_points_from_csv(filename: &str) -> Vec<Vec<u8>> {
+    let file = File::open(filename).expect("file open error");
+    let reader = BufReader::new(file);
+    let mut point_vec = Vec::new();
+    for line in reader.lines().into_iter() {
+        if let Ok(point) = line {
+            let columns: Vec<&str> = point.split(',').map(|column| column.trim()).collect();
+            if columns.len() < 4 {
+                panic!("invalid raw point line, expect hash,unixepoch,latitude,longitude");
+            }
+            let unixepoch: u64 = columns[1].parse().expect("invalid unixepoch");
+            let latitude: f64 = columns[2].parse().expect("invalid latitude");
+            let longitude: f64 = columns[3].parse().expect("invalid longitude");
+            let mut record = hex_to_bytes(columns[0]);
+            record.extend_from_slice(&latitude.to_bits().to_be_bytes());
+            record.extend_from_slice(&longitude.to_bits().to_be_bytes());
+            record.extend_from_slice(&unixepoch.to_be_bytes());
+            point_vec.push(record);
+        }
+    }
+    point_vec
+}
+
+// the enclave reads every hash of a client first and then the raw point of each
+pub fn keys_then_raw_points(detail: &Vec<Vec<u8>>, encoded_value_size: usize) -> Vec<Vec<u8>> {
+    detail.iter().map(|record| record[..encoded_value_size].to_vec())
+        .chain(detail.iter().map(|record| record[encoded_value_size..].to_vec()))
+        .collect()
+}
+
+// the sample client files pad their trajectory with 0000... or 1111... lines
+fn _is_padding_key(key: &[u8]) -> bool {
+    key.iter().all(|byte| *byte == 0x00) || key.iter().all(|byte| *byte == 0x11)
+}
+
+// the client marks its padding points, bit i (byte i/8, bit i%8) for point i
+// the mask is encrypted after the keys (and raw points), so every upload has the same size
+pub fn dummy_mask(detail: &Vec<Vec<u8>>, encoded_value_size: usize) -> Vec<u8> {
+    let mut mask: Vec<u8> = vec![0; (detail.len() + 7) / 8];
+    for (point_index, record) in detail.iter().enumerate() {
+        if _is_padding_key(&record[..encoded_value_size]) {
+            mask[point_index / 8] |= 1 << (point_index % 8);
+        }
+    }
+    mask
+}
+
 pub fn read_trajectory_hash_from_csv_for_clients(dirname: &str, client_num: u32) -> Vec<Vec<Vec<u8>>> {
+    _read_clients(dirname, client_num, read_trajectory_hash_from_csv)
+}
+
+pub fn read_visits_from_csv_for_clients(dirname: &str, client_num: u32) -> Vec<Vec<Vec<u8>>> {
+    _read_clients(dirname, client_num, read_visits_from_csv)
+}
+
+pub fn read_raw_points_from_csv_for_clients(dirname: &str, client_num: u32) -> Vec<Vec<Vec<u8>>> {
+    _read_clients(dirname, client_num, read_raw_points_from_csv)
+}
+
+fn _read_clients(dirname: &str, client_num: u32, read_client: fn(&str) -> Vec<Vec<u8>>) -> Vec<Vec<Vec<u8>>> {
     let mut query_data = Vec::new();
     let re = Regex::new(r".*/client-\d+-\d+-(?P<client_id>\d+).*.csv").unwrap();
     for entry in glob(format!("{}/*.csv", dirname).as_str()).expect("Failed to read glob pattern") {
@@ -124,7 +248,7 @@ pub fn read_trajectory_hash_from_csv_for_clients(dirname: &str, client_num: u32)
                     continue;
                 }
                 // println!("start ... filepath {}, client_id {}", filepath, client_id);
-                let client_data = read_trajectory_hash_from_csv(path.to_str().unwrap());
+                let client_data = read_client(path.to_str().unwrap());
 
                 query_data.push(client_data);
             }
@@ -134,6 +258,19 @@ pub fn read_trajectory_hash_from_csv_for_clients(dirname: &str, client_num: u32)
     query_data
 }
 
+// every trajectory hash of a session must have the same byte length
+pub fn encoded_value_size(data: &Vec<Vec<u8>>) -> Option<usize> {
+    let size = match data.first() {
+        Some(value) => value.len(),
+        None => return None,
+    };
+    if data.iter().all(|value| value.len() == size) {
+        Some(size)
+    } else {
+        None
+    }
+}
+
 fn hex_to_num(c: char) -> u8 {
     match c {
         '0' => 0,
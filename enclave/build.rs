@@ -5,14 +5,6 @@ fn main () {
     let dest_path = Path::new(&out_dir).join("init_constants.rs");
     let mut f = File::create(&dest_path).expect("Could not create file");
 
-    let encoded_value_size = option_env!("ENCODEDVALUE_SIZE");
-    let encoded_value_size: usize = encoded_value_size
-        .expect("Could not parse ENCODEDVALUE_SIZE")
-        .parse()
-        .expect("Could not parse ENCODEDVALUE_SIZE");
-    write!(&mut f, "pub const ENCODEDVALUE_SIZE: usize = {};", encoded_value_size)
-        .expect("Could not write file");
-
     let query_size = option_env!("QUERY_SIZE");
     let query_size: usize = query_size
         .expect("Could not parse QUERY_SIZE")
@@ -20,4 +12,17 @@ fn main () {
         .expect("Could not parse QUERY_SIZE");
     write!(&mut f, "pub const QUERY_SIZE: usize = {};", query_size)
         .expect("Could not write file");
+
+    // gx || gy in hex, little endian as the SGX SDK (tools/data_owner_key/keygen.py prints it)
+    let data_owner_public_key = option_env!("DATA_OWNER_PUBLIC_KEY")
+        .expect("Could not find DATA_OWNER_PUBLIC_KEY");
+    let data_owner_public_key = data_owner_public_key.trim();
+    if data_owner_public_key.len() != 128 || !data_owner_public_key.chars().all(|c| c.is_ascii_hexdigit()) {
+        panic!("DATA_OWNER_PUBLIC_KEY must be 64 bytes in hex");
+    }
+    let bytes: Vec<String> = (0..64).map(|i| format!("0x{}", &data_owner_public_key[2*i..2*i+2])).collect();
+    write!(&mut f, "\npub const DATA_OWNER_PUBLIC_KEY_GX: [u8; 32] = [{}];", bytes[..32].join(", "))
+        .expect("Could not write file");
+    write!(&mut f, "\npub const DATA_OWNER_PUBLIC_KEY_GY: [u8; 32] = [{}];", bytes[32..].join(", "))
+        .expect("Could not write file");
 }
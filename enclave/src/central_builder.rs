@@ -0,0 +1,307 @@
+use sgx_types::*;
+use std::vec::Vec;
+use std::mem;
+#[cfg(feature = "hashtable")]
+use std::collections::HashSet;
+#[cfg(feature = "hashtable")]
+use bincode;
+#[cfg(feature = "fsa")]
+use succinct_trie::trie::Trie;
+use sgx_tcrypto::rsgx_sha256_slice;
+use constant::*;
+use utils::zeroize;
+use chunk_header::{ChunkHeader, ChunkFormat, is_valid_encoded_value_size};
+use chunk_manifest::ChunkManifest;
+use chunk_padding;
+use memory_budget;
+use secure_channel::{SecureChannel, message_aad, encrypt_message};
+use enclave_error::{EnclaveError, EnclaveResult};
+
+/*
+Type CentralBuilder
+    data ownerが暗号化した生のrecordからenclaveの中でchunkを作る，hostは平文のkeyを見ない
+    begin_central_build -> append_central_records (batchごと) -> build_central_chunks (何回でも)
+    record batch: batch数(8byte) + recordの繰り返し
+        AAD = central session id + (CENTRAL_RECORDS_MESSAGE_ID_BASE + batch index)
+        batch数も認証されるので，hostがbatchを落とすとbuildできない
+    record: Dictionary, SortedKeysはkey，Visitsはlocation + 時刻，VerifiedDictionaryはhash + 生の点
+    作ったchunkはchunk_paddingで包んでcentral session keyでchunk indexをAADにして暗号化し，manifestはenclaveが自分で宣言する
+    recordはbuildの後も持っておき，hostがOutOfMemoryでchunkを分けるときに作り直す
+*/
+pub struct CentralBuilder {
+    header: ChunkHeader,
+    batch_count: Option<usize>,
+    seen: Vec<bool>,
+    records: Vec<Vec<u8>>,
+    sorted: bool,
+    // 暗号化済み，hostが取り出したものは空になる
+    chunks: Vec<Vec<u8>>,
+}
+
+impl CentralBuilder {
+    pub fn new(encoded_value_size: usize, format: ChunkFormat) -> EnclaveResult<Self> {
+        if !is_valid_encoded_value_size(encoded_value_size) {
+            return Err(EnclaveError::InvalidEncodedValueSize);
+        }
+        // ORAMはソート済みのkeyから作るのでdictionaryは作らない
+        if cfg!(feature = "oram") && (format == ChunkFormat::Dictionary || format == ChunkFormat::VerifiedDictionary) {
+            return Err(EnclaveError::ChunkFormatMismatch);
+        }
+        Ok(CentralBuilder {
+            header: ChunkHeader { encoded_value_size, format },
+            batch_count: None,
+            seen: vec![],
+            records: vec![],
+            sorted: false,
+            chunks: vec![],
+        })
+    }
+
+    pub fn record_size(&self) -> usize {
+        match self.header.format {
+            ChunkFormat::Dictionary | ChunkFormat::SortedKeys => self.header.encoded_value_size,
+            ChunkFormat::Visits => self.header.encoded_value_size + UNIXEPOCH_BE_SIZE_U8,
+            ChunkFormat::VerifiedDictionary => self.header.encoded_value_size + RAW_POINT_SIZE_U8,
+        }
+    }
+
+    // batch: 復号したrecord batch，どのbatchもちょうど1回ずつ
+    pub fn append(&mut self, batch_index: usize, batch: &[u8]) -> EnclaveResult<()> {
+        if batch.len() < RECORD_BATCH_HEADER_SIZE_U8 || (batch.len() - RECORD_BATCH_HEADER_SIZE_U8) % self.record_size() != 0 {
+            return Err(EnclaveError::InvalidLength);
+        }
+        let mut batch_count_bytes = [0_u8; 8];
+        batch_count_bytes.copy_from_slice(&batch[..RECORD_BATCH_HEADER_SIZE_U8]);
+        let batch_count = u64::from_be_bytes(batch_count_bytes) as usize;
+        if batch_count == 0 || batch_count > MAX_RECORD_BATCHES || self.batch_count.map_or(false, |count| count != batch_count) {
+            return Err(EnclaveError::RecordBatchMismatch);
+        }
+        if self.batch_count.is_none() {
+            self.batch_count = Some(batch_count);
+            self.seen = vec![false; batch_count];
+        }
+        if batch_index >= batch_count || self.seen[batch_index] {
+            return Err(EnclaveError::RecordBatchMismatch);
+        }
+        let _reservation = memory_budget::reserve(batch.len())?;
+        self.seen[batch_index] = true;
+        self.records.extend(batch[RECORD_BATCH_HEADER_SIZE_U8..].chunks_exact(self.record_size()).map(|record| record.to_vec()));
+        self.sorted = false;
+        Ok(())
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.batch_count.is_some() && self.seen.iter().all(|seen| *seen)
+    }
+
+    // threashould: chunkごとのrecord数 (Visitsは訪問時刻の数)，前のbuildのchunkは捨てる
+    // size_class > 0: 最後のchunkのrecordを足してから，どのchunkもちょうどsize classに0埋めする (chunk_padding)
+    pub fn build(&mut self, threashould: usize, size_class: usize, central_channel: &SecureChannel) -> EnclaveResult<ChunkManifest> {
+        if !self.is_complete() {
+            return Err(EnclaveError::RecordBatchMismatch);
+        }
+        if threashould == 0 || size_class > MAX_CHUNK_SIZE_CLASS {
+            return Err(EnclaveError::InvalidLength);
+        }
+        // chunkの平文と暗号文，decodeした辞書の分
+        let _reservation = memory_budget::reserve(3*self.records.len()*self.record_size())?;
+        if !self.sorted {
+            self.records.sort();
+            self.records.dedup();
+            self.sorted = true;
+        }
+        let mut record_chunks = self._record_chunks(threashould);
+        if size_class > 0 {
+            self._pad_last_chunk(&mut record_chunks, threashould);
+            if self.header.format == ChunkFormat::Visits {
+                self._pad_visit_chunks(&mut record_chunks);
+            }
+        }
+        let mut plaintexts: Vec<Vec<u8>> = record_chunks.iter().map(|records| match self.header.format {
+            ChunkFormat::Dictionary => self._dictionary_chunk(records),
+            ChunkFormat::SortedKeys => Ok(self._sorted_key_chunk(records)),
+            ChunkFormat::Visits => Ok(self._visit_chunk(records)),
+            ChunkFormat::VerifiedDictionary => self._verified_chunk(records),
+        }).collect::<EnclaveResult<Vec<Vec<u8>>>>()?;
+
+        self.chunks = Vec::with_capacity(plaintexts.len());
+        let mut digests: Vec<sgx_sha256_hash_t> = Vec::with_capacity(plaintexts.len());
+        let mut ret = Ok(());
+        for (chunk_index, plaintext) in plaintexts.iter().enumerate() {
+            let mut padded = match chunk_padding::pad(plaintext, size_class) {
+                Ok(padded) => padded,
+                Err(e) => {
+                    ret = Err(e);
+                    break;
+                },
+            };
+            let mut chunk: Vec<u8> = vec![0; padded.len() + MESSAGE_OVERHEAD_U8];
+            ret = rsgx_sha256_slice(plaintext).map_err(EnclaveError::from)
+                .and_then(|digest| {
+                    digests.push(digest);
+                    encrypt_message(&central_channel.key, &padded, &message_aad(central_channel.session_id, chunk_index as u64), &mut chunk)
+                });
+            zeroize(&mut padded);
+            if ret.is_err() {
+                break;
+            }
+            self.chunks.push(chunk);
+        }
+        for plaintext in plaintexts.iter_mut() {
+            zeroize(plaintext);
+        }
+        ret?;
+        ChunkManifest::from_digests(digests).ok_or(EnclaveError::RecordBatchMismatch)
+    }
+
+    pub fn chunk_count(&self) -> usize {
+        self.chunks.len()
+    }
+
+    pub fn max_chunk_size(&self) -> usize {
+        self.chunks.iter().map(|chunk| chunk.len()).max().unwrap_or(0)
+    }
+
+    // hostに渡したchunkはenclaveに残さない
+    pub fn take_chunk(&mut self, chunk_index: usize) -> EnclaveResult<Vec<u8>> {
+        match self.chunks.get_mut(chunk_index) {
+            Some(chunk) if !chunk.is_empty() => Ok(mem::replace(chunk, vec![])),
+            Some(_) => Err(EnclaveError::ChunkReplayed),
+            None => Err(EnclaveError::ChunkOutOfRange),
+        }
+    }
+
+    fn _same_location(&self, a: &[u8], b: &[u8]) -> bool {
+        a[..self.header.encoded_value_size] == b[..self.header.encoded_value_size]
+    }
+
+    // threashould個ずつ，Visitsは1つのlocationの訪問を同じchunkに入れてthreashould個を超えたら閉じる
+    fn _record_chunks(&self, threashould: usize) -> Vec<Vec<&[u8]>> {
+        if self.header.format != ChunkFormat::Visits {
+            return self.records.chunks(threashould).map(|records| records.iter().map(|record| &record[..]).collect()).collect();
+        }
+        let mut chunks: Vec<Vec<&[u8]>> = vec![];
+        let mut records: Vec<&[u8]> = vec![];
+        for (index, record) in self.records.iter().enumerate() {
+            records.push(&record[..]);
+            let location_ends = self.records.get(index + 1).map_or(true, |next| !self._same_location(record, next));
+            if location_ends && records.len() >= threashould {
+                chunks.push(records);
+                records = vec![];
+            }
+        }
+        if !records.is_empty() {
+            chunks.push(records);
+        }
+        chunks
+    }
+
+    // key padding: 最後のchunkに最初のchunkの先頭のrecordを足して，ほかのchunkと同じthreashould個にする
+    // 足したrecordもcentral dataなので，hitしても本当のcontact (chunkの結果はORする)
+    // 最初のchunkのrecordはどれも最後のchunkより小さいので，前に足せばソート済みのまま
+    fn _pad_last_chunk(&self, chunks: &mut Vec<Vec<&[u8]>>, threashould: usize) {
+        if chunks.len() < 2 || chunks[chunks.len() - 1].len() >= threashould {
+            return;
+        }
+        let first = chunks[0].clone();
+        let mut borrowed = (threashould - chunks[chunks.len() - 1].len()).min(first.len());
+        // Visitsはlocationの訪問を全部持ってくる
+        while self.header.format == ChunkFormat::Visits && borrowed < first.len() && self._same_location(first[borrowed - 1], first[borrowed]) {
+            borrowed += 1;
+        }
+        let last = chunks.last_mut().unwrap();
+        let mut padded: Vec<&[u8]> = first[..borrowed].to_vec();
+        padded.extend(last.iter());
+        *last = padded;
+    }
+
+    // Visitsはlocationの途中で閉じないのでchunkごとに訪問の数が違う
+    // 一番多いchunkにそろえるまで，各chunkの最後のlocationの訪問をくり返して足す
+    // PeriodTableは続く同じlocationの時刻を重複なしでmergeするので，periodは変わらない
+    fn _pad_visit_chunks(&self, chunks: &mut Vec<Vec<&[u8]>>) {
+        let target = chunks.iter().map(|records| records.len()).max().unwrap_or(0);
+        for records in chunks.iter_mut() {
+            let last_visit_count = match records.last() {
+                Some(last) => records.iter().rev().take_while(|record| self._same_location(last, record)).count(),
+                None => continue,
+            };
+            let last_visits: Vec<&[u8]> = records[records.len() - last_visit_count..].to_vec();
+            for record in last_visits.iter().cycle().take(target - records.len()) {
+                records.push(*record);
+            }
+        }
+    }
+
+    fn _dictionary_chunk(&self, keys: &[&[u8]]) -> EnclaveResult<Vec<u8>> {
+        let mut bytes = self.header.to_be_bytes();
+        bytes.extend(_serialize_dictionary(keys)?);
+        Ok(bytes)
+    }
+
+    // format: header + key_1 + key_2 + ...
+    fn _sorted_key_chunk(&self, keys: &[&[u8]]) -> Vec<u8> {
+        let mut bytes = self.header.to_be_bytes();
+        for key in keys.iter() {
+            bytes.extend_from_slice(key);
+        }
+        bytes
+    }
+
+    // format: header + (location + 時刻の数(8byte) + 昇順の時刻) の繰り返し
+    // recordはlocation, 時刻 (big endian) の順にソート済み，_pad_visit_chunksで足した訪問は時刻が戻るので別のentryにする
+    fn _visit_chunk(&self, records: &[&[u8]]) -> Vec<u8> {
+        let encoded_value_size = self.header.encoded_value_size;
+        let mut bytes = self.header.to_be_bytes();
+        let mut start = 0;
+        while start < records.len() {
+            let end = start + 1 + records[start..].windows(2).take_while(|pair| self._same_location(pair[0], pair[1]) && pair[0] < pair[1]).count();
+            bytes.extend_from_slice(&records[start][..encoded_value_size]);
+            bytes.extend_from_slice(&((end - start) as u64).to_be_bytes());
+            for record in records[start..end].iter() {
+                bytes.extend_from_slice(&record[encoded_value_size..]);
+            }
+            start = end;
+        }
+        bytes
+    }
+
+    // format: header + dictionaryのbyte数(8byte) + dictionary + (hash + 生の点) の繰り返し
+    fn _verified_chunk(&self, records: &[&[u8]]) -> EnclaveResult<Vec<u8>> {
+        let mut keys: Vec<&[u8]> = records.iter().map(|record| &record[..self.header.encoded_value_size]).collect();
+        keys.dedup();
+        let dictionary = _serialize_dictionary(&keys)?;
+        let mut bytes = self.header.to_be_bytes();
+        bytes.extend_from_slice(&(dictionary.len() as u64).to_be_bytes());
+        bytes.extend(dictionary);
+        for record in records.iter() {
+            bytes.extend_from_slice(record);
+        }
+        Ok(bytes)
+    }
+}
+
+impl Drop for CentralBuilder {
+    fn drop(&mut self) {
+        for record in self.records.iter_mut() {
+            zeroize(record);
+        }
+    }
+}
+
+// keysはソート済み，hostのCentralTrieと同じbyte列
+#[cfg(feature = "fsa")]
+fn _serialize_dictionary(keys: &[&[u8]]) -> EnclaveResult<Vec<u8>> {
+    Ok(Trie::new(&keys.iter().map(|key| key.to_vec()).collect()).serialize())
+}
+
+// hostのCentralHashSetと同じbyte列
+#[cfg(feature = "hashtable")]
+fn _serialize_dictionary(keys: &[&[u8]]) -> EnclaveResult<Vec<u8>> {
+    let hashset: HashSet<Vec<u8>> = keys.iter().map(|key| key.to_vec()).collect();
+    bincode::serialize(&hashset).map_err(|_| EnclaveError::Internal)
+}
+
+#[cfg(feature = "oram")]
+fn _serialize_dictionary(_keys: &[&[u8]]) -> EnclaveResult<Vec<u8>> {
+    Err(EnclaveError::ChunkFormatMismatch)
+}
@@ -0,0 +1,71 @@
+use std::vec::Vec;
+use constant::*;
+
+/* 
+Type ChunkHeader
+    central data chunkの先頭に付くヘッダ
+    format: encoded_value_size(8byte) + chunk_format(8byte)
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChunkFormat {
+    // trie or hashtable (build feature)
+    Dictionary = 0,
+    // encoded_value_sizeごとに区切られたソート済みのキー列 (oblivious join, ORAM)
+    SortedKeys = 1,
+    // locationごとの訪問時刻 (interval mode)
+    // location(encoded_value_size) + 時刻の数(8byte) + 昇順の時刻(8byteずつ) の繰り返し
+    Visits = 2,
+    // Dictionaryに生の点を付けたもの (exact verification)
+    // dictionaryのbyte数(8byte) + Dictionaryと同じbyte列 + keyの昇順の (key + 生の点) の繰り返し
+    VerifiedDictionary = 3,
+}
+
+impl ChunkFormat {
+    pub fn from_u64(format: u64) -> Option<Self> {
+        match format {
+            0 => Some(ChunkFormat::Dictionary),
+            1 => Some(ChunkFormat::SortedKeys),
+            2 => Some(ChunkFormat::Visits),
+            3 => Some(ChunkFormat::VerifiedDictionary),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ChunkHeader {
+    pub encoded_value_size: usize,
+    pub format: ChunkFormat,
+}
+
+impl ChunkHeader {
+    pub fn from_be_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < CHUNK_HEADER_SIZE_U8 {
+            return None;
+        }
+        let mut encoded_value_size_bytes = [0_u8; 8];
+        encoded_value_size_bytes.copy_from_slice(&bytes[..8]);
+        let encoded_value_size = u64::from_be_bytes(encoded_value_size_bytes) as usize;
+        let mut format_bytes = [0_u8; 8];
+        format_bytes.copy_from_slice(&bytes[8..16]);
+        let format = ChunkFormat::from_u64(u64::from_be_bytes(format_bytes))?;
+        Some(ChunkHeader { encoded_value_size, format })
+    }
+
+    // enclaveの中でchunkを作るとき (central_builder)
+    pub fn to_be_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::with_capacity(CHUNK_HEADER_SIZE_U8);
+        bytes.extend_from_slice(&(self.encoded_value_size as u64).to_be_bytes());
+        bytes.extend_from_slice(&(self.format as u64).to_be_bytes());
+        bytes
+    }
+}
+
+pub fn is_valid_encoded_value_size(encoded_value_size: usize) -> bool {
+    MIN_ENCODEDVALUE_SIZE <= encoded_value_size && encoded_value_size <= MAX_ENCODEDVALUE_SIZE
+}
+
+// TrajectoryHashのmaskで近傍やcellを作るmodeは，keyがTRAJECTORY_GEO_LENGTH, TRAJECTORY_TIME_LENGTHのlayoutのときだけ使える
+pub fn is_trajectory_hash_size(encoded_value_size: usize) -> bool {
+    encoded_value_size == TRAJECTORY_HASH_SIZE_U8
+}
@@ -0,0 +1,88 @@
+use sgx_types::*;
+use std::vec::Vec;
+use constant::*;
+use enclave_error::{EnclaveError, EnclaveResult};
+
+/* 
+Type ChunkManifest
+    data ownerがcentral data chunkの数と各chunkのdigestを宣言したもの
+    format: chunk_count(8byte) + sha256(chunk_i)(32byte) * chunk_count
+    hostがchunkを落としたり2回送ったりするとfalse negativeになるので，
+    enclaveは各chunkをちょうど1回ずつ処理したことを確認してから結果を返す
+*/
+#[derive(Clone, Default, Debug)]
+pub struct ChunkManifest {
+    digests: Vec<sgx_sha256_hash_t>,
+    seen: Vec<bool>,
+}
+
+impl ChunkManifest {
+    pub fn from_be_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < MANIFEST_HEADER_SIZE_U8 {
+            return None;
+        }
+        let mut chunk_count_bytes = [0_u8; 8];
+        chunk_count_bytes.copy_from_slice(&bytes[..MANIFEST_HEADER_SIZE_U8]);
+        let chunk_count = u64::from_be_bytes(chunk_count_bytes) as usize;
+        let digest_bytes = &bytes[MANIFEST_HEADER_SIZE_U8..];
+        if chunk_count == 0 || digest_bytes.len() != chunk_count*CHUNK_DIGEST_SIZE_U8 {
+            return None;
+        }
+
+        let mut digests: Vec<sgx_sha256_hash_t> = Vec::with_capacity(chunk_count);
+        for digest_slice in digest_bytes.chunks_exact(CHUNK_DIGEST_SIZE_U8) {
+            let mut digest: sgx_sha256_hash_t = [0_u8; CHUNK_DIGEST_SIZE_U8];
+            digest.copy_from_slice(digest_slice);
+            digests.push(digest);
+        }
+        Some(ChunkManifest { seen: vec![false; chunk_count], digests })
+    }
+
+    // enclaveが自分で作ったchunkのmanifest (central_builder)
+    pub fn from_digests(digests: Vec<sgx_sha256_hash_t>) -> Option<Self> {
+        if digests.is_empty() {
+            return None;
+        }
+        Some(ChunkManifest { seen: vec![false; digests.len()], digests })
+    }
+
+    pub fn to_be_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::with_capacity(MANIFEST_HEADER_SIZE_U8 + self.chunk_count()*CHUNK_DIGEST_SIZE_U8);
+        bytes.extend_from_slice(&(self.chunk_count() as u64).to_be_bytes());
+        for digest in self.digests.iter() {
+            bytes.extend_from_slice(digest);
+        }
+        bytes
+    }
+
+    pub fn chunk_count(&self) -> usize {
+        self.digests.len()
+    }
+
+    pub fn verify(&self, chunk_index: usize, digest: &sgx_sha256_hash_t) -> EnclaveResult<()> {
+        if chunk_index >= self.chunk_count() {
+            return Err(EnclaveError::ChunkOutOfRange);
+        }
+        if self.digests[chunk_index] != *digest {
+            return Err(EnclaveError::ChunkDigestMismatch);
+        }
+        Ok(())
+    }
+
+    pub fn mark_seen(&mut self, chunk_index: usize, digest: &sgx_sha256_hash_t) -> EnclaveResult<()> {
+        self.verify(chunk_index, digest)?;
+        if self.seen[chunk_index] {
+            return Err(EnclaveError::ChunkReplayed);
+        }
+        self.seen[chunk_index] = true;
+        Ok(())
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.seen.iter().all(|seen| *seen)
+    }
+
+    pub fn missing_count(&self) -> usize {
+        self.seen.iter().filter(|seen| !**seen).count()
+    }
+}
@@ -0,0 +1,37 @@
+use std::vec::Vec;
+use constant::*;
+use enclave_error::{EnclaveError, EnclaveResult};
+
+/*
+Chunk padding
+    暗号化 (とseal) するchunkの平文: chunkのbyte数(8byte) + chunk + 0埋め
+    size class > 0のときはどのchunkもちょうどsize classのbyte数にするので，hostにはchunkの大きさが見えない
+    収まらないchunkはChunkTooLarge (倍数に切り上げるとchunkの大きさがclassの単位で見えてしまう)
+    byte数は認証された暗号文の中にあるので，hostは変えられない
+    manifestのdigestは0埋めを外したchunkのもの
+*/
+pub fn pad(chunk: &[u8], size_class: usize) -> EnclaveResult<Vec<u8>> {
+    let framed_size = CHUNK_LENGTH_SIZE_U8 + chunk.len();
+    if size_class > 0 && framed_size > size_class {
+        return Err(EnclaveError::ChunkTooLarge);
+    }
+    let padded_size = if size_class == 0 { framed_size } else { size_class };
+    let mut padded: Vec<u8> = Vec::with_capacity(padded_size);
+    padded.extend_from_slice(&(chunk.len() as u64).to_be_bytes());
+    padded.extend_from_slice(chunk);
+    padded.resize(padded_size, 0);
+    Ok(padded)
+}
+
+pub fn unpad(padded: &[u8]) -> EnclaveResult<&[u8]> {
+    if padded.len() < CHUNK_LENGTH_SIZE_U8 {
+        return Err(EnclaveError::MalformedChunk);
+    }
+    let mut length_bytes = [0_u8; CHUNK_LENGTH_SIZE_U8];
+    length_bytes.copy_from_slice(&padded[..CHUNK_LENGTH_SIZE_U8]);
+    let length = u64::from_be_bytes(length_bytes) as usize;
+    if length > padded.len() - CHUNK_LENGTH_SIZE_U8 {
+        return Err(EnclaveError::MalformedChunk);
+    }
+    Ok(&padded[CHUNK_LENGTH_SIZE_U8..CHUNK_LENGTH_SIZE_U8+length])
+}
@@ -5,10 +5,13 @@ include!(concat!(env!("OUT_DIR"), "/init_constants.rs"));
 pub const UNIXEPOCH_U8_SIZE: usize = 10;
 pub const GEOHASH_U8_SIZE: usize = 10;
 pub const QUERY_U8_SIZE: usize = UNIXEPOCH_U8_SIZE + GEOHASH_U8_SIZE;
-// risk_level 1バイト + qeuryId
+// qeuryId + status 1バイト + risk_level 1バイト
 pub const QUERY_ID_SIZE_U8: usize = 8;
+pub const QUERY_STATUS_U8: usize = 1;
 pub const QUERY_RESULT_U8: usize = 1;
-pub const RESPONSE_DATA_SIZE_U8: usize = QUERY_ID_SIZE_U8 + QUERY_RESULT_U8;
+pub const RESPONSE_DATA_SIZE_U8: usize = QUERY_ID_SIZE_U8 + QUERY_STATUS_U8 + QUERY_RESULT_U8;
+// qeuryId + encrypted status and risk_level (iv + 2バイト + mac)
+pub const ENCRYPTED_RESPONSE_DATA_SIZE_U8: usize = RESPONSE_DATA_SIZE_U8 + MESSAGE_OVERHEAD_U8;
 
 pub const THREASHOLD: usize = 100000;
 
@@ -17,9 +20,91 @@ pub const CONTACT_TIME_THREASHOLD: u64 = 600;
 // UNIX EPOCH INTERVAL OF THE GPS DATA
 pub const TIME_INTERVAL: u64 = 600;
 
-pub const CENTRAL_KEY: u64 = 777;
+// TrajectoryHashのgeohashと時刻のbit数 (nfpの近傍とcontact policyのvariant)
+pub const TRAJECTORY_GEO_LENGTH: usize = 24;
+pub const TRAJECTORY_TIME_LENGTH: usize = 7;
+// このbit数のTrajectoryHashのkeyのbyte数，ほかのencoded value sizeのkeyはTrajectoryHashとして読めない
+pub const TRAJECTORY_HASH_SIZE_U8: usize = (2*TRAJECTORY_GEO_LENGTH + TRAJECTORY_TIME_LENGTH + 7) / 8;
+// nfpの近傍の数 (自分を含む3x3x3)
+pub const NFP_NEIGHBOR_COUNT: usize = 27;
+
+// Indirect policy: 何時間step前までさかのぼるか，stepごとにquery bufferが1つ分増える
+pub const MAX_INDIRECT_WINDOW: usize = 16;
+
+// interval mode: query pointとcentral dataの時刻 (unixepoch, big endian)
+pub const UNIXEPOCH_BE_SIZE_U8: usize = 8;
+
+// exact verification: 生の点 緯度(f64) + 経度(f64) + 時刻(u64)，どれもbig endian
+pub const RAW_POINT_SIZE_U8: usize = 8 + 8 + UNIXEPOCH_BE_SIZE_U8;
+pub const EARTH_RADIUS_METERS: f64 = 6_371_000.0;
+
+// exposure statistics: sessionごとのepsilonの上限，hostからは変えられない
+pub const STATISTICS_EPSILON_BUDGET: f64 = 1.0;
+pub const MIN_STATISTICS_EPSILON: f64 = 0.01;
+// 粗いcellは2^MAX_STATISTICS_CELL_BITS個まで，1 clientが数えられるcellの数がcellの集計の感度
+pub const MAX_STATISTICS_CELL_BITS: usize = 12;
+pub const MAX_STATISTICS_CELLS_PER_CLIENT: usize = 4;
+// 集計の値 (i64, big endian)
+pub const STATISTICS_VALUE_SIZE_U8: usize = 8;
+
+// query padding: QUERY_SIZEに満たないclientはdummy pointで埋め，暗号化したbitmaskで印を付ける (point iはbyte i/8のbit i%8)
+pub const DUMMY_MASK_SIZE_U8: usize = (QUERY_SIZE + 7) / 8;
+
+// anti-probing: clientのquery 1つが通る経度緯度のcell (TrajectoryHashの最小cell) の種類
+pub const MAX_QUERY_DISTINCT_CELLS: usize = 256;
+// 続くpointの間の移動，time step 1つあたりの最小cellの数 (経度と緯度の大きい方)
+pub const MAX_CELL_STEPS_PER_TIME_STEP: u64 = 64;
+// 生の点があるとき (exact verification) の速さ，250km/hくらい
+pub const MAX_SPEED_METERS_PER_SECOND: f64 = 70.0;
+// 認証されたclientのidentityごとに，windowあたりに受け付けるquery
+pub const MAX_QUERIES_PER_WINDOW: u32 = 24;
+pub const RATE_LIMIT_WINDOW_SECONDS: u64 = 86400;
+pub const MAX_RATE_LIMITED_CLIENTS: usize = 65536;
+// sealed rate limits: entry count(8byte) + (identity(32byte) + window start(8byte) + count(4byte)) * entry count
+pub const CLIENT_IDENTITY_SIZE_U8: usize = 32;
+pub const RATE_LIMIT_ENTRY_SIZE_U8: usize = CLIENT_IDENTITY_SIZE_U8 + 8 + 4;
 
 // for secure channel encryption
-pub const COUNTER_BLOCK: [u8; 16] = [0; 16];
-pub const SGXSSL_CTR_BITS: u32 = 128;
-pub const QUERY_BYTES: usize = QUERY_SIZE*ENCODEDVALUE_SIZE;
\ No newline at end of file
+pub const SESSION_KEY_SIZE_U8: usize = 16;
+// iv(12byte) + encrypted session key(16byte) + mac(16byte)
+pub const WRAPPED_KEY_SIZE_U8: usize = 12 + SESSION_KEY_SIZE_U8 + 16;
+// AES-GCM message: iv(12byte) + ciphertext + mac(16byte)
+pub const MESSAGE_OVERHEAD_U8: usize = 12 + 16;
+// AAD: session id(8byte) + chunk index or query id(8byte)
+pub const MESSAGE_AAD_SIZE_U8: usize = 16;
+
+// data ownerの署名鍵 (P-256，SGX SDKと同じlittle endian)，central_key_exchangeの署名とclientのidentityの証明書を確かめる
+// DATA_OWNER_PUBLIC_KEY_GX/GYはbuildのときにDATA_OWNER_PUBLIC_KEYからinit_constants.rsに書く (build.rs)
+// enclaveに埋め込むのでMRENCLAVEに入る，demo用の鍵は持たない
+
+// encoded value (key) width is chosen per session, TrajectoryHash fits in u128
+pub const MIN_ENCODEDVALUE_SIZE: usize = 1;
+pub const MAX_ENCODEDVALUE_SIZE: usize = 16;
+
+// chunk header: encoded value size (8byte) + chunk format (8byte)
+pub const CHUNK_HEADER_SIZE_U8: usize = 16;
+
+// padded chunk: chunkのbyte数(8byte) + chunk + 0埋め (ちょうどsize classまで)
+pub const CHUNK_LENGTH_SIZE_U8: usize = 8;
+// size classは大きすぎるとhostのメモリを食うだけなので上限を置く
+pub const MAX_CHUNK_SIZE_CLASS: usize = 1 << 30;
+
+// chunk manifest: chunk count (8byte) + sha256 digest of each plaintext chunk (32byte)
+pub const MANIFEST_HEADER_SIZE_U8: usize = 8;
+pub const CHUNK_DIGEST_SIZE_U8: usize = 32;
+// AAD id of the manifest message, chunk indices never reach this value
+pub const MANIFEST_MESSAGE_ID: u64 = u64::max_value();
+
+// central build: data ownerのrecord batch = batch数(8byte) + record，AAD idはbaseにbatch indexを足す
+// chunk index (< base) ともmanifestのidとも重ならない
+pub const RECORD_BATCH_HEADER_SIZE_U8: usize = 8;
+pub const MAX_RECORD_BATCHES: usize = 1 << 32;
+pub const CENTRAL_RECORDS_MESSAGE_ID_BASE: u64 = 1 << 62;
+
+// sealed blob additional text: version(4byte) + kind(4byte) + chunk index(8byte)
+// version 2: sealed chunks are padded like the encrypted chunks
+pub const SEALED_FORMAT_VERSION: u32 = 2;
+pub const SEALED_AAD_SIZE_U8: usize = 16;
+
+// sessions open at the same time, each holds one query batch in the enclave heap
+pub const MAX_SESSIONS: usize = 16;
@@ -0,0 +1,64 @@
+use constant::*;
+
+/* 
+constant-time primitives
+    比較は分岐せずに0or1を返す，条件付きコピーはmaskで行う
+    oblivious_joinとpath_oramで使う
+*/
+pub fn ct_lt_u8(a: u8, b: u8) -> u8 {
+    ((a as u16).wrapping_sub(b as u16) >> 15) as u8
+}
+
+pub fn ct_eq_u8(a: u8, b: u8) -> u8 {
+    (((a ^ b) as u16).wrapping_sub(1) >> 15) as u8
+}
+
+pub fn ct_lt_u32(a: u32, b: u32) -> u8 {
+    ((a as u64).wrapping_sub(b as u64) >> 63) as u8
+}
+
+pub fn ct_eq_u32(a: u32, b: u32) -> u8 {
+    (((a ^ b) as u64).wrapping_sub(1) >> 63) as u8
+}
+
+// keyはMAX_ENCODEDVALUE_SIZEまで0埋めしたもの
+pub fn ct_eq_key(a: &[u8; MAX_ENCODEDVALUE_SIZE], b: &[u8; MAX_ENCODEDVALUE_SIZE]) -> u8 {
+    let mut diff = 0_u8;
+    for i in 0..MAX_ENCODEDVALUE_SIZE {
+        diff |= a[i] ^ b[i];
+    }
+    ct_eq_u8(diff, 0)
+}
+
+// 辞書順で a < b
+pub fn ct_lt_key(a: &[u8; MAX_ENCODEDVALUE_SIZE], b: &[u8; MAX_ENCODEDVALUE_SIZE]) -> u8 {
+    let mut lt = 0_u8;
+    let mut decided = 0_u8;
+    for i in 0..MAX_ENCODEDVALUE_SIZE {
+        lt |= (1 ^ decided) & ct_lt_u8(a[i], b[i]);
+        decided |= 1 ^ ct_eq_u8(a[i], b[i]);
+    }
+    lt
+}
+
+pub fn cmov_u8(cond: u8, dst: &mut u8, src: u8) {
+    let mask = 0_u8.wrapping_sub(cond);
+    *dst = (src & mask) | (*dst & !mask);
+}
+
+pub fn cmov_u32(cond: u8, dst: &mut u32, src: u32) {
+    let mask = 0_u32.wrapping_sub(cond as u32);
+    *dst = (src & mask) | (*dst & !mask);
+}
+
+pub fn cmov_key(cond: u8, dst: &mut [u8; MAX_ENCODEDVALUE_SIZE], src: &[u8; MAX_ENCODEDVALUE_SIZE]) {
+    for i in 0..MAX_ENCODEDVALUE_SIZE {
+        cmov_u8(cond, &mut dst[i], src[i]);
+    }
+}
+
+pub fn padded_key(encoded_value: &[u8]) -> [u8; MAX_ENCODEDVALUE_SIZE] {
+    let mut key = [0_u8; MAX_ENCODEDVALUE_SIZE];
+    key[..encoded_value.len()].copy_from_slice(encoded_value);
+    key
+}
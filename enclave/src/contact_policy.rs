@@ -0,0 +1,144 @@
+use std::vec::Vec;
+use constant::*;
+use encoded_query_buffer::EncodedQueryBuffer;
+use encoded_result_buffer::EncodedResultBuffer;
+use enclave_error::{EnclaveError, EnclaveResult};
+
+/*
+Type ContactPolicy
+    batchごとにset_contact_policiesで受け取る接触の判定ルール，responseのbyteのi bit目がi番目のpolicy
+    Direct  : 同じcell，同じ時間step
+    Neighbor: 縦横±1 cell(9 cell)，同じ時間step
+    Indirect: 同じcellで1..parameter step前 (患者が去ったあと)
+    どのpolicyもqueryのkeyをTrajectoryHashでずらしたvariantの照合結果のORで，
+    同じvariantは複数のpolicyで1回だけ照合する (query_variants)
+    min_exposure: 連続してhitしたquery pointがこの数以上でpositive (0と1はどれか1点)
+    EDLのcontact_policy_tと同じ並び
+*/
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ContactPolicy {
+    pub kind: u32,
+    pub parameter: u32,
+    pub min_exposure: u32,
+}
+
+pub const CONTACT_POLICY_DIRECT: u32 = 0;
+pub const CONTACT_POLICY_NEIGHBOR: u32 = 1;
+pub const CONTACT_POLICY_INDIRECT: u32 = 2;
+
+// responseのbyteに収まる数
+pub const MAX_CONTACT_POLICIES: usize = 8;
+
+// (経度, 緯度, 時間) のstep数
+pub type Offset = [i32; 3];
+
+impl ContactPolicy {
+    pub fn direct() -> Self {
+        ContactPolicy { kind: CONTACT_POLICY_DIRECT, parameter: 0, min_exposure: 0 }
+    }
+
+    fn offsets(&self) -> EnclaveResult<Vec<Offset>> {
+        match self.kind {
+            CONTACT_POLICY_DIRECT => Ok(vec![[0, 0, 0]]),
+            CONTACT_POLICY_NEIGHBOR => {
+                let mut offsets = Vec::with_capacity(9);
+                for lng in -1..=1 {
+                    for lat in -1..=1 {
+                        offsets.push([lng, lat, 0]);
+                    }
+                }
+                Ok(offsets)
+            },
+            CONTACT_POLICY_INDIRECT => {
+                let window = self.parameter as usize;
+                if window == 0 || window > MAX_INDIRECT_WINDOW {
+                    return Err(EnclaveError::InvalidContactPolicy);
+                }
+                Ok((1..=window as i32).map(|step| [0, 0, -step]).collect())
+            },
+            _ => Err(EnclaveError::InvalidContactPolicy),
+        }
+    }
+}
+
+/*
+Type PolicyVariants
+    policyの列をqueryのvariant(ずらし方)の列にする
+    variants[0]はいつもずらさないもの(clientのqueryそのもの)
+*/
+#[derive(Clone, Default, Debug)]
+pub struct PolicyVariants {
+    pub variants: Vec<Offset>,
+    // policy_variants[i]: i番目のpolicyがORするvariantの位置
+    policy_variants: Vec<Vec<usize>>,
+    // min_exposures[i]: i番目のpolicyの連続したpointの数，1ならpointごとのhitは要らない
+    min_exposures: Vec<usize>,
+}
+
+impl PolicyVariants {
+    // interval modeのqueryはTrajectoryHashではないのでDirectだけ
+    pub fn from_policies(policies: &[ContactPolicy], with_time: bool) -> EnclaveResult<Self> {
+        if policies.is_empty() || policies.len() > MAX_CONTACT_POLICIES {
+            return Err(EnclaveError::InvalidContactPolicy);
+        }
+        let mut this = PolicyVariants { variants: vec![[0, 0, 0]], policy_variants: Vec::with_capacity(policies.len()), min_exposures: Vec::with_capacity(policies.len()) };
+        for policy in policies.iter() {
+            if with_time && policy.kind != CONTACT_POLICY_DIRECT {
+                return Err(EnclaveError::InvalidContactPolicy);
+            }
+            if policy.min_exposure as usize > QUERY_SIZE {
+                return Err(EnclaveError::InvalidContactPolicy);
+            }
+            let mut positions = Vec::new();
+            for offset in policy.offsets()? {
+                let position = match this.variants.iter().position(|variant| *variant == offset) {
+                    Some(position) => position,
+                    None => {
+                        this.variants.push(offset);
+                        this.variants.len() - 1
+                    },
+                };
+                positions.push(position);
+            }
+            this.policy_variants.push(positions);
+            this.min_exposures.push((policy.min_exposure as usize).max(1));
+        }
+        Ok(this)
+    }
+
+    // どれかのpolicyが2 point以上の連続を求めるとき，resultはquery pointごとのhitを持つ
+    pub fn tracks_points(&self) -> bool {
+        self.min_exposures.iter().any(|min_exposure| *min_exposure > 1)
+    }
+
+    // clientごとのpolicyのbitmask
+    pub fn bitmask(&self, query_buffer: &EncodedQueryBuffer, result: &EncodedResultBuffer, client_index: usize) -> u8 {
+        let mut bitmask = 0;
+        for (bit, (positions, min_exposure)) in self.policy_variants.iter().zip(self.min_exposures.iter()).enumerate() {
+            let positive = if *min_exposure > 1 {
+                self.exposure(positions, query_buffer, result, client_index) >= *min_exposure
+            } else {
+                positions.iter().any(|variant| result.is_positive(query_buffer.position(*variant, client_index)))
+            };
+            if positive {
+                bitmask |= 1 << bit;
+            }
+        }
+        bitmask
+    }
+
+    // どれかのvariantでhitしたpointが続いた最長の数，chunkをまたいだhitはresultのpointsにORされている
+    fn exposure(&self, positions: &[usize], query_buffer: &EncodedQueryBuffer, result: &EncodedResultBuffer, client_index: usize) -> usize {
+        let (mut run, mut longest) = (0, 0);
+        for point_index in 0..QUERY_SIZE {
+            let mut hit = 0;
+            for variant in positions.iter() {
+                hit |= result.point_hit(query_buffer.position(*variant, client_index), point_index);
+            }
+            run = (run + 1) * hit as usize;
+            longest = longest.max(run);
+        }
+        longest
+    }
+}
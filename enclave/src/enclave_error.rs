@@ -0,0 +1,129 @@
+use sgx_types::*;
+
+/*
+Type EnclaveError
+    全ECALLが[out] errorで返すエラーコード，hostはこれを読めるメッセージにする
+    戻り値のsgx_status_tはおおまかな分類として今まで通り返す
+    値はapp側のenclave_error.rsと揃える
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EnclaveError {
+    Success = 0,
+
+    // arguments and lengths
+    InvalidLength = 1,
+    InvalidEncodedValueSize = 2,
+    InvalidJoinMode = 3,
+    InvalidKeyPolicy = 4,
+    InvalidContactPolicy = 5,
+    InvalidExactThresholds = 6,
+    InvalidStatisticsRequest = 7,
+    NotTrajectoryHash = 8,
+
+    // session state
+    UnknownSession = 10,
+    TooManySessions = 11,
+    WrongSessionState = 12,
+    UnknownClient = 13,
+    IncompleteUpload = 14,
+    NoCentralChannel = 15,
+    NoManifest = 16,
+    ResultNotReady = 17,
+    NotResident = 18,
+    PrivacyBudgetExceeded = 19,
+
+    // decryption and authentication
+    DecryptionFailed = 20,
+    UnsealFailed = 21,
+    IdentityVerificationFailed = 22,
+    ClientAlreadyKeyed = 23,
+    DataOwnerVerificationFailed = 24,
+    CentralDataDeclared = 25,
+    RateLimitsInUse = 26,
+
+    // manifest and chunks
+    MalformedManifest = 30,
+    MalformedChunk = 31,
+    ChunkOutOfRange = 32,
+    ChunkDigestMismatch = 33,
+    ChunkReplayed = 34,
+    EncodedValueSizeMismatch = 35,
+    ChunkFormatMismatch = 36,
+    QueryFormatMismatch = 37,
+    RecordBatchMismatch = 38,
+    ChunkTooLarge = 39,
+
+    // resources and SGX SDK failures
+    OutOfMemory = 40,
+    Internal = 41,
+}
+
+pub type EnclaveResult<T> = Result<T, EnclaveError>;
+
+impl EnclaveError {
+    pub fn status(&self) -> sgx_status_t {
+        match *self {
+            EnclaveError::Success => sgx_status_t::SGX_SUCCESS,
+            EnclaveError::InvalidLength
+            | EnclaveError::InvalidEncodedValueSize
+            | EnclaveError::InvalidJoinMode
+            | EnclaveError::InvalidKeyPolicy
+            | EnclaveError::InvalidContactPolicy
+            | EnclaveError::InvalidExactThresholds
+            | EnclaveError::InvalidStatisticsRequest
+            | EnclaveError::NotTrajectoryHash
+            | EnclaveError::UnknownSession
+            | EnclaveError::MalformedManifest
+            | EnclaveError::MalformedChunk
+            | EnclaveError::ChunkOutOfRange
+            | EnclaveError::EncodedValueSizeMismatch
+            | EnclaveError::ChunkFormatMismatch
+            | EnclaveError::QueryFormatMismatch
+            | EnclaveError::ChunkTooLarge => sgx_status_t::SGX_ERROR_INVALID_PARAMETER,
+            EnclaveError::TooManySessions => sgx_status_t::SGX_ERROR_BUSY,
+            EnclaveError::WrongSessionState
+            | EnclaveError::UnknownClient
+            | EnclaveError::IncompleteUpload
+            | EnclaveError::NoCentralChannel
+            | EnclaveError::NoManifest
+            | EnclaveError::ResultNotReady
+            | EnclaveError::NotResident
+            | EnclaveError::RecordBatchMismatch
+            | EnclaveError::ClientAlreadyKeyed
+            | EnclaveError::CentralDataDeclared
+            | EnclaveError::RateLimitsInUse
+            | EnclaveError::PrivacyBudgetExceeded
+            | EnclaveError::ChunkReplayed => sgx_status_t::SGX_ERROR_INVALID_STATE,
+            EnclaveError::DecryptionFailed
+            | EnclaveError::UnsealFailed
+            | EnclaveError::IdentityVerificationFailed
+            | EnclaveError::DataOwnerVerificationFailed
+            | EnclaveError::ChunkDigestMismatch => sgx_status_t::SGX_ERROR_MAC_MISMATCH,
+            EnclaveError::OutOfMemory => sgx_status_t::SGX_ERROR_OUT_OF_MEMORY,
+            EnclaveError::Internal => sgx_status_t::SGX_ERROR_UNEXPECTED,
+        }
+    }
+}
+
+// SGX SDK (sgx_tcrypto, sgx_trts, sgx_tse, sgx_tseal) のエラー
+impl From<sgx_status_t> for EnclaveError {
+    fn from(status: sgx_status_t) -> Self {
+        match status {
+            sgx_status_t::SGX_ERROR_OUT_OF_MEMORY => EnclaveError::OutOfMemory,
+            sgx_status_t::SGX_ERROR_MAC_MISMATCH => EnclaveError::DecryptionFailed,
+            _ => EnclaveError::Internal,
+        }
+    }
+}
+
+// ECALLの出口，errorに書いてsgx_status_tを返す
+pub fn report(ret: EnclaveResult<()>, error: *mut u32) -> sgx_status_t {
+    let enclave_error = match ret {
+        Ok(()) => EnclaveError::Success,
+        Err(enclave_error) => enclave_error,
+    };
+    if !error.is_null() {
+        unsafe { *error = enclave_error as u32; }
+    }
+    enclave_error.status()
+}
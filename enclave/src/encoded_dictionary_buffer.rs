@@ -1,26 +1,48 @@
-use std::vec::Vec;
-
+use enclave_error::EnclaveResult;
+#[cfg(feature = "oram")]
+use enclave_error::EnclaveError;
+#[cfg(not(feature = "oram"))]
+use exact_point::{RawPointTable, ExactVerifier, split_verified_chunk};
 use encoded_query_buffer::EncodedQueryBuffer;
 use encoded_result_buffer::EncodedResultBuffer;
+use query_index::QueryIndex;
 #[cfg(feature = "hashtable")]
 use encoded_hash_table::EncodedHashTable;
 #[cfg(feature = "fsa")]
 use fast_succinct_trie::FST;
+#[cfg(feature = "oram")]
+use oram_dictionary::OramDictionary;
 
 #[cfg(feature = "fsa")]
 pub struct EncodedDictionaryBuffer {
     pub data: FST,
+    // ChunkFormat::VerifiedDictionaryのときだけ
+    pub raw_points: Option<RawPointTable>,
 }
 #[cfg(feature = "fsa")]
 impl EncodedDictionaryBuffer {
-    pub fn intersect(&self, query_buffer: &EncodedQueryBuffer, result: &mut EncodedResultBuffer) {
-        self.data.intersect(query_buffer, result);
+    pub fn intersect(&mut self, query_buffer: &EncodedQueryBuffer, neighbor_index: Option<&QueryIndex>, result: &mut EncodedResultBuffer) -> EnclaveResult<()> {
+        let verifier = ExactVerifier::new(query_buffer.exact_thresholds, self.raw_points.as_ref())?;
+        self.data.intersect(query_buffer, neighbor_index, verifier.as_ref(), result);
+        Ok(())
     }
 
     pub fn build_dictionary_buffer(
-        encoded_value_vec: Vec<u8>,
-    ) -> Self {
-        Self { data: FST::build_dictionary_buffer(encoded_value_vec) }
+        encoded_value_vec: &[u8],
+        encoded_value_size: usize,
+    ) -> EnclaveResult<Self> {
+        // trieのbyte列はmanifestのdigestで認証済みなので，succinct_trieのdeserializeでは検査しない
+        Ok(Self { data: FST::build_dictionary_buffer(encoded_value_vec, encoded_value_size), raw_points: None })
+    }
+
+    pub fn build_verified_dictionary_buffer(
+        encoded_value_vec: &[u8],
+        encoded_value_size: usize,
+    ) -> EnclaveResult<Self> {
+        let (dictionary, raw_points) = split_verified_chunk(encoded_value_vec)?;
+        let mut this = Self::build_dictionary_buffer(dictionary, encoded_value_size)?;
+        this.raw_points = Some(RawPointTable::from_bytes(raw_points, encoded_value_size)?);
+        Ok(this)
     }
 
     pub fn show_size(&self) {
@@ -29,23 +51,69 @@ impl EncodedDictionaryBuffer {
 }
 
 #[cfg(feature = "hashtable")]
-#[derive(Clone, Debug)]
 pub struct EncodedDictionaryBuffer {
     pub data: EncodedHashTable,
+    // ChunkFormat::VerifiedDictionaryのときだけ
+    pub raw_points: Option<RawPointTable>,
 }
 #[cfg(feature = "hashtable")]
 impl EncodedDictionaryBuffer {
-    pub fn intersect(&self, query_buffer: &EncodedQueryBuffer, result: &mut EncodedResultBuffer) {
-        self.data.intersect(query_buffer, result);
+    // hashtableはexact matchだけなので近傍の索引は使わない
+    pub fn intersect(&mut self, query_buffer: &EncodedQueryBuffer, _neighbor_index: Option<&QueryIndex>, result: &mut EncodedResultBuffer) -> EnclaveResult<()> {
+        let verifier = ExactVerifier::new(query_buffer.exact_thresholds, self.raw_points.as_ref())?;
+        self.data.intersect(query_buffer, verifier.as_ref(), result);
+        Ok(())
     }
 
     pub fn build_dictionary_buffer(
-        encoded_value_vec: Vec<u8>,
-    ) -> Self {
-        Self { data: EncodedHashTable::build_dictionary_buffer(encoded_value_vec) }
+        encoded_value_vec: &[u8],
+        _encoded_value_size: usize,
+    ) -> EnclaveResult<Self> {
+        Ok(Self { data: EncodedHashTable::build_dictionary_buffer(encoded_value_vec)?, raw_points: None })
+    }
+
+    pub fn build_verified_dictionary_buffer(
+        encoded_value_vec: &[u8],
+        encoded_value_size: usize,
+    ) -> EnclaveResult<Self> {
+        let (dictionary, raw_points) = split_verified_chunk(encoded_value_vec)?;
+        let mut this = Self::build_dictionary_buffer(dictionary, encoded_value_size)?;
+        this.raw_points = Some(RawPointTable::from_bytes(raw_points, encoded_value_size)?);
+        Ok(this)
     }
 
     pub fn show_size(&self) {
         self.data.calc_memory();
     }
-}
\ No newline at end of file
+}
+
+// chunkはソート済みのkey列(ChunkFormat::SortedKeys)
+#[cfg(feature = "oram")]
+pub struct EncodedDictionaryBuffer {
+    pub data: OramDictionary,
+}
+#[cfg(feature = "oram")]
+impl EncodedDictionaryBuffer {
+    pub fn intersect(&mut self, query_buffer: &EncodedQueryBuffer, _neighbor_index: Option<&QueryIndex>, result: &mut EncodedResultBuffer) -> EnclaveResult<()> {
+        Ok(self.data.intersect(query_buffer, result)?)
+    }
+
+    pub fn build_dictionary_buffer(
+        encoded_value_vec: &[u8],
+        encoded_value_size: usize,
+    ) -> EnclaveResult<Self> {
+        Ok(Self { data: OramDictionary::build_dictionary_buffer(encoded_value_vec, encoded_value_size)? })
+    }
+
+    // ORAMはソート済みのkeyから作るので生の点は持たない
+    pub fn build_verified_dictionary_buffer(
+        _encoded_value_vec: &[u8],
+        _encoded_value_size: usize,
+    ) -> EnclaveResult<Self> {
+        Err(EnclaveError::ChunkFormatMismatch)
+    }
+
+    pub fn show_size(&self) {
+        self.data.calc_memory();
+    }
+}
@@ -2,30 +2,32 @@ use std::vec::Vec;
 use std::collections::HashSet;
 use std::mem;
 use bincode;
-use primitive::*;
 use constant::*;
+use enclave_error::{EnclaveError, EnclaveResult};
 use encoded_result_buffer::EncodedResultBuffer;
 use encoded_query_buffer::EncodedQueryBuffer;
+use exact_point::ExactVerifier;
+use utils::zeroize;
 
 #[derive(Clone, Debug)]
 pub struct EncodedHashTable {
-    pub map: HashSet<EncodedValue>,
+    pub map: HashSet<Vec<u8>>,
 }
 impl EncodedHashTable {
     pub fn new() -> Self {
         EncodedHashTable {
-            map: HashSet::<EncodedValue>::with_capacity(THREASHOLD)
+            map: HashSet::<Vec<u8>>::with_capacity(THREASHOLD)
         }
     }
 
-    pub fn intersect(&self, query_buffer: &EncodedQueryBuffer, result: &mut EncodedResultBuffer) {
-        for encoded_value_vec in query_buffer.queries.iter() {
-            if result.data.contains(&encoded_value_vec.id) {
+    pub fn intersect(&self, query_buffer: &EncodedQueryBuffer, verifier: Option<&ExactVerifier>, result: &mut EncodedResultBuffer) {
+        for (query_index, encoded_value_vec) in query_buffer.queries.iter().enumerate() {
+            if result.is_settled(query_index) {
                 continue; 
             }
-            for key in encoded_value_vec.parameters.iter() {
-                if self.map.contains(key) {
-                    result.data.insert(encoded_value_vec.id);
+            for (point_index, key) in encoded_value_vec.encoded_values(query_buffer.encoded_value_size).enumerate() {
+                if self.map.contains(key) && verifier.map_or(true, |verifier| verifier.is_close(key, &encoded_value_vec.raw_point(point_index))) {
+                    result.set_positive(query_index, point_index);
                     continue;
                 }
             }
@@ -33,13 +35,23 @@ impl EncodedHashTable {
     }
 
     pub fn build_dictionary_buffer(
-        bytes: Vec<u8>,
-    ) -> Self {
-        let dict: HashSet<EncodedValue> = bincode::deserialize(&bytes[..]).unwrap();
-        Self { map: dict }
+        bytes: &[u8],
+    ) -> EnclaveResult<Self> {
+        let dict: HashSet<Vec<u8>> = bincode::deserialize(bytes).map_err(|_| EnclaveError::MalformedChunk)?;
+        Ok(Self { map: dict })
     }
 
     pub fn calc_memory(&self) {
-        println!("[HashTable] r_i size = {} bytes", (self.map.capacity() * 11 / 10) * (mem::size_of::<EncodedValue>() + mem::size_of::<()>() + mem::size_of::<u64>()));
+        let encoded_value_size = self.map.iter().next().map_or(0, |key| key.len());
+        println!("[HashTable] r_i size = {} bytes", (self.map.capacity() * 11 / 10) * (mem::size_of::<Vec<u8>>() + encoded_value_size + mem::size_of::<()>() + mem::size_of::<u64>()));
     }
-}
\ No newline at end of file
+}
+
+// setの要素は書き換えられないので，取り出してから消す
+impl Drop for EncodedHashTable {
+    fn drop(&mut self) {
+        for mut key in self.map.drain() {
+            zeroize(&mut key);
+        }
+    }
+}
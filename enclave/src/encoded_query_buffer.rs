@@ -1,34 +1,54 @@
 use encoded_query_rep::EncodedQueryRep;
-use constant::*;
 use std::vec::Vec;
+use constant::*;
+use exact_point::ExactThresholds;
 
 #[derive(Clone, Default, Debug)]
 pub struct EncodedQueryBuffer {
+    pub encoded_value_size: usize,
+    // interval modeではquery pointはencoded value(location) + 時刻
+    pub with_time: bool,
+    // finish_uploadでcontact policyのvariantごとにclientのqueryをずらして並べる (query_variants)
+    pub variant_count: usize,
+    // set_exact_thresholdsされたsessionではclientは生の点も送る
+    pub exact_thresholds: Option<ExactThresholds>,
     pub queries: Vec<EncodedQueryRep>,
+    // clientごと，anti-probingでrejectしたqueryはchunkと照合してもresultを返さない
+    pub rejected: Vec<bool>,
 }
 
 impl EncodedQueryBuffer {
-    pub fn new() -> Self {
-        EncodedQueryBuffer::default()
+    pub fn new(encoded_value_size: usize, with_time: bool) -> Self {
+        EncodedQueryBuffer {
+            encoded_value_size,
+            with_time,
+            variant_count: 1,
+            exact_thresholds: None,
+            queries: Vec::default(),
+            rejected: Vec::default(),
+        }
+    }
+
+    // 先頭のclient_count個がclientのquery，残りはずらしたvariant
+    pub fn client_count(&self) -> usize {
+        self.queries.len() / self.variant_count.max(1)
     }
 
-    // !!このメソッドでは全くerror処理していない
-    // queryを個々に組み立ててbufferに保持する
-    pub fn build_query_buffer(
-        &mut self,
-        total_query_data_vec: Vec<u8>,
-        query_id_list_vec   : Vec<u64>,
-    ) -> i8 {
-        for i in 0_usize..(query_id_list_vec.len()) {
-            let mut query = EncodedQueryRep::new();
-            query.id = query_id_list_vec[i];
-            for j in 0_usize..QUERY_SIZE {
-                let mut encoded_value = [0_u8; ENCODEDVALUE_SIZE];
-                encoded_value.copy_from_slice(&total_query_data_vec[(i*QUERY_SIZE+j)*ENCODEDVALUE_SIZE..(i*QUERY_SIZE+j+1)*ENCODEDVALUE_SIZE]);
-                query.parameters.push(encoded_value);
-            }
-            self.queries.push(query);
+    pub fn is_rejected(&self, client_index: usize) -> bool {
+        self.rejected[client_index]
+    }
+
+    // variantのclient_index番目のclientのqueryの位置
+    pub fn position(&self, variant: usize, client_index: usize) -> usize {
+        variant * self.client_count() + client_index
+    }
+
+    // query point 1つのbyte数
+    pub fn point_size(&self) -> usize {
+        if self.with_time {
+            self.encoded_value_size + UNIXEPOCH_BE_SIZE_U8
+        } else {
+            self.encoded_value_size
         }
-        return 0;
     }
-}
\ No newline at end of file
+}
@@ -1,19 +1,61 @@
 use std::vec::Vec;
-use primitive::{ QueryId, EncodedValue };
+use std::slice::ChunksExact;
+use primitive::{QueryId, UnixEpoch};
 use constant::*;
+use utils::zeroize;
+use exact_point::RawPoint;
 
 /* Type EncodedQueryRep */
+// parametersはencoded_value_sizeごとに区切られたフラットなバイト列
+// interval modeでは (encoded value + 時刻) ごとに区切られる
+// exact verificationのときはpointごとの生の点をraw_pointsに別に持つ (parametersはkeyだけのまま)
+// dummiesはclientのdummy pointのbitmask (DUMMY_MASK_SIZE_U8)，ずらしたvariantのqueryは持たない
 #[derive(Clone, Default, Debug)]
 pub struct EncodedQueryRep {
     pub id: QueryId,
-    pub parameters: Vec<EncodedValue>,
+    pub parameters: Vec<u8>,
+    pub raw_points: Vec<u8>,
+    pub dummies: Vec<u8>,
 }
 
 impl EncodedQueryRep {
-    pub fn new() -> Self {
+    pub fn new(point_size: usize) -> Self {
         EncodedQueryRep {
             id: 0,
-            parameters: Vec::with_capacity(QUERY_SIZE),
+            parameters: Vec::with_capacity(QUERY_SIZE*point_size),
+            raw_points: Vec::new(),
+            dummies: Vec::new(),
         }
     }
-}
\ No newline at end of file
+
+    pub fn is_dummy(&self, point_index: usize) -> bool {
+        self.dummies.get(point_index / 8).map_or(false, |byte| (byte >> (point_index % 8)) & 1 == 1)
+    }
+
+    pub fn raw_point(&self, point_index: usize) -> RawPoint {
+        RawPoint::from_be_bytes(&self.raw_points[point_index*RAW_POINT_SIZE_U8..(point_index+1)*RAW_POINT_SIZE_U8])
+    }
+
+    pub fn encoded_values(&self, encoded_value_size: usize) -> ChunksExact<u8> {
+        self.parameters.chunks_exact(encoded_value_size)
+    }
+
+    // interval mode，(location, 時刻)
+    pub fn timed_values<'a>(&'a self, encoded_value_size: usize) -> impl Iterator<Item = (&'a [u8], UnixEpoch)> + 'a {
+        self.parameters.chunks_exact(encoded_value_size + UNIXEPOCH_BE_SIZE_U8).map(move |point| {
+            let mut unixepoch_bytes = [0_u8; UNIXEPOCH_BE_SIZE_U8];
+            unixepoch_bytes.copy_from_slice(&point[encoded_value_size..]);
+            (&point[..encoded_value_size], UnixEpoch::from_be_bytes(unixepoch_bytes))
+        })
+    }
+}
+
+// 復号したqueryはどのコピーも捨てるときにゼロ埋めする
+impl Drop for EncodedQueryRep {
+    fn drop(&mut self) {
+        zeroize(&mut self.parameters);
+        zeroize(&mut self.raw_points);
+        zeroize(&mut self.dummies);
+        self.id = 0;
+    }
+}
@@ -1,36 +1,126 @@
 use std::vec::Vec;
-use std::collections::HashSet;
-use primitive::*;
+use std::sync::Arc;
+use constant::*;
 use encoded_query_buffer::EncodedQueryBuffer;
-use query_result::QueryResult;
+use query_result::{QueryResult, QUERY_STATUS_REJECTED};
+use utils::zeroize;
+use contact_policy::PolicyVariants;
 
+// queryの位置(query_buffer.queriesのindex)ごとの結果フラグ 0or1
+// oblivious modeでも書き込み位置がデータに依存しないようにVecで持つ
+// duration of exposureを見るときはquery pointごとのhit (queryの位置 * QUERY_SIZE + pointの位置) も持つ
+// chunkはkeyの範囲で分かれているので，連続したpointのhitは別々のchunkから来る
+// clientのdummy pointのhitはどのjoin modeでもここで捨てる，chunkごとのコピーはmaskをArcで共有する
 #[derive(Clone, Default, Debug)]
 pub struct EncodedResultBuffer {
-    pub data: HashSet<QueryId>,
+    pub flags: Vec<u8>,
+    pub points: Vec<u8>,
+    // clientごとのDUMMY_MASK_SIZE_U8 byte，variantのqueryは同じclientのmaskを使う
+    dummies: Arc<Vec<u8>>,
+    client_count: usize,
 }
 
 impl EncodedResultBuffer {
-    pub fn new() -> Self {
-        EncodedResultBuffer::default()
+    pub fn new(query_size: usize, track_points: bool) -> Self {
+        EncodedResultBuffer {
+            flags: vec![0; query_size],
+            points: if track_points { vec![0; query_size*QUERY_SIZE] } else { Vec::new() },
+            dummies: Arc::new(Vec::new()),
+            client_count: 0,
+        }
+    }
+
+    pub fn with_dummies(mut self, dummies: Vec<u8>, client_count: usize) -> Self {
+        self.dummies = Arc::new(dummies);
+        self.client_count = client_count;
+        self
+    }
+
+    // 0or1，oblivious joinでも分岐しないようにbitで返す
+    fn dummy_bit(&self, query_index: usize, point_index: usize) -> u8 {
+        if self.client_count == 0 {
+            return 0;
+        }
+        let client_index = query_index % self.client_count;
+        (self.dummies[client_index*DUMMY_MASK_SIZE_U8 + point_index/8] >> (point_index % 8)) & 1
+    }
+
+    pub fn tracks_points(&self) -> bool {
+        !self.points.is_empty()
+    }
+
+    pub fn is_positive(&self, query_index: usize) -> bool {
+        self.flags[query_index] > 0
+    }
+
+    // これ以上見なくてよいquery，pointごとのhitを持つときはpositiveでも残りのpointを照合する
+    pub fn is_settled(&self, query_index: usize) -> bool {
+        self.is_positive(query_index) && !self.tracks_points()
+    }
+
+    pub fn set_positive(&mut self, query_index: usize, point_index: usize) {
+        if self.dummy_bit(query_index, point_index) == 1 {
+            return;
+        }
+        self.flags[query_index] = 1;
+        if self.tracks_points() {
+            self.points[query_index*QUERY_SIZE + point_index] = 1;
+        }
+    }
+
+    // oblivious join用，hitは0or1で分岐しない
+    pub fn or_hit(&mut self, query_index: usize, point_index: usize, hit: u8) {
+        let hit = hit & (self.dummy_bit(query_index, point_index) ^ 1);
+        self.flags[query_index] |= hit;
+        if self.tracks_points() {
+            self.points[query_index*QUERY_SIZE + point_index] |= hit;
+        }
+    }
+
+    pub fn point_hit(&self, query_index: usize, point_index: usize) -> u8 {
+        self.points[query_index*QUERY_SIZE + point_index]
+    }
+
+    // chunkごとの部分結果をsessionの結果にORする
+    pub fn merge(&mut self, partial: &EncodedResultBuffer) {
+        for (flag, partial_flag) in self.flags.iter_mut().zip(partial.flags.iter()) {
+            *flag |= *partial_flag;
+        }
+        for (point, partial_point) in self.points.iter_mut().zip(partial.points.iter()) {
+            *point |= *partial_point;
+        }
     }
 
     // reposne format
-    // query.id(8byte) + reuslt(0or1 1byte)
+    // query.id(8byte) + status(1byte) + reuslt(contact policyごとのbit 1byte)
     pub fn build_query_response(
         &self,
         query_buffer: &EncodedQueryBuffer,
+        policy_variants: &PolicyVariants,
         response_vec: &mut Vec<u8>,
     ) {
-        for query in query_buffer.queries.iter() {
+        for (query_index, query) in query_buffer.queries.iter().take(query_buffer.client_count()).enumerate() {
             let mut result = QueryResult::new();
             result.query_id = query.id;
-            if self.data.contains(&query.id) {
-                result.risk_level = 1;
+            // rejectしたqueryのhitは返さない
+            if query_buffer.is_rejected(query_index) {
+                result.status = QUERY_STATUS_REJECTED;
             } else {
-                result.risk_level = 0;
-            };
+                result.risk_level = policy_variants.bitmask(query_buffer, self, query_index);
+            }
 
             response_vec.extend_from_slice(&result.to_be_bytes());
         }
     }
-}
\ No newline at end of file
+}
+
+impl Drop for EncodedResultBuffer {
+    fn drop(&mut self) {
+        zeroize(&mut self.flags);
+        zeroize(&mut self.points);
+        // 最後のコピーがmaskを消す
+        if let Some(dummies) = Arc::get_mut(&mut self.dummies) {
+            zeroize(dummies);
+        }
+    }
+}
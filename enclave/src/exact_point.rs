@@ -0,0 +1,158 @@
+use std::vec::Vec;
+use std::cmp::Ordering;
+use constant::*;
+use primitive::UnixEpoch;
+use constant_time::padded_key;
+use utils::zeroize;
+use enclave_error::{EnclaveError, EnclaveResult};
+
+/*
+Exact verification
+    trie/hashtableのhitはcell単位なので，cellの端どうしでもhitするし隣のcellの近い点はhitしない(nfpで一部拾う)
+    chunk(ChunkFormat::VerifiedDictionary)とqueryが生の(緯度, 経度, 時刻)を持つときは，
+    hitしたkeyの生の点のどれかがhaversine距離と時刻の差の閾値に入るときだけpositiveにする
+    trieはそのままpre-filterで，生の点を見るのはhitしたpointだけ
+*/
+
+// set_exact_thresholdsでsessionごとに決める
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ExactThresholds {
+    pub max_distance_meters: f64,
+    pub max_time_difference: u64,
+}
+
+impl ExactThresholds {
+    pub fn is_valid(&self) -> bool {
+        self.max_distance_meters.is_finite() && self.max_distance_meters >= 0.0
+    }
+}
+
+// 緯度(f64 BE) + 経度(f64 BE) + 時刻(unixepoch u64 BE)
+#[derive(Clone, Copy, Default, Debug)]
+pub struct RawPoint {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub unixepoch: UnixEpoch,
+}
+
+fn _read_u64(bytes: &[u8]) -> u64 {
+    let mut value_bytes = [0_u8; 8];
+    value_bytes.copy_from_slice(&bytes[..8]);
+    u64::from_be_bytes(value_bytes)
+}
+
+impl RawPoint {
+    // bytes.len() >= RAW_POINT_SIZE_U8
+    pub fn from_be_bytes(bytes: &[u8]) -> Self {
+        RawPoint {
+            latitude: f64::from_bits(_read_u64(&bytes[0..8])),
+            longitude: f64::from_bits(_read_u64(&bytes[8..16])),
+            unixepoch: _read_u64(&bytes[16..24]),
+        }
+    }
+
+    // 大円距離(m)
+    pub fn distance_meters(&self, other: &RawPoint) -> f64 {
+        let (latitude1, latitude2) = (self.latitude.to_radians(), other.latitude.to_radians());
+        let half_latitude = (latitude2 - latitude1) / 2.0;
+        let half_longitude = (other.longitude - self.longitude).to_radians() / 2.0;
+        let a = half_latitude.sin().powi(2) + latitude1.cos() * latitude2.cos() * half_longitude.sin().powi(2);
+        2.0 * EARTH_RADIUS_METERS * a.sqrt().min(1.0).asin()
+    }
+
+    pub fn is_close(&self, other: &RawPoint, thresholds: &ExactThresholds) -> bool {
+        let time_difference = if self.unixepoch > other.unixepoch { self.unixepoch - other.unixepoch } else { other.unixepoch - self.unixepoch };
+        time_difference <= thresholds.max_time_difference && self.distance_meters(other) <= thresholds.max_distance_meters
+    }
+}
+
+// VerifiedDictionaryのchunkをdictionaryと生の点に分ける
+pub fn split_verified_chunk(bytes: &[u8]) -> EnclaveResult<(&[u8], &[u8])> {
+    if bytes.len() < 8 {
+        return Err(EnclaveError::MalformedChunk);
+    }
+    let dictionary_size = _read_u64(bytes) as usize;
+    if dictionary_size > bytes.len() - 8 {
+        return Err(EnclaveError::MalformedChunk);
+    }
+    Ok(bytes[8..].split_at(dictionary_size))
+}
+
+/*
+Type RawPointTable
+    VerifiedDictionaryのchunkの後ろ半分，keyの昇順に (key, 生の点) を並べる
+    同じkeyの点は続けて並び，keyを二分探索して続く点を全部見る
+*/
+#[derive(Default)]
+pub struct RawPointTable {
+    keys: Vec<[u8; MAX_ENCODEDVALUE_SIZE]>,
+    points: Vec<RawPoint>,
+}
+
+impl RawPointTable {
+    // bytes: (key(encoded_value_size) + 生の点(RAW_POINT_SIZE_U8)) の繰り返し
+    pub fn from_bytes(bytes: &[u8], encoded_value_size: usize) -> EnclaveResult<Self> {
+        let record_size = encoded_value_size + RAW_POINT_SIZE_U8;
+        if bytes.len() % record_size != 0 {
+            return Err(EnclaveError::MalformedChunk);
+        }
+        let mut this = RawPointTable::default();
+        for record in bytes.chunks_exact(record_size) {
+            let key = padded_key(&record[..encoded_value_size]);
+            if this.keys.last().map_or(false, |last| *last > key) {
+                return Err(EnclaveError::MalformedChunk);
+            }
+            this.keys.push(key);
+            this.points.push(RawPoint::from_be_bytes(&record[encoded_value_size..]));
+        }
+        Ok(this)
+    }
+
+    // keyの生の点のどれかがpointの閾値に入るか
+    pub fn has_close(&self, key: &[u8], point: &RawPoint, thresholds: &ExactThresholds) -> bool {
+        let key = padded_key(key);
+        let (mut low, mut high) = (0, self.keys.len());
+        while low < high {
+            let middle = (low + high) / 2;
+            match self.keys[middle].cmp(&key) {
+                Ordering::Less => low = middle + 1,
+                _ => high = middle,
+            }
+        }
+        (low..self.keys.len())
+            .take_while(|position| self.keys[*position] == key)
+            .any(|position| self.points[position].is_close(point, thresholds))
+    }
+}
+
+// sessionの閾値とchunkの生の点，sessionが閾値を持つのにchunkに生の点がなければ確かめられない
+pub struct ExactVerifier<'a> {
+    table: &'a RawPointTable,
+    thresholds: ExactThresholds,
+}
+
+impl<'a> ExactVerifier<'a> {
+    pub fn new(thresholds: Option<ExactThresholds>, table: Option<&'a RawPointTable>) -> EnclaveResult<Option<Self>> {
+        match (thresholds, table) {
+            (Some(thresholds), Some(table)) => Ok(Some(ExactVerifier { table, thresholds })),
+            (Some(_), None) => Err(EnclaveError::ChunkFormatMismatch),
+            // 生の点があっても閾値がなければtrieの結果のまま
+            (None, _) => Ok(None),
+        }
+    }
+
+    pub fn is_close(&self, key: &[u8], point: &RawPoint) -> bool {
+        self.table.has_close(key, point, &self.thresholds)
+    }
+}
+
+impl Drop for RawPointTable {
+    fn drop(&mut self) {
+        for key in self.keys.iter_mut() {
+            zeroize(key);
+        }
+        for point in self.points.iter_mut() {
+            *point = RawPoint::default();
+        }
+    }
+}
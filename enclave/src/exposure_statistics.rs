@@ -0,0 +1,144 @@
+use sgx_trts::trts::rsgx_read_rand;
+use std::vec::Vec;
+use succinct_trie::trie::TrajectoryHash;
+use constant::*;
+use encoded_query_buffer::EncodedQueryBuffer;
+use encoded_result_buffer::EncodedResultBuffer;
+use contact_policy::PolicyVariants;
+use enclave_error::EnclaveResult;
+
+/*
+Exposure statistics
+    保健当局向けに，個々のclientの結果の代わりにノイズを足した集計だけを返す
+    - バッチのexposed client数 (どれかのcontact policyでpositive，rejectしたclientは数えない)
+    - 粗いcell (経度と緯度の上位region_bits bitずつ + 時刻の上位period_bits bit) ごとのexposed client数
+    cellはclientのquery key (variant 0) をTrajectoryHashのmaskで戻して作る
+    pointごとのhitを持つsessionではhitしたpointのcell，持たないときはexposed clientが通ったcellを数える (dummy pointは数えない)
+    1 clientはMAX_STATISTICS_CELLS_PER_CLIENT個のcellまでしか数えないので，cellの集計の感度はその数
+    ノイズは両側幾何分布 (離散Laplace)，epsilonは2つの集計に半分ずつ使う
+*/
+#[derive(Clone, Copy, Debug)]
+pub struct StatisticsRequest {
+    pub epsilon: f64,
+    pub region_bits: usize,
+    pub period_bits: usize,
+}
+
+impl StatisticsRequest {
+    // keyのlayoutはTRAJECTORY_*の1つだけで，ほかのlayoutのsessionは_get_exposure_statisticsがNotTrajectoryHashで断る
+    pub fn is_valid(&self) -> bool {
+        self.epsilon.is_finite() && self.epsilon >= MIN_STATISTICS_EPSILON
+            && 2*self.region_bits + self.period_bits <= MAX_STATISTICS_CELL_BITS
+            && self.region_bits <= TRAJECTORY_GEO_LENGTH && self.period_bits <= TRAJECTORY_TIME_LENGTH
+    }
+
+    pub fn cell_count(&self) -> usize {
+        1 << (2*self.region_bits + self.period_bits)
+    }
+
+    // exposed client数 + cellごとの数，どれもi64 BE
+    pub fn release_size(&self) -> usize {
+        (1 + self.cell_count())*STATISTICS_VALUE_SIZE_U8
+    }
+}
+
+fn _read_be_u128(key: &[u8]) -> u128 {
+    key.iter().fold(0u128, |value, byte| (value << 8) | *byte as u128)
+}
+
+// masksは下位bitから，上位kept_bits bitだけ取り出す
+fn _coarse_coordinate(value: u128, masks: &[u128], kept_bits: usize) -> usize {
+    let dropped_bits = masks.len() - kept_bits.min(masks.len());
+    masks.iter().skip(dropped_bits).enumerate().fold(0, |coordinate, (bit, mask)| {
+        if value & mask != 0 { coordinate | (1 << bit) } else { coordinate }
+    })
+}
+
+// (経度, 緯度, 時刻) の順に詰めたcellの番号
+fn _coarse_cell(th: &TrajectoryHash, key: &[u8], request: &StatisticsRequest) -> usize {
+    let value = _read_be_u128(key);
+    let longitude = _coarse_coordinate(value, &th.mask_lists[0], request.region_bits);
+    let latitude = _coarse_coordinate(value, &th.mask_lists[1], request.region_bits);
+    let period = _coarse_coordinate(value, &th.mask_lists[2], request.period_bits);
+    (((longitude << request.region_bits) | latitude) << request.period_bits) | period
+}
+
+// ノイズを足す前の集計 (exposed client数, cellごとのexposed client数)
+fn _aggregate(
+    query_buffer: &EncodedQueryBuffer,
+    result_buffer: &EncodedResultBuffer,
+    policy_variants: &PolicyVariants,
+    request: &StatisticsRequest,
+) -> (i64, Vec<i64>) {
+    let encoded_value_size = query_buffer.encoded_value_size;
+    let th = TrajectoryHash::new(encoded_value_size, TRAJECTORY_GEO_LENGTH, TRAJECTORY_TIME_LENGTH);
+    let mut exposed_clients = 0;
+    let mut cell_counts = vec![0; request.cell_count()];
+    let mut cells: Vec<usize> = Vec::with_capacity(MAX_STATISTICS_CELLS_PER_CLIENT);
+    for client_index in 0..query_buffer.client_count() {
+        if query_buffer.is_rejected(client_index) || policy_variants.bitmask(query_buffer, result_buffer, client_index) == 0 {
+            continue;
+        }
+        exposed_clients += 1;
+        cells.clear();
+        for (point_index, key) in query_buffer.queries[client_index].encoded_values(encoded_value_size).enumerate() {
+            if query_buffer.queries[client_index].is_dummy(point_index) {
+                continue;
+            }
+            if result_buffer.tracks_points() && (0..query_buffer.variant_count.max(1))
+                .all(|variant| result_buffer.point_hit(query_buffer.position(variant, client_index), point_index) == 0) {
+                continue;
+            }
+            let cell = _coarse_cell(&th, key, request);
+            if !cells.contains(&cell) {
+                cells.push(cell);
+                if cells.len() == MAX_STATISTICS_CELLS_PER_CLIENT {
+                    break;
+                }
+            }
+        }
+        for cell in cells.iter() {
+            cell_counts[*cell] += 1;
+        }
+    }
+    (exposed_clients, cell_counts)
+}
+
+// (0, 1]の一様乱数
+fn _uniform() -> EnclaveResult<f64> {
+    let mut bytes = [0_u8; 8];
+    rsgx_read_rand(&mut bytes)?;
+    Ok(((u64::from_be_bytes(bytes) >> 11) + 1) as f64 / (1u64 << 53) as f64)
+}
+
+// P(k) = (1 - alpha) * alpha^k, k >= 0
+fn _geometric(alpha: f64) -> EnclaveResult<i64> {
+    Ok((_uniform()?.ln() / alpha.ln()).floor() as i64)
+}
+
+// 両側幾何分布 P(k) ∝ exp(-epsilon * |k| / sensitivity)，同じalphaの幾何分布2つの差
+fn _two_sided_geometric(epsilon: f64, sensitivity: usize) -> EnclaveResult<i64> {
+    let alpha = (-epsilon / sensitivity as f64).exp();
+    Ok(_geometric(alpha)? - _geometric(alpha)?)
+}
+
+// release: release_size()のbyte数，ノイズを足した値だけを書く
+pub fn release(
+    query_buffer: &EncodedQueryBuffer,
+    result_buffer: &EncodedResultBuffer,
+    policy_variants: &PolicyVariants,
+    request: &StatisticsRequest,
+    release: &mut [u8],
+) -> EnclaveResult<()> {
+    let (exposed_clients, cell_counts) = _aggregate(query_buffer, result_buffer, policy_variants, request);
+    let epsilon = request.epsilon / 2.0;
+    let mut values: Vec<i64> = Vec::with_capacity(1 + cell_counts.len());
+    values.push(exposed_clients + _two_sided_geometric(epsilon, 1)?);
+    for count in cell_counts.iter() {
+        values.push(*count + _two_sided_geometric(epsilon, MAX_STATISTICS_CELLS_PER_CLIENT)?);
+    }
+    for (value, bytes) in values.iter().zip(release.chunks_exact_mut(STATISTICS_VALUE_SIZE_U8)) {
+        bytes.copy_from_slice(&value.to_be_bytes());
+    }
+    Ok(())
+}
@@ -1,8 +1,11 @@
 use succinct_trie::trie::{Trie, TrajectoryHash};
-use std::vec::Vec;
+#[cfg(feature = "nfp")]
+use constant::*;
 
 use encoded_result_buffer::EncodedResultBuffer;
 use encoded_query_buffer::EncodedQueryBuffer;
+use exact_point::ExactVerifier;
+use query_index::QueryIndex;
 
 // queryデータの方に使います．
 pub struct FST {
@@ -12,38 +15,69 @@ pub struct FST {
 }
 
 impl FST {
-    pub fn intersect(&self, query_buffer: &EncodedQueryBuffer, result: &mut EncodedResultBuffer) {
-        for encoded_value_vec in query_buffer.queries.iter() {
-            if result.data.contains(&encoded_value_vec.id) {
+    // verifierがあるときはtrieのhitを生の点で確かめる，nfpではhitしたかもしれない近傍を全部見る
+    // neighbor_index: nfpでupload時に広げた近傍keyの索引，あればchunkごとに近傍を作り直さない
+    #[cfg_attr(not(feature = "nfp"), allow(unused_variables))]
+    pub fn intersect(&self, query_buffer: &EncodedQueryBuffer, neighbor_index: Option<&QueryIndex>, verifier: Option<&ExactVerifier>, result: &mut EncodedResultBuffer) {
+        #[cfg(feature = "nfp")]
+        {
+            if let Some(neighbor_index) = neighbor_index {
+                self.intersect_neighbors(query_buffer, neighbor_index, verifier, result);
+                return;
+            }
+        }
+        for (query_index, encoded_value_vec) in query_buffer.queries.iter().enumerate() {
+            if result.is_settled(query_index) {
                 continue; 
             }
-            for key in encoded_value_vec.parameters.iter() {
+            for (point_index, key) in encoded_value_vec.encoded_values(query_buffer.encoded_value_size).enumerate() {
                 #[cfg(feature = "st")]
-                if self.map.contains(key) {
-                    result.data.insert(encoded_value_vec.id);
+                if self.map.contains(key) && verifier.map_or(true, |verifier| verifier.is_close(key, &encoded_value_vec.raw_point(point_index))) {
+                    result.set_positive(query_index, point_index);
                     continue;
                 }
                 #[cfg(feature = "nfp")]
-                if self.map.accurate_search(key, &self.th) {
-                    result.data.insert(encoded_value_vec.id);
+                if self.map.accurate_search(key, &self.th) && verifier.map_or(true, |verifier| {
+                    let point = encoded_value_vec.raw_point(point_index);
+                    self.th.get_neighbors(key).iter().any(|neighbor| verifier.is_close(neighbor, &point))
+                }) {
+                    result.set_positive(query_index, point_index);
                     continue;
                 }
             }
         }
     }
 
+    // distinctな近傍keyを1回ずつtrieで引き，hitしたらその近傍を持つquery pointを全部positiveにする
+    #[cfg(feature = "nfp")]
+    fn intersect_neighbors(&self, query_buffer: &EncodedQueryBuffer, neighbor_index: &QueryIndex, verifier: Option<&ExactVerifier>, result: &mut EncodedResultBuffer) {
+        for (position, key) in neighbor_index.keys.iter().enumerate() {
+            let neighbor = &key[..query_buffer.encoded_value_size];
+            if !self.map.contains(neighbor) {
+                continue;
+            }
+            for query_point in neighbor_index.postings[position].iter() {
+                let (query_index, point_index) = (*query_point as usize / QUERY_SIZE, *query_point as usize % QUERY_SIZE);
+                if verifier.map_or(true, |verifier| verifier.is_close(neighbor, &query_buffer.queries[query_index].raw_point(point_index))) {
+                    result.set_positive(query_index, point_index);
+                }
+            }
+        }
+    }
+
     pub fn build_dictionary_buffer(
-        bytes: Vec<u8>,
+        bytes: &[u8],
+        encoded_value_size: usize,
     ) -> Self {
         #[cfg(feature = "nfp")]
-        let th = TrajectoryHash::new(7, 24, 7);
+        let th = TrajectoryHash::new(encoded_value_size, TRAJECTORY_GEO_LENGTH, TRAJECTORY_TIME_LENGTH);
         // let th = TrajectoryHash::new(7, 21, 10);
         // let th = TrajectoryHash::new(8, 25, 14);
         // let th = TrajectoryHash::new(8, 24, 11);
         #[cfg(feature = "nfp")]
-        return Self { map: Trie::deserialize(&bytes), th };
+        return Self { map: Trie::deserialize(bytes), th };
         #[cfg(feature = "st")]
-        return Self { map: Trie::deserialize(&bytes) };
+        return Self { map: Trie::deserialize(bytes) };
     }
 
     pub fn calc_memory(&self) {
@@ -0,0 +1,57 @@
+use chunk_header::ChunkFormat;
+
+/* 
+Type JoinMode
+    set_join_modeでhostが切り替える
+    Dictionary: chunkのtrie/hashtable/ORAMをqueryで引く
+    Oblivious: oblivious_join，アクセスパターンがhitに依存しない
+    Adaptive: ソート済みのkey列のchunkに対して，join_strategyのcost modelでchunkごとにjoinの方法を選ぶ
+    Interval: queryはlocationと時刻の組，chunkのlocationごとの訪問periodとCONTACT_TIME_THREASHOLDで判定する
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum JoinMode {
+    Dictionary = 0,
+    Oblivious = 1,
+    Adaptive = 2,
+    Interval = 3,
+}
+
+impl JoinMode {
+    pub fn from_u8(join_mode: u8) -> Option<Self> {
+        match join_mode {
+            0 => Some(JoinMode::Dictionary),
+            1 => Some(JoinMode::Oblivious),
+            2 => Some(JoinMode::Adaptive),
+            3 => Some(JoinMode::Interval),
+            _ => None,
+        }
+    }
+
+    // query pointに時刻が付くか
+    pub fn with_time(&self) -> bool {
+        *self == JoinMode::Interval
+    }
+
+    // data ownerはモードに合わせた形式でchunkを作る
+    pub fn chunk_format(&self) -> ChunkFormat {
+        match self {
+            #[cfg(not(feature = "oram"))]
+            JoinMode::Dictionary => ChunkFormat::Dictionary,
+            // ORAMはenclave内でソート済みのkeyから組み立てる
+            #[cfg(feature = "oram")]
+            JoinMode::Dictionary => ChunkFormat::SortedKeys,
+            JoinMode::Oblivious | JoinMode::Adaptive => ChunkFormat::SortedKeys,
+            JoinMode::Interval => ChunkFormat::Visits,
+        }
+    }
+
+    // trie/hashtableのDictionaryには生の点を付けてもよい
+    pub fn accepts(&self, format: ChunkFormat) -> bool {
+        format == self.chunk_format() || (self.verifies_exact() && format == ChunkFormat::VerifiedDictionary)
+    }
+
+    // exact verificationはtrie/hashtableのhitのあとに行う
+    pub fn verifies_exact(&self) -> bool {
+        *self == JoinMode::Dictionary && self.chunk_format() == ChunkFormat::Dictionary
+    }
+}
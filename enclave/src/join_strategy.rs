@@ -0,0 +1,148 @@
+use std::cmp::Ordering;
+use constant::*;
+use constant_time::padded_key;
+use query_index::QueryIndex;
+use encoded_result_buffer::EncodedResultBuffer;
+use enclave_error::{EnclaveError, EnclaveResult};
+
+/*
+Join strategy
+    JoinMode::Adaptiveではchunkはソート済みのkey列(ChunkFormat::SortedKeys)で，
+    chunkごとにqueryのdistinct key数 D とchunkのkey数 C から一番安いstrategyを選ぶ
+    ProbeCentral   : queryの各keyをchunkから二分探索する            D log C
+    ProbeQueryIndex: chunkのkeyを順にqueryのhash indexで引く         C * HASH_PROBE_COST
+    SortMerge      : 昇順のquery keyとchunkを1回ずつ前に進めてマージ  D + C
+    queryの索引(QueryIndex)はsessionごとに1回作るので見積もりに入れない
+    どれもhitしたkeyに依存したアクセスになる(obliviousではない)
+*/
+// hashとslotの比較がkeyの比較何回分か
+const HASH_PROBE_COST: u64 = 4;
+
+pub trait JoinStrategy {
+    fn name(&self) -> &'static str;
+
+    // keyの比較回数の見積もり
+    fn cost(&self, distinct_query_key_count: usize, central_key_count: usize) -> u64;
+
+    // central_keys: encoded_value_sizeごとに区切られた昇順のkey列
+    fn intersect(&self, central_keys: &[u8], encoded_value_size: usize, query_index: &QueryIndex, result: &mut EncodedResultBuffer);
+}
+
+pub struct ProbeCentral;
+pub struct ProbeQueryIndex;
+pub struct SortMerge;
+
+fn _central_key(central_keys: &[u8], encoded_value_size: usize, i: usize) -> [u8; MAX_ENCODEDVALUE_SIZE] {
+    padded_key(&central_keys[i*encoded_value_size..(i+1)*encoded_value_size])
+}
+
+fn _log2_ceil(n: usize) -> u64 {
+    (n.max(1).next_power_of_two().trailing_zeros() + 1) as u64
+}
+
+impl JoinStrategy for ProbeCentral {
+    fn name(&self) -> &'static str {
+        "probe central"
+    }
+
+    fn cost(&self, distinct_query_key_count: usize, central_key_count: usize) -> u64 {
+        distinct_query_key_count as u64 * _log2_ceil(central_key_count)
+    }
+
+    fn intersect(&self, central_keys: &[u8], encoded_value_size: usize, query_index: &QueryIndex, result: &mut EncodedResultBuffer) {
+        let central_key_count = central_keys.len() / encoded_value_size;
+        for (position, key) in query_index.keys.iter().enumerate() {
+            let (mut low, mut high) = (0, central_key_count);
+            while low < high {
+                let middle = (low + high) / 2;
+                match _central_key(central_keys, encoded_value_size, middle).cmp(key) {
+                    Ordering::Less => low = middle + 1,
+                    Ordering::Greater => high = middle,
+                    Ordering::Equal => {
+                        query_index.set_positive(position, result);
+                        break;
+                    },
+                }
+            }
+        }
+    }
+}
+
+impl JoinStrategy for ProbeQueryIndex {
+    fn name(&self) -> &'static str {
+        "probe query index"
+    }
+
+    fn cost(&self, _distinct_query_key_count: usize, central_key_count: usize) -> u64 {
+        central_key_count as u64 * HASH_PROBE_COST
+    }
+
+    fn intersect(&self, central_keys: &[u8], encoded_value_size: usize, query_index: &QueryIndex, result: &mut EncodedResultBuffer) {
+        for key in central_keys.chunks_exact(encoded_value_size) {
+            if let Some(position) = query_index.lookup(&padded_key(key)) {
+                query_index.set_positive(position, result);
+            }
+        }
+    }
+}
+
+impl JoinStrategy for SortMerge {
+    fn name(&self) -> &'static str {
+        "sort-merge"
+    }
+
+    fn cost(&self, distinct_query_key_count: usize, central_key_count: usize) -> u64 {
+        (distinct_query_key_count + central_key_count) as u64
+    }
+
+    fn intersect(&self, central_keys: &[u8], encoded_value_size: usize, query_index: &QueryIndex, result: &mut EncodedResultBuffer) {
+        let central_key_count = central_keys.len() / encoded_value_size;
+        let (mut position, mut i) = (0, 0);
+        while position < query_index.keys.len() && i < central_key_count {
+            match query_index.keys[position].cmp(&_central_key(central_keys, encoded_value_size, i)) {
+                Ordering::Less => position += 1,
+                Ordering::Greater => i += 1,
+                Ordering::Equal => {
+                    query_index.set_positive(position, result);
+                    position += 1;
+                    i += 1;
+                },
+            }
+        }
+    }
+}
+
+static PROBE_CENTRAL: ProbeCentral = ProbeCentral;
+static PROBE_QUERY_INDEX: ProbeQueryIndex = ProbeQueryIndex;
+static SORT_MERGE: SortMerge = SortMerge;
+
+// cost modelで一番安いもの
+pub fn choose(distinct_query_key_count: usize, central_key_count: usize) -> &'static dyn JoinStrategy {
+    let strategies: [&'static dyn JoinStrategy; 3] = [&PROBE_CENTRAL, &PROBE_QUERY_INDEX, &SORT_MERGE];
+    let mut best = strategies[0];
+    for strategy in strategies.iter().skip(1) {
+        if strategy.cost(distinct_query_key_count, central_key_count) < best.cost(distinct_query_key_count, central_key_count) {
+            best = *strategy;
+        }
+    }
+    best
+}
+
+// ProbeCentralとSortMergeはchunkが昇順であることに頼る
+pub fn check_sorted(central_keys: &[u8], encoded_value_size: usize) -> EnclaveResult<()> {
+    if central_keys.len() % encoded_value_size != 0 {
+        return Err(EnclaveError::MalformedChunk);
+    }
+    let mut keys = central_keys.chunks_exact(encoded_value_size);
+    let mut previous = match keys.next() {
+        Some(key) => key,
+        None => return Ok(()),
+    };
+    for key in keys {
+        if previous > key {
+            return Err(EnclaveError::MalformedChunk);
+        }
+        previous = key;
+    }
+    Ok(())
+}
@@ -27,18 +27,20 @@ extern crate sgx_trts;
 #[macro_use]
 extern crate sgx_tstd as std;
 extern crate sgx_tcrypto;
+extern crate sgx_tse;
+extern crate sgx_tseal;
 extern crate bincode;
 extern crate succinct_trie;
 
 use sgx_types::*;
-use sgx_tcrypto::*;
 use std::vec::Vec;
-use std::cell::RefCell;
 use std::slice;
+use std::ptr;
 use std::boxed::Box;
-use std::time::{Instant};
-use std::untrusted::time::InstantEx;
-use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::{SgxMutex, SgxMutexGuard};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use std::untrusted::time::{InstantEx, SystemTimeEx};
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
 
 mod constant;
 mod primitive;
@@ -51,121 +53,781 @@ mod encoded_result_buffer;
 mod encoded_dictionary_buffer;
 mod encoded_hash_table;
 mod fast_succinct_trie;
+mod chunk_header;
+mod chunk_manifest;
+mod chunk_padding;
+mod central_builder;
+mod sealed_storage;
+mod join_mode;
+mod oblivious_join;
+mod query_index;
+mod join_strategy;
+mod period_table;
+mod contact_policy;
+mod query_variants;
+mod exact_point;
+mod exposure_statistics;
+mod query_guard;
+mod constant_time;
+mod path_oram;
+mod oram_dictionary;
+mod secure_channel;
+mod session;
+mod enclave_error;
+mod memory_budget;
+mod resident_cache;
 
 
 use constant::*;
-use encoded_query_buffer::EncodedQueryBuffer;
-use encoded_result_buffer::EncodedResultBuffer;
+use utils::zeroize;
 use encoded_dictionary_buffer::EncodedDictionaryBuffer;
+use chunk_header::{ChunkHeader, ChunkFormat, is_valid_encoded_value_size, is_trajectory_hash_size};
+use chunk_manifest::ChunkManifest;
+use central_builder::CentralBuilder;
+use sealed_storage::SealedKind;
+use join_mode::JoinMode;
+use sgx_tcrypto::rsgx_sha256_slice;
+use session::{Sessions, Session, SessionHandle};
+use enclave_error::{EnclaveError, EnclaveResult, report};
+use memory_budget::{MemoryBudget, Reservation};
+use resident_cache::{ResidentCache, ResidentChunk, ResidentData};
+use contact_policy::{ContactPolicy, MAX_CONTACT_POLICIES};
+use period_table::PeriodTable;
+use exact_point::ExactThresholds;
+use exposure_statistics::StatisticsRequest;
+use query_guard::RateLimits;
+use encoded_query_buffer::EncodedQueryBuffer;
+use secure_channel::{SecureChannel, KeyExchangeResponse, message_aad, decrypt_message, encrypt_message, verify_client_identity, verify_data_owner};
 
 
 /* 
 SGXのステート
     ステートは全部グローバル変数に持ってヒープにメモリを確保する
+    chunkのECALLは複数のTCSから同時に呼ばれるのでMutexで守る
+*/
+
+// 最初に呼ばれたときに作る，同時に作ったときはstoreできた方を使う
+fn _lazy_global<T>(global: &'static AtomicPtr<()>, init: fn() -> T) -> &'static SgxMutex<T> {
+    let ptr = global.load(Ordering::SeqCst) as * mut SgxMutex<T>;
+    if !ptr.is_null() {
+        return unsafe { &* ptr };
+    }
+    let new_ptr = Box::into_raw(Box::new(SgxMutex::new(init())));
+    match global.compare_exchange(ptr::null_mut(), new_ptr as *mut (), Ordering::SeqCst, Ordering::SeqCst) {
+        Ok(_) => unsafe { &* new_ptr },
+        Err(current) => {
+            unsafe { drop(Box::from_raw(new_ptr)); }
+            unsafe { &* (current as * mut SgxMutex<T>) }
+        }
+    }
+}
+
+// a panicked holder poisons the lock, the state may be inconsistent
+fn _lock<T>(mutex: &'static SgxMutex<T>) -> EnclaveResult<SgxMutexGuard<'static, T>> {
+    mutex.lock().map_err(|_| EnclaveError::Internal)
+}
+
+pub static SESSIONS: AtomicPtr<()> = AtomicPtr::new(0 as * mut ());
+pub fn get_ref_sessions() -> &'static SgxMutex<Sessions> {
+    _lazy_global(&SESSIONS, Sessions::new)
+}
+
+// data ownerのchannelはsessionをまたいで1つ
+pub static CENTRAL_CHANNEL: AtomicPtr<()> = AtomicPtr::new(0 as * mut ());
+pub fn get_ref_central_channel() -> &'static SgxMutex<Option<SecureChannel>> {
+    _lazy_global(&CENTRAL_CHANNEL, || None)
+}
+
+pub static CHUNK_MANIFEST: AtomicPtr<()> = AtomicPtr::new(0 as * mut ());
+pub fn get_ref_chunk_manifest() -> &'static SgxMutex<Option<ChunkManifest>> {
+    _lazy_global(&CHUNK_MANIFEST, || None)
+}
+
+// data ownerのrecordから作るchunk，begin_central_buildで作り直す
+pub static CENTRAL_BUILDER: AtomicPtr<()> = AtomicPtr::new(0 as * mut ());
+pub fn get_ref_central_builder() -> &'static SgxMutex<Option<CentralBuilder>> {
+    _lazy_global(&CENTRAL_BUILDER, || None)
+}
+
+// batchをまたいで置いておくdecode済みのchunk
+pub static RESIDENT_CACHE: AtomicPtr<()> = AtomicPtr::new(0 as * mut ());
+pub fn get_ref_resident_cache() -> &'static SgxMutex<ResidentCache> {
+    _lazy_global(&RESIDENT_CACHE, ResidentCache::new)
+}
+
+// 認証されたclientごとのquery数，sessionをまたいで持ちhostにsealさせる
+pub static RATE_LIMITS: AtomicPtr<()> = AtomicPtr::new(0 as * mut ());
+pub fn get_ref_rate_limits() -> &'static SgxMutex<RateLimits> {
+    _lazy_global(&RATE_LIMITS, RateLimits::new)
+}
+
+pub static JOIN_MODE: AtomicUsize = AtomicUsize::new(JoinMode::Dictionary as usize);
+pub fn get_join_mode() -> JoinMode {
+    // set_join_mode stores only valid modes
+    JoinMode::from_u8(JOIN_MODE.load(Ordering::SeqCst) as u8).unwrap_or(JoinMode::Dictionary)
+}
+
+/*
+    ECALL
+        どのECALLも最後の引数 error (EnclaveError) に結果を書き，
+        戻り値にはそのおおまかな分類のsgx_status_tを返す
 */
+#[no_mangle]
+pub extern "C" fn set_join_mode(join_mode: u8, error: *mut u32) -> sgx_status_t {
+    report(_set_join_mode(join_mode), error)
+}
 
-pub static ENCODED_QUERY_BUFFER: AtomicPtr<()> = AtomicPtr::new(0 as * mut ());
-pub fn get_ref_encoded_query_buffer() -> Option<&'static RefCell<EncodedQueryBuffer>> {
-    let ptr = ENCODED_QUERY_BUFFER.load(Ordering::SeqCst) as * mut RefCell<EncodedQueryBuffer>;
-    if ptr.is_null() {
-        None
-    } else {
-        Some(unsafe { &* ptr })
+fn _set_join_mode(join_mode: u8) -> EnclaveResult<()> {
+    let join_mode = JoinMode::from_u8(join_mode).ok_or(EnclaveError::InvalidJoinMode)?;
+    // resident chunks are decoded for the previous mode
+    if JOIN_MODE.swap(join_mode as usize, Ordering::SeqCst) != join_mode as usize {
+        _lock(get_ref_resident_cache())?.clear();
     }
+    Ok(())
 }
 
-pub static ENCODED_RESULT_BUFFER: AtomicPtr<()> = AtomicPtr::new(0 as * mut ());
-pub fn get_ref_encoded_result_buffer() -> Option<&'static RefCell<EncodedResultBuffer>> {
-    let ptr = ENCODED_RESULT_BUFFER.load(Ordering::SeqCst) as * mut RefCell<EncodedResultBuffer>;
-    if ptr.is_null() {
-        None
-    } else {
-        Some(unsafe { &* ptr })
+/*
+    Memory budget
+        heap size，今の使用量，ピークと，chunk 1つの処理に要るkeyあたりのheapを返す
+        hostはこれでquery bufferと処理中のchunkがheapに収まるchunkサイズを決める
+*/
+#[no_mangle]
+pub extern "C" fn get_memory_budget(
+    encoded_value_size: usize,
+    budget: *mut MemoryBudget,
+    error: *mut u32,
+) -> sgx_status_t {
+    report(_get_memory_budget(encoded_value_size, budget), error)
+}
+
+fn _get_memory_budget(encoded_value_size: usize, budget: *mut MemoryBudget) -> EnclaveResult<()> {
+    if !is_valid_encoded_value_size(encoded_value_size) {
+        return Err(EnclaveError::InvalidEncodedValueSize);
     }
+    unsafe { *budget = memory_budget::memory_budget(get_join_mode(), encoded_value_size); }
+    Ok(())
 }
 
+/*
+    Session
+        クエリのバッチごとにsessionを開く，client key，query，resultはsessionに属する
+        close_sessionでゼロ埋めして捨てる
+*/
+#[no_mangle]
+pub extern "C" fn open_session(
+    session_handle: *mut u64,
+    error: *mut u32,
+) -> sgx_status_t {
+    let ret = _lock(get_ref_sessions()).and_then(|mut sessions| sessions.open()).map(|handle| unsafe { *session_handle = handle; });
+    report(ret, error)
+}
+
+#[no_mangle]
+pub extern "C" fn close_session(
+    session_handle: u64,
+    error: *mut u32,
+) -> sgx_status_t {
+    report(_lock(get_ref_sessions()).and_then(|mut sessions| sessions.close(session_handle)), error)
+}
+
+/*
+    Key exchange
+        clientごと，data ownerごとにECDHでsession keyを共有する
+*/
+#[no_mangle]
+pub extern "C" fn client_key_exchange(
+    session_handle      : u64,
+    query_id            : u64,
+    client_public_key   : *const sgx_ec256_public_t,
+    identity_key        : *const sgx_ec256_public_t,
+    identity_signature  : *const sgx_ec256_signature_t,
+    identity_certificate: *const sgx_ec256_signature_t,
+    target_info         : *const sgx_target_info_t,
+    enclave_public_key  : *mut sgx_ec256_public_t,
+    report_out          : *mut sgx_report_t,
+    wrapped_key         : *mut u8,
+    wrapped_key_size    : usize,
+    session_id          : *mut u64,
+    error               : *mut u32,
+) -> sgx_status_t {
+    let ret = _lock(get_ref_sessions()).and_then(|mut sessions| {
+        let session = sessions.get_mut(session_handle)?;
+        session.check_new_client(&query_id)?;
+        // data ownerが証明したidentity keyがこのephemeral keyに署名している
+        let identity = unsafe { verify_client_identity(&*client_public_key, &*identity_key, &*identity_signature, &*identity_certificate) }?;
+        let channel = _key_exchange(client_public_key, target_info, enclave_public_key, report_out, wrapped_key, wrapped_key_size, session_id)?;
+        session.add_client(query_id, channel, identity)
+    });
+    report(ret, error)
+}
+
+#[no_mangle]
+pub extern "C" fn central_key_exchange(
+    client_public_key : *const sgx_ec256_public_t,
+    owner_signature   : *const sgx_ec256_signature_t,
+    target_info       : *const sgx_target_info_t,
+    enclave_public_key: *mut sgx_ec256_public_t,
+    report_out        : *mut sgx_report_t,
+    wrapped_key       : *mut u8,
+    wrapped_key_size  : usize,
+    session_id        : *mut u64,
+    error             : *mut u32,
+) -> sgx_status_t {
+    let ret = _lock(get_ref_central_channel()).and_then(|mut central_channel| {
+        // data ownerの鍵でephemeral keyに署名している
+        unsafe { verify_data_owner(&*client_public_key, &*owner_signature) }?;
+        // manifestかcentral buildのあとはkeyを替えさせない
+        let declared = _lock(get_ref_chunk_manifest())?.is_some();
+        if declared || _lock(get_ref_central_builder())?.is_some() {
+            return Err(EnclaveError::CentralDataDeclared);
+        }
+        *central_channel = Some(_key_exchange(client_public_key, target_info, enclave_public_key, report_out, wrapped_key, wrapped_key_size, session_id)?);
+        Ok(())
+    });
+    report(ret, error)
+}
+
+fn _key_exchange(
+    client_public_key : *const sgx_ec256_public_t,
+    target_info       : *const sgx_target_info_t,
+    enclave_public_key: *mut sgx_ec256_public_t,
+    report            : *mut sgx_report_t,
+    wrapped_key       : *mut u8,
+    wrapped_key_size  : usize,
+    session_id        : *mut u64,
+) -> EnclaveResult<SecureChannel> {
+    if wrapped_key_size != WRAPPED_KEY_SIZE_U8 {
+        return Err(EnclaveError::InvalidLength);
+    }
+    let (client_public_key, target_info) = unsafe { (&*client_public_key, &*target_info) };
+    let (channel, response): (SecureChannel, KeyExchangeResponse) = secure_channel::key_exchange(client_public_key, target_info)?;
+    unsafe {
+        *enclave_public_key = response.enclave_public_key;
+        *report = response.report;
+        slice::from_raw_parts_mut(wrapped_key, wrapped_key_size).copy_from_slice(&response.wrapped_key);
+        *session_id = channel.session_id;
+    }
+    Ok(channel)
+}
+
+/*
+    Query upload
+        begin_upload -> append_queries (何回でも) -> finish_upload
+        append_queriesは受け取ったclientを1つずつ復号してsessionのquery bufferに積むので
+        バッチ全体を一度にenclaveへコピーしなくてよい
+*/
+#[no_mangle]
+pub extern "C" fn begin_upload(
+    session_handle    : u64,
+    client_size       : usize,
+    encoded_value_size: usize,
+    error             : *mut u32,
+) -> sgx_status_t {
+    report(_begin_upload(session_handle, client_size, encoded_value_size), error)
+}
+
+fn _begin_upload(session_handle: SessionHandle, client_size: usize, encoded_value_size: usize) -> EnclaveResult<()> {
+    if !is_valid_encoded_value_size(encoded_value_size) {
+        return Err(EnclaveError::InvalidEncodedValueSize);
+    }
+    // interval modeのquery pointには時刻が付く
+    _lock(get_ref_sessions())?.get_mut(session_handle)?.begin_upload(encoded_value_size, client_size, get_join_mode().with_time())
+}
+
+/*
+    Contact policies
+        finish_uploadの前にbatchの判定ルールを最大8個渡す，responseのbyteのi bit目がi番目のpolicyの結果
+        渡さなければDirectだけ (今までの0or1)
+        min_exposureが2以上のpolicyはchunkをまたいでquery pointごとのhitを集め，連続した数で判定する
+*/
+#[no_mangle]
+pub extern "C" fn set_contact_policies(
+    session_handle: u64,
+    policies      : *const ContactPolicy,
+    policy_count  : usize,
+    error         : *mut u32,
+) -> sgx_status_t {
+    report(_set_contact_policies(session_handle, policies, policy_count), error)
+}
+
+fn _set_contact_policies(session_handle: SessionHandle, policies: *const ContactPolicy, policy_count: usize) -> EnclaveResult<()> {
+    if policy_count == 0 || policy_count > MAX_CONTACT_POLICIES {
+        return Err(EnclaveError::InvalidContactPolicy);
+    }
+    let policies = unsafe { slice::from_raw_parts(policies, policy_count) };
+    _lock(get_ref_sessions())?.get_mut(session_handle)?.set_contact_policies(policies)
+}
+
+/*
+    Exact verification
+        begin_uploadの前に距離(m)と時刻の差(秒)の閾値を渡すと，clientはkeyの後ろに生の点も送り，
+        trie/hashtableのhitをVerifiedDictionaryのchunkの生の点で確かめる (dictionary modeだけ)
+*/
+#[no_mangle]
+pub extern "C" fn set_exact_thresholds(
+    session_handle     : u64,
+    max_distance_meters: f64,
+    max_time_difference: u64,
+    error              : *mut u32,
+) -> sgx_status_t {
+    report(_set_exact_thresholds(session_handle, ExactThresholds { max_distance_meters, max_time_difference }), error)
+}
+
+fn _set_exact_thresholds(session_handle: SessionHandle, exact_thresholds: ExactThresholds) -> EnclaveResult<()> {
+    if !exact_thresholds.is_valid() {
+        return Err(EnclaveError::InvalidExactThresholds);
+    }
+    _lock(get_ref_sessions())?.get_mut(session_handle)?.set_exact_thresholds(exact_thresholds)
+}
+
+#[no_mangle]
+pub extern "C" fn append_queries(
+    session_handle : u64,
+    query_data     : *const u8,
+    query_data_size: usize,
+    client_size    : usize,
+    query_id_list  : *const u64,
+    error          : *mut u32,
+) -> sgx_status_t {
+    report(_append_queries(session_handle, query_data, query_data_size, client_size, query_id_list), error)
+}
+
+fn _append_queries(
+    session_handle : SessionHandle,
+    query_data     : *const u8,
+    query_data_size: usize,
+    client_size    : usize,
+    query_id_list  : *const u64,
+) -> EnclaveResult<()> {
+    // [in] buffers are already copied into the enclave, decrypted directly from them
+    let query_data_slice = unsafe { slice::from_raw_parts(query_data, query_data_size) };
+    let query_id_slice = unsafe { slice::from_raw_parts(query_id_list, client_size) };
+
+    let start = Instant::now();
+    let mut sessions = _lock(get_ref_sessions())?;
+    let session = sessions.get_mut(session_handle)?;
+    session.append_queries(query_data_slice, query_id_slice, &mut *_lock(get_ref_rate_limits())?, _unix_now())?;
+    let end = start.elapsed();
+    println!("[SGX CLOCK] {}:  {}.{:06} seconds", "decrypt each queries", end.as_secs(), end.subsec_nanos() / 1_000);
+    Ok(())
+}
+
+#[no_mangle]
+pub extern "C" fn finish_upload(
+    session_handle: u64,
+    error         : *mut u32,
+) -> sgx_status_t {
+    report(_finish_upload(session_handle), error)
+}
+
+fn _finish_upload(session_handle: SessionHandle) -> EnclaveResult<()> {
+    // every chunk of the current manifest must be processed for this batch
+    let chunk_manifest = _lock(get_ref_chunk_manifest())?.clone().ok_or(EnclaveError::NoManifest)?;
+    // adaptive joinのqueryの索引はここで1回だけ作る
+    _lock(get_ref_sessions())?.get_mut(session_handle)?.finish_upload(chunk_manifest, get_join_mode())
+}
+
+// data ownerがchunkを分け直して新しいmanifestを送ったあと，sessionのchunkの処理を最初からやり直す
+#[no_mangle]
+pub extern "C" fn restart_chunks(
+    session_handle: u64,
+    error         : *mut u32,
+) -> sgx_status_t {
+    report(_restart_chunks(session_handle), error)
+}
+
+fn _restart_chunks(session_handle: SessionHandle) -> EnclaveResult<()> {
+    let chunk_manifest = _lock(get_ref_chunk_manifest())?.clone().ok_or(EnclaveError::NoManifest)?;
+    _lock(get_ref_sessions())?.get_mut(session_handle)?.restart_chunks(chunk_manifest)
+}
+
+/*
+    Resident central data
+        capacity(byte)が0でなければ，chunkのECALLでdecodeしたchunkをbatchをまたいで置いておく
+        query_residentはmanifestの全chunkがそろっているときだけ，chunkを受け取らずにsessionのbatchに答える
+        1つでも欠けていればNotResidentで，hostはいつも通りchunkを送る
+*/
+#[no_mangle]
+pub extern "C" fn set_resident_cache(
+    capacity: usize,
+    error   : *mut u32,
+) -> sgx_status_t {
+    let ret = _lock(get_ref_resident_cache()).map(|mut resident_cache| {
+        resident_cache.set_capacity(capacity);
+        if capacity == 0 {
+            resident_cache.clear();
+        }
+    });
+    report(ret, error)
+}
+
+#[no_mangle]
+pub extern "C" fn query_resident(
+    session_handle: u64,
+    error         : *mut u32,
+) -> sgx_status_t {
+    report(_query_resident(session_handle), error)
+}
+
+fn _query_resident(session_handle: SessionHandle) -> EnclaveResult<()> {
+    let (query_buffer, query_index, mut partial_result, chunk_manifest, chunk_generation) = {
+        let sessions = _lock(get_ref_sessions())?;
+        let session = sessions.get(session_handle)?;
+        if !session.is_uploaded() {
+            return Err(EnclaveError::WrongSessionState);
+        }
+        let chunk_manifest = session.chunk_manifest.clone().ok_or(EnclaveError::WrongSessionState)?;
+        _check_query_format(&session.query_buffer, get_join_mode())?;
+        let query_index = session.query_index_for(get_join_mode())?;
+        (session.query_buffer.clone(), query_index, session.result_buffer.clone(), chunk_manifest, session.chunk_generation)
+    };
+
+    let start = Instant::now();
+    let digests = _lock(get_ref_resident_cache())?.intersect_all(&chunk_manifest, &query_buffer, query_index.as_ref().map(|query_index| &**query_index), &mut partial_result)?;
+    let end = start.elapsed();
+    println!("[SGX CLOCK] {}:  {}.{:06} seconds", "intersect resident chunks", end.as_secs(), end.subsec_nanos() / 1_000);
+
+    // every chunk of the manifest has been answered from the resident data
+    let mut sessions = _lock(get_ref_sessions())?;
+    let session = sessions.get_mut(session_handle)?;
+    if session.chunk_generation != chunk_generation {
+        return Err(EnclaveError::WrongSessionState);
+    }
+    let session_manifest = session.chunk_manifest.as_mut().ok_or(EnclaveError::WrongSessionState)?;
+    for (chunk_index, digest) in digests.iter().enumerate() {
+        session_manifest.mark_seen(chunk_index, digest)?;
+    }
+    session.result_buffer.merge(&partial_result);
+    Ok(())
+}
+
+// the whole batch in one call, same as begin_upload + append_queries + finish_upload
 #[no_mangle]
 pub extern "C" fn upload_encoded_query_data(
+    session_handle  : u64,
     total_query_data: *const u8,
     total_size       : usize,
     client_size     : usize,
     query_id_list   : *const u64,
+    encoded_value_size: usize,
+    error           : *mut u32,
 ) -> sgx_status_t {
     let whole_start = Instant::now();
-    let start = Instant::now();
-    _init_encoded_buffers();
-    let end = start.elapsed();
-    println!("[SGX CLOCK] {}:  {}.{:06} seconds", "buffers initialize", end.as_secs(), end.subsec_nanos() / 1_000);
+    let ret = _begin_upload(session_handle, client_size, encoded_value_size)
+        .and_then(|_| _append_queries(session_handle, total_query_data, total_size, client_size, query_id_list))
+        .and_then(|_| _finish_upload(session_handle));
+    let end = whole_start.elapsed();
+    println!("[SGX CLOCK] {}:  {}.{:06} seconds", "whole", end.as_secs(), end.subsec_nanos() / 1_000);
+    report(ret, error)
+}
 
-    let start = Instant::now();
-    let total_query_data_vec: Vec<u8> = unsafe {
-        slice::from_raw_parts(total_query_data, total_size)
+// rate limitのwindowはhostのclock (OCALL) で数える
+fn _unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0)
+}
+
+/*
+    Rate limits
+        anti-probingのclientごとのquery数をsealしてhostに置かせ，enclaveを再起動しても数え続ける
+*/
+#[no_mangle]
+pub extern "C" fn seal_rate_limits(
+    key_policy: u16,
+    sealed: *mut u8,
+    sealed_buffer_size: usize,
+    sealed_size: *mut usize,
+    error: *mut u32,
+) -> sgx_status_t {
+    report(_seal_rate_limits(key_policy, sealed, sealed_buffer_size, sealed_size), error)
+}
+
+fn _seal_rate_limits(
+    key_policy: u16,
+    sealed: *mut u8,
+    sealed_buffer_size: usize,
+    sealed_size: *mut usize,
+) -> EnclaveResult<()> {
+    let rate_limits_bytes = _lock(get_ref_rate_limits())?.to_be_bytes();
+    let sealed_slice = unsafe { slice::from_raw_parts_mut(sealed, sealed_buffer_size) };
+    let size = sealed_storage::seal(SealedKind::RateLimits, 0, key_policy, &rate_limits_bytes, sealed_slice)?;
+    unsafe { *sealed_size = size; }
+    Ok(())
+}
+
+#[no_mangle]
+pub extern "C" fn load_rate_limits(
+    sealed: *const u8,
+    sealed_size: usize,
+    error: *mut u32,
+) -> sgx_status_t {
+    report(_load_rate_limits(sealed, sealed_size), error)
+}
+
+fn _load_rate_limits(sealed: *const u8, sealed_size: usize) -> EnclaveResult<()> {
+    let mut sealed_vec: Vec<u8> = unsafe {
+        slice::from_raw_parts(sealed, sealed_size)
     }.to_vec();
-    if total_query_data_vec.len() != total_size {
-        return sgx_status_t::SGX_ERROR_INVALID_PARAMETER;
+    let rate_limits_bytes = sealed_storage::unseal(SealedKind::RateLimits, 0, &mut sealed_vec)?;
+    _lock(get_ref_rate_limits())?.restore(&rate_limits_bytes)
+}
+
+/*
+    Chunk manifest
+        data ownerがcentral_key_exchangeのsession keyで暗号化したmanifest
+        chunk数と各chunkのdigestを受け取り，以降のchunkはこれと照合する
+*/
+#[no_mangle]
+pub extern "C" fn upload_central_manifest(
+    manifest: *const u8,
+    manifest_size: usize,
+    error: *mut u32,
+) -> sgx_status_t {
+    report(_upload_central_manifest(manifest, manifest_size), error)
+}
+
+fn _upload_central_manifest(manifest: *const u8, manifest_size: usize) -> EnclaveResult<()> {
+    if manifest_size < MESSAGE_OVERHEAD_U8 + MANIFEST_HEADER_SIZE_U8 {
+        return Err(EnclaveError::InvalidLength);
     }
+    let manifest_slice = unsafe { slice::from_raw_parts(manifest, manifest_size) };
 
-    let query_id_list_vec: Vec<u64> = unsafe {
-        slice::from_raw_parts(query_id_list, client_size)
-    }.to_vec();
-    if query_id_list_vec.len() != client_size {
-        return sgx_status_t::SGX_ERROR_INVALID_PARAMETER;
+    let central_channel: SecureChannel = _lock(get_ref_central_channel())?.clone().ok_or(EnclaveError::NoCentralChannel)?;
+    let mut decrypted: Vec<u8> = vec![0; manifest_size - MESSAGE_OVERHEAD_U8];
+    decrypt_message(
+        &central_channel.key,
+        manifest_slice,
+        &message_aad(central_channel.session_id, MANIFEST_MESSAGE_ID),
+        &mut decrypted
+    )?;
+
+    let chunk_manifest = ChunkManifest::from_be_bytes(&decrypted).ok_or(EnclaveError::MalformedManifest)?;
+    _set_chunk_manifest(chunk_manifest)
+}
+
+// resident chunks stay only where the new manifest declares the same digest
+fn _set_chunk_manifest(chunk_manifest: ChunkManifest) -> EnclaveResult<()> {
+    _lock(get_ref_resident_cache())?.retain_manifest(&chunk_manifest);
+    *_lock(get_ref_chunk_manifest())? = Some(chunk_manifest);
+    Ok(())
+}
+
+/*
+    Central build
+        hostが平文のcentral dataを見ないように，data ownerが暗号化した生のrecordからenclaveがchunkを作る
+        begin_central_build -> append_central_records (batchごと) -> build_central_chunks -> take_central_chunk (chunkごと)
+        manifestはenclaveが作ったchunkのdigestで宣言するので，upload_central_manifestは要らない
+        recordはenclaveに残るので，chunkを分けるときはbuild_central_chunksからやり直す
+*/
+#[no_mangle]
+pub extern "C" fn begin_central_build(
+    encoded_value_size: usize,
+    chunk_format: u64,
+    error: *mut u32,
+) -> sgx_status_t {
+    report(_begin_central_build(encoded_value_size, chunk_format), error)
+}
+
+fn _begin_central_build(encoded_value_size: usize, chunk_format: u64) -> EnclaveResult<()> {
+    if _lock(get_ref_central_channel())?.is_none() {
+        return Err(EnclaveError::NoCentralChannel);
     }
-    let end = start.elapsed();
-    println!("[SGX CLOCK] {}:  {}.{:06} seconds", "reading", end.as_secs(), end.subsec_nanos() / 1_000);
+    let format = ChunkFormat::from_u64(chunk_format).ok_or(EnclaveError::ChunkFormatMismatch)?;
+    *_lock(get_ref_central_builder())? = Some(CentralBuilder::new(encoded_value_size, format)?);
+    Ok(())
+}
 
-    /* decryption */
-    let start = Instant::now();
+#[no_mangle]
+pub extern "C" fn append_central_records(
+    records: *const u8,
+    records_size: usize,
+    batch_index: usize,
+    error: *mut u32,
+) -> sgx_status_t {
+    report(_append_central_records(records, records_size, batch_index), error)
+}
 
-    let mut decrypted_query_data_vec: Vec<u8> = vec![1; total_query_data_vec.len()];
-    for (i, query_id) in query_id_list_vec.iter().enumerate() {
-        let mut counter_block: [u8; 16] = COUNTER_BLOCK;
-        let ctr_inc_bits: u32 = SGXSSL_CTR_BITS;
-
-        // Originally shared_key is derived by following Remote Attestation protocol.
-        // This is mock of shared key-based encryption.
-        let mut shared_key: [u8; 16] = [0; 16];
-        shared_key[..8].copy_from_slice(&query_id.to_be_bytes());
-        let current_cursor = i*QUERY_BYTES;
-        let ret = rsgx_aes_ctr_decrypt(
-            &shared_key,
-            &total_query_data_vec[current_cursor..current_cursor+QUERY_BYTES],
-            &mut counter_block,
-            ctr_inc_bits,
-            &mut decrypted_query_data_vec[current_cursor..current_cursor+QUERY_BYTES]
-        );
-        match ret { Ok(()) => {}, Err(_) => { return sgx_status_t::SGX_ERROR_UNEXPECTED; } }    
+fn _append_central_records(records: *const u8, records_size: usize, batch_index: usize) -> EnclaveResult<()> {
+    if records_size < MESSAGE_OVERHEAD_U8 + RECORD_BATCH_HEADER_SIZE_U8 || batch_index >= MAX_RECORD_BATCHES {
+        return Err(EnclaveError::InvalidLength);
     }
-    let end = start.elapsed();
-    println!("[SGX CLOCK] {}:  {}.{:06} seconds", "decrypt each queries", end.as_secs(), end.subsec_nanos() / 1_000);
+    let records_slice = unsafe { slice::from_raw_parts(records, records_size) };
 
+    let central_channel: SecureChannel = _lock(get_ref_central_channel())?.clone().ok_or(EnclaveError::NoCentralChannel)?;
+    let mut decrypted: Vec<u8> = vec![0; records_size - MESSAGE_OVERHEAD_U8];
+    // batch index is authenticated, the host cannot drop or repeat a batch unnoticed
+    decrypt_message(
+        &central_channel.key,
+        records_slice,
+        &message_aad(central_channel.session_id, CENTRAL_RECORDS_MESSAGE_ID_BASE + batch_index as u64),
+        &mut decrypted
+    )?;
+    let ret = match *_lock(get_ref_central_builder())? {
+        Some(ref mut builder) => builder.append(batch_index, &decrypted),
+        None => Err(EnclaveError::RecordBatchMismatch),
+    };
+    zeroize(&mut decrypted);
+    ret
+}
 
-    /* for more optiizaton this part can be conducted in decryption phase together, but to measure each part */
+#[no_mangle]
+pub extern "C" fn build_central_chunks(
+    threashould: usize,
+    size_class: usize,
+    chunk_count: *mut usize,
+    max_chunk_size: *mut usize,
+    error: *mut u32,
+) -> sgx_status_t {
+    report(_build_central_chunks(threashould, size_class, chunk_count, max_chunk_size), error)
+}
+
+fn _build_central_chunks(threashould: usize, size_class: usize, chunk_count: *mut usize, max_chunk_size: *mut usize) -> EnclaveResult<()> {
+    let central_channel: SecureChannel = _lock(get_ref_central_channel())?.clone().ok_or(EnclaveError::NoCentralChannel)?;
+    let mut central_builder = _lock(get_ref_central_builder())?;
+    let builder = central_builder.as_mut().ok_or(EnclaveError::RecordBatchMismatch)?;
     let start = Instant::now();
-    let mut query_buffer = get_ref_encoded_query_buffer().unwrap().borrow_mut();
-    query_buffer.build_query_buffer(decrypted_query_data_vec, query_id_list_vec);
+    let chunk_manifest = builder.build(threashould, size_class, &central_channel)?;
     let end = start.elapsed();
-    println!("[SGX CLOCK] {}:  {}.{:06} seconds", "store queies", end.as_secs(), end.subsec_nanos() / 1_000);
+    println!("[SGX CLOCK] {}:  {}.{:06} seconds", "central data build", end.as_secs(), end.subsec_nanos() / 1_000);
+    unsafe {
+        *chunk_count = builder.chunk_count();
+        *max_chunk_size = builder.max_chunk_size();
+    }
+    _set_chunk_manifest(chunk_manifest)
+}
 
-    let end = whole_start.elapsed();
-    println!("[SGX CLOCK] {}:  {}.{:06} seconds", "whole", end.as_secs(), end.subsec_nanos() / 1_000);
-    
-    sgx_status_t::SGX_SUCCESS
+#[no_mangle]
+pub extern "C" fn take_central_chunk(
+    chunk_index: usize,
+    chunk: *mut u8,
+    chunk_buffer_size: usize,
+    chunk_size: *mut usize,
+    error: *mut u32,
+) -> sgx_status_t {
+    report(_take_central_chunk(chunk_index, chunk, chunk_buffer_size, chunk_size), error)
 }
 
-fn _init_encoded_buffers() {
+fn _take_central_chunk(chunk_index: usize, chunk: *mut u8, chunk_buffer_size: usize, chunk_size: *mut usize) -> EnclaveResult<()> {
+    let mut central_builder = _lock(get_ref_central_builder())?;
+    let builder = central_builder.as_mut().ok_or(EnclaveError::RecordBatchMismatch)?;
+    // a chunk taken out is not kept, so the buffer is checked first
+    if builder.max_chunk_size() > chunk_buffer_size {
+        return Err(EnclaveError::InvalidLength);
+    }
+    let built_chunk = builder.take_chunk(chunk_index)?;
+    unsafe {
+        slice::from_raw_parts_mut(chunk, built_chunk.len()).copy_from_slice(&built_chunk);
+        *chunk_size = built_chunk.len();
+    }
+    Ok(())
+}
 
-    // initialize query buffer
-    let query_buffer = EncodedQueryBuffer::new();
-    let query_buffer_box = Box::new(RefCell::<EncodedQueryBuffer>::new(query_buffer));
-    let query_buffer_ptr = Box::into_raw(query_buffer_box);
-    ENCODED_QUERY_BUFFER.store(query_buffer_ptr as *mut (), Ordering::SeqCst);
+/*
+    Sealed storage
+        復号したcentral data chunkとmanifestをsealしてhostのストレージに置く
+        再起動後はload_sealed_manifestとprivate_sealed_contact_traceで再開する
+*/
+#[no_mangle]
+pub extern "C" fn seal_central_manifest(
+    key_policy: u16,
+    sealed: *mut u8,
+    sealed_buffer_size: usize,
+    sealed_size: *mut usize,
+    error: *mut u32,
+) -> sgx_status_t {
+    report(_seal_central_manifest(key_policy, sealed, sealed_buffer_size, sealed_size), error)
+}
 
-    // initialize result buffer
-    let result_buffer = EncodedResultBuffer::new();
-    let result_buffer_box = Box::new(RefCell::<EncodedResultBuffer>::new(result_buffer));
-    let result_buffer_ptr = Box::into_raw(result_buffer_box);
-    ENCODED_RESULT_BUFFER.store(result_buffer_ptr as *mut (), Ordering::SeqCst);
+fn _seal_central_manifest(
+    key_policy: u16,
+    sealed: *mut u8,
+    sealed_buffer_size: usize,
+    sealed_size: *mut usize,
+) -> EnclaveResult<()> {
+    let manifest_bytes = match *_lock(get_ref_chunk_manifest())? {
+        Some(ref chunk_manifest) => chunk_manifest.to_be_bytes(),
+        None => { return Err(EnclaveError::NoManifest); }
+    };
+    let sealed_slice = unsafe { slice::from_raw_parts_mut(sealed, sealed_buffer_size) };
+    let size = sealed_storage::seal(SealedKind::Manifest, 0, key_policy, &manifest_bytes, sealed_slice)?;
+    unsafe { *sealed_size = size; }
+    Ok(())
+}
+
+#[no_mangle]
+pub extern "C" fn seal_central_chunk(
+    encoded_value_u8: *const u8,
+    encoded_value_u8_size: usize,
+    chunk_index: usize,
+    key_policy: u16,
+    sealed: *mut u8,
+    sealed_buffer_size: usize,
+    sealed_size: *mut usize,
+    error: *mut u32,
+) -> sgx_status_t {
+    report(_seal_central_chunk(encoded_value_u8, encoded_value_u8_size, chunk_index, key_policy, sealed, sealed_buffer_size, sealed_size), error)
+}
+
+fn _seal_central_chunk(
+    encoded_value_u8: *const u8,
+    encoded_value_u8_size: usize,
+    chunk_index: usize,
+    key_policy: u16,
+    sealed: *mut u8,
+    sealed_buffer_size: usize,
+    sealed_size: *mut usize,
+) -> EnclaveResult<()> {
+    let encoded_value_slice = unsafe { slice::from_raw_parts(encoded_value_u8, encoded_value_u8_size) };
+    let mut decrypted = _decrypt_central_chunk(encoded_value_slice, chunk_index)?;
+    let ret = _seal_decrypted_chunk(&decrypted, chunk_index, key_policy, sealed, sealed_buffer_size, sealed_size);
+    zeroize(&mut decrypted);
+    ret
+}
+
+fn _seal_decrypted_chunk(
+    decrypted: &[u8],
+    chunk_index: usize,
+    key_policy: u16,
+    sealed: *mut u8,
+    sealed_buffer_size: usize,
+    sealed_size: *mut usize,
+) -> EnclaveResult<()> {
+    // only chunks declared in the manifest are persisted, with their padding
+    let digest = rsgx_sha256_slice(chunk_padding::unpad(decrypted)?)?;
+    match *_lock(get_ref_chunk_manifest())? {
+        Some(ref chunk_manifest) => chunk_manifest.verify(chunk_index, &digest)?,
+        None => { return Err(EnclaveError::NoManifest); }
+    }
+
+    let sealed_slice = unsafe { slice::from_raw_parts_mut(sealed, sealed_buffer_size) };
+    let size = sealed_storage::seal(SealedKind::Chunk, chunk_index as u64, key_policy, decrypted, sealed_slice)?;
+    unsafe { *sealed_size = size; }
+    Ok(())
+}
+
+#[no_mangle]
+pub extern "C" fn load_sealed_manifest(
+    sealed: *const u8,
+    sealed_size: usize,
+    error: *mut u32,
+) -> sgx_status_t {
+    report(_load_sealed_manifest(sealed, sealed_size), error)
+}
+
+// 起動したばかりのenclaveで1回だけ，data ownerのkey exchange，manifest，central buildのどれもないときに限る
+// 動いているenclaveのmanifestを古い (chunkの少ない) sealed manifestに戻させない
+// 再起動のときに古いfileを渡すrollbackは，trusted monotonic counterがないので防げない
+fn _load_sealed_manifest(sealed: *const u8, sealed_size: usize) -> EnclaveResult<()> {
+    let central_channel = _lock(get_ref_central_channel())?;
+    let declared = _lock(get_ref_chunk_manifest())?.is_some();
+    if central_channel.is_some() || declared || _lock(get_ref_central_builder())?.is_some() {
+        return Err(EnclaveError::CentralDataDeclared);
+    }
+    let mut sealed_vec: Vec<u8> = unsafe {
+        slice::from_raw_parts(sealed, sealed_size)
+    }.to_vec();
+    let manifest_bytes = sealed_storage::unseal(SealedKind::Manifest, 0, &mut sealed_vec)?;
+    let chunk_manifest = ChunkManifest::from_be_bytes(&manifest_bytes).ok_or(EnclaveError::MalformedManifest)?;
+    _set_chunk_manifest(chunk_manifest)
 }
 
 /*
@@ -173,88 +835,339 @@ fn _init_encoded_buffers() {
 */
 #[no_mangle]
 pub extern "C" fn private_encode_contact_trace(
+    session_handle: u64,
     encoded_value_u8: *const u8,
     encoded_value_u8_size: usize,
+    chunk_index: usize,
+    error: *mut u32,
 ) -> sgx_status_t {
-    let encoded_value_vec: Vec<u8> = unsafe {
-        slice::from_raw_parts(encoded_value_u8, encoded_value_u8_size)
-    }.to_vec();
-    if encoded_value_vec.len() != encoded_value_u8_size {
-        return sgx_status_t::SGX_ERROR_INVALID_PARAMETER;
-    }
+    report(_private_encode_contact_trace(session_handle, encoded_value_u8, encoded_value_u8_size, chunk_index), error)
+}
+
+fn _private_encode_contact_trace(
+    session_handle: SessionHandle,
+    encoded_value_u8: *const u8,
+    encoded_value_u8_size: usize,
+    chunk_index: usize,
+) -> EnclaveResult<()> {
+    let _reservation = _reserve_chunk(session_handle, encoded_value_u8_size)?;
+    let encoded_value_slice = unsafe { slice::from_raw_parts(encoded_value_u8, encoded_value_u8_size) };
 
     /* decryption */
     let start = Instant::now();
-    let mut decrypted: Vec<u8> = vec![1; encoded_value_vec.len()];
-    let mut counter_block: [u8; 16] = COUNTER_BLOCK;
-    let ctr_inc_bits: u32 = SGXSSL_CTR_BITS;
-    // Originally shared_key is derived by following Remote Attestation protocol.
-    // This is mock of shared key-based encryption.
-    let mut shared_key: [u8; 16] = [0; 16];
-    shared_key[..8].copy_from_slice(&CENTRAL_KEY.to_be_bytes());
-    let ret = rsgx_aes_ctr_decrypt(
-        &shared_key,
-        &encoded_value_vec,
-        &mut counter_block,
-        ctr_inc_bits,
-        &mut decrypted
-    );
-    match ret { Ok(()) => {}, Err(_) => { return sgx_status_t::SGX_ERROR_UNEXPECTED; } }    
-
+    let mut decrypted = _decrypt_central_chunk(encoded_value_slice, chunk_index)?;
     let end = start.elapsed();
     println!("[SGX CLOCK] {}:  {}.{:06} seconds", "central data decryption", end.as_secs(), end.subsec_nanos() / 1_000);
 
+    let ret = chunk_padding::unpad(&decrypted).and_then(|chunk| _contact_trace(session_handle, chunk, chunk_index));
+    zeroize(&mut decrypted);
+    ret
+}
+
+#[no_mangle]
+pub extern "C" fn private_sealed_contact_trace(
+    session_handle: u64,
+    sealed: *const u8,
+    sealed_size: usize,
+    chunk_index: usize,
+    error: *mut u32,
+) -> sgx_status_t {
+    report(_private_sealed_contact_trace(session_handle, sealed, sealed_size, chunk_index), error)
+}
+
+fn _private_sealed_contact_trace(
+    session_handle: SessionHandle,
+    sealed: *const u8,
+    sealed_size: usize,
+    chunk_index: usize,
+) -> EnclaveResult<()> {
+    let _reservation = _reserve_chunk(session_handle, sealed_size)?;
+    let mut sealed_vec: Vec<u8> = unsafe {
+        slice::from_raw_parts(sealed, sealed_size)
+    }.to_vec();
+
+    /* unsealing */
+    let start = Instant::now();
+    let mut decrypted = sealed_storage::unseal(SealedKind::Chunk, chunk_index as u64, &mut sealed_vec)?;
+    let end = start.elapsed();
+    println!("[SGX CLOCK] {}:  {}.{:06} seconds", "central data unsealing", end.as_secs(), end.subsec_nanos() / 1_000);
+
+    let ret = chunk_padding::unpad(&decrypted).and_then(|chunk| _contact_trace(session_handle, chunk, chunk_index));
+    zeroize(&mut decrypted);
+    ret
+}
+
+// 復号とdecodeの前にheapを予約する，足りなければOutOfMemoryでhostがchunkを分ける
+fn _reserve_chunk(session_handle: SessionHandle, chunk_size: usize) -> EnclaveResult<Reservation> {
+    let (encoded_value_size, query_key_count) = {
+        let sessions = _lock(get_ref_sessions())?;
+        let session = sessions.get(session_handle)?;
+        if !session.is_uploaded() {
+            return Err(EnclaveError::WrongSessionState);
+        }
+        (session.query_buffer.encoded_value_size, session.query_buffer.queries.len()*QUERY_SIZE)
+    };
+    let working_bytes = memory_budget::chunk_working_bytes(get_join_mode(), chunk_size, encoded_value_size, query_key_count);
+    loop {
+        match memory_budget::reserve(working_bytes) {
+            // resident chunks give way to the batch being processed
+            Err(EnclaveError::OutOfMemory) if _lock(get_ref_resident_cache())?.evict_lru() => continue,
+            ret => return ret,
+        }
+    }
+}
+
+fn _decrypt_central_chunk(encoded_value_slice: &[u8], chunk_index: usize) -> EnclaveResult<Vec<u8>> {
+    if encoded_value_slice.len() < MESSAGE_OVERHEAD_U8 {
+        return Err(EnclaveError::InvalidLength);
+    }
+    let mut decrypted: Vec<u8> = vec![0; encoded_value_slice.len() - MESSAGE_OVERHEAD_U8];
+    // session key has been exchanged with the data owner by central_key_exchange
+    let central_channel: SecureChannel = _lock(get_ref_central_channel())?.clone().ok_or(EnclaveError::NoCentralChannel)?;
+    // chunk index is authenticated, the host cannot swap chunks
+    decrypt_message(
+        &central_channel.key,
+        encoded_value_slice,
+        &message_aad(central_channel.session_id, chunk_index as u64),
+        &mut decrypted
+    )?;
+    Ok(decrypted)
+}
+
+// 時刻付きでuploadしたqueryはinterval modeでしか，時刻なしのqueryはそれ以外でしか使えない
+fn _check_query_format(query_buffer: &EncodedQueryBuffer, join_mode: JoinMode) -> EnclaveResult<()> {
+    if query_buffer.with_time != join_mode.with_time() {
+        println!("[SGX] queries uploaded {} time cannot be used in join mode {:?}", if query_buffer.with_time { "with" } else { "without" }, join_mode);
+        return Err(EnclaveError::QueryFormatMismatch);
+    }
+    if query_buffer.exact_thresholds.is_some() && !join_mode.verifies_exact() {
+        println!("[SGX] queries uploaded with raw points cannot be used in join mode {:?}", join_mode);
+        return Err(EnclaveError::QueryFormatMismatch);
+    }
+    Ok(())
+}
+
+/*
+    chunkの処理は複数のTCSで並行に走る
+    1. lockしてmanifestと照合し，queryのArcと今の結果のコピー(部分結果)を取る
+    2. lockを外して部分結果にintersectする
+    3. もう一度lockしてchunkを処理済みにし，部分結果をsessionの結果にORする
+    同じchunkが同時に2回来ても，3でmark_seenに失敗した方の部分結果は捨てる
+    1と3の間にrestart_chunksされたときも部分結果は捨てる
+*/
+fn _contact_trace(session_handle: SessionHandle, decrypted: &[u8], chunk_index: usize) -> EnclaveResult<()> {
+    let digest = rsgx_sha256_slice(decrypted)?;
+    let join_mode = get_join_mode();
+    let (query_buffer, query_index, mut partial_result, chunk_generation) = {
+        let sessions = _lock(get_ref_sessions())?;
+        let session = sessions.get(session_handle)?;
+        if !session.is_uploaded() {
+            return Err(EnclaveError::WrongSessionState);
+        }
+        // each chunk in the manifest is processed exactly once per session
+        let chunk_manifest = session.chunk_manifest.as_ref().ok_or(EnclaveError::WrongSessionState)?;
+        if let Err(enclave_error) = chunk_manifest.verify(chunk_index, &digest) {
+            println!("[SGX] chunk {} is rejected by the manifest", chunk_index);
+            return Err(enclave_error);
+        }
+        _check_query_format(&session.query_buffer, join_mode)?;
+        let query_index = session.query_index_for(join_mode)?;
+        (session.query_buffer.clone(), query_index, session.result_buffer.clone(), session.chunk_generation)
+    };
+
+    // queries and chunks must agree on the key width
+    let header = ChunkHeader::from_be_bytes(decrypted).ok_or(EnclaveError::MalformedChunk)?;
+    if header.encoded_value_size != query_buffer.encoded_value_size {
+        println!("[SGX] chunk encoded value size {} does not match query encoded value size {}", header.encoded_value_size, query_buffer.encoded_value_size);
+        return Err(EnclaveError::EncodedValueSizeMismatch);
+    }
+
+    if !join_mode.accepts(header.format) {
+        println!("[SGX] chunk format {:?} cannot be used in join mode {:?}", header.format, join_mode);
+        return Err(EnclaveError::ChunkFormatMismatch);
+    }
+
+    let start = Instant::now();
+    let central_keys = &decrypted[CHUNK_HEADER_SIZE_U8..];
+    let keep_resident = _lock(get_ref_resident_cache())?.is_enabled();
+    let resident_data = match join_mode {
+        JoinMode::Dictionary => {
+            let mut dictionary_buffer = if header.format == ChunkFormat::VerifiedDictionary {
+                EncodedDictionaryBuffer::build_verified_dictionary_buffer(central_keys, header.encoded_value_size)?
+            } else {
+                EncodedDictionaryBuffer::build_dictionary_buffer(central_keys, header.encoded_value_size)?
+            };
+            dictionary_buffer.intersect(&query_buffer, query_index.as_ref().map(|query_index| &**query_index), &mut partial_result)?;
+            Some(ResidentData::Dictionary(dictionary_buffer))
+        },
+        JoinMode::Oblivious => {
+            if central_keys.len() % header.encoded_value_size != 0 {
+                return Err(EnclaveError::MalformedChunk);
+            }
+            oblivious_join::intersect(central_keys, header.encoded_value_size, &query_buffer, &mut partial_result)?;
+            if keep_resident { Some(ResidentData::SortedKeys(central_keys.to_vec())) } else { None }
+        },
+        JoinMode::Adaptive => {
+            join_strategy::check_sorted(central_keys, header.encoded_value_size)?;
+            let query_index = query_index.as_ref().ok_or(EnclaveError::Internal)?;
+            let central_key_count = central_keys.len() / header.encoded_value_size;
+            let strategy = join_strategy::choose(query_index.distinct_key_count(), central_key_count);
+            println!("[SGX] chunk {}: {} ({} query keys, {} central keys)", chunk_index, strategy.name(), query_index.distinct_key_count(), central_key_count);
+            strategy.intersect(central_keys, header.encoded_value_size, query_index, &mut partial_result);
+            if keep_resident { Some(ResidentData::SortedKeys(central_keys.to_vec())) } else { None }
+        },
+        JoinMode::Interval => {
+            let period_table = PeriodTable::from_chunk(central_keys, header.encoded_value_size)?;
+            period_table.intersect(&query_buffer, &mut partial_result);
+            Some(ResidentData::Periods(period_table))
+        },
+    };
+    let end = start.elapsed();
+    println!("[SGX CLOCK] {}:  {}.{:06} seconds", "intersect", end.as_secs(), end.subsec_nanos() / 1_000);
 
-    let dictionary_buffer = EncodedDictionaryBuffer::build_dictionary_buffer(decrypted);
-    let mut result_buffer = get_ref_encoded_result_buffer().unwrap().borrow_mut();
-    let mut query_buffer = get_ref_encoded_query_buffer().unwrap().borrow_mut();
+    // the chunk has been verified against the manifest, later batches can use it with query_resident
+    if let (true, Some(resident_data)) = (keep_resident, resident_data) {
+        let mut resident_cache = _lock(get_ref_resident_cache())?;
+        if resident_cache.is_enabled() {
+            let bytes = memory_budget::resident_bytes(join_mode, central_keys.len(), header.encoded_value_size);
+            resident_cache.insert(chunk_index, ResidentChunk::new(digest, header.encoded_value_size, resident_data, bytes));
+        }
+    }
 
-    dictionary_buffer.intersect(&query_buffer, &mut result_buffer);
-    
-    sgx_status_t::SGX_SUCCESS
+    // the session may have been closed meanwhile
+    let mut sessions = _lock(get_ref_sessions())?;
+    let session = sessions.get_mut(session_handle)?;
+    if session.chunk_generation != chunk_generation {
+        return Err(EnclaveError::WrongSessionState);
+    }
+    let chunk_manifest = session.chunk_manifest.as_mut().ok_or(EnclaveError::WrongSessionState)?;
+    if let Err(enclave_error) = chunk_manifest.mark_seen(chunk_index, &digest) {
+        println!("[SGX] chunk {} is rejected by the manifest", chunk_index);
+        return Err(enclave_error);
+    }
+    session.result_buffer.merge(&partial_result);
+    Ok(())
 }
 
 // Response construction
 #[no_mangle]
 pub extern "C" fn get_encoded_result(
+    session_handle: u64,
     response: *mut u8,
     response_size: usize,
+    error: *mut u32,
 ) -> sgx_status_t {
-    let result_buffer = get_ref_encoded_result_buffer().unwrap().borrow_mut();
-    let query_buffer = get_ref_encoded_query_buffer().unwrap().borrow_mut();
-    let mut response_vec: Vec<u8> = Vec::with_capacity(response_size);
+    report(_get_encoded_result(session_handle, response, response_size), error)
+}
 
-    result_buffer.build_query_response(&query_buffer, &mut response_vec);
+fn _get_encoded_result(
+    session_handle: SessionHandle,
+    response: *mut u8,
+    response_size: usize,
+) -> EnclaveResult<()> {
+    let sessions = _lock(get_ref_sessions())?;
+    let session = sessions.get(session_handle)?;
+    _check_result_ready(session)?;
+
+    let result_buffer = &session.result_buffer;
+    let query_buffer = &session.query_buffer;
+    // one response per client, the lag queries of the indirect mode are folded into it
+    if response_size != query_buffer.client_count()*ENCRYPTED_RESPONSE_DATA_SIZE_U8 {
+        return Err(EnclaveError::InvalidLength);
+    }
+    let mut response_vec: Vec<u8> = Vec::with_capacity(query_buffer.client_count()*RESPONSE_DATA_SIZE_U8);
+
+    result_buffer.build_query_response(query_buffer, &session.policy_variants, &mut response_vec);
 
     /* encryption */
-    let mut encrypted_response_vec: Vec<u8> = response_vec.clone();
-    for (i, query_rep) in query_buffer.queries.iter().enumerate() {
-        let mut counter_block: [u8; 16] = COUNTER_BLOCK;
-        let ctr_inc_bits: u32 = SGXSSL_CTR_BITS;
-
-        // Originally shared_key is derived by following Remote Attestation protocol.
-        // This is mock of shared key-based encryption.
-        let mut shared_key: [u8; 16] = [0; 16];
-        shared_key[..8].copy_from_slice(&query_rep.id.to_be_bytes());
+    // format: query_id(8byte) + iv + encrypted result + mac
+    let mut encrypted_response_vec: Vec<u8> = vec![0; response_size];
+    let ret = _encrypt_response(session, &response_vec, &mut encrypted_response_vec);
+    zeroize(&mut response_vec);
+    ret?;
+
+    unsafe { slice::from_raw_parts_mut(response, response_size) }.copy_from_slice(&encrypted_response_vec);
+    Ok(())
+}
+
+// results are released only after all chunks of the manifest have been seen
+fn _check_result_ready(session: &Session) -> EnclaveResult<()> {
+    if !session.is_uploaded() {
+        return Err(EnclaveError::WrongSessionState);
+    }
+    let chunk_manifest = session.chunk_manifest.as_ref().ok_or(EnclaveError::WrongSessionState)?;
+    if !chunk_manifest.is_complete() {
+        println!("[SGX] {} of {} chunks have not been processed", chunk_manifest.missing_count(), chunk_manifest.chunk_count());
+        return Err(EnclaveError::ResultNotReady);
+    }
+    Ok(())
+}
+
+fn _encrypt_response(session: &Session, response_vec: &[u8], encrypted_response_vec: &mut [u8]) -> EnclaveResult<()> {
+    for (i, query_rep) in session.query_buffer.queries.iter().take(session.query_buffer.client_count()).enumerate() {
+        let channel = session.client(&query_rep.id).ok_or(EnclaveError::UnknownClient)?;
         let current_cursor = i*RESPONSE_DATA_SIZE_U8;
+        let encrypted_cursor = i*ENCRYPTED_RESPONSE_DATA_SIZE_U8;
         
         // Encrypt only sensitive part, result. query_id should not be encrypted.
-        let ret = rsgx_aes_ctr_encrypt(
-            &shared_key,
+        encrypted_response_vec[encrypted_cursor..encrypted_cursor+QUERY_ID_SIZE_U8]
+            .copy_from_slice(&response_vec[current_cursor..current_cursor+QUERY_ID_SIZE_U8]);
+        encrypt_message(
+            &channel.key,
             &response_vec[current_cursor+QUERY_ID_SIZE_U8..current_cursor+RESPONSE_DATA_SIZE_U8],
-            &mut counter_block,
-            ctr_inc_bits,
-            &mut encrypted_response_vec[current_cursor+QUERY_ID_SIZE_U8..current_cursor+RESPONSE_DATA_SIZE_U8]
-        );
-        match ret { Ok(()) => {}, Err(_) => { return sgx_status_t::SGX_ERROR_UNEXPECTED; } }    
+            &message_aad(channel.session_id, query_rep.id),
+            &mut encrypted_response_vec[encrypted_cursor+QUERY_ID_SIZE_U8..encrypted_cursor+ENCRYPTED_RESPONSE_DATA_SIZE_U8]
+        )?;
     }
+    Ok(())
+}
 
-    let slice = encrypted_response_vec.as_mut_slice();
-    unsafe {
-        for i in 0..response_size {
-            *response.offset(i as isize) = slice[i];
-        }
+/*
+Exposure statistics
+    個々のclientの結果の代わりに，ノイズを足したバッチの集計だけをhostに平文で返す
+    epsilonはsessionの予算から引かれ，使い切ったsessionはPrivacyBudgetExceeded
+    cellはTrajectoryHashのkeyから作るのでinterval modeのqueryには使えない
+*/
+#[no_mangle]
+pub extern "C" fn get_exposure_statistics(
+    session_handle : u64,
+    epsilon        : f64,
+    region_bits    : u32,
+    period_bits    : u32,
+    statistics     : *mut u8,
+    statistics_size: usize,
+    error          : *mut u32,
+) -> sgx_status_t {
+    let request = StatisticsRequest { epsilon, region_bits: region_bits as usize, period_bits: period_bits as usize };
+    report(_get_exposure_statistics(session_handle, request, statistics, statistics_size), error)
+}
+
+fn _get_exposure_statistics(
+    session_handle : SessionHandle,
+    request        : StatisticsRequest,
+    statistics     : *mut u8,
+    statistics_size: usize,
+) -> EnclaveResult<()> {
+    if !request.is_valid() {
+        return Err(EnclaveError::InvalidStatisticsRequest);
+    }
+    if statistics_size != request.release_size() {
+        return Err(EnclaveError::InvalidLength);
+    }
+    let mut sessions = _lock(get_ref_sessions())?;
+    let session = sessions.get_mut(session_handle)?;
+    _check_result_ready(session)?;
+    if session.query_buffer.with_time {
+        return Err(EnclaveError::QueryFormatMismatch);
+    }
+    // cellはTRAJECTORY_GEO_LENGTH, TRAJECTORY_TIME_LENGTHのmaskで戻すので，ほかのlayoutのkeyでは意味がない
+    if !is_trajectory_hash_size(session.query_buffer.encoded_value_size) {
+        return Err(EnclaveError::NotTrajectoryHash);
     }
+    session.spend_statistics_epsilon(request.epsilon)?;
 
-    sgx_status_t::SGX_SUCCESS
+    let mut release: Vec<u8> = vec![0; statistics_size];
+    exposure_statistics::release(&session.query_buffer, &session.result_buffer, &session.policy_variants, &request, &mut release)?;
+    unsafe { slice::from_raw_parts_mut(statistics, statistics_size) }.copy_from_slice(&release);
+    Ok(())
 }
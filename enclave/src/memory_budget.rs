@@ -0,0 +1,213 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+#[cfg(feature = "hashtable")]
+use std::mem;
+#[cfg(feature = "hashtable")]
+use std::vec::Vec;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use sgx_trts::enclave::SgxGlobalData;
+use join_mode::JoinMode;
+#[cfg(all(feature = "fsa", feature = "nfp"))]
+use constant::NFP_NEIGHBOR_COUNT;
+use oblivious_join;
+use query_index;
+use period_table;
+#[cfg(feature = "oram")]
+use oram_dictionary::OramDictionary;
+use enclave_error::{EnclaveError, EnclaveResult};
+
+/*
+Memory budget
+    global allocatorを包んでRustが確保したheapの使用量とピークを数える
+    heap sizeはEnclave.config.xmlのHeapMaxSize
+    Rustの確保失敗はenclaveごとabortするので，chunkのECALLは使う分を先に予約し
+    足りなければOutOfMemoryを返してhostにchunkを小さくさせる
+    edger8rの[in]バッファや SDK の C コードの確保は数えられないので HEAP_MARGIN_U8 を残す
+*/
+pub struct CountingAllocator;
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+static HEAP_USED: AtomicUsize = AtomicUsize::new(0);
+static HEAP_PEAK: AtomicUsize = AtomicUsize::new(0);
+// 処理中のchunkが予約してまだ確保していない分も含む
+static HEAP_RESERVED: AtomicUsize = AtomicUsize::new(0);
+
+// 数えられない確保のための余白
+pub const HEAP_MARGIN_U8: usize = 4 * 1024 * 1024;
+
+fn _count_alloc(size: usize) {
+    let used = HEAP_USED.fetch_add(size, Ordering::SeqCst) + size;
+    HEAP_PEAK.fetch_max(used, Ordering::SeqCst);
+}
+
+fn _count_dealloc(size: usize) {
+    HEAP_USED.fetch_sub(size, Ordering::SeqCst);
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            _count_alloc(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            _count_alloc(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        _count_dealloc(layout.size());
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = System.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            _count_dealloc(layout.size());
+            _count_alloc(new_size);
+        }
+        new_ptr
+    }
+}
+
+pub fn heap_size() -> usize {
+    SgxGlobalData::new().heap_size()
+}
+
+pub fn heap_used() -> usize {
+    HEAP_USED.load(Ordering::SeqCst)
+}
+
+pub fn heap_peak() -> usize {
+    HEAP_PEAK.load(Ordering::SeqCst)
+}
+
+// dropで予約を返す
+pub struct Reservation {
+    size: usize,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        HEAP_RESERVED.fetch_sub(self.size, Ordering::SeqCst);
+    }
+}
+
+// 他のスレッドの予約と合わせてheapに収まるときだけ予約できる
+// 予約した分を確保するとusedにも入るので，処理中は多めに見積もる側に倒れる
+pub fn reserve(size: usize) -> EnclaveResult<Reservation> {
+    // 他のスレッドの予約
+    let reserved = HEAP_RESERVED.fetch_add(size, Ordering::SeqCst);
+    let reservation = Reservation { size };
+    let required = reserved
+        .checked_add(size)
+        .and_then(|required| required.checked_add(heap_used()))
+        .and_then(|required| required.checked_add(HEAP_MARGIN_U8))
+        .ok_or(EnclaveError::OutOfMemory)?;
+    if required > heap_size() {
+        println!("[SGX] {} bytes for a chunk do not fit in the heap ({} used, {} reserved)", size, heap_used(), reserved);
+        return Err(EnclaveError::OutOfMemory);
+    }
+    Ok(reservation)
+}
+
+/*
+    chunk 1つの処理に要るheapの見積もり (get_memory_budgetでhostにも返す)
+    chunk_key_cost: chunkのkey 1つあたり，復号したchunkとsealedのコピー + decodeした辞書
+    query_key_cost: query key 1つあたり，処理中のchunkごとに追加で要る分 (oblivious joinの配列，adaptive joinの索引)
+*/
+pub fn chunk_key_cost(join_mode: JoinMode, encoded_value_size: usize) -> usize {
+    let copies = 2 * encoded_value_size;
+    copies + match join_mode {
+        JoinMode::Dictionary => _dictionary_key_cost(encoded_value_size),
+        // 2の冪への切り上げで最大2倍
+        JoinMode::Oblivious => 2 * oblivious_join::entry_size(),
+        // 復号したkey列をそのまま引く
+        JoinMode::Adaptive => 0,
+        JoinMode::Interval => period_table::key_cost(encoded_value_size),
+    }
+}
+
+pub fn query_key_cost(join_mode: JoinMode) -> usize {
+    match join_mode {
+        JoinMode::Dictionary => _dictionary_query_key_cost(),
+        JoinMode::Oblivious => 2 * oblivious_join::entry_size(),
+        // sessionのQueryIndexはchunkごとではないが，upload後に切り替えたときはchunkごとに作るので多めに数える
+        JoinMode::Adaptive => query_index::key_cost(),
+        JoinMode::Interval => 0,
+    }
+}
+
+// nfpの近傍keyの索引はquery keyごとにNFP_NEIGHBOR_COUNT個，Adaptiveと同じく多めに数える
+#[cfg(all(feature = "fsa", feature = "nfp"))]
+fn _dictionary_query_key_cost() -> usize {
+    NFP_NEIGHBOR_COUNT * query_index::key_cost()
+}
+
+#[cfg(not(all(feature = "fsa", feature = "nfp")))]
+fn _dictionary_query_key_cost() -> usize {
+    0
+}
+
+// succinct trieはserializeしたbyte列とほぼ同じ大きさ
+#[cfg(feature = "fsa")]
+fn _dictionary_key_cost(encoded_value_size: usize) -> usize {
+    encoded_value_size
+}
+
+// keyごとのVecとそのheap，hash，load factor (EncodedHashTable::calc_memoryと同じ見積もり)
+#[cfg(feature = "hashtable")]
+fn _dictionary_key_cost(encoded_value_size: usize) -> usize {
+    (mem::size_of::<Vec<u8>>() + encoded_value_size + mem::size_of::<u64>()) * 11 / 10
+}
+
+#[cfg(feature = "oram")]
+fn _dictionary_key_cost(_encoded_value_size: usize) -> usize {
+    OramDictionary::key_cost()
+}
+
+// get_memory_budgetの[out]，EDLのmemory_budget_tと同じ並び
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct MemoryBudget {
+    pub heap_size: usize,
+    pub heap_used: usize,
+    pub heap_peak: usize,
+    pub heap_margin: usize,
+    pub chunk_key_cost: usize,
+    pub query_key_cost: usize,
+}
+
+pub fn memory_budget(join_mode: JoinMode, encoded_value_size: usize) -> MemoryBudget {
+    MemoryBudget {
+        heap_size: heap_size(),
+        heap_used: heap_used(),
+        heap_peak: heap_peak(),
+        heap_margin: HEAP_MARGIN_U8,
+        chunk_key_cost: chunk_key_cost(join_mode, encoded_value_size),
+        query_key_cost: query_key_cost(join_mode),
+    }
+}
+
+// session のqueryに対してchunkを1つ処理するときの予約サイズ
+pub fn chunk_working_bytes(join_mode: JoinMode, chunk_size: usize, encoded_value_size: usize, query_key_count: usize) -> usize {
+    let chunk_key_count = chunk_size / encoded_value_size + 1;
+    chunk_key_count.saturating_mul(chunk_key_cost(join_mode, encoded_value_size))
+        .saturating_add(query_key_count.saturating_mul(query_key_cost(join_mode)))
+}
+
+// 常駐させたchunk 1つの見積もり，decodeした辞書 (oblivious/adaptive joinはkey列そのもの)
+pub fn resident_bytes(join_mode: JoinMode, chunk_size: usize, encoded_value_size: usize) -> usize {
+    match join_mode {
+        JoinMode::Dictionary => (chunk_size / encoded_value_size + 1).saturating_mul(_dictionary_key_cost(encoded_value_size)),
+        JoinMode::Oblivious | JoinMode::Adaptive => chunk_size,
+        JoinMode::Interval => (chunk_size / encoded_value_size + 1).saturating_mul(period_table::key_cost(encoded_value_size)),
+    }
+}
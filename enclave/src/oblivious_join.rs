@@ -0,0 +1,161 @@
+use std::vec::Vec;
+use std::mem;
+use constant::*;
+use encoded_query_buffer::EncodedQueryBuffer;
+use encoded_result_buffer::EncodedResultBuffer;
+use constant_time::*;
+use enclave_error::{EnclaveError, EnclaveResult};
+
+/*
+Oblivious join
+    FST/HashTableのintersectはどのqueryがhitしたかでメモリアクセスが変わるので，
+    page faultやcache lineを見ているhostに漏れる．このモードでは
+    1. query keyとcentral keyを1つの配列にして (key, tag) でbitonic sort
+    2. linear scanで直前のcentral keyと一致するquery keyにhitを立てる
+    3. (query index, point index) でもう一度bitonic sortすると，i番目のqueryのj番目のkeyは i*QUERY_SIZE + j に並ぶ
+       bitonic sortは安定ではないので，point indexまで比べないとquery内のpointの順が崩れる
+    4. 位置だけで決まるresult flag (とquery pointごとのhit) にhitをORする，dummy pointのhitはresult bufferが落とす
+    アクセスパターンは要素数 n = queries*QUERY_SIZE + central keys だけで決まる
+    計算量は O(n log^2 n)，positiveになったqueryも最後まで処理する
+    一致判定はexact matchのみ(nfpの近傍探索はしない)
+*/
+
+const TAG_CENTRAL: u8 = 0;
+const TAG_QUERY: u8 = 1;
+const TAG_DUMMY: u8 = 2;
+const INDEX_NONE: u32 = u32::max_value();
+
+// keyはMAX_ENCODEDVALUE_SIZEまで0埋めして幅によらず同じ比較をする
+#[derive(Clone, Copy)]
+struct Entry {
+    key: [u8; MAX_ENCODEDVALUE_SIZE],
+    tag: u8,
+    hit: u8,
+    query_index: u32,
+    point_index: u32,
+}
+
+impl Entry {
+    fn new(encoded_value: &[u8], tag: u8, query_index: u32, point_index: u32) -> Self {
+        Entry { key: padded_key(encoded_value), tag, hit: 0, query_index, point_index }
+    }
+
+    // 2の冪に揃えるための要素，どのkeyよりも後ろに並ぶ
+    fn dummy() -> Self {
+        Entry { key: [0xff_u8; MAX_ENCODEDVALUE_SIZE], tag: TAG_DUMMY, hit: 0, query_index: INDEX_NONE, point_index: INDEX_NONE }
+    }
+}
+
+// memory_budgetの見積もり用
+pub fn entry_size() -> usize {
+    mem::size_of::<Entry>()
+}
+
+// (key, tag) の辞書順，同じkeyならcentralがqueryより前
+fn lt_by_key(a: &Entry, b: &Entry) -> u8 {
+    ct_lt_key(&a.key, &b.key) | (ct_eq_key(&a.key, &b.key) & ct_lt_u8(a.tag, b.tag))
+}
+
+// (query index, point index) の辞書順，central keyとdummyは後ろ
+fn lt_by_position(a: &Entry, b: &Entry) -> u8 {
+    ct_lt_u32(a.query_index, b.query_index) | (ct_eq_u32(a.query_index, b.query_index) & ct_lt_u32(a.point_index, b.point_index))
+}
+
+// swap == 1 のときだけ入れ替えるが，どちらでも同じ読み書きをする
+fn cswap(swap: u8, a: &mut Entry, b: &mut Entry) {
+    let mask = 0_u8.wrapping_sub(swap);
+    for i in 0..MAX_ENCODEDVALUE_SIZE {
+        let t = (a.key[i] ^ b.key[i]) & mask;
+        a.key[i] ^= t;
+        b.key[i] ^= t;
+    }
+    let t = (a.tag ^ b.tag) & mask;
+    a.tag ^= t;
+    b.tag ^= t;
+    let t = (a.hit ^ b.hit) & mask;
+    a.hit ^= t;
+    b.hit ^= t;
+    let mask32 = 0_u32.wrapping_sub(swap as u32);
+    let t = (a.query_index ^ b.query_index) & mask32;
+    a.query_index ^= t;
+    b.query_index ^= t;
+    let t = (a.point_index ^ b.point_index) & mask32;
+    a.point_index ^= t;
+    b.point_index ^= t;
+}
+
+// entries.len() は2の冪，比較する位置の列は長さだけで決まる
+fn bitonic_sort(entries: &mut [Entry], lt: fn(&Entry, &Entry) -> u8) {
+    let n = entries.len();
+    let mut k = 2;
+    while k <= n {
+        let mut j = k >> 1;
+        while j > 0 {
+            for i in 0..n {
+                let l = i ^ j;
+                if l > i {
+                    let (left, right) = entries.split_at_mut(l);
+                    let (a, b) = (&mut left[i], &mut right[0]);
+                    let swap = if i & k == 0 { lt(b, a) } else { lt(a, b) };
+                    cswap(swap, a, b);
+                }
+            }
+            j >>= 1;
+        }
+        k <<= 1;
+    }
+}
+
+// central_keys: encoded_value_sizeごとに区切られたkey列(ChunkFormat::SortedKeys)
+pub fn intersect(
+    central_keys: &[u8],
+    encoded_value_size: usize,
+    query_buffer: &EncodedQueryBuffer,
+    result: &mut EncodedResultBuffer,
+) -> EnclaveResult<()> {
+    let query_key_count = query_buffer.queries.len()*QUERY_SIZE;
+    let central_key_count = central_keys.len() / encoded_value_size;
+    let n = (query_key_count + central_key_count).next_power_of_two();
+
+    let mut entries: Vec<Entry> = Vec::with_capacity(n);
+    for (query_index, query) in query_buffer.queries.iter().enumerate() {
+        for (point_index, key) in query.encoded_values(encoded_value_size).enumerate() {
+            entries.push(Entry::new(key, TAG_QUERY, query_index as u32, point_index as u32));
+        }
+    }
+    for key in central_keys.chunks_exact(encoded_value_size) {
+        entries.push(Entry::new(key, TAG_CENTRAL, INDEX_NONE, INDEX_NONE));
+    }
+    while entries.len() < n {
+        entries.push(Entry::dummy());
+    }
+
+    bitonic_sort(&mut entries, lt_by_key);
+
+    // 直前のcentral keyを条件付きで更新しながら全要素を1回ずつ見る
+    let mut current = [0_u8; MAX_ENCODEDVALUE_SIZE];
+    let mut has_current = 0_u8;
+    for entry in entries.iter_mut() {
+        let is_central = ct_eq_u8(entry.tag, TAG_CENTRAL);
+        let is_query = ct_eq_u8(entry.tag, TAG_QUERY);
+        entry.hit = is_query & has_current & ct_eq_key(&entry.key, &current);
+        cmov_key(is_central, &mut current, &entry.key);
+        has_current |= is_central;
+    }
+
+    // (query index, point index) 順に戻すとpointごとの位置が固定される
+    bitonic_sort(&mut entries, lt_by_position);
+    // 並びが元の位置と違えば，hitを別のpoint (dummy pointも) に付けてしまうので何も書かない
+    let mut misplaced = 0_u8;
+    for position in 0..query_key_count {
+        let entry = &entries[position];
+        misplaced |= 1 ^ (ct_eq_u32(entry.query_index, (position / QUERY_SIZE) as u32) & ct_eq_u32(entry.point_index, (position % QUERY_SIZE) as u32));
+    }
+    if misplaced == 1 {
+        return Err(EnclaveError::Internal);
+    }
+    for position in 0..query_key_count {
+        result.or_hit(position / QUERY_SIZE, position % QUERY_SIZE, entries[position].hit);
+    }
+    Ok(())
+}
@@ -0,0 +1,143 @@
+use sgx_types::*;
+use std::vec::Vec;
+use std::mem;
+use constant::*;
+use constant_time::*;
+use path_oram::{PathOram, Cmov};
+use encoded_result_buffer::EncodedResultBuffer;
+use encoded_query_buffer::EncodedQueryBuffer;
+
+/*
+ORAM Dictionary
+    ソート済みのcentral keyから静的なB+木を作り，各nodeをPath ORAMの1 blockにする
+    lookupは根から葉まで必ずheight回ORAMを読むので，どのnodeを通ったかは隠れる
+    node内はconstant-timeに全slotを見る
+    1 lookupあたりheight回のORAM access，1 accessはposition mapの O(log N) 段それぞれで O((stash + path)^2) blockのコピー
+    chunkのkeyがbatchのquery point数 (clients * QUERY_SIZE) の約140倍以上のときだけoblivious_joinより安い (README)
+    stashが溢れたdictionaryはそれ以降のlookupがすべて失敗する (chunkを送り直せば作り直す)
+    一致判定はexact matchのみ(nfpの近傍探索はしない)
+*/
+pub const NODE_FANOUT: usize = 8;
+
+// 葉ではkeys[..len]がcentral key，内部nodeではkeys[i]がchildren[i]の最小key
+#[derive(Clone, Copy)]
+pub struct OramNode {
+    keys: [[u8; MAX_ENCODEDVALUE_SIZE]; NODE_FANOUT],
+    children: [u32; NODE_FANOUT],
+    len: u32,
+}
+
+impl Default for OramNode {
+    fn default() -> Self {
+        OramNode {
+            keys: [[0_u8; MAX_ENCODEDVALUE_SIZE]; NODE_FANOUT],
+            children: [0_u32; NODE_FANOUT],
+            len: 0,
+        }
+    }
+}
+
+impl Cmov for OramNode {
+    fn cmov(&mut self, cond: u8, other: &Self) {
+        for i in 0..NODE_FANOUT {
+            cmov_key(cond, &mut self.keys[i], &other.keys[i]);
+            cmov_u32(cond, &mut self.children[i], other.children[i]);
+        }
+        cmov_u32(cond, &mut self.len, other.len);
+    }
+}
+
+pub struct OramDictionary {
+    oram: PathOram<OramNode>,
+    root: u32,
+    // 根から葉までのnode数
+    height: usize,
+}
+
+impl OramDictionary {
+    pub fn build_dictionary_buffer(
+        bytes: &[u8],
+        encoded_value_size: usize,
+    ) -> SgxResult<Self> {
+        let mut nodes: Vec<OramNode> = Vec::new();
+
+        // leaves: (node id, 最小key)
+        let mut level: Vec<(u32, [u8; MAX_ENCODEDVALUE_SIZE])> = Vec::new();
+        let keys: Vec<[u8; MAX_ENCODEDVALUE_SIZE]> = bytes.chunks_exact(encoded_value_size).map(padded_key).collect();
+        for group in keys.chunks(NODE_FANOUT) {
+            let mut node = OramNode::default();
+            node.keys[..group.len()].copy_from_slice(group);
+            node.len = group.len() as u32;
+            level.push((nodes.len() as u32, group[0]));
+            nodes.push(node);
+        }
+        if level.is_empty() {
+            level.push((0, [0_u8; MAX_ENCODEDVALUE_SIZE]));
+            nodes.push(OramNode::default());
+        }
+
+        let mut height = 1;
+        while level.len() > 1 {
+            let mut upper: Vec<(u32, [u8; MAX_ENCODEDVALUE_SIZE])> = Vec::new();
+            for group in level.chunks(NODE_FANOUT) {
+                let mut node = OramNode::default();
+                for (i, (child, min_key)) in group.iter().enumerate() {
+                    node.keys[i] = *min_key;
+                    node.children[i] = *child;
+                }
+                node.len = group.len() as u32;
+                upper.push((nodes.len() as u32, group[0].1));
+                nodes.push(node);
+            }
+            level = upper;
+            height += 1;
+        }
+
+        Ok(Self { root: level[0].0, height, oram: PathOram::new(nodes)? })
+    }
+
+    // 1 or 0
+    pub fn contains(&mut self, encoded_value: &[u8]) -> SgxResult<u8> {
+        let key = padded_key(encoded_value);
+        let mut id = self.root;
+        let mut found = 0_u8;
+        for depth in 0..self.height {
+            let node = self.oram.read(id)?;
+            if depth + 1 < self.height {
+                // 最小keyが key 以下の最後のchild
+                let mut child = node.children[0];
+                for i in 0..NODE_FANOUT {
+                    let valid = ct_lt_u32(i as u32, node.len);
+                    let le = 1 ^ ct_lt_key(&key, &node.keys[i]);
+                    cmov_u32(valid & le, &mut child, node.children[i]);
+                }
+                id = child;
+            } else {
+                for i in 0..NODE_FANOUT {
+                    found |= ct_lt_u32(i as u32, node.len) & ct_eq_key(&node.keys[i], &key);
+                }
+            }
+        }
+        Ok(found)
+    }
+
+    // positiveになったqueryも飛ばさない
+    pub fn intersect(&mut self, query_buffer: &EncodedQueryBuffer, result: &mut EncodedResultBuffer) -> SgxError {
+        for (query_index, query) in query_buffer.queries.iter().enumerate() {
+            for (point_index, key) in query.encoded_values(query_buffer.encoded_value_size).enumerate() {
+                result.or_hit(query_index, point_index, self.contains(key)?);
+            }
+        }
+        Ok(())
+    }
+
+    // central key 1つあたりのheap，葉は1 nodeにNODE_FANOUT個で内部nodeは多くても同じ数
+    // 構築中はnodeのVecとORAMが両方ある
+    pub fn key_cost() -> usize {
+        2*(PathOram::<OramNode>::bytes_per_block() + mem::size_of::<OramNode>()) / NODE_FANOUT + 1
+    }
+
+    pub fn calc_memory(&self) {
+        println!("[ORAM] r_i size = {} bytes ({} blocks)", self.oram.byte_size(), self.oram.block_count());
+    }
+}
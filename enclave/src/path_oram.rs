@@ -0,0 +1,278 @@
+use sgx_types::*;
+use sgx_trts::trts::rsgx_read_rand;
+use std::vec::Vec;
+use std::mem;
+use std::ptr;
+use constant_time::*;
+
+/*
+Path ORAM
+    blockはbucket(Z個のblock)の完全二分木に置かれ，各blockはランダムなleafへのpath上にある
+    readではposition mapからleafを引いてpath 1本とstashを全部読み，
+    blockを新しいランダムなleafに付け替えてpathに書き戻す
+    hostから見えるのはランダムなpathだけで，どのblockを読んだかはわからない
+    tree, stash, position mapはすべてenclaveのheapに置く
+    stashはconstant-timeに全体をscanし，position mapは小さいPath ORAMに入れる (PositionMap)
+    1 accessは O(log N) 段の O((stash + path)^2) blockのコピー
+*/
+const BUCKET_SIZE: usize = 4;
+const STASH_SIZE: usize = 32;
+const DUMMY_ID: u32 = u32::max_value();
+// position mapの1 blockに入るleafの数
+const POSITIONS_PER_BLOCK: usize = 16;
+// これ以下のblock数ならposition mapは配列のまま全体をscanする
+const MAX_FLAT_POSITIONS: usize = 1024;
+
+// blockの中身，条件付きコピーだけできればよい
+pub trait Cmov: Copy + Default {
+    fn cmov(&mut self, cond: u8, other: &Self);
+}
+
+#[derive(Clone, Copy, Default)]
+struct Block<T> {
+    id: u32,
+    leaf: u32,
+    data: T,
+}
+
+impl<T: Cmov> Block<T> {
+    fn dummy() -> Self {
+        Block { id: DUMMY_ID, leaf: 0, data: T::default() }
+    }
+
+    fn cmov(&mut self, cond: u8, other: &Self) {
+        cmov_u32(cond, &mut self.id, other.id);
+        cmov_u32(cond, &mut self.leaf, other.leaf);
+        self.data.cmov(cond, &other.data);
+    }
+}
+
+type PositionBlock = [u32; POSITIONS_PER_BLOCK];
+
+impl Cmov for PositionBlock {
+    fn cmov(&mut self, cond: u8, other: &Self) {
+        for (position, other) in self.iter_mut().zip(other.iter()) {
+            cmov_u32(cond, position, *other);
+        }
+    }
+}
+
+/*
+Position map
+    blockごとのleaf，小さいときは配列を全体scanし，
+    大きいときはPOSITIONS_PER_BLOCK個ずつ1 blockにしてPath ORAMに入れる (recursive position map)
+    1段ごとにblock数が1/POSITIONS_PER_BLOCKになるので，段数は O(log N)
+*/
+enum PositionMap {
+    Flat(Vec<u32>),
+    Oram(Box<PathOram<PositionBlock>>),
+}
+
+impl PositionMap {
+    fn new(leaves: Vec<u32>) -> SgxResult<Self> {
+        if leaves.len() <= MAX_FLAT_POSITIONS {
+            return Ok(PositionMap::Flat(leaves));
+        }
+        let blocks: Vec<PositionBlock> = leaves.chunks(POSITIONS_PER_BLOCK).map(|chunk| {
+            let mut block = [0_u32; POSITIONS_PER_BLOCK];
+            block[..chunk.len()].copy_from_slice(chunk);
+            block
+        }).collect();
+        Ok(PositionMap::Oram(Box::new(PathOram::new(blocks)?)))
+    }
+
+    // idのleafをnew_leafに替えて前のleafを返す
+    fn swap(&mut self, id: u32, new_leaf: u32) -> SgxResult<u32> {
+        match *self {
+            PositionMap::Flat(ref mut positions) => {
+                let mut leaf = 0_u32;
+                for (i, position) in positions.iter_mut().enumerate() {
+                    let hit = ct_eq_u32(i as u32, id);
+                    cmov_u32(hit, &mut leaf, *position);
+                    cmov_u32(hit, position, new_leaf);
+                }
+                Ok(leaf)
+            },
+            PositionMap::Oram(ref mut oram) => {
+                let offset = id % POSITIONS_PER_BLOCK as u32;
+                let block = oram.access(id / POSITIONS_PER_BLOCK as u32, |block: &mut PositionBlock| {
+                    for (i, position) in block.iter_mut().enumerate() {
+                        cmov_u32(ct_eq_u32(i as u32, offset), position, new_leaf);
+                    }
+                })?;
+                let mut leaf = 0_u32;
+                for (i, position) in block.iter().enumerate() {
+                    cmov_u32(ct_eq_u32(i as u32, offset), &mut leaf, *position);
+                }
+                Ok(leaf)
+            },
+        }
+    }
+
+    fn byte_size(&self) -> usize {
+        match *self {
+            PositionMap::Flat(ref positions) => positions.len()*4,
+            PositionMap::Oram(ref oram) => oram.byte_size(),
+        }
+    }
+}
+
+pub struct PathOram<T: Cmov> {
+    // leafの数は 1 << depth
+    depth: usize,
+    tree: Vec<Block<T>>,
+    stash: Vec<Block<T>>,
+    position: PositionMap,
+    block_count: usize,
+    // stashが溢れるとtreeとposition mapが合わなくなるので，以降のaccessはすべて失敗させる
+    overflowed: bool,
+}
+
+impl<T: Cmov> PathOram<T> {
+    // blocks[i] のidは i
+    pub fn new(blocks: Vec<T>) -> SgxResult<Self> {
+        let block_count = blocks.len();
+        let depth = block_count.max(1).next_power_of_two().trailing_zeros() as usize;
+        let bucket_count = (2 << depth) - 1;
+        let mut oram = PathOram {
+            depth,
+            tree: vec![Block::dummy(); bucket_count*BUCKET_SIZE],
+            stash: vec![Block::dummy(); STASH_SIZE],
+            position: PositionMap::Flat(vec![]),
+            block_count,
+            overflowed: false,
+        };
+        let mut leaves: Vec<u32> = Vec::with_capacity(block_count);
+        for (id, data) in blocks.into_iter().enumerate() {
+            let leaf = oram.random_leaf()?;
+            leaves.push(leaf);
+            // 構築はcentral dataだけに依存するので普通に空きを探して置く
+            if !oram.place(Block { id: id as u32, leaf, data }) {
+                return Err(sgx_status_t::SGX_ERROR_OUT_OF_MEMORY);
+            }
+        }
+        oram.position = PositionMap::new(leaves)?;
+        Ok(oram)
+    }
+
+    pub fn block_count(&self) -> usize {
+        self.block_count
+    }
+
+    pub fn byte_size(&self) -> usize {
+        (self.tree.len() + self.stash.len())*mem::size_of::<Block<T>>() + self.position.byte_size()
+    }
+
+    // block 1つあたりのtreeとposition map，leafは2の冪に切り上げるのでbucketはblock数の最大4倍
+    // position mapの段はPOSITIONS_PER_BLOCK分の1ずつ小さくなるので，全段で1段目の2倍までに収まる
+    pub fn bytes_per_block() -> usize {
+        4*BUCKET_SIZE*mem::size_of::<Block<T>>() + 2*4*BUCKET_SIZE*mem::size_of::<Block<PositionBlock>>()/POSITIONS_PER_BLOCK + 4
+    }
+
+    fn random_leaf(&self) -> SgxResult<u32> {
+        let mut bytes = [0_u8; 4];
+        rsgx_read_rand(&mut bytes)?;
+        Ok(u32::from_le_bytes(bytes) & ((1_u32 << self.depth) - 1))
+    }
+
+    fn bucket_index(&self, leaf: u32, level: usize) -> usize {
+        (1 << level) - 1 + ((leaf as usize) >> (self.depth - level))
+    }
+
+    fn place(&mut self, block: Block<T>) -> bool {
+        for level in (0..=self.depth).rev() {
+            let bucket = self.bucket_index(block.leaf, level);
+            for slot in bucket*BUCKET_SIZE..(bucket+1)*BUCKET_SIZE {
+                if self.tree[slot].id == DUMMY_ID {
+                    self.tree[slot] = block;
+                    return true;
+                }
+            }
+        }
+        for slot in self.stash.iter_mut() {
+            if slot.id == DUMMY_ID {
+                *slot = block;
+                return true;
+            }
+        }
+        false
+    }
+
+    pub fn read(&mut self, id: u32) -> SgxResult<T> {
+        self.access(id, |_| {})
+    }
+
+    // idのblockを返し，updateで書き替える．updateはどのblockにも同じように呼ぶ
+    fn access<F: Fn(&mut T)>(&mut self, id: u32, update: F) -> SgxResult<T> {
+        if self.overflowed {
+            return Err(sgx_status_t::SGX_ERROR_UNEXPECTED);
+        }
+        let new_leaf = self.random_leaf()?;
+        let leaf = self.position.swap(id, new_leaf)?;
+
+        // stash + leafへのpath
+        let mut working: Vec<Block<T>> = Vec::with_capacity(STASH_SIZE + (self.depth + 1)*BUCKET_SIZE);
+        working.extend_from_slice(&self.stash);
+        for level in 0..=self.depth {
+            let bucket = self.bucket_index(leaf, level);
+            working.extend_from_slice(&self.tree[bucket*BUCKET_SIZE..(bucket+1)*BUCKET_SIZE]);
+        }
+
+        let mut data = T::default();
+        for block in working.iter_mut() {
+            let hit = ct_eq_u32(block.id, id);
+            data.cmov(hit, &block.data);
+            let mut updated = block.data;
+            update(&mut updated);
+            block.data.cmov(hit, &updated);
+            cmov_u32(hit, &mut block.leaf, new_leaf);
+        }
+
+        // 深いbucketから詰めて書き戻す，各slotでworking全体をscanする
+        for level in (0..=self.depth).rev() {
+            let shift = self.depth - level;
+            let bucket = self.bucket_index(leaf, level);
+            for slot in bucket*BUCKET_SIZE..(bucket+1)*BUCKET_SIZE {
+                self.tree[slot] = take_block(&mut working, |block| ct_eq_u32(block.leaf >> shift, leaf >> shift));
+            }
+        }
+        // 残りはstashへ
+        for slot in 0..STASH_SIZE {
+            self.stash[slot] = take_block(&mut working, |_| 1);
+        }
+
+        let mut overflow = 0_u8;
+        for block in working.iter() {
+            overflow |= 1 ^ ct_eq_u32(block.id, DUMMY_ID);
+        }
+        // 溢れたblockはどこにも書き戻されていない，このORAM (を使うdictionary) は作り直すまで使えない
+        if overflow == 1 {
+            println!("[SGX] ORAM stash overflow, the ORAM is no longer usable");
+            self.overflowed = true;
+            return Err(sgx_status_t::SGX_ERROR_UNEXPECTED);
+        }
+        Ok(data)
+    }
+}
+
+// treeとstashのblockはcentral dataそのもの (dictionaryのnode) なので，freeの前に消す
+impl<T: Cmov> Drop for PathOram<T> {
+    fn drop(&mut self) {
+        for block in self.tree.iter_mut().chain(self.stash.iter_mut()) {
+            unsafe { ptr::write_volatile(block, Block::dummy()) };
+        }
+    }
+}
+
+// eligibleな最初の実blockを取り出す，workingのすべての要素に同じ操作をする
+fn take_block<T: Cmov, F: Fn(&Block<T>) -> u8>(working: &mut Vec<Block<T>>, eligible: F) -> Block<T> {
+    let mut taken = Block::dummy();
+    let mut found = 0_u8;
+    for block in working.iter_mut() {
+        let take = (1 ^ ct_eq_u32(block.id, DUMMY_ID)) & eligible(block) & (1 ^ found);
+        taken.cmov(take, block);
+        cmov_u32(take, &mut block.id, DUMMY_ID);
+        found |= take;
+    }
+    taken
+}
@@ -41,8 +41,13 @@ impl Period {
         period_vec
     }
 
+    pub fn end(&self) -> UnixEpoch {
+        self.1
+    }
+
     // period - CONTACT_TIME_THREASHOLD < unixepoch < period + CONTACT_TIME_THREASHOLD
+    // startがCONTACT_TIME_THREASHOLDより小さくてもunderflowしないように左辺を移項する
     pub fn is_include(&self, unixepoch: UnixEpoch) -> bool {
-        self.0 - CONTACT_TIME_THREASHOLD < unixepoch && unixepoch < self.1 + CONTACT_TIME_THREASHOLD
+        self.0 < unixepoch.saturating_add(CONTACT_TIME_THREASHOLD) && unixepoch < self.1.saturating_add(CONTACT_TIME_THREASHOLD)
     }
 }
\ No newline at end of file
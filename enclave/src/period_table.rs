@@ -0,0 +1,136 @@
+use std::vec::Vec;
+use std::mem;
+use std::ptr;
+use std::cmp::Ordering;
+use constant::*;
+use primitive::UnixEpoch;
+use period::Period;
+use utils::{_sorted_merge, zeroize};
+use encoded_query_buffer::EncodedQueryBuffer;
+use encoded_result_buffer::EncodedResultBuffer;
+use enclave_error::{EnclaveError, EnclaveResult};
+
+/*
+Type PeriodTable
+    interval mode (ChunkFormat::Visits) のchunkをdecodeしたもの
+    locationごとに患者の訪問時刻をTIME_INTERVALでつないだPeriodの列を持ち，locationの昇順に並べる
+    queryの (location, 時刻) は同じlocationのどれかのperiodの前後CONTACT_TIME_THREASHOLDに入ればpositive
+    時刻の丸めたbucketの一致ではなく，間隔で判定する
+*/
+#[derive(Default)]
+pub struct PeriodTable {
+    locations: Vec<Vec<u8>>,
+    // periods[i]: locations[i]の訪問period
+    periods: Vec<Vec<Period>>,
+}
+
+fn _read_u64(bytes: &[u8], cursor: &mut usize) -> EnclaveResult<u64> {
+    let end = cursor.checked_add(8).ok_or(EnclaveError::MalformedChunk)?;
+    if end > bytes.len() {
+        return Err(EnclaveError::MalformedChunk);
+    }
+    let mut value_bytes = [0_u8; 8];
+    value_bytes.copy_from_slice(&bytes[*cursor..end]);
+    *cursor = end;
+    Ok(u64::from_be_bytes(value_bytes))
+}
+
+// chunkのencoded_value_size byteあたりのdecodeしたtableの大きさの上限
+// 時刻(8byte)ごとにdecode中の時刻とPeriodが1つずつ，location(少なくともencoded value + 16byte)ごとにlocationとVecのheader
+pub fn key_cost(encoded_value_size: usize) -> usize {
+    let per_unixepoch = (UNIXEPOCH_BE_SIZE_U8 + mem::size_of::<Period>()) * encoded_value_size / UNIXEPOCH_BE_SIZE_U8;
+    let location_header = mem::size_of::<Vec<u8>>() + mem::size_of::<Vec<UnixEpoch>>() + mem::size_of::<Vec<Period>>();
+    let per_location = (encoded_value_size + location_header) * encoded_value_size / (encoded_value_size + 2 * UNIXEPOCH_BE_SIZE_U8);
+    per_unixepoch + per_location + 1
+}
+
+impl PeriodTable {
+    // chunkの時刻は昇順，同じlocationが続けて何度か出てきたら時刻をmergeする
+    pub fn from_chunk(bytes: &[u8], encoded_value_size: usize) -> EnclaveResult<Self> {
+        let mut visits: Vec<(Vec<u8>, Vec<UnixEpoch>)> = Vec::new();
+        let mut cursor = 0;
+        while cursor < bytes.len() {
+            let location_end = cursor + encoded_value_size;
+            if location_end > bytes.len() {
+                return Err(EnclaveError::MalformedChunk);
+            }
+            let location = bytes[cursor..location_end].to_vec();
+            cursor = location_end;
+            let count = _read_u64(bytes, &mut cursor)? as usize;
+            if count == 0 || count > (bytes.len() - cursor) / UNIXEPOCH_BE_SIZE_U8 {
+                return Err(EnclaveError::MalformedChunk);
+            }
+            let mut unixepochs: Vec<UnixEpoch> = Vec::with_capacity(count);
+            for _ in 0..count {
+                let unixepoch = _read_u64(bytes, &mut cursor)?;
+                if unixepochs.last().map_or(false, |last| *last >= unixepoch) {
+                    return Err(EnclaveError::MalformedChunk);
+                }
+                unixepochs.push(unixepoch);
+            }
+
+            let merged = match visits.last_mut() {
+                Some(&mut (ref last_location, ref mut last_unixepochs)) if *last_location == location => {
+                    *last_unixepochs = _sorted_merge(last_unixepochs, &unixepochs);
+                    true
+                },
+                Some(&mut (ref last_location, _)) if *last_location > location => return Err(EnclaveError::MalformedChunk),
+                _ => false,
+            };
+            if !merged {
+                visits.push((location, unixepochs));
+            }
+        }
+
+        let mut this = PeriodTable::default();
+        for (location, unixepochs) in visits {
+            this.periods.push(Period::from_unixepoch_vector(&unixepochs));
+            this.locations.push(location);
+        }
+        Ok(this)
+    }
+
+    fn periods_of(&self, location: &[u8]) -> Option<&Vec<Period>> {
+        let (mut low, mut high) = (0, self.locations.len());
+        while low < high {
+            let middle = (low + high) / 2;
+            match self.locations[middle].as_slice().cmp(location) {
+                Ordering::Less => low = middle + 1,
+                Ordering::Greater => high = middle,
+                Ordering::Equal => return Some(&self.periods[middle]),
+            }
+        }
+        None
+    }
+
+    pub fn intersect(&self, query_buffer: &EncodedQueryBuffer, result: &mut EncodedResultBuffer) {
+        for (query_index, query) in query_buffer.queries.iter().enumerate() {
+            if result.is_settled(query_index) {
+                continue;
+            }
+            for (point_index, (location, unixepoch)) in query.timed_values(query_buffer.encoded_value_size).enumerate() {
+                let contact = self.periods_of(location).map_or(false, |periods| periods.iter().any(|period| period.is_include(unixepoch)));
+                if contact {
+                    result.set_positive(query_index, point_index);
+                    if !result.tracks_points() {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Drop for PeriodTable {
+    fn drop(&mut self) {
+        for location in self.locations.iter_mut() {
+            zeroize(location);
+        }
+        // 訪問時刻も患者のデータ
+        for periods in self.periods.iter_mut() {
+            for period in periods.iter_mut() {
+                unsafe { ptr::write_volatile(period, Period::default()) };
+            }
+        }
+    }
+}
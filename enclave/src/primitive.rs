@@ -11,4 +11,5 @@ pub fn unixepoch_from_u8(u_timestamp: [u8; UNIXEPOCH_U8_SIZE]) -> UnixEpoch {
 // バファリングするクエリはせいぜい10000なので64bitで余裕
 pub type QueryId = u64;
 
-pub type EncodedValue = [u8; ENCODEDVALUE_SIZE];
\ No newline at end of file
+// 幅はセッションごとに決まるのでスライスで扱う
+pub type EncodedValue = [u8];
\ No newline at end of file
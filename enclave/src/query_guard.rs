@@ -0,0 +1,183 @@
+use std::vec::Vec;
+use std::collections::{HashMap, HashSet};
+use succinct_trie::trie::TrajectoryHash;
+use constant::*;
+use encoded_query_rep::EncodedQueryRep;
+use chunk_header::is_trajectory_hash_size;
+use secure_channel::ClientIdentity;
+use enclave_error::{EnclaveError, EnclaveResult};
+
+/*
+Anti-probing
+    多くのcellを通る作ったqueryで患者の居場所を探られないように，append_queriesでclientのqueryを確かめる
+    - 経度緯度のcell (時刻は見ない) の種類はMAX_QUERY_DISTINCT_CELLSまで
+    - 続くpointの間の移動はtime step 1つあたりMAX_CELL_STEPS_PER_TIME_STEP cellまで，
+      生の点があるときはMAX_SPEED_METERS_PER_SECONDまで (瞬間移動するtrajectoryはreject)
+    - 認証されたclientのidentityごとにRATE_LIMIT_WINDOW_SECONDSあたりMAX_QUERIES_PER_WINDOW個まで
+    rejectしたqueryの結果は返さず，statusだけを暗号化して返す
+    clientが印を付けたdummy pointは移動にもcellにも数えない
+    cellと移動はTRAJECTORY_*のlayoutで戻すので，keyがTrajectoryHashでない (7byteでない) ときは見ない
+    そのときは生の点の速さとrate limitだけ
+*/
+
+fn _read_be_u128(key: &[u8]) -> u128 {
+    key.iter().fold(0u128, |value, byte| (value << 8) | *byte as u128)
+}
+
+// masksは下位bitから
+fn _coordinate(value: u128, masks: &[u128]) -> u64 {
+    masks.iter().enumerate().fold(0, |coordinate, (bit, mask)| {
+        if value & mask != 0 { coordinate | (1 << bit) } else { coordinate }
+    })
+}
+
+fn _distance(a: u64, b: u64) -> u64 {
+    if a > b { a - b } else { b - a }
+}
+
+// TrajectoryHashのkeyを (経度, 緯度, 時刻) に戻して，cellの種類と続くpointの間の移動を見る
+fn _is_plausible_trajectory(query: &EncodedQueryRep, encoded_value_size: usize) -> bool {
+    let th = TrajectoryHash::new(encoded_value_size, TRAJECTORY_GEO_LENGTH, TRAJECTORY_TIME_LENGTH);
+    let mut cells: HashSet<(u64, u64)> = HashSet::new();
+    let mut previous: Option<(u64, u64, u64)> = None;
+    let keys = query.encoded_values(encoded_value_size).enumerate()
+        .filter(|(point_index, _)| !query.is_dummy(*point_index))
+        .map(|(_, key)| key);
+    for key in keys {
+        let value = _read_be_u128(key);
+        let point = (_coordinate(value, &th.mask_lists[0]), _coordinate(value, &th.mask_lists[1]), _coordinate(value, &th.mask_lists[2]));
+        if let Some(previous) = previous {
+            let steps = _distance(point.0, previous.0).max(_distance(point.1, previous.1));
+            let time_steps = _distance(point.2, previous.2).max(1);
+            if steps > MAX_CELL_STEPS_PER_TIME_STEP*time_steps {
+                return false;
+            }
+        }
+        cells.insert((point.0, point.1));
+        if cells.len() > MAX_QUERY_DISTINCT_CELLS {
+            return false;
+        }
+        previous = Some(point);
+    }
+    true
+}
+
+// exact verificationの生の点で，続くpointの間の速さを見る
+fn _is_plausible_speed(query: &EncodedQueryRep) -> bool {
+    let points: Vec<usize> = (0..QUERY_SIZE).filter(|point_index| !query.is_dummy(*point_index)).collect();
+    points.windows(2).all(|pair| {
+        let (previous, point) = (query.raw_point(pair[0]), query.raw_point(pair[1]));
+        let seconds = _distance(point.unixepoch, previous.unixepoch).max(1) as f64;
+        point.distance_meters(&previous) <= MAX_SPEED_METERS_PER_SECOND*seconds
+    })
+}
+
+// interval modeのlocationはTrajectoryHashとは限らないので，locationの種類だけを見る
+fn _is_plausible_visits(query: &EncodedQueryRep, encoded_value_size: usize) -> bool {
+    let locations: HashSet<&[u8]> = query.timed_values(encoded_value_size).enumerate()
+        .filter(|(point_index, _)| !query.is_dummy(*point_index))
+        .map(|(_, (location, _))| location)
+        .collect();
+    locations.len() <= MAX_QUERY_DISTINCT_CELLS
+}
+
+pub fn is_plausible(query: &EncodedQueryRep, encoded_value_size: usize, with_time: bool) -> bool {
+    if with_time {
+        return _is_plausible_visits(query, encoded_value_size);
+    }
+    (!is_trajectory_hash_size(encoded_value_size) || _is_plausible_trajectory(query, encoded_value_size))
+        && (query.raw_points.is_empty() || _is_plausible_speed(query))
+}
+
+/*
+Type RateLimits
+    identityごとのwindowの始まりと，そのwindowで受け付けたqueryの数
+    enclaveの中に1つだけ持ち，hostにsealさせて再起動をまたぐ
+    sealしたcountを読めるのはqueryを数える前に1回だけ (動いているenclaveのcountを古いfileで戻させない)
+    再起動のときに古いfileを渡すrollbackは，trusted monotonic counterがないので防げない
+    時刻もhostのclockなので，rate limitはclientに対するもので，hostに対するものではない
+*/
+#[derive(Clone, Copy)]
+struct RateWindow {
+    start: u64,
+    count: u32,
+}
+
+#[derive(Default)]
+pub struct RateLimits {
+    windows: HashMap<ClientIdentity, RateWindow>,
+    // restoreしたか，queryを数えたか
+    in_use: bool,
+}
+
+impl RateLimits {
+    pub fn new() -> Self {
+        RateLimits::default()
+    }
+
+    // 受け付けられるならqueryを1つ数える，identityが多すぎるときは終わったwindowを捨てる
+    pub fn charge(&mut self, identity: &ClientIdentity, now: u64) -> bool {
+        self.in_use = true;
+        if !self.windows.contains_key(identity) && self.windows.len() >= MAX_RATE_LIMITED_CLIENTS {
+            self.windows.retain(|_, window| now < window.start.saturating_add(RATE_LIMIT_WINDOW_SECONDS));
+            if self.windows.len() >= MAX_RATE_LIMITED_CLIENTS {
+                return false;
+            }
+        }
+        let window = self.windows.entry(*identity).or_insert(RateWindow { start: now, count: 0 });
+        // clockが戻ったときはwindowを延ばさない
+        if now >= window.start.saturating_add(RATE_LIMIT_WINDOW_SECONDS) {
+            *window = RateWindow { start: now, count: 0 };
+        }
+        if window.count >= MAX_QUERIES_PER_WINDOW {
+            return false;
+        }
+        window.count += 1;
+        true
+    }
+
+    // entry count(8byte) + (identity + window start(8byte) + count(4byte)) * entry count
+    pub fn to_be_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::with_capacity(8 + self.windows.len()*RATE_LIMIT_ENTRY_SIZE_U8);
+        bytes.extend_from_slice(&(self.windows.len() as u64).to_be_bytes());
+        for (identity, window) in self.windows.iter() {
+            bytes.extend_from_slice(identity);
+            bytes.extend_from_slice(&window.start.to_be_bytes());
+            bytes.extend_from_slice(&window.count.to_be_bytes());
+        }
+        bytes
+    }
+
+    // sealしたcountに置き換える，enclaveの起動から1回だけで，queryを数える前に限る
+    pub fn restore(&mut self, bytes: &[u8]) -> EnclaveResult<()> {
+        if self.in_use {
+            return Err(EnclaveError::RateLimitsInUse);
+        }
+        *self = RateLimits::from_be_bytes(bytes)?;
+        self.in_use = true;
+        Ok(())
+    }
+
+    fn from_be_bytes(bytes: &[u8]) -> EnclaveResult<Self> {
+        if bytes.len() < 8 {
+            return Err(EnclaveError::UnsealFailed);
+        }
+        let mut count_bytes = [0_u8; 8];
+        count_bytes.copy_from_slice(&bytes[..8]);
+        let entry_count = u64::from_be_bytes(count_bytes) as usize;
+        if entry_count > MAX_RATE_LIMITED_CLIENTS || bytes.len() != 8 + entry_count*RATE_LIMIT_ENTRY_SIZE_U8 {
+            return Err(EnclaveError::UnsealFailed);
+        }
+        let mut rate_limits = RateLimits::new();
+        for entry in bytes[8..].chunks_exact(RATE_LIMIT_ENTRY_SIZE_U8) {
+            let mut identity: ClientIdentity = [0; CLIENT_IDENTITY_SIZE_U8];
+            identity.copy_from_slice(&entry[..CLIENT_IDENTITY_SIZE_U8]);
+            let mut start_bytes = [0_u8; 8];
+            start_bytes.copy_from_slice(&entry[CLIENT_IDENTITY_SIZE_U8..CLIENT_IDENTITY_SIZE_U8+8]);
+            let mut count_bytes = [0_u8; 4];
+            count_bytes.copy_from_slice(&entry[CLIENT_IDENTITY_SIZE_U8+8..]);
+            rate_limits.windows.insert(identity, RateWindow { start: u64::from_be_bytes(start_bytes), count: u32::from_be_bytes(count_bytes) });
+        }
+        Ok(rate_limits)
+    }
+}
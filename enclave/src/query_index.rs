@@ -0,0 +1,147 @@
+use std::mem;
+use std::vec::Vec;
+use constant::*;
+use constant_time::padded_key;
+use encoded_query_buffer::EncodedQueryBuffer;
+use encoded_result_buffer::EncodedResultBuffer;
+use utils::zeroize;
+#[cfg(feature = "nfp")]
+use succinct_trie::trie::TrajectoryHash;
+#[cfg(feature = "nfp")]
+use chunk_header::is_trajectory_hash_size;
+#[cfg(feature = "nfp")]
+use enclave_error::{EnclaveError, EnclaveResult};
+
+const EMPTY_SLOT: u32 = u32::max_value();
+
+/*
+Type QueryIndex
+    batchのquery keyの索引，distinct keyごとにそのkeyを持つquery pointの列を持つ
+    query pointはqueryのposition(query_buffer.queriesのindex) * QUERY_SIZE + pointの位置
+    keysは昇順なのでsort-mergeにもそのまま使い，hashのslotからkeysの位置を引く
+    JoinMode::Adaptiveのときfinish_uploadで1回だけ作る
+    nfpのDictionaryでは各keyをTrajectoryHashの近傍(EXTEND_NUMBER個)に広げた索引を作り，
+    chunkごとのaccurate_searchの代わりに近傍keyのmembershipだけを引く (build_neighbors)
+*/
+#[derive(Default)]
+pub struct QueryIndex {
+    // distinct keyの昇順，MAX_ENCODEDVALUE_SIZEまで0埋め
+    pub keys: Vec<[u8; MAX_ENCODEDVALUE_SIZE]>,
+    // postings[i]: keys[i]を持つquery point
+    pub postings: Vec<Vec<u32>>,
+    // open addressing，keysの位置かEMPTY_SLOT
+    slots: Vec<u32>,
+}
+
+// query key 1つあたりの索引の大きさ，buildのときのpairsと，keyとposting，2倍のslot
+pub fn key_cost() -> usize {
+    let pair = MAX_ENCODEDVALUE_SIZE + mem::size_of::<u32>();
+    let entry = MAX_ENCODEDVALUE_SIZE + mem::size_of::<Vec<u32>>() + mem::size_of::<u32>();
+    pair + entry + 2 * 2 * mem::size_of::<u32>()
+}
+
+impl QueryIndex {
+    pub fn build(query_buffer: &EncodedQueryBuffer) -> Self {
+        let mut pairs: Vec<([u8; MAX_ENCODEDVALUE_SIZE], u32)> = Vec::with_capacity(query_buffer.queries.len()*QUERY_SIZE);
+        for (query_index, query) in query_buffer.queries.iter().enumerate() {
+            for (point_index, key) in query.encoded_values(query_buffer.encoded_value_size).enumerate() {
+                pairs.push((padded_key(key), (query_index*QUERY_SIZE + point_index) as u32));
+            }
+        }
+        Self::from_pairs(pairs)
+    }
+
+    // 近傍keyから元のquery pointを引く，同じ近傍を持つpointはpostingにまとまる
+    // 近傍はTrajectoryHashのmaskで作るので，keyがそのlayoutでなければNotTrajectoryHash
+    #[cfg(feature = "nfp")]
+    pub fn build_neighbors(query_buffer: &EncodedQueryBuffer) -> EnclaveResult<Self> {
+        if !is_trajectory_hash_size(query_buffer.encoded_value_size) {
+            return Err(EnclaveError::NotTrajectoryHash);
+        }
+        let th = TrajectoryHash::new(query_buffer.encoded_value_size, TRAJECTORY_GEO_LENGTH, TRAJECTORY_TIME_LENGTH);
+        let mut pairs: Vec<([u8; MAX_ENCODEDVALUE_SIZE], u32)> = Vec::with_capacity(query_buffer.queries.len()*QUERY_SIZE*NFP_NEIGHBOR_COUNT);
+        for (query_index, query) in query_buffer.queries.iter().enumerate() {
+            for (point_index, key) in query.encoded_values(query_buffer.encoded_value_size).enumerate() {
+                for mut neighbor in th.get_neighbors(key) {
+                    pairs.push((padded_key(&neighbor), (query_index*QUERY_SIZE + point_index) as u32));
+                    zeroize(&mut neighbor);
+                }
+            }
+        }
+        Ok(Self::from_pairs(pairs))
+    }
+
+    fn from_pairs(mut pairs: Vec<([u8; MAX_ENCODEDVALUE_SIZE], u32)>) -> Self {
+        pairs.sort_unstable();
+        pairs.dedup();
+
+        let mut this = QueryIndex::default();
+        for (key, query_point) in pairs.iter() {
+            if this.keys.last() != Some(key) {
+                this.keys.push(*key);
+                this.postings.push(Vec::new());
+            }
+            if let Some(posting) = this.postings.last_mut() {
+                posting.push(*query_point);
+            }
+        }
+        for pair in pairs.iter_mut() {
+            zeroize(&mut pair.0);
+        }
+
+        this.slots = vec![EMPTY_SLOT; (this.keys.len()*2).next_power_of_two()];
+        for position in 0..this.keys.len() {
+            let mut slot = this.first_slot(&this.keys[position]);
+            while this.slots[slot] != EMPTY_SLOT {
+                slot = (slot + 1) & (this.slots.len() - 1);
+            }
+            this.slots[slot] = position as u32;
+        }
+        this
+    }
+
+    pub fn distinct_key_count(&self) -> usize {
+        self.keys.len()
+    }
+
+    // FNV-1a
+    fn first_slot(&self, key: &[u8; MAX_ENCODEDVALUE_SIZE]) -> usize {
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for byte in key.iter() {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        (hash as usize) & (self.slots.len() - 1)
+    }
+
+    // keysの位置
+    pub fn lookup(&self, key: &[u8; MAX_ENCODEDVALUE_SIZE]) -> Option<usize> {
+        let mut slot = self.first_slot(key);
+        loop {
+            let position = self.slots[slot];
+            if position == EMPTY_SLOT {
+                return None;
+            }
+            if self.keys[position as usize] == *key {
+                return Some(position as usize);
+            }
+            slot = (slot + 1) & (self.slots.len() - 1);
+        }
+    }
+
+    // keys[position]を持つquery pointを全部positiveにする
+    pub fn set_positive(&self, position: usize, result: &mut EncodedResultBuffer) {
+        for query_point in self.postings[position].iter() {
+            let query_point = *query_point as usize;
+            result.set_positive(query_point / QUERY_SIZE, query_point % QUERY_SIZE);
+        }
+    }
+}
+
+impl Drop for QueryIndex {
+    fn drop(&mut self) {
+        for key in self.keys.iter_mut() {
+            zeroize(key);
+        }
+    }
+}
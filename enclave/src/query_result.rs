@@ -1,6 +1,10 @@
 use primitive::QueryId;
 use constant::*;
 
+// anti-probingでrejectしたqueryにはrisk_levelの代わりにREJECTEDを返す
+pub const QUERY_STATUS_ANSWERED: u8 = 0;
+pub const QUERY_STATUS_REJECTED: u8 = 1;
+
 /* 
 Type QueryResult 
     バイトへのシリアライズを担当するよ
@@ -8,6 +12,7 @@ Type QueryResult
 #[derive(Clone, Default, Debug)]
 pub struct QueryResult {
     pub query_id: QueryId,
+    pub status: u8,
     pub risk_level: u8,
 }
 
@@ -15,6 +20,7 @@ impl QueryResult {
     pub fn new() -> Self {
         return QueryResult {
             query_id: 1,
+            status: QUERY_STATUS_ANSWERED,
             risk_level: 0,
         }
     }
@@ -22,6 +28,7 @@ impl QueryResult {
     pub fn to_be_bytes(&self) -> [u8; RESPONSE_DATA_SIZE_U8] {
         let mut res = [0; RESPONSE_DATA_SIZE_U8];
         res[..QUERY_ID_SIZE_U8].clone_from_slice(&self.query_id.to_be_bytes());
+        res[QUERY_ID_SIZE_U8] = self.status;
         res[RESPONSE_DATA_SIZE_U8-QUERY_RESULT_U8] = self.risk_level;
         res
     }
@@ -0,0 +1,63 @@
+use std::vec::Vec;
+use succinct_trie::trie::TrajectoryHash;
+use constant::*;
+use utils::zeroize;
+use contact_policy::Offset;
+use encoded_query_rep::EncodedQueryRep;
+use encoded_query_buffer::EncodedQueryBuffer;
+use chunk_header::is_trajectory_hash_size;
+use enclave_error::{EnclaveError, EnclaveResult};
+
+/*
+Query variants
+    finish_uploadでclientのqueryの各keyをTrajectoryHashの近傍にずらしたqueryを，variantごとに1つずつ作る
+    query bufferはvariantの順に並び，variant vのclient cは v * client数 + c の位置 (variant 0はclientのqueryそのもの)
+    chunkとの照合はclientのqueryと同じなので，どのjoin modeでもそのまま使え，
+    同じchunkのlookupを全部のpolicyで共有する
+    ずらすのはTrajectoryHashのmaskなので，keyがそのlayoutでなければNotTrajectoryHash
+*/
+pub fn expand(query_buffer: &mut EncodedQueryBuffer, variants: &[Offset]) -> EnclaveResult<()> {
+    let encoded_value_size = query_buffer.encoded_value_size;
+    if !is_trajectory_hash_size(encoded_value_size) {
+        return Err(EnclaveError::NotTrajectoryHash);
+    }
+    let th = TrajectoryHash::new(encoded_value_size, TRAJECTORY_GEO_LENGTH, TRAJECTORY_TIME_LENGTH);
+    let client_count = query_buffer.queries.len();
+    let mut shifted_queries: Vec<EncodedQueryRep> = Vec::with_capacity(client_count*(variants.len() - 1));
+    for offset in variants.iter().skip(1) {
+        for query in query_buffer.queries[..client_count].iter() {
+            let mut shifted = EncodedQueryRep::new(encoded_value_size);
+            shifted.id = query.id;
+            for key in query.encoded_values(encoded_value_size) {
+                let mut value_bytes = _shift(&th, _read_be_u128(key), *offset).to_be_bytes();
+                shifted.parameters.extend_from_slice(&value_bytes[16 - encoded_value_size..]);
+                zeroize(&mut value_bytes);
+            }
+            shifted_queries.push(shifted);
+        }
+    }
+    query_buffer.queries.extend(shifted_queries);
+    query_buffer.variant_count = variants.len();
+    Ok(())
+}
+
+fn _read_be_u128(key: &[u8]) -> u128 {
+    key.iter().fold(0u128, |value, byte| (value << 8) | *byte as u128)
+}
+
+// TrajectoryHash::calcは各次元1 stepなので，offsetの分だけ繰り返す
+// 時間が0のstepより前にはさかのぼらない (0ならそのまま)
+fn _shift(th: &TrajectoryHash, value: u128, offset: Offset) -> u128 {
+    let mut shifted = value;
+    for (dimension, steps) in offset.iter().enumerate() {
+        for _ in 0..steps.abs() {
+            if dimension == 2 && *steps < 0 && th.mask_lists[2].iter().all(|mask| shifted & mask == 0) {
+                break;
+            }
+            let mut position = [0; 3];
+            position[dimension] = steps.signum();
+            shifted = th.calc(shifted, position);
+        }
+    }
+    shifted
+}
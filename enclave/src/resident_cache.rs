@@ -0,0 +1,174 @@
+use sgx_types::*;
+use std::vec::Vec;
+use std::collections::HashMap;
+use oblivious_join;
+use join_strategy;
+use query_index::QueryIndex;
+use period_table::PeriodTable;
+use utils::zeroize;
+use chunk_manifest::ChunkManifest;
+use encoded_dictionary_buffer::EncodedDictionaryBuffer;
+use encoded_query_buffer::EncodedQueryBuffer;
+use encoded_result_buffer::EncodedResultBuffer;
+use enclave_error::{EnclaveError, EnclaveResult};
+
+/*
+Type ResidentCache
+    decodeしたchunkをbatchをまたいでenclaveに置いておく
+    manifestの全chunkがそろっていれば，query_residentはchunkを受け取らずにbatchに答える
+    capacity(byte)を超えるとき，chunkのECALLのheapが足りないときは最後に使ったのが一番古いchunkから捨てる
+    manifestのdigestと照合済みのchunkだけを入れる
+*/
+pub enum ResidentData {
+    Dictionary(EncodedDictionaryBuffer),
+    // oblivious/adaptive joinはkey列をそのまま使う
+    SortedKeys(Vec<u8>),
+    // interval mode
+    Periods(PeriodTable),
+}
+
+// Dictionary (trie, hash table, ORAM) とPeriodsはそれぞれの型のDropで消す
+impl Drop for ResidentData {
+    fn drop(&mut self) {
+        if let ResidentData::SortedKeys(ref mut keys) = *self {
+            zeroize(keys);
+        }
+    }
+}
+
+pub struct ResidentChunk {
+    digest: sgx_sha256_hash_t,
+    encoded_value_size: usize,
+    data: ResidentData,
+    bytes: usize,
+    last_used: u64,
+}
+
+impl ResidentChunk {
+    pub fn new(digest: sgx_sha256_hash_t, encoded_value_size: usize, data: ResidentData, bytes: usize) -> Self {
+        ResidentChunk { digest, encoded_value_size, data, bytes, last_used: 0 }
+    }
+
+    // query_indexがあるとき(JoinMode::Adaptive)はjoin_strategyで選ぶ，nfpのDictionaryでは近傍keyの索引
+    fn intersect(&mut self, query_buffer: &EncodedQueryBuffer, query_index: Option<&QueryIndex>, result: &mut EncodedResultBuffer) -> EnclaveResult<()> {
+        match (&mut self.data, query_index) {
+            (&mut ResidentData::Dictionary(ref mut dictionary_buffer), query_index) => dictionary_buffer.intersect(query_buffer, query_index, result),
+            (&mut ResidentData::SortedKeys(ref keys), Some(query_index)) => {
+                let strategy = join_strategy::choose(query_index.distinct_key_count(), keys.len() / self.encoded_value_size);
+                strategy.intersect(keys, self.encoded_value_size, query_index, result);
+                Ok(())
+            },
+            (&mut ResidentData::SortedKeys(ref keys), None) => {
+                oblivious_join::intersect(keys, self.encoded_value_size, query_buffer, result)
+            },
+            (&mut ResidentData::Periods(ref period_table), _) => {
+                period_table.intersect(query_buffer, result);
+                Ok(())
+            },
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct ResidentCache {
+    // 0のときは何も置かない
+    capacity: usize,
+    used: usize,
+    clock: u64,
+    chunks: HashMap<usize, ResidentChunk>,
+}
+
+impl ResidentCache {
+    pub fn new() -> Self {
+        ResidentCache::default()
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.used > self.capacity && self.evict_lru() {}
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub fn clear(&mut self) {
+        self.chunks.clear();
+        self.used = 0;
+    }
+
+    pub fn insert(&mut self, chunk_index: usize, mut chunk: ResidentChunk) {
+        if chunk.bytes > self.capacity {
+            return;
+        }
+        if let Some(old) = self.chunks.remove(&chunk_index) {
+            self.used -= old.bytes;
+        }
+        while self.used + chunk.bytes > self.capacity && self.evict_lru() {}
+        self.clock += 1;
+        chunk.last_used = self.clock;
+        self.used += chunk.bytes;
+        self.chunks.insert(chunk_index, chunk);
+    }
+
+    // 捨てるchunkがなければfalse
+    pub fn evict_lru(&mut self) -> bool {
+        let oldest = self.chunks.iter().min_by_key(|(_, chunk)| chunk.last_used).map(|(chunk_index, _)| *chunk_index);
+        match oldest.and_then(|chunk_index| self.chunks.remove(&chunk_index)) {
+            Some(chunk) => {
+                self.used -= chunk.bytes;
+                true
+            },
+            None => false,
+        }
+    }
+
+    // 新しいmanifestで同じ位置に同じdigestのchunkだけを残す
+    pub fn retain_manifest(&mut self, chunk_manifest: &ChunkManifest) {
+        let stale: Vec<usize> = self.chunks.iter()
+            .filter(|(chunk_index, chunk)| chunk_manifest.verify(**chunk_index, &chunk.digest).is_err())
+            .map(|(chunk_index, _)| *chunk_index)
+            .collect();
+        for chunk_index in stale {
+            if let Some(chunk) = self.chunks.remove(&chunk_index) {
+                self.used -= chunk.bytes;
+            }
+        }
+    }
+
+    pub fn covers(&self, chunk_manifest: &ChunkManifest, encoded_value_size: usize) -> bool {
+        (0..chunk_manifest.chunk_count()).all(|chunk_index| match self.chunks.get(&chunk_index) {
+            Some(chunk) => chunk.encoded_value_size == encoded_value_size && chunk_manifest.verify(chunk_index, &chunk.digest).is_ok(),
+            None => false,
+        })
+    }
+
+    // manifestの全chunkとintersectしてdigestを返す，1つでも欠けていればNotResident
+    pub fn intersect_all(
+        &mut self,
+        chunk_manifest: &ChunkManifest,
+        query_buffer: &EncodedQueryBuffer,
+        query_index: Option<&QueryIndex>,
+        result: &mut EncodedResultBuffer,
+    ) -> EnclaveResult<Vec<sgx_sha256_hash_t>> {
+        if !self.covers(chunk_manifest, query_buffer.encoded_value_size) {
+            return Err(EnclaveError::NotResident);
+        }
+        let mut digests: Vec<sgx_sha256_hash_t> = Vec::with_capacity(chunk_manifest.chunk_count());
+        for chunk_index in 0..chunk_manifest.chunk_count() {
+            self.clock += 1;
+            let clock = self.clock;
+            let chunk = self.chunks.get_mut(&chunk_index).ok_or(EnclaveError::NotResident)?;
+            chunk.last_used = clock;
+            if let Err(enclave_error) = chunk.intersect(query_buffer, query_index, result) {
+                // 使えなくなったdata (ORAMのstash overflow) は捨てて，次はhostのchunkから作り直す
+                if let Some(chunk) = self.chunks.remove(&chunk_index) {
+                    self.used -= chunk.bytes;
+                }
+                return Err(enclave_error);
+            }
+            digests.push(chunk.digest);
+        }
+        Ok(digests)
+    }
+}
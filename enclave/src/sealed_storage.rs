@@ -0,0 +1,81 @@
+use sgx_types::*;
+use sgx_tseal::SgxSealedData;
+use std::vec::Vec;
+use constant::*;
+use enclave_error::{EnclaveError, EnclaveResult};
+
+/* 
+Sealed storage
+    復号済みのcentral data chunkとmanifestをsealしてhostに保存させる
+    enclaveを再起動してもdata ownerの再アップロードなしで再開できる
+    anti-probingのclientごとのquery数 (RateLimits) も同じようにsealする
+    additional text: version(4byte) + kind(4byte) + chunk index(8byte)
+    key policyはMRENCLAVE(同じenclaveのみ)かMRSIGNER(同じ署名者のenclave)
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SealedKind {
+    Manifest = 1,
+    Chunk = 2,
+    RateLimits = 3,
+}
+
+pub fn is_valid_key_policy(key_policy: u16) -> bool {
+    key_policy == SGX_KEYPOLICY_MRENCLAVE || key_policy == SGX_KEYPOLICY_MRSIGNER
+}
+
+fn additional_text(kind: SealedKind, index: u64) -> [u8; SEALED_AAD_SIZE_U8] {
+    let mut text = [0_u8; SEALED_AAD_SIZE_U8];
+    text[..4].copy_from_slice(&SEALED_FORMAT_VERSION.to_be_bytes());
+    text[4..8].copy_from_slice(&(kind as u32).to_be_bytes());
+    text[8..].copy_from_slice(&index.to_be_bytes());
+    text
+}
+
+pub fn sealed_size(plaintext_size: usize) -> usize {
+    SgxSealedData::<[u8]>::calc_raw_sealed_data_size(SEALED_AAD_SIZE_U8 as u32, plaintext_size as u32) as usize
+}
+
+// returns the byte length written to sealed
+pub fn seal(kind: SealedKind, index: u64, key_policy: u16, plaintext: &[u8], sealed: &mut [u8]) -> EnclaveResult<usize> {
+    if !is_valid_key_policy(key_policy) {
+        return Err(EnclaveError::InvalidKeyPolicy);
+    }
+    let size = sealed_size(plaintext.len());
+    if size == u32::max_value() as usize || sealed.len() < size {
+        return Err(EnclaveError::InvalidLength);
+    }
+    let attribute_mask = sgx_attributes_t { flags: TSEAL_DEFAULT_FLAGSMASK, xfrm: 0 };
+    let sealed_data = SgxSealedData::<[u8]>::seal_data_ex(
+        key_policy,
+        attribute_mask,
+        TSEAL_DEFAULT_MISCMASK,
+        &additional_text(kind, index),
+        plaintext,
+    )?;
+    let ret = unsafe {
+        sealed_data.to_raw_sealed_data_t(sealed.as_mut_ptr() as *mut sgx_sealed_data_t, size as u32)
+    };
+    match ret {
+        Some(_) => Ok(size),
+        None => Err(EnclaveError::Internal),
+    }
+}
+
+// MRENCLAVE/MRSIGNERが一致しなければunsealに失敗する
+pub fn unseal(kind: SealedKind, index: u64, sealed: &mut [u8]) -> EnclaveResult<Vec<u8>> {
+    let sealed_data = match unsafe {
+        SgxSealedData::<[u8]>::from_raw_sealed_data_t(sealed.as_mut_ptr() as *mut sgx_sealed_data_t, sealed.len() as u32)
+    } {
+        Some(sealed_data) => sealed_data,
+        None => { return Err(EnclaveError::UnsealFailed); }
+    };
+    if !is_valid_key_policy(sealed_data.get_key_request().key_policy) {
+        return Err(EnclaveError::UnsealFailed);
+    }
+    let unsealed_data = sealed_data.unseal_data().map_err(|_| EnclaveError::UnsealFailed)?;
+    // old version or another slot
+    if unsealed_data.get_additional_txt() != &additional_text(kind, index)[..] {
+        return Err(EnclaveError::UnsealFailed);
+    }
+    Ok(unsealed_data.get_decrypt_txt().to_vec())
+}
@@ -0,0 +1,250 @@
+use sgx_types::*;
+use sgx_tcrypto::*;
+use sgx_tse::rsgx_create_report;
+use sgx_trts::trts::rsgx_read_rand;
+use std::vec::Vec;
+use constant::*;
+use utils::zeroize;
+use enclave_error::{EnclaveError, EnclaveResult};
+
+pub type SessionKey = sgx_aes_gcm_128bit_key_t;
+pub type SessionId = u64;
+
+/* 
+Type SecureChannel
+    key exchangeで確立したsession keyとsession id
+    session idはenclaveが生成し，AADとして全メッセージに含める
+*/
+#[derive(Clone, Default, Debug)]
+pub struct SecureChannel {
+    pub session_id: SessionId,
+    pub key: SessionKey,
+}
+
+// clientのkeyはsessionを閉じたとき，data ownerのkeyは次のkey exchangeで消える
+impl Drop for SecureChannel {
+    fn drop(&mut self) {
+        zeroize(&mut self.key);
+    }
+}
+
+/* 
+Type KeyExchangeResponse
+    enclaveからclientへ返すもの
+    wrapped_key = iv(12byte) + encrypted session key(16byte) + mac(16byte)
+*/
+pub struct KeyExchangeResponse {
+    pub enclave_public_key: sgx_ec256_public_t,
+    pub report: sgx_report_t,
+    pub wrapped_key: [u8; WRAPPED_KEY_SIZE_U8],
+}
+
+/*
+    message format (AES-GCM): iv(12byte) + ciphertext + mac(16byte)
+    iv is random for every message, AAD = session id(8byte) + chunk index or query id(8byte)
+*/
+pub fn message_aad(session_id: SessionId, id: u64) -> [u8; MESSAGE_AAD_SIZE_U8] {
+    let mut aad = [0_u8; MESSAGE_AAD_SIZE_U8];
+    aad[..8].copy_from_slice(&session_id.to_be_bytes());
+    aad[8..].copy_from_slice(&id.to_be_bytes());
+    aad
+}
+
+// tampered (or wrongly bound) messages are rejected with DecryptionFailed
+pub fn decrypt_message(key: &SessionKey, message: &[u8], aad: &[u8], plaintext: &mut [u8]) -> EnclaveResult<()> {
+    if message.len() != plaintext.len() + MESSAGE_OVERHEAD_U8 {
+        return Err(EnclaveError::InvalidLength);
+    }
+    let ciphertext_end = message.len() - SGX_AESGCM_MAC_SIZE;
+    let mut mac: sgx_aes_gcm_128bit_tag_t = [0; SGX_AESGCM_MAC_SIZE];
+    mac.copy_from_slice(&message[ciphertext_end..]);
+    rsgx_rijndael128GCM_decrypt(
+        key,
+        &message[SGX_AESGCM_IV_SIZE..ciphertext_end],
+        &message[..SGX_AESGCM_IV_SIZE],
+        aad,
+        &mac,
+        plaintext,
+    ).map_err(|_| EnclaveError::DecryptionFailed)
+}
+
+pub fn encrypt_message(key: &SessionKey, plaintext: &[u8], aad: &[u8], message: &mut [u8]) -> EnclaveResult<()> {
+    if message.len() != plaintext.len() + MESSAGE_OVERHEAD_U8 {
+        return Err(EnclaveError::InvalidLength);
+    }
+    let ciphertext_end = message.len() - SGX_AESGCM_MAC_SIZE;
+    let mut iv = [0_u8; SGX_AESGCM_IV_SIZE];
+    rsgx_read_rand(&mut iv)?;
+    let mut mac: sgx_aes_gcm_128bit_tag_t = [0; SGX_AESGCM_MAC_SIZE];
+    rsgx_rijndael128GCM_encrypt(
+        key,
+        plaintext,
+        &iv,
+        aad,
+        &mut message[SGX_AESGCM_IV_SIZE..ciphertext_end],
+        &mut mac,
+    )?;
+    message[..SGX_AESGCM_IV_SIZE].copy_from_slice(&iv);
+    message[ciphertext_end..].copy_from_slice(&mac);
+    Ok(())
+}
+
+// ECDH (P-256) with the client's ephemeral key, then an enclave generated session key
+// is wrapped under the derived key encryption key. The report binds the whole transcript.
+// ephemeral private key，DHの共有秘密，KEKは成功しても失敗しても返る前にゼロ埋めする
+pub fn key_exchange(
+    client_public_key: &sgx_ec256_public_t,
+    target_info: &sgx_target_info_t,
+) -> SgxResult<(SecureChannel, KeyExchangeResponse)> {
+    let ecc_handle = SgxEccHandle::new();
+    ecc_handle.open()?;
+    let (mut private_key, enclave_public_key) = ecc_handle.create_key_pair()?;
+    let shared_key = ecc_handle.compute_shared_dhkey(&private_key, client_public_key);
+    zeroize(&mut private_key.r);
+    let mut shared_key = shared_key?;
+    let key_encryption_key = ecc_handle.close().and_then(|_| derive_key_encryption_key(&shared_key));
+    zeroize(&mut shared_key.s);
+    let mut key_encryption_key = key_encryption_key?;
+
+    let wrapped = _wrap_session_key(&key_encryption_key, client_public_key, enclave_public_key, target_info);
+    zeroize(&mut key_encryption_key);
+    wrapped
+}
+
+fn _wrap_session_key(
+    key_encryption_key: &sgx_key_128bit_t,
+    client_public_key: &sgx_ec256_public_t,
+    enclave_public_key: sgx_ec256_public_t,
+    target_info: &sgx_target_info_t,
+) -> SgxResult<(SecureChannel, KeyExchangeResponse)> {
+    // session keyは最初からchannelに置くので，途中で失敗してもdropで消える
+    let mut channel = SecureChannel::default();
+    rsgx_read_rand(&mut channel.key)?;
+    let mut session_id_bytes = [0_u8; 8];
+    rsgx_read_rand(&mut session_id_bytes)?;
+    channel.session_id = u64::from_be_bytes(session_id_bytes);
+
+    let transcript = key_exchange_transcript(client_public_key, &enclave_public_key);
+    let mut iv = [0_u8; SGX_AESGCM_IV_SIZE];
+    rsgx_read_rand(&mut iv)?;
+    let mut encrypted_session_key = [0_u8; SESSION_KEY_SIZE_U8];
+    let mut mac: sgx_aes_gcm_128bit_tag_t = [0; SGX_AESGCM_MAC_SIZE];
+    rsgx_rijndael128GCM_encrypt(
+        key_encryption_key,
+        &channel.key,
+        &iv,
+        &transcript,
+        &mut encrypted_session_key,
+        &mut mac,
+    )?;
+
+    let mut wrapped_key = [0_u8; WRAPPED_KEY_SIZE_U8];
+    wrapped_key[..SGX_AESGCM_IV_SIZE].copy_from_slice(&iv);
+    wrapped_key[SGX_AESGCM_IV_SIZE..SGX_AESGCM_IV_SIZE+SESSION_KEY_SIZE_U8].copy_from_slice(&encrypted_session_key);
+    wrapped_key[SGX_AESGCM_IV_SIZE+SESSION_KEY_SIZE_U8..].copy_from_slice(&mac);
+
+    // report_data = SHA256(client public key + enclave public key + session id + wrapped key)
+    let mut bound = transcript;
+    bound.extend_from_slice(&channel.session_id.to_be_bytes());
+    bound.extend_from_slice(&wrapped_key);
+    let hash = rsgx_sha256_slice(&bound)?;
+    let mut report_data = sgx_report_data_t::default();
+    report_data.d[..SGX_SHA256_HASH_SIZE].copy_from_slice(&hash);
+    let report = rsgx_create_report(target_info, &report_data)?;
+
+    Ok((channel, KeyExchangeResponse { enclave_public_key, report, wrapped_key }))
+}
+
+/*
+    Client identity
+        clientは長期のidentity key (P-256) で自分のephemeral keyに署名してkey exchangeに添える
+        identity keyにはdata ownerの証明書 (identity keyへのdata ownerの署名) が要る
+        hostやclientが自分で作ったidentity keyは通らないので，rate limitを新しいidentityで逃れられない
+        identityはidentity keyのSHA-256で，anti-probingのrate limitのkeyにする
+*/
+pub type ClientIdentity = sgx_sha256_hash_t;
+
+// 同じdata ownerの鍵で署名するので，証明書とkey exchangeの署名を取り違えないように前に付ける
+const IDENTITY_CERTIFICATE_CONTEXT: &[u8] = b"client identity certificate";
+const DATA_OWNER_KEY_EXCHANGE_CONTEXT: &[u8] = b"data owner key exchange";
+
+pub fn verify_client_identity(
+    client_public_key: &sgx_ec256_public_t,
+    identity_key: &sgx_ec256_public_t,
+    signature: &sgx_ec256_signature_t,
+    certificate: &sgx_ec256_signature_t,
+) -> EnclaveResult<ClientIdentity> {
+    let identity_key_bytes = _public_key_bytes(identity_key);
+    if !_verify_signature(&_with_context(IDENTITY_CERTIFICATE_CONTEXT, &identity_key_bytes), &_data_owner_key(), certificate)?
+        || !_verify_signature(&_public_key_bytes(client_public_key), identity_key, signature)? {
+        return Err(EnclaveError::IdentityVerificationFailed);
+    }
+    Ok(rsgx_sha256_slice(&identity_key_bytes)?)
+}
+
+/*
+    Data owner
+        data ownerはenclaveに埋め込んだ鍵 (DATA_OWNER_PUBLIC_KEY_*) で自分のephemeral keyに署名してcentral_key_exchangeに添える
+        署名がないとhostが自分でdata ownerとしてkey exchangeし，作ったchunkで好きな場所を問い合わせられる
+*/
+pub fn verify_data_owner(
+    client_public_key: &sgx_ec256_public_t,
+    signature: &sgx_ec256_signature_t,
+) -> EnclaveResult<()> {
+    let signed = _with_context(DATA_OWNER_KEY_EXCHANGE_CONTEXT, &_public_key_bytes(client_public_key));
+    if !_verify_signature(&signed, &_data_owner_key(), signature)? {
+        return Err(EnclaveError::DataOwnerVerificationFailed);
+    }
+    Ok(())
+}
+
+fn _data_owner_key() -> sgx_ec256_public_t {
+    sgx_ec256_public_t { gx: DATA_OWNER_PUBLIC_KEY_GX, gy: DATA_OWNER_PUBLIC_KEY_GY }
+}
+
+fn _with_context(context: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut signed: Vec<u8> = Vec::with_capacity(context.len() + message.len());
+    signed.extend_from_slice(context);
+    signed.extend_from_slice(message);
+    signed
+}
+
+// gx + gy
+fn _public_key_bytes(public_key: &sgx_ec256_public_t) -> Vec<u8> {
+    let mut bytes: Vec<u8> = Vec::with_capacity(2*SGX_ECP256_KEY_SIZE);
+    bytes.extend_from_slice(&public_key.gx);
+    bytes.extend_from_slice(&public_key.gy);
+    bytes
+}
+
+fn _verify_signature(signed: &[u8], public_key: &sgx_ec256_public_t, signature: &sgx_ec256_signature_t) -> EnclaveResult<bool> {
+    let ecc_handle = SgxEccHandle::new();
+    ecc_handle.open()?;
+    let verified = ecc_handle.ecdsa_verify_slice(signed, public_key, signature);
+    ecc_handle.close()?;
+    // a key which is not on the curve is an error of the SDK
+    Ok(verified.unwrap_or(false))
+}
+
+// same derivation as the SGX remote attestation sample
+// KDK = CMAC(0, shared_key), KEK = CMAC(KDK, 0x01 || "SK" || 0x00 || 0x80 0x00)
+fn derive_key_encryption_key(shared_key: &sgx_ec256_dh_shared_t) -> SgxResult<sgx_key_128bit_t> {
+    let zero_key: sgx_cmac_128bit_key_t = [0; SGX_CMAC_KEY_SIZE];
+    let mut key_derivation_key = rsgx_rijndael128_cmac_slice(&zero_key, &shared_key.s)?;
+    let derivation_string: [u8; 6] = [0x01, b'S', b'K', 0x00, 0x80, 0x00];
+    let key_encryption_key = rsgx_rijndael128_cmac_slice(&key_derivation_key, &derivation_string);
+    zeroize(&mut key_derivation_key);
+    key_encryption_key
+}
+
+fn key_exchange_transcript(
+    client_public_key: &sgx_ec256_public_t,
+    enclave_public_key: &sgx_ec256_public_t,
+) -> Vec<u8> {
+    let mut transcript: Vec<u8> = Vec::with_capacity(4*SGX_ECP256_KEY_SIZE);
+    transcript.extend_from_slice(&client_public_key.gx);
+    transcript.extend_from_slice(&client_public_key.gy);
+    transcript.extend_from_slice(&enclave_public_key.gx);
+    transcript.extend_from_slice(&enclave_public_key.gy);
+    transcript
+}
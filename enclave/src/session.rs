@@ -0,0 +1,338 @@
+use sgx_trts::trts::rsgx_read_rand;
+use std::vec::Vec;
+use std::boxed::Box;
+use std::sync::Arc;
+use std::collections::HashMap;
+use primitive::QueryId;
+use constant::*;
+use secure_channel::{SecureChannel, ClientIdentity, message_aad, decrypt_message};
+use encoded_query_rep::EncodedQueryRep;
+use encoded_query_buffer::EncodedQueryBuffer;
+use encoded_result_buffer::EncodedResultBuffer;
+use chunk_manifest::ChunkManifest;
+use chunk_header::is_trajectory_hash_size;
+use join_mode::JoinMode;
+use query_index::QueryIndex;
+use query_variants;
+use contact_policy::{ContactPolicy, PolicyVariants};
+use exact_point::ExactThresholds;
+use query_guard::{self, RateLimits};
+use utils::zeroize;
+use enclave_error::{EnclaveError, EnclaveResult};
+
+pub type SessionHandle = u64;
+
+// Open -> (begin_upload) Uploading -> (finish_upload) Uploaded
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SessionState {
+    Open,
+    Uploading,
+    Uploaded,
+}
+
+impl Default for SessionState {
+    fn default() -> Self {
+        SessionState::Open
+    }
+}
+
+/*
+Type Session
+    open_sessionで開くクエリのバッチ1つ分のステート
+    clientのsession key，復号したquery，result，処理済みのchunkをsessionごとに持つので
+    複数のバッチを同時に扱える．close_sessionで捨てるとqueryとresultはそれぞれのdropでゼロ埋めされる
+    SessionとSecureChannelはBoxに入れる，HashMapのrehashで動くとゼロ埋めされないコピーが古い領域に残るため
+    queryはuploadが終わると読むだけなので，chunkを処理する各スレッドにArcで渡す
+*/
+#[derive(Default)]
+pub struct Session {
+    pub state: SessionState,
+    pub clients: HashMap<QueryId, Box<SecureChannel>>,
+    // key exchangeで署名を確かめたclientのidentity，rate limitのkey
+    pub client_identities: HashMap<QueryId, ClientIdentity>,
+    pub query_buffer: Arc<EncodedQueryBuffer>,
+    pub result_buffer: EncodedResultBuffer,
+    // JoinMode::Adaptiveのときfinish_uploadで作るqueryの索引
+    pub query_index: Option<Arc<QueryIndex>>,
+    // nfpのJoinMode::Dictionaryのときfinish_uploadで作る近傍keyの索引
+    pub neighbor_index: Option<Arc<QueryIndex>>,
+    // set_contact_policiesで受け取る，なければDirectだけ
+    contact_policies: Vec<ContactPolicy>,
+    // finish_uploadでcontact_policiesから作る
+    pub policy_variants: PolicyVariants,
+    // set_exact_thresholdsで受け取る，あればtrieのhitを生の点で確かめる
+    exact_thresholds: Option<ExactThresholds>,
+    // finish_upload時にcentral dataのmanifestを写す
+    pub chunk_manifest: Option<ChunkManifest>,
+    // begin_uploadで宣言されたclient数
+    upload_client_size: usize,
+    // restart_chunksで増える，それより前に始まったchunkの部分結果は捨てる
+    pub chunk_generation: u64,
+    // get_exposure_statisticsで使ったepsilonの合計，STATISTICS_EPSILON_BUDGETを超えて出さない
+    statistics_epsilon_spent: f64,
+}
+
+impl Session {
+    pub fn client(&self, query_id: &QueryId) -> Option<&SecureChannel> {
+        self.clients.get(query_id).map(|channel| &**channel)
+    }
+
+    pub fn is_uploaded(&self) -> bool {
+        self.state == SessionState::Uploaded
+    }
+
+    // key exchangeはbegin_uploadより前だけ，query idごとに1回
+    // あとから同じquery idのchannelを差し替えられると，hostが自分のkeyでclientのresultを読める
+    pub fn check_new_client(&self, query_id: &QueryId) -> EnclaveResult<()> {
+        if self.state != SessionState::Open {
+            return Err(EnclaveError::WrongSessionState);
+        }
+        if self.clients.contains_key(query_id) || self.client_identities.contains_key(query_id) {
+            return Err(EnclaveError::ClientAlreadyKeyed);
+        }
+        Ok(())
+    }
+
+    pub fn add_client(&mut self, query_id: QueryId, channel: SecureChannel, identity: ClientIdentity) -> EnclaveResult<()> {
+        self.check_new_client(&query_id)?;
+        self.clients.insert(query_id, Box::new(channel));
+        self.client_identities.insert(query_id, identity);
+        Ok(())
+    }
+
+    /*
+        Streaming upload
+            clientのqueryを何回かに分けて受け取り，1 clientずつ復号してそのまま保持する
+            バッチ全体の平文バッファを作らないので，enclave内の確保はqueryの分だけで済む
+    */
+    pub fn begin_upload(&mut self, encoded_value_size: usize, client_size: usize, with_time: bool) -> EnclaveResult<()> {
+        // one query batch per session
+        if self.state != SessionState::Open {
+            return Err(EnclaveError::WrongSessionState);
+        }
+        let mut query_buffer = EncodedQueryBuffer::new(encoded_value_size, with_time);
+        query_buffer.exact_thresholds = self.exact_thresholds;
+        self.query_buffer = Arc::new(query_buffer);
+        self.upload_client_size = client_size;
+        self.state = SessionState::Uploading;
+        Ok(())
+    }
+
+    // clientのmessageの大きさが変わるのでbegin_uploadより前に決める
+    pub fn set_exact_thresholds(&mut self, exact_thresholds: ExactThresholds) -> EnclaveResult<()> {
+        if self.state != SessionState::Open {
+            return Err(EnclaveError::WrongSessionState);
+        }
+        self.exact_thresholds = Some(exact_thresholds);
+        Ok(())
+    }
+
+    // query_data: (iv + encrypted queries + mac) * query_ids.len()
+    // exact verificationのときqueriesはkeyの後ろに生の点(RAW_POINT_SIZE_U8)をQUERY_SIZE個並べる
+    // 最後にdummy pointのbitmask (DUMMY_MASK_SIZE_U8)，hostにはどのclientも同じ大きさに見える
+    // 途中で失敗したときはこの呼び出しの分を1つも追加しない (rate limitも数えない)
+    // 復号できたqueryはrate limitで1つ数え，limitを超えたかquery_guardを通らなければrejectする
+    pub fn append_queries(&mut self, query_data: &[u8], query_ids: &[QueryId], rate_limits: &mut RateLimits, now: u64) -> EnclaveResult<()> {
+        if self.state != SessionState::Uploading {
+            return Err(EnclaveError::WrongSessionState);
+        }
+        if self.query_buffer.queries.len() + query_ids.len() > self.upload_client_size {
+            return Err(EnclaveError::InvalidLength);
+        }
+        let point_size = self.query_buffer.point_size();
+        let query_bytes = QUERY_SIZE*point_size;
+        let raw_bytes = if self.query_buffer.exact_thresholds.is_some() { QUERY_SIZE*RAW_POINT_SIZE_U8 } else { 0 };
+        let message_bytes = query_bytes + raw_bytes + DUMMY_MASK_SIZE_U8 + MESSAGE_OVERHEAD_U8;
+        if query_data.len() != query_ids.len()*message_bytes {
+            return Err(EnclaveError::InvalidLength);
+        }
+
+        // 失敗したときは途中まで復号したqueryもdropでゼロ埋めされる
+        let mut queries: Vec<EncodedQueryRep> = Vec::with_capacity(query_ids.len());
+        let mut identities: Vec<ClientIdentity> = Vec::with_capacity(query_ids.len());
+        for (message, query_id) in query_data.chunks_exact(message_bytes).zip(query_ids.iter()) {
+            // session key has been exchanged by client_key_exchange in this session, before begin_upload
+            let channel = self.clients.get(query_id).ok_or(EnclaveError::UnknownClient)?;
+            identities.push(*self.client_identities.get(query_id).ok_or(EnclaveError::UnknownClient)?);
+            let mut query = EncodedQueryRep::new(point_size);
+            query.id = *query_id;
+            query.parameters.resize(query_bytes + raw_bytes + DUMMY_MASK_SIZE_U8, 0);
+            decrypt_message(&channel.key, message, &message_aad(channel.session_id, *query_id), &mut query.parameters)?;
+            query.dummies.extend_from_slice(&query.parameters[query_bytes + raw_bytes..]);
+            if raw_bytes > 0 {
+                query.raw_points.extend_from_slice(&query.parameters[query_bytes..query_bytes + raw_bytes]);
+            }
+            zeroize(&mut query.parameters[query_bytes..]);
+            query.parameters.truncate(query_bytes);
+            queries.push(query);
+        }
+        let query_buffer = self.query_buffer_mut()?;
+        let (encoded_value_size, with_time) = (query_buffer.encoded_value_size, query_buffer.with_time);
+        let rejected: Vec<bool> = queries.iter().zip(identities.iter()).map(|(query, identity)| {
+            let within_limit = rate_limits.charge(identity, now);
+            !(within_limit && query_guard::is_plausible(query, encoded_value_size, with_time))
+        }).collect();
+        query_buffer.queries.extend(queries);
+        query_buffer.rejected.extend(rejected);
+        Ok(())
+    }
+
+    // 宣言した数のclientがそろったらchunkを受け付ける
+    // upload が終わるまでにbatchのcontact policyを決める
+    pub fn set_contact_policies(&mut self, contact_policies: &[ContactPolicy]) -> EnclaveResult<()> {
+        if self.state == SessionState::Uploaded {
+            return Err(EnclaveError::WrongSessionState);
+        }
+        self.contact_policies = contact_policies.to_vec();
+        Ok(())
+    }
+
+    pub fn finish_upload(&mut self, chunk_manifest: ChunkManifest, join_mode: JoinMode) -> EnclaveResult<()> {
+        if self.state != SessionState::Uploading {
+            return Err(EnclaveError::WrongSessionState);
+        }
+        if self.query_buffer.queries.len() != self.upload_client_size {
+            return Err(EnclaveError::IncompleteUpload);
+        }
+        // nfpのDictionaryはchunkとqueryのkeyをTrajectoryHashの近傍に広げる
+        if cfg!(feature = "nfp") && join_mode == JoinMode::Dictionary && !is_trajectory_hash_size(self.query_buffer.encoded_value_size) {
+            return Err(EnclaveError::NotTrajectoryHash);
+        }
+        if self.contact_policies.is_empty() {
+            self.contact_policies.push(ContactPolicy::direct());
+        }
+        let policy_variants = PolicyVariants::from_policies(&self.contact_policies, join_mode.with_time())?;
+        // ずらしたkeyのhitをclientの生の点で確かめても意味がないので，exact verificationはDirectだけ
+        if self.exact_thresholds.is_some() && policy_variants.variants.len() > 1 {
+            return Err(EnclaveError::InvalidContactPolicy);
+        }
+        if policy_variants.variants.len() > 1 {
+            query_variants::expand(self.query_buffer_mut()?, &policy_variants.variants)?;
+        }
+        self.policy_variants = policy_variants;
+        self.query_buffer_mut()?.queries.shrink_to_fit();
+        if join_mode == JoinMode::Adaptive {
+            self.query_index = Some(Arc::new(QueryIndex::build(&self.query_buffer)));
+        }
+        #[cfg(all(feature = "fsa", feature = "nfp"))]
+        {
+            if join_mode == JoinMode::Dictionary {
+                self.neighbor_index = Some(Arc::new(QueryIndex::build_neighbors(&self.query_buffer)?));
+            }
+        }
+        self.result_buffer = self.new_result_buffer();
+        self.chunk_manifest = Some(chunk_manifest);
+        self.state = SessionState::Uploaded;
+        Ok(())
+    }
+
+    // chunkを分け直したmanifestで最初からやり直す，それまでの結果と処理済みのchunkは捨てる
+    pub fn restart_chunks(&mut self, chunk_manifest: ChunkManifest) -> EnclaveResult<()> {
+        if self.state != SessionState::Uploaded {
+            return Err(EnclaveError::WrongSessionState);
+        }
+        self.result_buffer = self.new_result_buffer();
+        self.chunk_manifest = Some(chunk_manifest);
+        self.chunk_generation += 1;
+        Ok(())
+    }
+
+    // restart_chunksしても戻さない，失敗したreleaseの分も使ったことにする
+    pub fn spend_statistics_epsilon(&mut self, epsilon: f64) -> EnclaveResult<()> {
+        if self.statistics_epsilon_spent + epsilon > STATISTICS_EPSILON_BUDGET {
+            return Err(EnclaveError::PrivacyBudgetExceeded);
+        }
+        self.statistics_epsilon_spent += epsilon;
+        Ok(())
+    }
+
+    // clientのdummy pointのmaskを並べて持たせる
+    fn new_result_buffer(&self) -> EncodedResultBuffer {
+        let client_count = self.query_buffer.client_count();
+        let mut dummies: Vec<u8> = Vec::with_capacity(client_count*DUMMY_MASK_SIZE_U8);
+        for query in self.query_buffer.queries.iter().take(client_count) {
+            dummies.extend_from_slice(&query.dummies);
+        }
+        EncodedResultBuffer::new(self.query_buffer.queries.len(), self.policy_variants.tracks_points()).with_dummies(dummies, client_count)
+    }
+
+    // upload後にJoinMode::Adaptiveに切り替えられたときはchunkごとに作る
+    pub fn query_index(&self) -> Arc<QueryIndex> {
+        match self.query_index {
+            Some(ref query_index) => query_index.clone(),
+            None => Arc::new(QueryIndex::build(&self.query_buffer)),
+        }
+    }
+
+    // upload後にnfpのJoinMode::Dictionaryに切り替えられたときはchunkごとに作る
+    #[cfg(all(feature = "fsa", feature = "nfp"))]
+    pub fn neighbor_index(&self) -> EnclaveResult<Arc<QueryIndex>> {
+        match self.neighbor_index {
+            Some(ref neighbor_index) => Ok(neighbor_index.clone()),
+            None => Ok(Arc::new(QueryIndex::build_neighbors(&self.query_buffer)?)),
+        }
+    }
+
+    // chunkの照合に使うqueryの索引，Adaptiveはquery key，nfpのDictionaryは近傍key
+    pub fn query_index_for(&self, join_mode: JoinMode) -> EnclaveResult<Option<Arc<QueryIndex>>> {
+        match join_mode {
+            JoinMode::Adaptive => Ok(Some(self.query_index())),
+            #[cfg(all(feature = "fsa", feature = "nfp"))]
+            JoinMode::Dictionary => Ok(Some(self.neighbor_index()?)),
+            _ => Ok(None),
+        }
+    }
+
+    // upload中はchunkの処理が始まっていないので，他にArcを持っているスレッドはない
+    fn query_buffer_mut(&mut self) -> EnclaveResult<&mut EncodedQueryBuffer> {
+        Arc::get_mut(&mut self.query_buffer).ok_or(EnclaveError::WrongSessionState)
+    }
+}
+
+/*
+Type Sessions
+    handleからSessionを引く，知らないhandleはUnknownSession
+*/
+#[derive(Default)]
+pub struct Sessions {
+    sessions: HashMap<SessionHandle, Box<Session>>,
+}
+
+impl Sessions {
+    pub fn new() -> Self {
+        Sessions::default()
+    }
+
+    // handleは推測されないように乱数にする，0は使わない
+    pub fn open(&mut self) -> EnclaveResult<SessionHandle> {
+        if self.sessions.len() >= MAX_SESSIONS {
+            return Err(EnclaveError::TooManySessions);
+        }
+        loop {
+            let mut handle_bytes = [0_u8; 8];
+            rsgx_read_rand(&mut handle_bytes)?;
+            let handle: SessionHandle = u64::from_be_bytes(handle_bytes);
+            if handle != 0 && !self.sessions.contains_key(&handle) {
+                self.sessions.insert(handle, Box::new(Session::default()));
+                return Ok(handle);
+            }
+        }
+    }
+
+    pub fn get(&self, handle: SessionHandle) -> EnclaveResult<&Session> {
+        self.sessions.get(&handle).map(|session| &**session).ok_or(EnclaveError::UnknownSession)
+    }
+
+    pub fn get_mut(&mut self, handle: SessionHandle) -> EnclaveResult<&mut Session> {
+        self.sessions.get_mut(&handle).map(|session| &mut **session).ok_or(EnclaveError::UnknownSession)
+    }
+
+    // dropでquery，result，keyがゼロ埋めされる
+    // chunkを処理中のスレッドが持っているqueryはそのスレッドが終わったときに消える
+    pub fn close(&mut self, handle: SessionHandle) -> EnclaveResult<()> {
+        match self.sessions.remove(&handle) {
+            Some(_) => Ok(()),
+            None => Err(EnclaveError::UnknownSession),
+        }
+    }
+}
@@ -1,4 +1,6 @@
 use std::vec::Vec;
+use std::ptr;
+use std::sync::atomic::{compiler_fence, Ordering};
 use primitive::*;
 
 
@@ -67,4 +69,12 @@ pub fn _sorted_push(sorted_list: &mut Vec<UnixEpoch>, unixepoch: UnixEpoch) {
         }
     }
     sorted_list.push(unixepoch);
-}
\ No newline at end of file
+}
+
+// 復号したqueryやkeyを捨てる前にゼロ埋めする，volatileなので最適化で消されない
+pub fn zeroize(bytes: &mut [u8]) {
+    for byte in bytes.iter_mut() {
+        unsafe { ptr::write_volatile(byte, 0) };
+    }
+    compiler_fence(Ordering::SeqCst);
+}
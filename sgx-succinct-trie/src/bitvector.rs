@@ -8,6 +8,12 @@ pub struct BitVector {
     bits: Vec<word_t>,
 }
 
+impl Drop for BitVector {
+    fn drop(&mut self) {
+        crate::wipe(&mut self.bits);
+    }
+}
+
 impl BitVector{
     pub fn serialize(&self) -> Vec<u8> {
         let mut bytes: Vec<u8> = Vec::with_capacity(1000);
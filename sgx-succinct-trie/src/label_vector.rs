@@ -6,6 +6,12 @@ pub struct LabelVector {
     labels: Vec<label_t>,
 }
 
+impl Drop for LabelVector {
+    fn drop(&mut self) {
+        crate::wipe(&mut self.labels);
+    }
+}
+
 impl LabelVector {
     pub fn serialize(&self) -> Vec<u8> {
         let mut bytes: Vec<u8> = Vec::with_capacity(1000);
@@ -22,3 +22,11 @@ mod select;
 mod suffix;
 mod cache;
 pub mod trie;
+
+// Overwrites the key-derived contents with zeros before they are freed.
+pub(crate) fn wipe<T: Copy + Default>(values: &mut [T]) {
+    for value in values.iter_mut() {
+        unsafe { core::ptr::write_volatile(value, T::default()) };
+    }
+    core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::SeqCst);
+}
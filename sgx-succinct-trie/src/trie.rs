@@ -13,6 +13,12 @@ pub struct Trie {
     suffix_ptrs: CompactArray,
 }
 
+impl Drop for Trie {
+    fn drop(&mut self) {
+        crate::wipe(&mut self.suffixes);
+    }
+}
+
 struct CompactArray {
     size: u32,
     mask: u32,
@@ -20,6 +26,12 @@ struct CompactArray {
     chunks: Vec<u32>,
 }
 
+impl Drop for CompactArray {
+    fn drop(&mut self) {
+        crate::wipe(&mut self.chunks);
+    }
+}
+
 impl CompactArray {
     pub fn serialize(&self) -> Vec<u8> {
         let mut bytes: Vec<u8> = Vec::with_capacity(1000);
@@ -0,0 +1,36 @@
+"""Generate the data owner's P-256 signing key.
+
+The private key is written to the given file in hex (little endian as the SGX SDK),
+it is passed to bin/app with --data-owner-key and must stay with the data owner.
+The public key (gx || gy, little endian) is printed as DATA_OWNER_PUBLIC_KEY,
+the enclave is built with it.
+
+usage: python3 keygen.py <private key file>
+"""
+import os
+import sys
+
+from cryptography.hazmat.primitives.asymmetric import ec
+
+
+def _little_endian_hex(value):
+    return value.to_bytes(32, "big")[::-1].hex()
+
+
+def main():
+    if len(sys.argv) != 2:
+        print(__doc__)
+        sys.exit(1)
+    path = sys.argv[1]
+    private_key = ec.generate_private_key(ec.SECP256R1())
+    numbers = private_key.private_numbers()
+    # only the owner can read the private key
+    fd = os.open(path, os.O_WRONLY | os.O_CREAT | os.O_EXCL, 0o600)
+    with os.fdopen(fd, "w") as f:
+        f.write(_little_endian_hex(numbers.private_value) + "\n")
+    public_numbers = numbers.public_numbers
+    print("DATA_OWNER_PUBLIC_KEY=" + _little_endian_hex(public_numbers.x) + _little_endian_hex(public_numbers.y))
+
+
+if __name__ == "__main__":
+    main()
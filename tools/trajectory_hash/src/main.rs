@@ -38,7 +38,11 @@ struct Opts {
 
     /// data flag because CSV data had some format... 1 => 'time', 'lat', 'lng' , 2 => 'time', 'lng', 'lat'
     #[clap(short, long, default_value = "1")]
-    format: String
+    format: String,
+
+    /// also write time, lat and lng after each hash, for exact verification in the enclave (--exact-distance)
+    #[clap(long)]
+    with_raw: bool,
 }
 
 fn main() {
@@ -54,8 +58,12 @@ fn main() {
     match opts.target.as_str() {
         "server" => {
             let trajectories = utils::read_trajectory_from_csv(opts.input_file.as_str(), true, format);
-            let hashed = utils::bulk_encode(trajectories, &mix_type, opts.theta_t, opts.theta_l, time_period);
-            utils::write_trajectory_hash_csv(opts.output_file.as_str(), hashed);
+            let hashed = utils::bulk_encode(&trajectories, &mix_type, opts.theta_t, opts.theta_l, time_period);
+            if opts.with_raw {
+                utils::write_trajectory_hash_with_raw_csv(opts.output_file.as_str(), &trajectories, hashed);
+            } else {
+                utils::write_trajectory_hash_csv(opts.output_file.as_str(), hashed);
+            }
         },
         "client" => {
             let re = Regex::new(r".+/client-(?P<client_id>\d+)-.+.csv").unwrap();
@@ -69,8 +77,13 @@ fn main() {
                         };
                         let client_id: u32 = caps["client_id"].parse().unwrap();
                         let trajectories = utils::read_trajectory_from_csv(path.to_str().unwrap(), true, format);
-                        let hashed = utils::bulk_encode(trajectories, &mix_type, opts.theta_t, opts.theta_l, time_period);
-                        utils::write_trajectory_hash_csv(format!("{}-{}.csv", opts.output_file.as_str(), client_id).as_str(), hashed);
+                        let hashed = utils::bulk_encode(&trajectories, &mix_type, opts.theta_t, opts.theta_l, time_period);
+                        let output_file = format!("{}-{}.csv", opts.output_file.as_str(), client_id);
+                        if opts.with_raw {
+                            utils::write_trajectory_hash_with_raw_csv(output_file.as_str(), &trajectories, hashed);
+                        } else {
+                            utils::write_trajectory_hash_csv(output_file.as_str(), hashed);
+                        }
                     },
                     Err(_) => panic!("failed to find path"),
                 }
@@ -82,8 +82,33 @@ pub fn write_trajectory_hash_csv(filename: &str, trajectory_hashes: Vec<Vec<u8>>
     csv_writer.flush().expect("flush doesn't work");
 }
 
+// "hash,time,latitude,longitude", read by the app with --exact-distance
+pub fn write_trajectory_hash_with_raw_csv(filename: &str, trajectories: &[Trajectory], trajectory_hashes: Vec<Vec<u8>>) {
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .write(true)
+        .open(filename)
+        .expect("file open error");
+    let writer = BufWriter::new(file);
+    let mut csv_writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(writer);
+    for (trajectory, hash) in trajectories.iter().zip(trajectory_hashes) {
+        csv_writer
+            .write_record(&[
+                Trajectory::serialize_to_string_record(hash),
+                trajectory.time.to_string(),
+                trajectory.latitude.to_string(),
+                trajectory.longitude.to_string(),
+            ])
+            .expect("write falied");
+    }
+    csv_writer.flush().expect("flush doesn't work");
+}
+
 pub fn bulk_encode(
-    trajectories: Vec<Trajectory>,
+    trajectories: &[Trajectory],
     mix_type: &MixType,
     theta_t: u32,
     theta_l: u32,